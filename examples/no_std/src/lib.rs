@@ -0,0 +1,43 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Building and encoding messages without std. This crate exists to check
+//! that ciborium-rpc builds with `default-features = false`:
+//! ```sh
+//! cargo build -p ciborium-rpc-no-std
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use ciborium_rpc::proto::v0::RPCMsg;
+use ciborium_rpc::proto::{Params, Request, Response};
+
+/// Encode a request for `method`, with the given positional args.
+pub fn encode_request(method: &str, args: &[i64], id: u64) -> Option<Vec<u8>> {
+    let mut params = Params::default();
+    for arg in args {
+        params.push(*arg).ok()?;
+    }
+    let request = Request::new(method, params.into_option(), Some(id.into()));
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&RPCMsg::from(request), &mut buf).ok()?;
+    Some(buf)
+}
+
+/// Decode a request and encode a reply to it, with the sum of its args.
+pub fn reply(bytes: &[u8]) -> Option<Vec<u8>> {
+    let msg: RPCMsg = ciborium::de::from_reader(bytes).ok()?;
+    let request = Request::try_from(msg).ok()?;
+    let mut sum = 0i64;
+    for (_, arg) in request.params().clone().unwrap_or_default() {
+        sum += i64::try_from(arg.as_integer()?).ok()?;
+    }
+    let response = Response::ok(sum, request.req_id().clone()?);
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&RPCMsg::from(response), &mut buf).ok()?;
+    Some(buf)
+}
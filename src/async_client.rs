@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! An async RPC client, for use with tokio.
+//!
+//! [AsyncClient::new] takes an [AsyncTransport] and gives back the client and
+//! a driver future, which does all the reading and writing; spawn it (or
+//! otherwise poll it) for the client to get anywhere. The client is just a
+//! handle to the driver, so it's cheap to clone, and any number of tasks can
+//! make calls through it at once. Each call waits on a oneshot channel, and
+//! the driver routes each Response to the channel for its `req_id`.
+//!
+//! The driver finishes with `Ok(())` once every handle is gone, or with the
+//! error if reading from the transport fails. Calls still waiting when it
+//! stops fail with [TransportError::Eof].
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::client::{RequestIdGen, SequentialIds};
+use crate::error::{ClientError, TransportError};
+use crate::proto::{MethodID, Notification, Params, Request, RequestID, Value};
+use crate::transport::AsyncTransport;
+
+// What the handles ask the driver to do
+enum Command {
+    Call(Request, oneshot::Sender<Result<Value, ClientError>>),
+    Notify(Request, oneshot::Sender<Result<(), TransportError>>),
+}
+
+/// A handle for making calls through a driver; see the [module docs](self).
+pub struct AsyncClient<G = SequentialIds> {
+    commands: mpsc::UnboundedSender<Command>,
+    ids: Arc<Mutex<G>>,
+}
+
+impl<G> Clone for AsyncClient<G> {
+    fn clone(&self) -> Self {
+        Self {
+            commands: self.commands.clone(),
+            ids: self.ids.clone(),
+        }
+    }
+}
+
+impl AsyncClient {
+    /// Create a client that numbers its requests from 0, and the driver
+    /// that runs it.
+    pub fn new<C>(
+        transport: AsyncTransport<C>,
+    ) -> (Self, impl Future<Output = Result<(), TransportError>>)
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        Self::with_id_gen(transport, SequentialIds::default())
+    }
+}
+
+impl<G: RequestIdGen> AsyncClient<G> {
+    pub fn with_id_gen<C>(
+        transport: AsyncTransport<C>,
+        ids: G,
+    ) -> (Self, impl Future<Output = Result<(), TransportError>>)
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let client = Self {
+            commands,
+            ids: Arc::new(Mutex::new(ids)),
+        };
+        (client, drive(transport, receiver))
+    }
+
+    /// Call `method` with the given `params` and wait for the result.
+    pub async fn call(
+        &self,
+        method: impl Into<MethodID>,
+        params: Option<Params>,
+    ) -> Result<Value, ClientError> {
+        let req_id = self.ids.lock().unwrap().next_id();
+        let (reply, result) = oneshot::channel();
+        let request = Request::new(method, params, Some(req_id));
+        self.send(Command::Call(request, reply))?;
+        result.await.unwrap_or(Err(TransportError::Eof.into()))
+    }
+
+    /// Send a notification for `method`. This only waits for it to be sent,
+    /// since the server won't reply.
+    pub async fn notify(
+        &self,
+        method: impl Into<MethodID>,
+        params: Option<Params>,
+    ) -> Result<(), ClientError> {
+        let (reply, result) = oneshot::channel();
+        let request = Notification::new(method, params).into();
+        self.send(Command::Notify(request, reply))?;
+        Ok(result.await.unwrap_or(Err(TransportError::Eof))?)
+    }
+
+    fn send(&self, command: Command) -> Result<(), ClientError> {
+        self.commands
+            .send(command)
+            .map_err(|_| TransportError::Eof.into())
+    }
+}
+
+async fn drive<C>(
+    mut transport: AsyncTransport<C>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) -> Result<(), TransportError>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut pending: HashMap<RequestID, oneshot::Sender<Result<Value, ClientError>>> =
+        HashMap::new();
+    loop {
+        // Reading is cancel-safe - anything read stays in the transport's
+        // buffer - so it's fine to drop it whenever a command comes in.
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(Command::Call(request, reply)) => {
+                    let req_id = request.req_id().clone().unwrap();
+                    match transport.send_request(request).await {
+                        Ok(()) => {
+                            pending.insert(req_id, reply);
+                        }
+                        Err(e) => {
+                            let _ = reply.send(Err(e.into()));
+                        }
+                    }
+                }
+                Some(Command::Notify(request, reply)) => {
+                    let _ = reply.send(transport.send_request(request).await);
+                }
+                None => return Ok(()),
+            },
+            response = transport.read_response() => {
+                // We only want final results, and nobody's waiting for a
+                // Response with an id we don't know.
+                let response = response?;
+                if response.is_partial() {
+                    continue;
+                }
+                if let Some(reply) = pending.remove(response.req_id()) {
+                    let result = response.into_result_value();
+                    let _ = reply.send(result.map_err(ClientError::Application));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncClient;
+    use crate::error::{ClientError, TransportError};
+    use crate::proto::{ErrorValue, Params, Response, Value};
+    use crate::transport::AsyncTransport;
+
+    #[tokio::test]
+    async fn concurrent_calls() {
+        let (c, s) = tokio::io::duplex(4096);
+        let (client, driver) = AsyncClient::new(AsyncTransport::new(c));
+        let driver = tokio::spawn(driver);
+        let server = tokio::spawn(async move {
+            let mut server = AsyncTransport::new(s);
+            // Wait for both calls and the notification, then answer the
+            // calls in the opposite order.
+            let mut reqs = Vec::new();
+            for _ in 0..3 {
+                reqs.push(server.read_request().await.unwrap());
+            }
+            assert!(reqs[2].is_notification());
+            for req in reqs[..2].iter().rev() {
+                let req_id = req.req_id().clone().unwrap();
+                let resp = match req.method().to_string().as_str() {
+                    "echo" => Response::ok(Value::from(req.params().clone().unwrap()), req_id),
+                    _ => Response::err(ErrorValue::method_not_found(req.method()), req_id),
+                };
+                server.send_response(resp).await.unwrap();
+            }
+        });
+
+        let other = client.clone();
+        let echo = tokio::spawn(async move {
+            let params = Params::Array(vec![1.into()]);
+            other.call("echo", Some(params)).await
+        });
+        // Make sure the echo call goes out first
+        tokio::task::yield_now().await;
+        let (nope, notified) = tokio::join!(client.call("nope", None), client.notify("hi", None));
+        notified.unwrap();
+        assert!(matches!(nope, Err(ClientError::Application(_))));
+        assert_eq!(echo.await.unwrap().unwrap(), Value::Array(vec![1.into()]));
+        server.await.unwrap();
+
+        // With the server gone, calls fail and the driver stops
+        assert!(matches!(
+            client.call("echo", None).await,
+            Err(ClientError::Transport(TransportError::Eof))
+        ));
+        assert!(matches!(driver.await.unwrap(), Err(TransportError::Eof)));
+    }
+}
@@ -0,0 +1,657 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A simple blocking RPC client.
+
+use crate::error::{ClientError, TransportError};
+use crate::proto::{
+    ErrorValue, MethodID, Notification, Params, Request, RequestID, Response, Value,
+};
+use crate::transport::simple::ClientTransport;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+// ----- Request ID generators ------------------------------------------------
+
+/// A source of [RequestID]s for a [Client].
+///
+/// The ids only need to be unique among the calls that are in flight at the
+/// same time, but most generators just never repeat.
+pub trait RequestIdGen {
+    fn next_id(&mut self) -> RequestID;
+}
+
+/// Numeric ids, counting up from a starting value.
+#[derive(Debug, Clone, Default)]
+pub struct SequentialIds {
+    next: u64,
+}
+
+impl SequentialIds {
+    pub fn new(start: u64) -> Self {
+        Self { next: start }
+    }
+}
+
+impl RequestIdGen for SequentialIds {
+    fn next_id(&mut self) -> RequestID {
+        let id = self.next;
+        self.next = self.next.wrapping_add(1);
+        id.into()
+    }
+}
+
+/// Random binary ids, `len` bytes long (16 by default).
+#[cfg(feature = "rand")]
+#[derive(Debug, Clone)]
+pub struct RandomBytesIds {
+    len: usize,
+}
+
+#[cfg(feature = "rand")]
+impl RandomBytesIds {
+    pub fn new(len: usize) -> Self {
+        Self { len }
+    }
+}
+
+#[cfg(feature = "rand")]
+impl Default for RandomBytesIds {
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+#[cfg(feature = "rand")]
+impl RequestIdGen for RandomBytesIds {
+    fn next_id(&mut self) -> RequestID {
+        use rand::RngCore;
+        let mut id = vec![0u8; self.len];
+        rand::thread_rng().fill_bytes(&mut id);
+        id.into()
+    }
+}
+
+// ----- Client ---------------------------------------------------------------
+
+/// A Client owns a [ClientTransport] and uses it to make calls, one at a time.
+///
+/// Each call gets a new [RequestID] from the Client's [RequestIdGen], and the
+/// Client checks that the Response it reads back carries the same id.
+///
+/// To have several calls in flight at once, [start](Self::start) each one and
+/// then [wait](Self::wait) for them in any order. The server can answer them
+/// in whatever order it likes; the Client keeps track of which calls are
+/// outstanding and holds on to each result until it's asked for.
+pub struct Client<T: ClientTransport, G = SequentialIds> {
+    transport: T,
+    ids: G,
+    // Calls made with `start`, and their final results once they arrive
+    pending: HashMap<RequestID, Option<Result<Value, ErrorValue>>>,
+}
+
+impl<T> Client<T>
+where
+    T: ClientTransport<Error = TransportError>,
+{
+    /// Create a Client that numbers its requests from 0.
+    pub fn new(transport: T) -> Self {
+        Self::with_id_gen(transport, SequentialIds::default())
+    }
+}
+
+impl<T, G> Client<T, G>
+where
+    T: ClientTransport<Error = TransportError>,
+    G: RequestIdGen,
+{
+    pub fn with_id_gen(transport: T, ids: G) -> Self {
+        Self {
+            transport,
+            ids,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// Call `method` with the given `params` and wait for the result.
+    pub fn call(
+        &mut self,
+        method: impl Into<MethodID>,
+        params: Option<Params>,
+    ) -> Result<Value, ClientError> {
+        let req_id = self.send_call(method, params)?;
+        // We only want the final result, so skip over any partial ones.
+        loop {
+            let response = self.read_matching(&req_id)?;
+            if !response.is_partial() {
+                return response
+                    .into_result_value()
+                    .map_err(ClientError::Application);
+            }
+        }
+    }
+
+    /// Call `method` and iterate over its results as they arrive.
+    ///
+    /// The iterator yields each partial result, then the final result (or
+    /// error), and then ends.
+    pub fn call_streaming(
+        &mut self,
+        method: impl Into<MethodID>,
+        params: Option<Params>,
+    ) -> Result<Streaming<'_, T, G>, ClientError> {
+        let req_id = self.send_call(method, params)?;
+        Ok(Streaming {
+            client: self,
+            req_id: Some(req_id),
+        })
+    }
+
+    fn send_call(
+        &mut self,
+        method: impl Into<MethodID>,
+        params: Option<Params>,
+    ) -> Result<RequestID, ClientError> {
+        let req_id = self.ids.next_id();
+        self.transport
+            .send_request(Request::new(method, params, Some(req_id.clone())))?;
+        Ok(req_id)
+    }
+
+    // Read the next Response for `req_id`, putting away any that are for
+    // calls made with `start`.
+    fn read_matching(&mut self, req_id: &RequestID) -> Result<Response, ClientError> {
+        loop {
+            let response = self.transport.read_response()?;
+            if response.req_id() == req_id {
+                return Ok(response);
+            }
+            if !self.pending.contains_key(response.req_id()) {
+                return Err(ClientError::IdMismatch {
+                    expected: req_id.clone(),
+                    got: response.req_id().clone(),
+                });
+            }
+            self.route(response);
+        }
+    }
+
+    /// Send a call to `method` without waiting for its result, and return
+    /// the [RequestID] to [wait](Self::wait) on.
+    pub fn start(
+        &mut self,
+        method: impl Into<MethodID>,
+        params: Option<Params>,
+    ) -> Result<RequestID, ClientError> {
+        let req_id = self.send_call(method, params)?;
+        self.pending.insert(req_id.clone(), None);
+        Ok(req_id)
+    }
+
+    /// Read one Response and hand it to the outstanding call it's for.
+    /// Partial results aren't kept; a call started this way only gets its
+    /// final result.
+    ///
+    /// A Response that isn't for any outstanding call is read and dropped,
+    /// and [ClientError::UnknownId] is returned so the caller can decide
+    /// what to make of it. The Client is still usable afterwards.
+    pub fn poll_responses(&mut self) -> Result<(), ClientError> {
+        let response = self.transport.read_response()?;
+        match self.pending.get(response.req_id()) {
+            Some(None) => {
+                self.route(response);
+                Ok(())
+            }
+            // Either we've never heard of it, or it's already finished
+            _ => Err(ClientError::UnknownId(response.req_id().clone())),
+        }
+    }
+
+    fn route(&mut self, response: Response) {
+        if !response.is_partial() {
+            let req_id = response.req_id().clone();
+            self.pending
+                .insert(req_id, Some(response.into_result_value()));
+        }
+    }
+
+    /// Wait for the result of a call made with [start](Self::start), reading
+    /// Responses (and holding on to those for other calls) until it arrives.
+    ///
+    /// If `req_id` isn't outstanding, this fails with
+    /// [ClientError::UnknownId] without reading anything. A stray Response
+    /// also stops the wait with an error, as in
+    /// [poll_responses](Self::poll_responses); waiting again carries on.
+    pub fn wait(&mut self, req_id: &RequestID) -> Result<Value, ClientError> {
+        loop {
+            match self.pending.get(req_id) {
+                None => return Err(ClientError::UnknownId(req_id.clone())),
+                Some(None) => self.poll_responses()?,
+                Some(Some(_)) => break,
+            }
+        }
+        let result = self.pending.remove(req_id).flatten().unwrap();
+        result.map_err(ClientError::Application)
+    }
+
+    /// The calls made with [start](Self::start) that haven't been waited on.
+    pub fn outstanding(&self) -> impl Iterator<Item = &RequestID> {
+        self.pending.keys()
+    }
+
+    /// Send a notification for `method`. This doesn't wait for anything,
+    /// since the server won't reply.
+    pub fn notify(
+        &mut self,
+        method: impl Into<MethodID>,
+        params: Option<Params>,
+    ) -> Result<(), ClientError> {
+        self.transport
+            .send_notification(Notification::new(method, params))?;
+        Ok(())
+    }
+}
+
+/// The iterator returned by [Client::call_streaming].
+pub struct Streaming<'a, T: ClientTransport, G> {
+    client: &'a mut Client<T, G>,
+    // None once the final result has been read
+    req_id: Option<RequestID>,
+}
+
+impl<T, G> Iterator for Streaming<'_, T, G>
+where
+    T: ClientTransport<Error = TransportError>,
+    G: RequestIdGen,
+{
+    type Item = Result<Value, ClientError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let req_id = self.req_id.take()?;
+        match self.client.read_matching(&req_id) {
+            Ok(response) if response.is_partial() => {
+                self.req_id = Some(req_id);
+                Some(Ok(response.into_result_value().unwrap()))
+            }
+            Ok(response) => Some(
+                response
+                    .into_result_value()
+                    .map_err(ClientError::Application),
+            ),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+// ----- Reconnecting and retrying --------------------------------------------
+
+/// How many times a [RetryingClient] retries, and how long it waits before
+/// each retry. The wait starts at `backoff` and doubles each time, up to
+/// `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .checked_mul(1 << retry.min(31))
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff))
+    }
+}
+
+/// A [Client] that replaces its transport when the connection fails.
+///
+/// A call that fails with [TransportError::Io] or [TransportError::Eof]
+/// leaves the connection marked as dead, and before the next attempt the
+/// RetryingClient gets a fresh transport from its `reconnect` closure.
+/// Reconnecting is retried according to the [RetryPolicy], since the server
+/// can't have seen anything yet.
+///
+/// Retrying the call itself is another matter. Once a Request has been sent -
+/// even partly - the server may have acted on it, and sending it again could
+/// do the work twice. So a failed [call](Self::call) is returned as an error,
+/// and only [call_idempotent](Self::call_idempotent) retries the Request on
+/// the new connection.
+pub struct RetryingClient<T: ClientTransport, F, G = SequentialIds> {
+    client: Client<T, G>,
+    reconnect: F,
+    policy: RetryPolicy,
+    dead: bool,
+}
+
+impl<T, F, G> RetryingClient<T, F, G>
+where
+    T: ClientTransport<Error = TransportError>,
+    F: FnMut() -> Result<T, TransportError>,
+    G: RequestIdGen,
+{
+    pub fn new(client: Client<T, G>, reconnect: F) -> Self {
+        Self {
+            client,
+            reconnect,
+            policy: RetryPolicy::default(),
+            dead: false,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn client(&self) -> &Client<T, G> {
+        &self.client
+    }
+
+    pub fn into_client(self) -> Client<T, G> {
+        self.client
+    }
+
+    /// Call `method`, reconnecting first if the last call lost the
+    /// connection. If this call loses it, the error is returned.
+    pub fn call(
+        &mut self,
+        method: impl Into<MethodID>,
+        params: Option<Params>,
+    ) -> Result<Value, ClientError> {
+        self.call_with_retries(method.into(), params, false)
+    }
+
+    /// Call `method`, reconnecting and calling it again if the connection is
+    /// lost along the way. Only use this for calls that are safe to repeat.
+    pub fn call_idempotent(
+        &mut self,
+        method: impl Into<MethodID>,
+        params: Option<Params>,
+    ) -> Result<Value, ClientError> {
+        self.call_with_retries(method.into(), params, true)
+    }
+
+    fn call_with_retries(
+        &mut self,
+        method: MethodID,
+        params: Option<Params>,
+        idempotent: bool,
+    ) -> Result<Value, ClientError> {
+        let mut retry = 0;
+        loop {
+            let error = match self.reconnect_if_dead() {
+                // Nothing has been sent, so this is always safe to retry
+                Err(e) => e,
+                Ok(()) => match self.client.call(method.clone(), params.clone()) {
+                    Err(ClientError::Transport(e)) if is_disconnect(&e) => {
+                        self.dead = true;
+                        if !idempotent {
+                            return Err(e.into());
+                        }
+                        e.into()
+                    }
+                    result => return result,
+                },
+            };
+            if retry == self.policy.max_retries {
+                return Err(error);
+            }
+            thread::sleep(self.policy.delay(retry));
+            retry += 1;
+        }
+    }
+
+    fn reconnect_if_dead(&mut self) -> Result<(), ClientError> {
+        if self.dead {
+            self.client.transport = (self.reconnect)()?;
+            self.dead = false;
+        }
+        Ok(())
+    }
+}
+
+fn is_disconnect(e: &TransportError) -> bool {
+    matches!(e, TransportError::Io(_) | TransportError::Eof)
+}
+
+#[cfg(all(test, unix, feature = "serde1"))]
+mod tests {
+    use super::{Client, RequestIdGen, RetryPolicy, RetryingClient, SequentialIds};
+    use crate::error::{ClientError, TransportError};
+    use crate::proto::{Params, RequestID, Response, Value};
+    use crate::transport::simple::ServerTransport;
+    use crate::transport::Transport;
+    use std::cell::Cell;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+
+    // A connection to a server that answers `replies` calls with "ok" and
+    // then hangs up.
+    fn connect(replies: usize) -> Transport<UnixStream> {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        thread::spawn(move || {
+            let mut tr = Transport::new(s2);
+            for _ in 0..replies {
+                let req = tr.read_request().unwrap();
+                let req_id = req.req_id().clone().unwrap();
+                tr.send_response(Response::ok("ok", req_id)).unwrap();
+            }
+            // Read one more request, and drop it along with the connection
+            let _ = tr.read_request();
+        });
+        Transport::new(s1)
+    }
+
+    fn no_waiting() -> RetryPolicy {
+        RetryPolicy {
+            backoff: Duration::ZERO,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn call_and_notify() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut tr = Transport::new(s2);
+            let note = tr.read_request().unwrap();
+            assert!(note.is_notification());
+            for _ in 0..2 {
+                let req = tr.read_request().unwrap();
+                let result = Ok(req.params().clone().map(Value::from).unwrap());
+                let req_id = req.req_id().clone().unwrap();
+                tr.send_response(Response::new(result, req_id)).unwrap();
+            }
+        });
+        let mut client = Client::new(Transport::new(s1));
+        client.notify("hello", None).unwrap();
+        for n in 0..2 {
+            let params = Params::Array(vec![Value::from(n)]);
+            let result = client.call("echo", Some(params.clone())).unwrap();
+            assert_eq!(result, Value::from(params));
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn id_mismatch() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut tr = Transport::new(s2);
+            tr.read_request().unwrap();
+            tr.send_response(Response::new(Ok(Value::Null), 99u8.into()))
+                .unwrap();
+        });
+        let mut client = Client::new(Transport::new(s1));
+        match client.call("echo", None) {
+            Err(ClientError::IdMismatch { expected, got }) => {
+                assert_eq!(expected, RequestID::from(0u8));
+                assert_eq!(got, RequestID::from(99u8));
+            }
+            r => panic!("expected IdMismatch, got {:?}", r),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn streaming() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut tr = Transport::new(s2);
+            for _ in 0..2 {
+                let req = tr.read_request().unwrap();
+                let req_id = req.req_id().clone().unwrap();
+                for n in 1..=3 {
+                    tr.send_partial(req_id.clone(), Value::from(n)).unwrap();
+                }
+                tr.send_response(Response::new(Ok("done".into()), req_id))
+                    .unwrap();
+            }
+        });
+        let mut client = Client::new(Transport::new(s1));
+        let results: Vec<_> = client
+            .call_streaming("count", None)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(results, vec![1.into(), 2.into(), 3.into(), "done".into()]);
+        // A plain call just gets the final result
+        assert_eq!(client.call("count", None).unwrap(), Value::from("done"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn out_of_order() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut tr = Transport::new(s2);
+            let mut ids: Vec<_> = (0..3)
+                .map(|_| tr.read_request().unwrap().req_id().clone().unwrap())
+                .collect();
+            // Answer them backwards, with a stray Response in the middle
+            tr.send_response(Response::ok("c", ids.pop().unwrap()))
+                .unwrap();
+            tr.send_response(Response::ok("?", 99u8.into())).unwrap();
+            tr.send_partial(ids[1].clone(), "...".into()).unwrap();
+            tr.send_response(Response::ok("b", ids.pop().unwrap()))
+                .unwrap();
+            tr.send_response(Response::ok("a", ids.pop().unwrap()))
+                .unwrap();
+        });
+        let mut client = Client::new(Transport::new(s1));
+        let a = client.start("a", None).unwrap();
+        let b = client.start("b", None).unwrap();
+        assert_eq!(client.outstanding().count(), 2);
+        // An ordinary call still works while the others are in flight
+        assert_eq!(client.call("c", None).unwrap(), "c".into());
+
+        assert!(matches!(
+            client.wait(&a),
+            Err(ClientError::UnknownId(id)) if id == RequestID::from(99u8)
+        ));
+        assert_eq!(client.wait(&a).unwrap(), "a".into());
+        assert_eq!(client.wait(&b).unwrap(), "b".into());
+        assert_eq!(client.outstanding().count(), 0);
+        assert!(matches!(client.wait(&b), Err(ClientError::UnknownId(_))));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn sequential_ids() {
+        let mut ids = SequentialIds::new(10);
+        assert_eq!(ids.next_id(), RequestID::from(10u8));
+        assert_eq!(ids.next_id(), RequestID::from(11u8));
+        let seen: std::collections::HashSet<_> = (0..1000).map(|_| ids.next_id()).collect();
+        assert_eq!(seen.len(), 1000);
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn random_ids() {
+        let mut ids = super::RandomBytesIds::new(8);
+        match ids.next_id() {
+            RequestID::Binary(b) => assert_eq!(b.len(), 8),
+            id => panic!("expected a binary id, got {:?}", id),
+        }
+        assert_ne!(ids.next_id(), ids.next_id());
+    }
+
+    #[test]
+    fn retry_idempotent() {
+        let reconnects = Cell::new(0);
+        let reconnect = || {
+            reconnects.set(reconnects.get() + 1);
+            Ok(connect(1))
+        };
+        // The first server hangs up without answering
+        let mut client =
+            RetryingClient::new(Client::new(connect(0)), reconnect).with_policy(no_waiting());
+        assert_eq!(client.call_idempotent("get", None).unwrap(), "ok".into());
+        assert_eq!(reconnects.get(), 1);
+        // Then the second one does the same
+        assert_eq!(client.call_idempotent("get", None).unwrap(), "ok".into());
+        assert_eq!(reconnects.get(), 2);
+    }
+
+    #[test]
+    fn no_retry_after_send() {
+        let failures = Cell::new(2);
+        let reconnect = || match failures.get() {
+            0 => Ok(connect(1)),
+            n => {
+                failures.set(n - 1);
+                Err(TransportError::Eof)
+            }
+        };
+        let mut client =
+            RetryingClient::new(Client::new(connect(0)), reconnect).with_policy(no_waiting());
+        // The server may have seen this one, so it's not sent again...
+        assert!(matches!(
+            client.call("put", None),
+            Err(ClientError::Transport(TransportError::Eof))
+        ));
+        // ...but reconnecting is retried before the next one goes out.
+        assert_eq!(client.call("put", None).unwrap(), "ok".into());
+        assert_eq!(failures.get(), 0);
+
+        // Retries run out eventually
+        let reconnect = || Err(TransportError::Eof);
+        let policy = RetryPolicy {
+            max_retries: 2,
+            ..no_waiting()
+        };
+        let mut client =
+            RetryingClient::new(Client::new(connect(0)), reconnect).with_policy(policy);
+        assert!(client.call_idempotent("get", None).is_err());
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+        };
+        let delays: Vec<_> = (0..4).map(|n| policy.delay(n).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 350, 350]);
+        assert_eq!(policy.delay(u32::MAX), policy.max_backoff);
+    }
+}
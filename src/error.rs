@@ -1,23 +1,118 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::alloc_prelude::*;
+#[cfg(feature = "std")]
+use crate::proto::RequestID;
+use crate::proto::{ErrorValue, INTERNAL_ERROR, INVALID_PARAMS, INVALID_REQUEST};
+use core::fmt;
+#[cfg(feature = "std")]
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Debug)]
 pub enum ProtocolError {
-    #[error("invalid method id")]
     InvalidMethodID,
-    #[error("invalid request id")]
     InvalidRequestID,
-    #[error("invalid type for params")]
     InvalidParamType,
-    #[error("non-string key in params")]
+    InvalidResultType,
+    InvalidDataType,
     InvalidKeyType,
-    #[error("not an RPC message")]
+    MixedParams,
+    EmptyBatch,
     InvalidMessage,
-    #[error("incorrect message type")]
     UnexpectedMessage,
+    MethodTooLong,
+    TooManyParams,
+    MissingArg(usize),
+    InvalidArgType(usize),
+    Unsupported,
+}
+
+// Written out rather than derived, since thiserror needs std.
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ProtocolError::*;
+        let msg = match self {
+            InvalidMethodID => "invalid method id",
+            InvalidRequestID => "invalid request id",
+            InvalidParamType => "invalid type for params",
+            InvalidResultType => "invalid type for result",
+            InvalidDataType => "invalid type for error data",
+            InvalidKeyType => "non-string key in params",
+            MixedParams => "mixed positional and named params",
+            EmptyBatch => "empty batch",
+            InvalidMessage => "not an RPC message",
+            UnexpectedMessage => "incorrect message type",
+            MethodTooLong => "method id too long",
+            TooManyParams => "too many params",
+            MissingArg(index) => return write!(f, "missing arg {}", index),
+            InvalidArgType(index) => return write!(f, "invalid type for arg {}", index),
+            Unsupported => "not supported by this transport",
+        };
+        f.write_str(msg)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ProtocolError {}
+
+impl ProtocolError {
+    /// Whether this error means the peer sent a malformed message, as opposed
+    /// to a well-formed one that didn't convert to the type we wanted or was
+    /// over a [RequestLimits](crate::proto::RequestLimits) limit.
+    pub fn is_malformed_message(&self) -> bool {
+        !matches!(
+            self,
+            ProtocolError::InvalidParamType
+                | ProtocolError::InvalidResultType
+                | ProtocolError::InvalidDataType
+                | ProtocolError::MethodTooLong
+                | ProtocolError::TooManyParams
+                | ProtocolError::MissingArg(_)
+                | ProtocolError::InvalidArgType(_)
+                | ProtocolError::Unsupported
+        )
+    }
+
+    /// The standard error code for this error, for reporting it back to the
+    /// peer in an [ErrorValue].
+    pub fn to_error_code(&self) -> i64 {
+        use ProtocolError::*;
+        match self {
+            InvalidMethodID | InvalidRequestID | EmptyBatch | InvalidMessage
+            | UnexpectedMessage | MethodTooLong => INVALID_REQUEST,
+            InvalidParamType | InvalidKeyType | MixedParams | TooManyParams | MissingArg(_)
+            | InvalidArgType(_) => INVALID_PARAMS,
+            InvalidResultType | InvalidDataType | Unsupported => INTERNAL_ERROR,
+        }
+    }
+}
+
+/// Raised inside serde decoding to mean [ProtocolError::InvalidMessage].
+///
+/// A serde error only carries a message, so this writes a marker that no
+/// other error's message can equal, and converting the decode error into a
+/// [TransportError] checks for that marker.
+#[cfg(feature = "serde1")]
+#[derive(Debug)]
+pub(crate) struct InvalidMessageSignal;
+
+#[cfg(any(feature = "serde1", feature = "std"))]
+const INVALID_MESSAGE_MARKER: &str = "\0ciborium-rpc: invalid message";
+
+#[cfg(feature = "serde1")]
+impl fmt::Display for InvalidMessageSignal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(INVALID_MESSAGE_MARKER)
+    }
+}
+
+impl From<ProtocolError> for ErrorValue {
+    fn from(err: ProtocolError) -> Self {
+        ErrorValue::new(err.to_error_code(), err.to_string())
+    }
 }
 
+#[cfg(feature = "std")]
 #[derive(Error, Debug)]
 pub enum TransportError {
     #[error("io error: {0}")]
@@ -32,9 +127,74 @@ pub enum TransportError {
     #[error("decode error{}: {msg}",
         .pos.map(|p| format!(" at pos {}", p)).unwrap_or("".into())
     )]
-    Decode { msg: String, pos: Option<usize> },
+    Decode {
+        msg: String,
+        pos: Option<usize>,
+        /// The bytes that failed to decode, if they were still available.
+        raw: Option<Vec<u8>>,
+    },
+
+    /// The channel was closed between messages.
+    #[error("end of stream")]
+    Eof,
+
+    /// No message arrived before the read timeout ran out.
+    #[error("timed out")]
+    Timeout,
+
+    #[error("message exceeds size limit ({limit} bytes)")]
+    MessageTooLarge { limit: usize },
+
+    #[error("message nested too deeply")]
+    RecursionLimitExceeded,
+
+    /// Too many Responses arrived that nobody has asked for yet.
+    #[error("too many unclaimed responses ({limit})")]
+    TooManyStashed { limit: usize },
 }
 
+#[cfg(feature = "std")]
+impl TransportError {
+    /// Attach the offending bytes to a Decode error. Other errors are
+    /// returned unchanged.
+    pub(crate) fn with_raw(self, bytes: &[u8]) -> Self {
+        match self {
+            TransportError::Decode { msg, pos, .. } => TransportError::Decode {
+                msg,
+                pos,
+                raw: Some(bytes.to_vec()),
+            },
+            e => e,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+/// ClientError separates failures on the wire ([ClientError::Transport])
+/// from errors the server returned ([ClientError::Application]).
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("transport error: {0}")]
+    Transport(#[from] TransportError),
+
+    #[error("application {0}")]
+    Application(#[from] ErrorValue),
+
+    #[error("response id {got:?} does not match request id {expected:?}")]
+    IdMismatch { expected: RequestID, got: RequestID },
+
+    #[error("response id {0:?} does not match any outstanding request")]
+    UnknownId(RequestID),
+}
+
+#[cfg(feature = "std")]
+impl From<ProtocolError> for ClientError {
+    fn from(err: ProtocolError) -> Self {
+        TransportError::from(err).into()
+    }
+}
+
+#[cfg(feature = "std")]
 impl<E> From<ciborium::ser::Error<E>> for TransportError
 where
     TransportError: From<E>,
@@ -48,6 +208,7 @@ where
     }
 }
 
+#[cfg(feature = "std")]
 impl<E> From<ciborium::de::Error<E>> for TransportError
 where
     TransportError: From<E>,
@@ -56,15 +217,116 @@ where
         use ciborium::de::Error::*;
         match err {
             Io(e) => TransportError::from(e),
-            Semantic(pos, msg) => TransportError::Decode { msg, pos },
+            // Raised by the RPC tag check or a strict decode
+            Semantic(_, msg) if msg == INVALID_MESSAGE_MARKER => {
+                ProtocolError::InvalidMessage.into()
+            }
+            Semantic(pos, msg) => TransportError::Decode {
+                msg,
+                pos,
+                raw: None,
+            },
             Syntax(pos) => TransportError::Decode {
                 msg: "syntax error".into(),
                 pos: Some(pos),
+                raw: None,
             },
-            RecursionLimitExceeded => TransportError::Decode {
-                msg: "recursion limit exceeded".into(),
-                pos: None,
-            },
+            RecursionLimitExceeded => TransportError::RecursionLimitExceeded,
         }
     }
 }
+
+#[cfg(feature = "websocket")]
+impl From<tungstenite::Error> for TransportError {
+    fn from(err: tungstenite::Error) -> Self {
+        use tungstenite::Error::*;
+        match err {
+            ConnectionClosed | AlreadyClosed => TransportError::Eof,
+            Io(e) => e.into(),
+            e => std::io::Error::other(e).into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn client_errors() {
+        let e = ClientError::from(TransportError::Encode("bad".into()));
+        assert!(matches!(
+            e,
+            ClientError::Transport(TransportError::Encode(_))
+        ));
+        assert_eq!(e.to_string(), "transport error: encode error: bad");
+
+        let e = ClientError::from(ProtocolError::UnexpectedMessage);
+        assert!(matches!(
+            e,
+            ClientError::Transport(TransportError::Proto(ProtocolError::UnexpectedMessage))
+        ));
+
+        let e = ClientError::from(ErrorValue::internal("oops"));
+        assert!(matches!(e, ClientError::Application(_)));
+        assert_eq!(e.to_string(), "application error -32603: oops");
+        assert!(std::error::Error::source(&e).is_some());
+
+        let e = ClientError::IdMismatch {
+            expected: 1u8.into(),
+            got: 2u8.into(),
+        };
+        assert_eq!(
+            e.to_string(),
+            "response id Number(2) does not match request id Number(1)"
+        );
+    }
+
+    #[cfg(all(feature = "std", feature = "serde1"))]
+    #[test]
+    fn invalid_message_signal() {
+        use ciborium::de::Error;
+        let signal = Error::<std::io::Error>::Semantic(None, InvalidMessageSignal.to_string());
+        assert!(matches!(
+            TransportError::from(signal),
+            TransportError::Proto(ProtocolError::InvalidMessage)
+        ));
+        // Only the signal means that, not an error that reads the same
+        let lookalike =
+            Error::<std::io::Error>::Semantic(None, ProtocolError::InvalidMessage.to_string());
+        assert!(matches!(
+            TransportError::from(lookalike),
+            TransportError::Decode { .. }
+        ));
+    }
+
+    #[test]
+    fn protocol_error_codes() {
+        use ProtocolError::*;
+        for (e, code, malformed) in [
+            (InvalidMethodID, INVALID_REQUEST, true),
+            (InvalidRequestID, INVALID_REQUEST, true),
+            (InvalidParamType, INVALID_PARAMS, false),
+            (InvalidResultType, INTERNAL_ERROR, false),
+            (InvalidDataType, INTERNAL_ERROR, false),
+            (InvalidKeyType, INVALID_PARAMS, true),
+            (MixedParams, INVALID_PARAMS, true),
+            (EmptyBatch, INVALID_REQUEST, true),
+            (InvalidMessage, INVALID_REQUEST, true),
+            (UnexpectedMessage, INVALID_REQUEST, true),
+            (MethodTooLong, INVALID_REQUEST, false),
+            (TooManyParams, INVALID_PARAMS, false),
+            (MissingArg(0), INVALID_PARAMS, false),
+            (InvalidArgType(2), INVALID_PARAMS, false),
+            (Unsupported, INTERNAL_ERROR, false),
+        ] {
+            assert_eq!(e.to_error_code(), code, "{:?}", e);
+            assert_eq!(e.is_malformed_message(), malformed, "{:?}", e);
+        }
+
+        let e = ErrorValue::from(MixedParams);
+        assert_eq!(e.code(), &INVALID_PARAMS);
+        assert_eq!(e.message(), "mixed positional and named params");
+    }
+}
@@ -22,13 +22,64 @@
 //! [CBOR]: https://cbor.io/
 //! [JSON-RPC]: https://www.jsonrpc.org/
 //! [JSON-RPC 2.0]: https://www.jsonrpc.org/specification
+//!
+//! Everything but the [proto] types needs the `std` feature, which is on by
+//! default. Without it, the crate is `no_std` and only needs `alloc`, so
+//! Requests and Responses can be built and encoded with ciborium on targets
+//! that don't have std.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
+#[cfg(all(feature = "tokio", feature = "serde1"))]
+pub mod async_client;
+#[cfg(feature = "std")]
+pub mod client;
 pub mod error;
+#[cfg(all(feature = "std", feature = "serde1"))]
+pub mod peer;
 pub mod proto;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(all(feature = "std", feature = "serde1", any(test, feature = "testing")))]
+pub mod testing;
+#[cfg(all(feature = "std", feature = "serde1"))]
+mod trace;
+#[cfg(feature = "std")]
 pub mod transport;
 
-// TODO
-//mod client;
+/// The items most programs need, so they can get started with one import:
+/// ```
+/// # #[cfg(all(feature = "std", feature = "serde1"))] {
+/// use ciborium_rpc::prelude::*;
+///
+/// let req = RequestBuilder::new("add").arg(1).arg(2).id(1u8).build().unwrap();
+/// let mut tr = BufTransport::new(bytes::BytesMut::new());
+/// tr.send_request(req.clone()).unwrap();
+/// assert_eq!(tr.read_request().unwrap(), req);
+/// # }
+/// ```
+pub mod prelude {
+    pub use crate::error::ProtocolError;
+    #[cfg(feature = "std")]
+    pub use crate::error::{ClientError, TransportError};
+    pub use crate::proto::{
+        ErrorValue, MethodID, Params, Request, RequestBuilder, RequestID, Response, Value,
+    };
+    #[cfg(feature = "std")]
+    pub use crate::transport::cbor::CBORTransport;
+    #[cfg(feature = "std")]
+    pub use crate::transport::simple::{ClientTransport, ServerTransport};
+    #[cfg(feature = "std")]
+    pub use crate::transport::{BufTransport, Transport};
+}
 
-// TODO
-//mod server;
+// The parts of the std prelude that come from alloc, for modules that are
+// built without std.
+mod alloc_prelude {
+    pub(crate) use alloc::boxed::Box;
+    pub(crate) use alloc::string::{String, ToString};
+    pub(crate) use alloc::vec::Vec;
+    pub(crate) use alloc::{format, vec};
+}
@@ -0,0 +1,73 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A connection where both ends make calls, and both answer them.
+
+use crate::error::TransportError;
+use crate::proto::{Control, Request, Response};
+use crate::transport::{Read, Transport, Write};
+
+/// Anything a [Peer] can read: a call from the other end, the answer to one
+/// of ours, or a Control message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerMessage {
+    Request(Request),
+    Response(Response),
+    Control(Control),
+}
+
+/// A Peer is a [Transport] whose reads don't assume which side of a call
+/// it's on. Send with `transport`, which is both a
+/// [ClientTransport](crate::transport::simple::ClientTransport) and a
+/// [ServerTransport](crate::transport::simple::ServerTransport), and use
+/// [read_message](Self::read_message) to find out what came in.
+pub struct Peer<C: Read + Write> {
+    pub transport: Transport<C>,
+}
+
+impl<C: Read + Write> Peer<C> {
+    pub fn new(transport: Transport<C>) -> Self {
+        Self { transport }
+    }
+
+    pub fn into_inner(self) -> Transport<C> {
+        self.transport
+    }
+
+    /// Read the next message, whatever kind it is.
+    pub fn read_message(&mut self) -> Result<PeerMessage, TransportError> {
+        Ok(self.transport.read_msg()?.into())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{Peer, PeerMessage};
+    use crate::proto::{Control, RequestBuilder, Response};
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+    use crate::transport::Transport;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn both_ways() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut alice = Peer::new(Transport::new(s1));
+        let mut bob = Peer::new(Transport::new(s2));
+
+        // Each asks the other something at the same time
+        let hi = RequestBuilder::new("hi").id(1u8).build().unwrap();
+        let yo = RequestBuilder::new("yo").id(1u8).build().unwrap();
+        alice.transport.send_request(hi.clone()).unwrap();
+        bob.transport.send_request(yo.clone()).unwrap();
+        assert_eq!(bob.read_message().unwrap(), PeerMessage::Request(hi));
+        let answer = Response::ok("hello", 1u8.into());
+        bob.transport.send_response(answer.clone()).unwrap();
+
+        assert_eq!(alice.read_message().unwrap(), PeerMessage::Request(yo));
+        assert_eq!(alice.read_message().unwrap(), PeerMessage::Response(answer));
+        alice.transport.send_cancel(1u8.into()).unwrap();
+        assert_eq!(
+            bob.read_message().unwrap(),
+            PeerMessage::Control(Control::Cancel(1u8.into()))
+        );
+    }
+}
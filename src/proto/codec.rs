@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pluggable CBOR backends.
+//!
+//! The crate encodes and decodes every message through a [CborCodec]. The
+//! transports all use [Ciborium]; a
+//! [CodecFraming](crate::transport::framing::CodecFraming) sends v0 messages
+//! through some other backend.
+//!
+//! A backend has to handle CBOR tags the way ciborium does. serde itself
+//! has no notion of a tag, so ciborium passes them through serde as an enum
+//! named `@@TAG@@`, and the message types (along with bignum RequestIDs)
+//! rely on that to write and check their tags. A backend that doesn't know
+//! the convention - serde_cbor, for one - writes that enum out as an enum,
+//! and nothing else can read what it sends.
+//!
+//! The indefinite-length and streaming encodings are the exception: they
+//! write their CBOR headers one at a time with `ciborium_ll`, so they don't
+//! go through a codec.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::TransportError;
+use crate::proto::value::canonical;
+use crate::proto::Value;
+use crate::transport::{Read, Write};
+
+/// A CBOR encoder and decoder for the message types.
+pub trait CborCodec {
+    fn to_writer<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+        writer: &mut impl Write,
+    ) -> Result<(), TransportError>;
+    // Named to match ciborium's own functions
+    #[allow(clippy::wrong_self_convention)]
+    fn from_reader<T: DeserializeOwned>(
+        &mut self,
+        reader: &mut impl Read,
+    ) -> Result<T, TransportError>;
+
+    /// Decode using `scratch` as working space, for backends that can use
+    /// it to avoid allocating. By default this is just
+    /// [from_reader](Self::from_reader).
+    #[allow(clippy::wrong_self_convention)]
+    fn from_reader_with_buffer<T: DeserializeOwned>(
+        &mut self,
+        reader: &mut impl Read,
+        scratch: &mut [u8],
+    ) -> Result<T, TransportError> {
+        let _ = scratch;
+        self.from_reader(reader)
+    }
+}
+
+/// The default [CborCodec].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ciborium;
+
+impl CborCodec for Ciborium {
+    fn to_writer<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+        writer: &mut impl Write,
+    ) -> Result<(), TransportError> {
+        Ok(ciborium::ser::into_writer(value, writer)?)
+    }
+    fn from_reader<T: DeserializeOwned>(
+        &mut self,
+        reader: &mut impl Read,
+    ) -> Result<T, TransportError> {
+        Ok(ciborium::de::from_reader(reader)?)
+    }
+    fn from_reader_with_buffer<T: DeserializeOwned>(
+        &mut self,
+        reader: &mut impl Read,
+        scratch: &mut [u8],
+    ) -> Result<T, TransportError> {
+        Ok(ciborium::de::from_reader_with_buffer(reader, scratch)?)
+    }
+}
+
+/// Ciborium, writing CBOR's deterministic encoding: the message goes through
+/// a [Value] so its maps can be sorted by [canonical].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Canonical;
+
+impl CborCodec for Canonical {
+    fn to_writer<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+        writer: &mut impl Write,
+    ) -> Result<(), TransportError> {
+        let value = Value::serialized(value).map_err(|e| TransportError::Encode(e.to_string()))?;
+        Ciborium.to_writer(&canonical(value), writer)
+    }
+    fn from_reader<T: DeserializeOwned>(
+        &mut self,
+        reader: &mut impl Read,
+    ) -> Result<T, TransportError> {
+        Ciborium.from_reader(reader)
+    }
+}
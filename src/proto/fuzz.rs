@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! [Arbitrary] impls for the message types, for fuzzing.
+//!
+//! The generated messages are meant to survive an encode/decode round trip
+//! unchanged, so the Values we generate avoid things that CBOR (or serde)
+//! can't carry faithfully: NaN floats (which never compare equal), tags,
+//! integers outside the i64/u64 range, and a `null` error `data` item (which
+//! decodes as no data at all).
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use super::{ErrorValue, MethodID, Params, Request, RequestID, Response, ResponseKind, Value};
+
+// How deeply Arrays and Maps may nest inside a generated Value, and how many
+// items each one (or a set of Params) may hold.
+const MAX_DEPTH: usize = 3;
+const MAX_ITEMS: usize = 8;
+
+fn items<'a, T>(
+    u: &mut Unstructured<'a>,
+    mut item: impl FnMut(&mut Unstructured<'a>) -> Result<T>,
+) -> Result<Vec<T>> {
+    let len = u.choose_index(MAX_ITEMS)?;
+    (0..len).map(|_| item(u)).collect()
+}
+
+fn value(u: &mut Unstructured<'_>, depth: usize) -> Result<Value> {
+    let kinds = if depth < MAX_DEPTH { 9 } else { 7 };
+    Ok(match u.choose_index(kinds)? {
+        0 => Value::from(i64::arbitrary(u)?),
+        1 => Value::from(u64::arbitrary(u)?),
+        2 => Value::Bytes(Vec::arbitrary(u)?),
+        3 => Value::Text(String::arbitrary(u)?),
+        4 => Value::Bool(bool::arbitrary(u)?),
+        5 => Value::Null,
+        6 => {
+            let f = f64::arbitrary(u)?;
+            Value::Float(if f.is_nan() { 0.0 } else { f })
+        }
+        7 => Value::Array(items(u, |u| value(u, depth + 1))?),
+        _ => Value::Map(items(u, |u| {
+            Ok((value(u, depth + 1)?, value(u, depth + 1)?))
+        })?),
+    })
+}
+
+impl<'a> Arbitrary<'a> for MethodID {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.choose_index(3)? {
+            0 => MethodID::String(String::arbitrary(u)?),
+            1 => MethodID::Number(u64::arbitrary(u)?),
+            _ => MethodID::Binary(Vec::arbitrary(u)?),
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for RequestID {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.choose_index(4)? {
+            0 => RequestID::String(String::arbitrary(u)?),
+            1 => RequestID::Number(u64::arbitrary(u)?),
+            2 => RequestID::from(u128::arbitrary(u)?),
+            _ => RequestID::Binary(Vec::arbitrary(u)?),
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Params {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        if bool::arbitrary(u)? {
+            Ok(Params::Array(items(u, |u| value(u, 1))?))
+        } else {
+            Ok(Params::Named(items(u, |u| {
+                Ok((String::arbitrary(u)?, value(u, 1)?))
+            })?))
+        }
+    }
+}
+
+impl<'a> Arbitrary<'a> for ErrorValue {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut error = ErrorValue::new(i64::arbitrary(u)?, String::arbitrary(u)?);
+        if bool::arbitrary(u)? {
+            match value(u, 1)? {
+                Value::Null => {}
+                data => error = error.with_data(data),
+            }
+        }
+        Ok(error)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Request {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut request = Request::new(
+            MethodID::arbitrary(u)?,
+            Option::arbitrary(u)?,
+            Option::arbitrary(u)?,
+        );
+        request.deadline = Option::<u64>::arbitrary(u)?;
+        Ok(request)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Response {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let req_id = RequestID::arbitrary(u)?;
+        Ok(match u.choose_index(3)? {
+            0 => Response::new(Ok(value(u, 0)?), req_id),
+            1 => Response::new(Err(ErrorValue::arbitrary(u)?), req_id),
+            _ => Response::partial(value(u, 0)?, req_id),
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for ResponseKind {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(if bool::arbitrary(u)? {
+            ResponseKind::Final
+        } else {
+            ResponseKind::Partial
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+    use crate::transport::BufTransport;
+    use bytes::BytesMut;
+
+    // A tiny xorshift PRNG, so the test covers lots of inputs without
+    // needing a fuzzer (or any more dependencies).
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut x = seed | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        let mut deadlines = 0;
+        for seed in 0..500 {
+            let data = noise(seed, 512);
+            let mut u = Unstructured::new(&data);
+            let req = Request::arbitrary(&mut u).unwrap();
+            deadlines += req.deadline().is_some() as usize;
+            tr.send_request_ref(&req).unwrap();
+            assert_eq!(tr.read_request().unwrap(), req);
+            let resp = Response::arbitrary(&mut u).unwrap();
+            tr.send_response_ref(&resp).unwrap();
+            assert_eq!(tr.read_response().unwrap(), resp);
+        }
+        assert!(tr.buffer.is_empty());
+        assert!(deadlines > 0);
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encoding Values with indefinite-length items.
+//!
+//! ciborium always writes definite lengths, so here we walk the Value
+//! ourselves, straight into a `ciborium_ll` Encoder: Arrays and Maps are
+//! written as indefinite-length items ended by a break, and Bytes and Text
+//! longer than [SEGMENT] are split into segments. Any CBOR decoder
+//! (including ours) reads the result back as the same Value.
+
+use ciborium::value::Value;
+use ciborium_ll::{simple, tag, Encoder, Header};
+use std::convert::TryFrom;
+use std::io::Write;
+
+use crate::error::TransportError;
+
+/// The maximum size of each segment of a Bytes or Text item.
+pub(crate) const SEGMENT: usize = 4096;
+
+pub(crate) fn encode<W: Write>(value: &Value, enc: &mut Encoder<W>) -> Result<(), TransportError> {
+    match value {
+        Value::Integer(i) => encode_integer(i128::from(*i), enc)?,
+        Value::Bytes(b) => enc.bytes(b, SEGMENT)?,
+        Value::Text(s) => enc.text(s, SEGMENT)?,
+        Value::Float(f) => enc.push(Header::Float(*f))?,
+        Value::Bool(b) => enc.push(Header::Simple(if *b {
+            simple::TRUE
+        } else {
+            simple::FALSE
+        }))?,
+        Value::Null => enc.push(Header::Simple(simple::NULL))?,
+        Value::Tag(t, v) => {
+            enc.push(Header::Tag(*t))?;
+            encode(v, enc)?;
+        }
+        Value::Array(items) => {
+            enc.push(Header::Array(None))?;
+            for item in items {
+                encode(item, enc)?;
+            }
+            enc.push(Header::Break)?;
+        }
+        Value::Map(entries) => {
+            enc.push(Header::Map(None))?;
+            for (k, v) in entries {
+                encode(k, enc)?;
+                encode(v, enc)?;
+            }
+            enc.push(Header::Break)?;
+        }
+        v => return Err(TransportError::Encode(format!("can't encode {:?}", v))),
+    }
+    Ok(())
+}
+
+// Integers that don't fit in a u64 are written as bignums, same as ciborium.
+fn encode_integer<W: Write>(i: i128, enc: &mut Encoder<W>) -> Result<(), TransportError> {
+    let (bignum, raw) = if i < 0 {
+        (tag::BIGNEG, i as u128 ^ !0)
+    } else {
+        (tag::BIGPOS, i as u128)
+    };
+    match u64::try_from(raw) {
+        Ok(x) if i < 0 => enc.push(Header::Negative(x))?,
+        Ok(x) => enc.push(Header::Positive(x))?,
+        Err(_) => {
+            let bytes = raw.to_be_bytes();
+            let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+            enc.push(Header::Tag(bignum))?;
+            enc.bytes(&bytes[start..], None)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,277 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Conversions between RPC messages and JSON, for logging and for bridging to
+//! JSON-RPC systems.
+//!
+//! Requests and Responses become JSON-RPC 2.0 objects:
+//! ```json
+//! {"jsonrpc": "2.0", "method": MethodID, "params": Params, "id": RequestID, "deadline": u64}
+//! {"jsonrpc": "2.0", "result": Value, "id": RequestID}
+//! {"jsonrpc": "2.0", "error": {"code": i64, "message": String, "data": Value}, "id": RequestID}
+//! ```
+//! A partial result uses `more` in place of `result`, and a Request's
+//! `deadline` is an extra member, like [v0](super::v0).
+//!
+//! JSON has no byte strings, so CBOR bytes - including binary MethodIDs and
+//! RequestIDs - are written as base64 text in an object with a single
+//! `$base64` item, which converts back to bytes. Other CBOR features that
+//! JSON can't express are flattened: tags are dropped, non-text map keys are
+//! written as text, and integers that don't fit in 64 bits become floats.
+//! JSON objects also don't keep their order, so named params come back sorted
+//! by name.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{Map, Number, Value as Json};
+use std::convert::TryFrom;
+
+use super::{ErrorValue, MethodID, Params, Request, RequestID, Response, ResponseKind, Value};
+use crate::error::ProtocolError;
+
+const BYTES_KEY: &str = "$base64";
+
+/// Convert a CBOR Value to JSON.
+pub fn to_json_value(value: &Value) -> Json {
+    match value {
+        Value::Integer(i) => match (i64::try_from(*i), u64::try_from(*i)) {
+            (Ok(n), _) => n.into(),
+            (_, Ok(n)) => n.into(),
+            _ => float(i128::from(*i) as f64),
+        },
+        Value::Bytes(b) => {
+            let mut map = Map::new();
+            map.insert(BYTES_KEY.into(), BASE64.encode(b).into());
+            Json::Object(map)
+        }
+        Value::Float(f) => float(*f),
+        Value::Text(s) => s.as_str().into(),
+        Value::Bool(b) => (*b).into(),
+        Value::Null => Json::Null,
+        Value::Tag(_, v) => to_json_value(v),
+        Value::Array(a) => a.iter().map(to_json_value).collect(),
+        Value::Map(m) => Json::Object(
+            m.iter()
+                .map(|(k, v)| (json_key(k), to_json_value(v)))
+                .collect(),
+        ),
+        // Value is non_exhaustive; anything new gets the same treatment as a
+        // Value we can't represent.
+        _ => Json::Null,
+    }
+}
+
+// NaN and the infinities aren't valid JSON numbers.
+fn float(f: f64) -> Json {
+    Number::from_f64(f).map_or(Json::Null, Json::Number)
+}
+
+fn json_key(key: &Value) -> String {
+    match key {
+        Value::Text(s) => s.clone(),
+        k => to_json_value(k).to_string(),
+    }
+}
+
+/// Convert JSON to a CBOR Value. Numbers become Integers if they can, and
+/// Floats otherwise.
+pub fn from_json_value(json: Json) -> Value {
+    match json {
+        Json::Null => Value::Null,
+        Json::Bool(b) => Value::Bool(b),
+        Json::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(u), _) => u.into(),
+            (_, Some(i)) => i.into(),
+            _ => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Json::String(s) => Value::Text(s),
+        Json::Array(a) => Value::Array(a.into_iter().map(from_json_value).collect()),
+        Json::Object(m) => match as_bytes(&m) {
+            Some(bytes) => Value::Bytes(bytes),
+            None => Value::Map(
+                m.into_iter()
+                    .map(|(k, v)| (Value::Text(k), from_json_value(v)))
+                    .collect(),
+            ),
+        },
+    }
+}
+
+// An object that's just {"$base64": "..."}, holding valid base64, is bytes.
+fn as_bytes(map: &Map<String, Json>) -> Option<Vec<u8>> {
+    match (map.len(), map.get(BYTES_KEY)) {
+        (1, Some(Json::String(s))) => BASE64.decode(s).ok(),
+        _ => None,
+    }
+}
+
+fn jsonrpc_object() -> Map<String, Json> {
+    let mut map = Map::new();
+    map.insert("jsonrpc".into(), "2.0".into());
+    map
+}
+
+/// Convert a Request to a JSON-RPC object, writing any bytes as `$base64`.
+pub fn request_to_json(request: &Request) -> Json {
+    let mut map = jsonrpc_object();
+    map.insert(
+        "method".into(),
+        to_json_value(&request.method.clone().into()),
+    );
+    if let Some(params) = &request.params {
+        map.insert("params".into(), to_json_value(&params.clone().into()));
+    }
+    if let Some(req_id) = &request.req_id {
+        map.insert("id".into(), to_json_value(&req_id.clone().into()));
+    }
+    if let Some(deadline) = request.deadline {
+        map.insert("deadline".into(), deadline.into());
+    }
+    Json::Object(map)
+}
+
+/// Read a Request from a JSON-RPC object; `$base64` objects become bytes.
+pub fn request_from_json(json: Json) -> Result<Request, ProtocolError> {
+    let mut map = match json {
+        Json::Object(map) => map,
+        _ => return Err(ProtocolError::InvalidMessage),
+    };
+    let method = match map.remove("method") {
+        Some(method) => MethodID::try_from(from_json_value(method))?,
+        None => return Err(ProtocolError::InvalidMessage),
+    };
+    let params = map
+        .remove("params")
+        .map(|p| Params::try_from(from_json_value(p)))
+        .transpose()?;
+    let req_id = map
+        .remove("id")
+        .map(|id| RequestID::try_from(from_json_value(id)))
+        .transpose()?;
+    let deadline = match map.remove("deadline") {
+        Some(Json::Number(n)) => Some(n.as_u64().ok_or(ProtocolError::InvalidMessage)?),
+        Some(_) => return Err(ProtocolError::InvalidMessage),
+        None => None,
+    };
+    Ok(Request {
+        method,
+        params,
+        req_id,
+        deadline,
+    })
+}
+
+/// Convert a Response to a JSON-RPC object, writing any bytes as `$base64`.
+pub fn response_to_json(response: &Response) -> Json {
+    let mut map = jsonrpc_object();
+    match &response.result {
+        Ok(value) if response.is_partial() => map.insert("more".into(), to_json_value(value)),
+        Ok(value) => map.insert("result".into(), to_json_value(value)),
+        Err(error) => {
+            let mut e = Map::new();
+            e.insert("code".into(), error.code.into());
+            e.insert("message".into(), error.message.as_str().into());
+            if let Some(data) = &error.data {
+                e.insert("data".into(), to_json_value(data));
+            }
+            map.insert("error".into(), Json::Object(e))
+        }
+    };
+    map.insert("id".into(), to_json_value(&response.req_id.clone().into()));
+    Json::Object(map)
+}
+
+/// Read a Response from a JSON-RPC object; `$base64` objects become bytes.
+pub fn response_from_json(json: Json) -> Result<Response, ProtocolError> {
+    let mut map = match json {
+        Json::Object(map) => map,
+        _ => return Err(ProtocolError::InvalidMessage),
+    };
+    let req_id = match map.remove("id") {
+        Some(id) => RequestID::try_from(from_json_value(id))?,
+        None => return Err(ProtocolError::InvalidMessage),
+    };
+    let (result, kind) = match (
+        map.remove("result"),
+        map.remove("more"),
+        map.remove("error"),
+    ) {
+        (Some(value), None, None) => (Ok(from_json_value(value)), ResponseKind::Final),
+        (None, Some(value), None) => (Ok(from_json_value(value)), ResponseKind::Partial),
+        (None, None, Some(error)) => (
+            Err(ErrorValue::try_from(from_json_value(error))?),
+            ResponseKind::Final,
+        ),
+        _ => return Err(ProtocolError::InvalidMessage),
+    };
+    Ok(Response {
+        result,
+        req_id,
+        kind,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::RequestBuilder;
+    use serde_json::json;
+
+    #[test]
+    fn request_roundtrip() {
+        let req = RequestBuilder::new("store")
+            .param("data", vec![0u8, 1, 2, 0xff])
+            .param("name", "blob")
+            .id(vec![0xca, 0xfe])
+            .build()
+            .unwrap();
+        let j = request_to_json(&req);
+        assert_eq!(
+            j,
+            json!({
+                "jsonrpc": "2.0",
+                "method": "store",
+                "params": {"name": "blob", "data": {"$base64": "AAEC/w=="}},
+                "id": {"$base64": "yv4="},
+            })
+        );
+        assert_eq!(request_from_json(j).unwrap(), req);
+
+        let note = RequestBuilder::new(vec![1u8])
+            .arg(1.5)
+            .arg(-2)
+            .build()
+            .unwrap()
+            .with_deadline(1_700_000_000_000);
+        assert_eq!(request_from_json(request_to_json(&note)).unwrap(), note);
+        assert!(matches!(
+            request_from_json(json!({"params": []})),
+            Err(ProtocolError::InvalidMessage)
+        ));
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let ok = Response::ok(vec![7u8], 3u8.into());
+        assert_eq!(
+            response_to_json(&ok),
+            json!({"jsonrpc": "2.0", "result": {"$base64": "Bw=="}, "id": 3})
+        );
+        let err = Response::err(ErrorValue::new(-1, "no").with_data("why"), "x".into());
+        let partial = Response::partial(Value::Null, 3u8.into());
+        for resp in [ok, err, partial] {
+            assert_eq!(response_from_json(response_to_json(&resp)).unwrap(), resp);
+        }
+    }
+
+    #[test]
+    fn values() {
+        let v = Value::Map(vec![
+            (1.into(), Value::Tag(1, Box::new(1000.into()))),
+            ("nan".into(), f64::NAN.into()),
+        ]);
+        assert_eq!(to_json_value(&v), json!({"1": 1000, "nan": null}));
+        // Not exactly one item, so not bytes
+        let j = json!({"$base64": "AA==", "more": 1});
+        assert!(matches!(from_json_value(j), Value::Map(_)));
+    }
+}
@@ -1,144 +1,1561 @@
 // SPDX-License-Identifier: Apache-2.0
 
 /// Defines the protocol's message types and their contents.
-use std::convert::TryFrom;
+use alloc::collections::BTreeMap;
+use core::convert::TryFrom;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
 
+use crate::alloc_prelude::*;
+use crate::error::ProtocolError;
+use ciborium_ll::tag::BIGPOS;
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+use codec::{Canonical, CborCodec, Ciborium};
 #[cfg(feature = "serde1")]
 use serde::{Deserialize, Serialize};
 
-#[cfg(feature = "serde1")]
-pub mod v0;
+// Each protocol version defines its own toplevel message types; these give
+// them the basic methods to read/write themselves as CBOR. A version module
+// implements them with `impl_cbor_io!`, using `@write` for types that only
+// get sent. The module also needs a `field_name` function that names the
+// part of a message each of its keys holds, for encode errors.
+#[cfg(all(feature = "serde1", feature = "std"))]
+pub(crate) trait WriteCbor: Serialize {
+    fn field_name(key: &Value) -> Option<&'static str>;
+
+    fn to_writer(
+        &self,
+        writer: &mut impl crate::transport::Write,
+    ) -> Result<(), crate::error::TransportError> {
+        self.to_writer_using(&mut Ciborium, writer)
+    }
+    /// Encode with `codec`. If that fails, the error says which part of
+    /// the message was to blame, found by trying each part on its own.
+    fn to_writer_using(
+        &self,
+        codec: &mut impl CborCodec,
+        writer: &mut impl crate::transport::Write,
+    ) -> Result<(), crate::error::TransportError> {
+        use crate::error::TransportError;
+        match codec.to_writer(self, writer) {
+            Err(TransportError::Encode(msg)) => {
+                let context = match Value::serialized(self) {
+                    Ok(value) => encode_context(&value, Self::field_name, &mut |part| {
+                        codec.to_writer(part, &mut ByteCounter(0)).is_err()
+                    }),
+                    Err(_) => "message".into(),
+                };
+                Err(TransportError::Encode(format!(
+                    "while encoding {}: {}",
+                    context, msg
+                )))
+            }
+            result => result,
+        }
+    }
+    #[cfg(feature = "tokio")]
+    fn to_buf(
+        &self,
+        buf_mut: &mut impl crate::transport::BufMut,
+    ) -> Result<(), crate::error::TransportError> {
+        self.to_writer(&mut crate::transport::BufMut::writer(buf_mut))
+    }
+    // The transports with a `canonical` option call these.
+    fn to_writer_with(
+        &self,
+        writer: &mut impl crate::transport::Write,
+        canonical: bool,
+    ) -> Result<(), crate::error::TransportError> {
+        if canonical {
+            self.to_writer_using(&mut Canonical, writer)
+        } else {
+            self.to_writer(writer)
+        }
+    }
+    fn to_buf_with(
+        &self,
+        buf_mut: &mut impl crate::transport::BufMut,
+        canonical: bool,
+    ) -> Result<(), crate::error::TransportError> {
+        self.to_writer_with(&mut crate::transport::BufMut::writer(buf_mut), canonical)
+    }
+    /// The number of bytes this message takes up when encoded, worked out
+    /// without building the encoding.
+    fn encoded_len(&self) -> Result<usize, crate::error::TransportError> {
+        let mut counter = ByteCounter(0);
+        self.to_writer(&mut counter)?;
+        Ok(counter.0)
+    }
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+pub(crate) trait ReadCbor: serde::de::DeserializeOwned {
+    fn from_reader(
+        reader: &mut impl crate::transport::Read,
+    ) -> Result<Self, crate::error::TransportError> {
+        Self::from_reader_using(&mut Ciborium, reader)
+    }
+    fn from_reader_using(
+        codec: &mut impl CborCodec,
+        reader: &mut impl crate::transport::Read,
+    ) -> Result<Self, crate::error::TransportError> {
+        codec.from_reader(reader)
+    }
+    fn from_reader_with_buffer(
+        reader: &mut impl crate::transport::Read,
+        scratch: &mut [u8],
+    ) -> Result<Self, crate::error::TransportError> {
+        Ciborium.from_reader_with_buffer(reader, scratch)
+    }
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+macro_rules! impl_cbor_io {
+    (@write $($type:ty),+ $(,)?) => {
+        $(
+        impl crate::proto::WriteCbor for $type {
+            fn field_name(key: &crate::proto::Value) -> Option<&'static str> {
+                field_name(key)
+            }
+        }
+        )+
+    };
+    ($($type:ty),+ $(,)?) => {
+        $(
+        impl crate::proto::ReadCbor for $type {}
+        impl $type {
+            /// The number of bytes this message takes up when encoded,
+            /// worked out without building the encoding.
+            pub fn encoded_len(&self) -> Result<usize, crate::error::TransportError> {
+                crate::proto::WriteCbor::encoded_len(self)
+            }
+        }
+        impl_cbor_io!(@write $type);
+        )+
+    };
+}
+
+/// Name the part of the message `msg` that fails `bad`, like "params" or
+/// "result of batch item 2", so an encode error can say where it happened.
+/// `field_name` knows the keys of the message's protocol version.
+#[cfg(all(feature = "serde1", feature = "std"))]
+pub(crate) fn encode_context(
+    msg: &Value,
+    field_name: fn(&Value) -> Option<&'static str>,
+    bad: &mut dyn FnMut(&Value) -> bool,
+) -> String {
+    match msg {
+        Value::Array(items) => match items.iter().position(&mut *bad) {
+            Some(n) => format!(
+                "{} of batch item {}",
+                encode_context(&items[n], field_name, &mut *bad),
+                n
+            ),
+            None => "batch".into(),
+        },
+        Value::Tag(_, map) => match &**map {
+            Value::Map(items) => items
+                .iter()
+                .find(|(_, v)| bad(v))
+                .and_then(|(k, _)| field_name(k))
+                .unwrap_or("message")
+                .into(),
+            _ => "message".into(),
+        },
+        _ => "message".into(),
+    }
+}
+
+/// A writer that throws away what it's given and just counts the bytes.
+#[cfg(all(feature = "serde1", feature = "std"))]
+pub(crate) struct ByteCounter(pub usize);
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 64-bit FNV-1a, as a writer so an encoding can be hashed as it's written.
+/// Unlike std's hashers, its output is fixed, so it's safe to store.
+#[cfg(all(feature = "serde1", feature = "std"))]
+struct Fnv64(u64);
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Fnv64(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl std::io::Write for Fnv64 {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &b in buf {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+pub mod codec;
+#[cfg(all(feature = "serde1", feature = "std"))]
+mod indefinite;
+
+#[cfg(feature = "serde1")]
+mod tag;
+
+#[cfg(feature = "arbitrary")]
+mod fuzz;
+
+#[cfg(feature = "json")]
+pub mod json;
+
+pub mod v0;
+
+// The v1 protocol uses small integer keys rather than string identifiers.
+// serde really has a hard time with non-string tags for enums, so v1 handles
+// the message framing itself.
+#[cfg(feature = "serde1")]
+pub mod v1;
+
+// ----- Value ----------------------------------------------------------------
+
+// Our basic dynamic type - an arbitrary CBOR value.
+pub use ciborium::value::Value;
+
+pub mod value;
+
+// ----- Message Types --------------------------------------------------------
+
+/// A Request consists of the MethodID (a string or integer), the Params to
+/// pass to that method, and an optional RequestID. It may also carry a
+/// deadline; see [Request::with_deadline].
+///
+/// This is usually built by a [RequestBuilder].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde1", derive(Serialize, Deserialize))]
+pub struct Request {
+    method: MethodID,
+    params: Option<Params>,
+    req_id: Option<RequestID>,
+    #[cfg_attr(
+        feature = "serde1",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    deadline: Option<u64>,
+}
+
+/// A Notification is a Request that doesn't expect a Response. It's sent over
+/// the wire as a Request with no RequestID.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    method: MethodID,
+    params: Option<Params>,
+}
+
+/// A Response message has two variants: Ok and Err.
+/// An Ok response contains an application-defined CBOR Value, and an Err
+/// contains an [ErrorValue] describing the error that occurred.
+/// Both must include the RequestID that was in the Request.
+///
+/// A long-running call may also send some Partial responses (say, progress
+/// updates) before the Final one; see [ResponseKind].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde1", derive(Serialize, Deserialize))]
+pub struct Response {
+    result: Result<Value, ErrorValue>,
+    req_id: RequestID,
+    #[cfg_attr(feature = "serde1", serde(default))]
+    kind: ResponseKind,
+}
+
+/// Whether a Response is the final result of a Request, or a partial result
+/// with more to follow. Only an Ok result can be Partial; an error always
+/// ends the call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(Serialize, Deserialize))]
+pub enum ResponseKind {
+    #[default]
+    Final,
+    Partial,
+}
+
+/// A Control message manages calls that are already in flight, rather than
+/// making new ones.
+///
+/// A server that runs calls concurrently should keep track of the work for
+/// each outstanding Request by its RequestID, so that a `Cancel` with the same
+/// id can find and stop it. A cancelled call should still get a final
+/// Response (usually an error), so the client isn't left waiting for one. If
+/// the call has already finished, or the id is unknown, the cancel is ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    /// Stop working on the Request with this id.
+    Cancel(RequestID),
+}
+
+/// A Batch holds several Requests (or Responses) that are sent together as a
+/// single message. A Batch is never empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch<T>(Vec<T>);
+
+// ----- Data Structures ------------------------------------------------------
+
+/// Methods can be referred to by name (String), a numeric ID/index, or a
+/// short binary tag (like a hash of the method signature).
+///
+/// MethodIDs are ordered by variant first (String < Number < Binary) and then
+/// by value, so they can be used as keys in a BTreeMap.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MethodID {
+    String(String),
+    Number(u64),
+    Binary(Vec<u8>),
+}
+
+/// A RequestID is a value that is used to identify a request so that it can
+/// be matched up with its corresponding Response.
+///
+/// Numbers too big for a u64 are sent as CBOR bignums (tag 2) and kept as
+/// a [BigNum]. Use [from_bignum](Self::from_bignum) to make one, so that
+/// every number has just one RequestID.
+///
+/// RequestIDs are ordered the same way as [MethodID]s: String < Number <
+/// BigNum < Binary, and then by value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RequestID {
+    String(String),
+    Number(u64),
+    BigNum(BigNum),
+    Binary(Vec<u8>),
+}
+
+/// The big-endian bytes of a number too big for a u64, with no leading
+/// zeros. Only [RequestID::from_bignum] makes these, so they're always too
+/// big for a [RequestID::Number].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigNum(Vec<u8>);
+
+impl BigNum {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// Comparing byte by byte would let a longer (and therefore bigger) BigNum
+// sort first.
+impl Ord for BigNum {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        (self.0.len(), &self.0).cmp(&(other.0.len(), &other.0))
+    }
+}
+
+impl PartialOrd for BigNum {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl RequestID {
+    /// The RequestID for the unsigned integer with big-endian bytes `bytes`.
+    /// This is a Number if the value fits in a u64, and a BigNum otherwise.
+    pub fn from_bignum(bytes: &[u8]) -> Self {
+        let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+        let bytes = &bytes[start..];
+        if bytes.len() <= 8 {
+            let mut buf = [0u8; 8];
+            buf[8 - bytes.len()..].copy_from_slice(bytes);
+            RequestID::Number(u64::from_be_bytes(buf))
+        } else {
+            RequestID::BigNum(BigNum(bytes.into()))
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            RequestID::String(_) => 0,
+            RequestID::Number(_) => 1,
+            RequestID::BigNum(_) => 2,
+            RequestID::Binary(_) => 3,
+        }
+    }
+}
+
+impl Ord for RequestID {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        match (self, other) {
+            (RequestID::String(a), RequestID::String(b)) => a.cmp(b),
+            (RequestID::Number(a), RequestID::Number(b)) => a.cmp(b),
+            (RequestID::BigNum(a), RequestID::BigNum(b)) => a.cmp(b),
+            (RequestID::Binary(a), RequestID::Binary(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for RequestID {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A `Params` item holds the arguments to be passed to a remote method.
+/// They can be sent in one of two forms:
+///
+///   Array: a simple argv-style list (`Vec<Value>`)
+///   Object: key-value pairs (`Vec<(String, Value)>`)
+///
+/// Note that (unlike, say, Python functions) a method's arguments must either
+/// be all key-value pairs or all simple values. You could have a method take
+/// an Array where each Value is (Option<String>, Value) if you wanted to mix
+/// keyval and non-keyval arguments, but... that's none of my business.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Params {
+    Array(Vec<Value>),
+    Named(Vec<(String, Value)>),
+}
+
+/// Which form a [Params] takes; see [Params::kind].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParamsKind {
+    Array,
+    Named,
+}
+
+// Params are encoded as whichever CBOR major type matches the variant - an
+// Array as an array, Named as a map - and decoded by looking at the major type
+// rather than trying each variant in turn, so empty Params keep their form.
+// Named params are kept in a Vec so the caller's ordering is preserved.
+#[cfg(feature = "serde1")]
+mod params_serde {
+    use super::{Params, Value};
+    use crate::alloc_prelude::*;
+    use core::fmt;
+    use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+    use serde::ser::{Serialize, Serializer};
+
+    impl Serialize for Params {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                Params::Array(v) => serializer.collect_seq(v),
+                Params::Named(v) => serializer.collect_map(v.iter().map(|(k, v)| (k, v))),
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Params {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct ParamsVisitor;
+
+            impl<'de> Visitor<'de> for ParamsVisitor {
+                type Value = Params;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("an array or a map with text keys")
+                }
+
+                fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Params, A::Error> {
+                    let mut args = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                    while let Some(v) = seq.next_element::<Value>()? {
+                        args.push(v);
+                    }
+                    Ok(Params::Array(args))
+                }
+
+                fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Params, A::Error> {
+                    let mut named = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                    while let Some(pair) = map.next_entry::<String, Value>()? {
+                        named.push(pair);
+                    }
+                    Ok(Params::Named(named))
+                }
+            }
+
+            deserializer.deserialize_any(ParamsVisitor)
+        }
+    }
+}
+
+// MethodID and RequestID are decoded by looking at the CBOR major type: Text
+// is a String, an unsigned integer is a Number, and a byte string is Binary.
+// (An untagged enum would let a Binary id that happens to be valid UTF-8
+// decode as a String.) A RequestID can also be a bignum (tag 2), which
+// ciborium hands us as a u128 if it fits and as a tagged item if it doesn't.
+#[cfg(feature = "serde1")]
+mod id_serde {
+    use super::{MethodID, RequestID, Value};
+    use crate::alloc_prelude::*;
+    use ciborium::tag::Required;
+    use ciborium_ll::tag::BIGPOS;
+    use core::convert::TryFrom;
+    use core::fmt;
+    use serde::de::{
+        Deserialize, Deserializer, EnumAccess, Error, SeqAccess, Unexpected, VariantAccess, Visitor,
+    };
+    use serde::ser::{Serialize, Serializer};
+
+    // The visit_* methods that both kinds of id have.
+    macro_rules! visit_id {
+        ($type:ident) => {
+            fn visit_u64<E: Error>(self, v: u64) -> Result<$type, E> {
+                Ok($type::Number(v))
+            }
+
+            fn visit_i64<E: Error>(self, v: i64) -> Result<$type, E> {
+                match u64::try_from(v) {
+                    Ok(u) => Ok($type::Number(u)),
+                    Err(_) => Err(E::invalid_value(Unexpected::Signed(v), &self)),
+                }
+            }
+
+            fn visit_str<E: Error>(self, v: &str) -> Result<$type, E> {
+                Ok($type::String(v.into()))
+            }
+
+            fn visit_string<E: Error>(self, v: String) -> Result<$type, E> {
+                Ok($type::String(v))
+            }
+
+            fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<$type, E> {
+                Ok($type::Binary(v.into()))
+            }
+
+            fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<$type, E> {
+                Ok($type::Binary(v))
+            }
+        };
+    }
+
+    impl Serialize for MethodID {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                MethodID::String(s) => serializer.serialize_str(s),
+                MethodID::Number(n) => serializer.serialize_u64(*n),
+                MethodID::Binary(b) => serializer.serialize_bytes(b),
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for MethodID {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct IDVisitor;
+
+            impl<'de> Visitor<'de> for IDVisitor {
+                type Value = MethodID;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("a text, unsigned integer, or byte string method id")
+                }
+
+                visit_id!(MethodID);
+            }
+
+            deserializer.deserialize_any(IDVisitor)
+        }
+    }
+
+    // Serializes as a CBOR byte string, for the inside of a bignum.
+    struct BigNumBytes<'a>(&'a [u8]);
+
+    impl Serialize for BigNumBytes<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(self.0)
+        }
+    }
+
+    impl Serialize for RequestID {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                RequestID::String(s) => serializer.serialize_str(s),
+                RequestID::Number(n) => serializer.serialize_u64(*n),
+                RequestID::BigNum(b) => {
+                    Required::<_, BIGPOS>(BigNumBytes(b.as_bytes())).serialize(serializer)
+                }
+                RequestID::Binary(b) => serializer.serialize_bytes(b),
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for RequestID {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct IDVisitor;
+
+            impl<'de> Visitor<'de> for IDVisitor {
+                type Value = RequestID;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("a text, unsigned integer, or byte string request id")
+                }
+
+                visit_id!(RequestID);
+
+                fn visit_u128<E: Error>(self, v: u128) -> Result<RequestID, E> {
+                    Ok(RequestID::from_bignum(&v.to_be_bytes()))
+                }
+
+                // This is how ciborium hands over a tagged item that isn't a
+                // small bignum.
+                fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<RequestID, A::Error> {
+                    let (_, variant): (String, _) = data.variant()?;
+                    variant.tuple_variant(2, BigNumVisitor)
+                }
+            }
+
+            // The (tag, item) pair for a tagged item, which has to be a
+            // positive bignum.
+            struct BigNumVisitor;
+
+            impl<'de> Visitor<'de> for BigNumVisitor {
+                type Value = RequestID;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("a bignum request id")
+                }
+
+                fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<RequestID, A::Error> {
+                    let tag: Option<u64> = seq.next_element()?;
+                    match (tag, seq.next_element()?) {
+                        (Some(BIGPOS), Some(Value::Bytes(b))) => Ok(RequestID::from_bignum(&b)),
+                        _ => Err(A::Error::invalid_type(
+                            Unexpected::Other("tagged item"),
+                            &self,
+                        )),
+                    }
+                }
+            }
+
+            deserializer.deserialize_any(IDVisitor)
+        }
+    }
+}
+
+/// An ErrorValue is returned by the server when a Request does not complete
+/// successfully.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde1", derive(Serialize, Deserialize))]
+pub struct ErrorValue {
+    code: i64,
+    message: String,
+    #[cfg_attr(feature = "serde1", serde(skip_serializing_if = "Option::is_none"))]
+    data: Option<Value>,
+}
+
+// ----- Standard error codes -------------------------------------------------
+
+// These are the reserved error codes from JSON-RPC 2.0. Applications should
+// pick codes outside the range -32768 to -32000 for their own errors.
+
+/// The server couldn't decode the message it received.
+pub const PARSE_ERROR: i64 = -32700;
+/// The message was valid CBOR, but not a valid Request.
+pub const INVALID_REQUEST: i64 = -32600;
+/// The requested method doesn't exist.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// The method exists, but the Params weren't what it expected.
+pub const INVALID_PARAMS: i64 = -32602;
+/// Something went wrong inside the server.
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The first of the codes JSON-RPC sets aside for implementation-defined
+/// server errors, and the code [ErrorValue::from_error] uses.
+pub const SERVER_ERROR: i64 = -32000;
+/// The server is refusing calls to a method because it's been called too
+/// often; try again later. This is one of the implementation-defined server
+/// error codes.
+pub const RATE_LIMITED: i64 = -32005;
+
+// ----- Useful methods for the above items -----------------------------------
+
+macro_rules! impl_getters {
+    ($(
+        $type:ty { $($field:ident: $fieldtype:ty),+ $(,)? }
+    ),+ $(,)?) => {
+        $(
+        impl $type {
+            $(
+            pub fn $field(&self) -> &$fieldtype {
+                &self.$field
+            }
+            )+
+        }
+        )+
+    };
+}
+
+impl_getters! {
+    ErrorValue { code: i64, message:String, data:Option<Value> },
+    Request { method: MethodID, params:Option<Params>, req_id:Option<RequestID> },
+    Notification { method: MethodID, params:Option<Params> },
+    Response { result: Result<Value,ErrorValue>, req_id:RequestID, kind:ResponseKind }
+}
+
+impl Request {
+    /// A Request for `method`. Without a `req_id`, it's a notification. See
+    /// [RequestBuilder] for a friendlier way to put one together.
+    pub fn new(
+        method: impl Into<MethodID>,
+        params: Option<Params>,
+        req_id: Option<RequestID>,
+    ) -> Self {
+        Self {
+            method: method.into(),
+            params,
+            req_id,
+            deadline: None,
+        }
+    }
+
+    /// Ask the server to give up on this Request if it's still waiting at
+    /// `deadline`, in milliseconds since the Unix epoch. The server decides
+    /// what to do with an expired Request; peers that predate deadlines just
+    /// ignore it.
+    pub fn with_deadline(mut self, deadline: u64) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// The deadline set by [with_deadline](Self::with_deadline), if any.
+    pub fn deadline(&self) -> Option<u64> {
+        self.deadline
+    }
+
+    /// A hash of the method and params, for recognizing a retried Request -
+    /// say, to answer it from a cache of recent Responses. The RequestID and
+    /// deadline aren't included, so a retry hashes the same as the original.
+    ///
+    /// The hash is taken over the [canonical](value::canonical) CBOR
+    /// encoding, so named params (and maps inside the params) hash the same
+    /// whatever order they're in, and empty params hash the same as none.
+    /// It's stable across runs and platforms, so it can be stored, but it's
+    /// not cryptographic; don't rely on it against a peer trying to collide.
+    #[cfg(all(feature = "serde1", feature = "std"))]
+    pub fn content_hash(&self) -> u64 {
+        let method = Value::from(self.method.clone());
+        let params = match self.params.clone().and_then(Params::into_option) {
+            Some(params) => value::canonical(params.into()),
+            None => Value::Null,
+        };
+        let mut hasher = Fnv64::default();
+        // Encoding a Value can't fail, and neither can writing to the hasher
+        ciborium::ser::into_writer(&Value::Array(vec![method, params]), &mut hasher).unwrap();
+        hasher.0
+    }
+
+    /// Take the Request apart, so its contents can be used without cloning.
+    pub fn into_parts(self) -> (MethodID, Option<Params>, Option<RequestID>) {
+        (self.method, self.params, self.req_id)
+    }
+
+    /// Take the Params out of the Request, leaving None in their place.
+    pub fn take_params(&mut self) -> Option<Params> {
+        self.params.take()
+    }
+
+    /// A Request without a RequestID is a notification; the server won't
+    /// send a Response for it.
+    pub fn is_notification(&self) -> bool {
+        self.req_id.is_none()
+    }
+
+    /// The [METHOD_NOT_FOUND] Response to this Request, naming its method, or
+    /// None for a notification.
+    pub fn method_not_found_response(&self) -> Option<Response> {
+        let req_id = self.req_id.clone()?;
+        Some(Response::err(
+            ErrorValue::method_not_found(&self.method),
+            req_id,
+        ))
+    }
+
+    /// Check whether `response` is the reply to this Request. A notification
+    /// never gets a reply, so nothing matches it.
+    pub fn matches_response(&self, response: &Response) -> bool {
+        self.req_id.as_ref() == Some(&response.req_id)
+    }
+
+    /// Check the Request against `limits`, so a server can turn away absurd
+    /// input before dispatching it.
+    pub fn validate(&self, limits: &RequestLimits) -> Result<(), ProtocolError> {
+        let method_len = match &self.method {
+            MethodID::String(s) => s.len(),
+            MethodID::Binary(b) => b.len(),
+            MethodID::Number(_) => 0,
+        };
+        if method_len > limits.max_method_len {
+            return Err(ProtocolError::MethodTooLong);
+        }
+        if self.params.as_ref().map_or(0, Params::len) > limits.max_params {
+            return Err(ProtocolError::TooManyParams);
+        }
+        Ok(())
+    }
+}
+
+/// Limits for [Request::validate]. The default is no limit at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    /// Maximum length of a text or binary MethodID, in bytes. Numeric
+    /// MethodIDs always pass.
+    pub max_method_len: usize,
+    /// Maximum number of positional or named Params.
+    pub max_params: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_method_len: usize::MAX,
+            max_params: usize::MAX,
+        }
+    }
+}
+
+impl ErrorValue {
+    /// An error with the given code and message, and no data. See the
+    /// standard error codes above, or use an application-defined one.
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    /// Attach additional information about the error.
+    pub fn with_data(mut self, data: impl Into<Value>) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    pub fn parse_error(message: impl Into<String>) -> Self {
+        Self::new(PARSE_ERROR, message)
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(INVALID_REQUEST, message)
+    }
+
+    pub fn method_not_found(method: &MethodID) -> Self {
+        Self::new(METHOD_NOT_FOUND, format!("method not found: {}", method))
+    }
+
+    pub fn rate_limited(method: &MethodID) -> Self {
+        Self::new(RATE_LIMITED, format!("too many requests: {}", method))
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(INTERNAL_ERROR, message)
+    }
+
+    /// Record `cause` as the reason for this error, by storing it as the
+    /// data. This replaces any data already attached.
+    pub fn chain(self, cause: ErrorValue) -> Self {
+        self.with_data(cause)
+    }
+
+    /// The error this one was [chain]ed to, or None if the data isn't an
+    /// ErrorValue (or there's no data).
+    ///
+    /// [chain]: Self::chain
+    pub fn cause(&self) -> Option<ErrorValue> {
+        self.data
+            .clone()
+            .and_then(|data| ErrorValue::try_from(data).ok())
+    }
+
+    /// Deserialize the data into a user-defined type, or None if there's no
+    /// data. If the data doesn't fit `T`, this returns
+    /// [ProtocolError::InvalidDataType].
+    #[cfg(feature = "serde1")]
+    pub fn data_as<T: serde::de::DeserializeOwned>(&self) -> Option<Result<T, ProtocolError>> {
+        self.data.as_ref().map(|data| {
+            data.deserialized()
+                .map_err(|_| ProtocolError::InvalidDataType)
+        })
+    }
+
+    /// A [SERVER_ERROR] with `err` as the message, for errors that don't
+    /// have a code of their own.
+    #[cfg(feature = "std")]
+    pub fn from_error(err: &dyn std::error::Error) -> Self {
+        Self::new(SERVER_ERROR, err.to_string())
+    }
+}
+
+/// An error that can be sent back to the client as an [ErrorValue].
+///
+/// Implement this for an application's error type to pick the codes its
+/// errors get, and a handler can use `.map_err(IntoErrorValue::into_error_value)`
+/// to return it:
+/// ```
+/// use ciborium_rpc::proto::{ErrorValue, IntoErrorValue};
+///
+/// #[derive(Debug)]
+/// enum AppError {
+///     NoSuchUser(String),
+///     Io(std::io::Error),
+/// }
+///
+/// impl IntoErrorValue for AppError {
+///     fn into_error_value(self) -> ErrorValue {
+///         match self {
+///             AppError::NoSuchUser(u) => ErrorValue::new(1, "no such user").with_data(u),
+///             AppError::Io(e) => ErrorValue::new(2, e.to_string()),
+///         }
+///     }
+/// }
+///
+/// let e = AppError::NoSuchUser("bob".into()).into_error_value();
+/// assert_eq!(e.code(), &1);
+/// ```
+///
+/// There's deliberately no blanket impl for every [std::error::Error]:
+/// ErrorValue and [ProtocolError] are errors too, and they already know
+/// their codes. Use [ErrorValue::from_error] for the generic case, or
+/// register the handler with
+/// [Server::register_fallible](crate::server::Server::register_fallible),
+/// which uses it for every error.
+pub trait IntoErrorValue {
+    fn into_error_value(self) -> ErrorValue;
+}
+
+impl IntoErrorValue for ErrorValue {
+    fn into_error_value(self) -> ErrorValue {
+        self
+    }
+}
+
+impl IntoErrorValue for ProtocolError {
+    fn into_error_value(self) -> ErrorValue {
+        self.into()
+    }
+}
+
+impl Response {
+    /// The final Response to the Request with the given id.
+    pub fn new(result: Result<Value, ErrorValue>, req_id: RequestID) -> Self {
+        Self {
+            result,
+            req_id,
+            kind: ResponseKind::Final,
+        }
+    }
+
+    /// The final Response to `request`, with its id. Notifications don't get
+    /// a Response, so for one of those this fails with
+    /// [ProtocolError::InvalidRequestID].
+    pub fn for_request(
+        request: &Request,
+        result: Result<Value, ErrorValue>,
+    ) -> Result<Self, ProtocolError> {
+        match &request.req_id {
+            Some(req_id) => Ok(Self::new(result, req_id.clone())),
+            None => Err(ProtocolError::InvalidRequestID),
+        }
+    }
+
+    /// A successful Response. An error Response is built much the same way:
+    ///
+    /// ```
+    /// use ciborium_rpc::proto::{ErrorValue, Response, Value, METHOD_NOT_FOUND};
+    ///
+    /// let ok = Response::ok(Value::from("done"), 1u8.into());
+    /// assert_eq!(ok.result(), &Ok(Value::from("done")));
+    ///
+    /// let err = Response::err(ErrorValue::new(METHOD_NOT_FOUND, "no such method"), 2u8.into());
+    /// assert!(err.result().is_err());
+    /// ```
+    pub fn ok(value: impl Into<Value>, req_id: RequestID) -> Self {
+        Self::new(Ok(value.into()), req_id)
+    }
+
+    /// An error Response. See [Response::ok] for an example.
+    pub fn err(error: ErrorValue, req_id: RequestID) -> Self {
+        Self::new(Err(error), req_id)
+    }
+
+    /// A partial result for the Request with the given id. The call isn't
+    /// finished until a Final Response (or an error) is sent.
+    #[cfg(any(test, feature = "std"))]
+    pub(crate) fn partial(value: Value, req_id: RequestID) -> Self {
+        Self {
+            result: Ok(value),
+            req_id,
+            kind: ResponseKind::Partial,
+        }
+    }
+
+    pub fn is_partial(&self) -> bool {
+        self.kind == ResponseKind::Partial && self.result.is_ok()
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+
+    pub fn is_err(&self) -> bool {
+        self.result.is_err()
+    }
+
+    /// Transform an Ok result with `f`. The RequestID, the kind of Response,
+    /// and any error are kept as they are.
+    pub fn map_value<F: FnOnce(Value) -> Value>(self, f: F) -> Response {
+        Self {
+            result: self.result.map(f),
+            ..self
+        }
+    }
+
+    /// Take the result out of the Response, without cloning it.
+    pub fn into_result_value(self) -> Result<Value, ErrorValue> {
+        self.result
+    }
+
+    /// Deserialize an Ok result into a user-defined type. An Err result is
+    /// passed through as-is, so callers can write:
+    ///
+    /// ```ignore
+    /// let n: i32 = response.into_result()??;
+    /// ```
+    ///
+    /// If the result doesn't fit `T`, this returns
+    /// [ProtocolError::InvalidResultType].
+    #[cfg(feature = "serde1")]
+    pub fn into_result<T: serde::de::DeserializeOwned>(
+        self,
+    ) -> Result<Result<T, ErrorValue>, ProtocolError> {
+        match self.result {
+            Ok(value) => value
+                .deserialized()
+                .map(Ok)
+                .map_err(|_| ProtocolError::InvalidResultType),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+
+    /// Take the bytes out of an Ok result that's [Value::Bytes], without
+    /// copying them. Like [into_result](Self::into_result), an Err result is
+    /// passed through as-is, and any other result returns
+    /// [ProtocolError::InvalidResultType].
+    ///
+    /// That's why this returns a nested Result, the same as `into_result`,
+    /// and not just `Result<Vec<u8>, ProtocolError>`: an error Response is a
+    /// well-formed reply, not a protocol error, and a ProtocolError has no
+    /// room for the ErrorValue's code, message, and data. With `??`, both
+    /// kinds of error convert into an application's own error type.
+    pub fn into_bytes(self) -> Result<Result<Vec<u8>, ErrorValue>, ProtocolError> {
+        match self.result {
+            Ok(Value::Bytes(bytes)) => Ok(Ok(bytes)),
+            Ok(_) => Err(ProtocolError::InvalidResultType),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+
+    /// Take the string out of an Ok result that's [Value::Text]; see
+    /// [into_bytes](Self::into_bytes).
+    pub fn into_text(self) -> Result<Result<String, ErrorValue>, ProtocolError> {
+        match self.result {
+            Ok(Value::Text(text)) => Ok(Ok(text)),
+            Ok(_) => Err(ProtocolError::InvalidResultType),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+}
+
+impl Notification {
+    pub fn new(method: impl Into<MethodID>, params: Option<Params>) -> Self {
+        Self {
+            method: method.into(),
+            params,
+        }
+    }
+}
+
+impl From<Notification> for Request {
+    fn from(n: Notification) -> Self {
+        Request {
+            method: n.method,
+            params: n.params,
+            req_id: None,
+            deadline: None,
+        }
+    }
+}
+
+impl TryFrom<Request> for Notification {
+    type Error = ProtocolError;
+    fn try_from(r: Request) -> Result<Self, Self::Error> {
+        match r.req_id {
+            None => Ok(Notification {
+                method: r.method,
+                params: r.params,
+            }),
+            Some(_) => Err(ProtocolError::UnexpectedMessage),
+        }
+    }
+}
+
+impl<T> Batch<T> {
+    /// Make a new Batch, or fail with [ProtocolError::EmptyBatch] if `items`
+    /// is empty.
+    pub fn new(items: Vec<T>) -> Result<Self, ProtocolError> {
+        if items.is_empty() {
+            Err(ProtocolError::EmptyBatch)
+        } else {
+            Ok(Self(items))
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Always false, since a Batch can't be empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'_, T> {
+        self.0.iter()
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.0
+    }
+}
+
+impl<T> IntoIterator for Batch<T> {
+    type Item = T;
+    type IntoIter = alloc::vec::IntoIter<T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl Batch<Request> {
+    /// Pass each Request in the Batch to `handler` and collect the Responses.
+    ///
+    /// Notifications are handled too, but they don't get a Response, so this
+    /// returns None if every item in the Batch was a notification.
+    pub fn respond<F>(self, mut handler: F) -> Option<Batch<Response>>
+    where
+        F: FnMut(Request) -> Result<Value, ErrorValue>,
+    {
+        let mut responses = Vec::new();
+        for request in self {
+            let req_id = request.req_id.clone();
+            let result = handler(request);
+            if let Some(req_id) = req_id {
+                responses.push(Response::new(result, req_id));
+            }
+        }
+        Batch::new(responses).ok()
+    }
+}
+
+impl fmt::Display for MethodID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MethodID::String(s) => f.write_str(s),
+            MethodID::Number(n) => write!(f, "#{}", n),
+            MethodID::Binary(b) => {
+                f.write_str("0x")?;
+                b.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+/// Numbers, BigNums included, are written in decimal, and Binary IDs in
+/// hex.
+impl fmt::Display for RequestID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestID::String(s) => f.write_str(s),
+            RequestID::Number(n) => write!(f, "{}", n),
+            RequestID::BigNum(n) => write!(f, "{}", n),
+            RequestID::Binary(b) => {
+                f.write_str("0x")?;
+                b.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+impl fmt::Display for BigNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Long division by 10, collecting the digits least significant first
+        let mut num = self.0.clone();
+        let mut digits = Vec::new();
+        while num.iter().any(|&b| b != 0) {
+            let mut rem = 0u16;
+            for byte in num.iter_mut() {
+                let cur = (rem << 8) | u16::from(*byte);
+                *byte = (cur / 10) as u8;
+                rem = cur % 10;
+            }
+            digits.push(b'0' + rem as u8);
+        }
+        if digits.is_empty() {
+            digits.push(b'0');
+        }
+        digits.reverse();
+        f.write_str(core::str::from_utf8(&digits).map_err(|_| fmt::Error)?)
+    }
+}
+
+impl fmt::Display for ErrorValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error {}: {}", self.code, self.message)?;
+        if self.data.is_some() {
+            f.write_str(" (with data)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ErrorValue {}
+
+impl Params {
+    /// The number of arguments, positional or named.
+    pub fn len(&self) -> usize {
+        match self {
+            Params::Array(v) => v.len(),
+            Params::Named(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Params::Array(v) => v.is_empty(),
+            Params::Named(v) => v.is_empty(),
+        }
+    }
+
+    pub fn kind(&self) -> ParamsKind {
+        match self {
+            Params::Array(_) => ParamsKind::Array,
+            Params::Named(_) => ParamsKind::Named,
+        }
+    }
+
+    pub fn is_array(&self) -> bool {
+        self.kind() == ParamsKind::Array
+    }
+
+    pub fn is_named(&self) -> bool {
+        self.kind() == ParamsKind::Named
+    }
+
+    /// Convert into Option<Params>, turning an empty set of Params into None.
+    pub fn into_option(self) -> Option<Self> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self)
+        }
+    }
+
+    /// Add a positional argument. Empty Params of either kind become Array
+    /// params; pushing onto non-empty Named params fails with
+    /// [ProtocolError::MixedParams] and leaves them unchanged.
+    pub fn push(&mut self, value: impl Into<Value>) -> Result<(), ProtocolError> {
+        match self {
+            Params::Array(v) => v.push(value.into()),
+            Params::Named(v) if v.is_empty() => *self = Params::Array(vec![value.into()]),
+            Params::Named(_) => return Err(ProtocolError::MixedParams),
+        }
+        Ok(())
+    }
+
+    /// Add a named argument, after any existing ones - even one with the same
+    /// name. Empty Params of either kind become Named params; inserting into
+    /// non-empty Array params fails with [ProtocolError::MixedParams] and
+    /// leaves them unchanged.
+    pub fn insert(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<(), ProtocolError> {
+        match self {
+            Params::Named(v) => v.push((key.into(), value.into())),
+            Params::Array(v) if v.is_empty() => {
+                *self = Params::Named(vec![(key.into(), value.into())])
+            }
+            Params::Array(_) => return Err(ProtocolError::MixedParams),
+        }
+        Ok(())
+    }
+
+    /// Get a positional argument by index. Always None for Named params.
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        match self {
+            Params::Array(v) => v.get(index),
+            Params::Named(_) => None,
+        }
+    }
+
+    /// Iterate over the positional arguments along with their indexes, for
+    /// error messages that say which one was wrong. Named params have none.
+    pub fn iter_positional(&self) -> impl Iterator<Item = (usize, &Value)> {
+        match self {
+            Params::Array(v) => v.as_slice(),
+            Params::Named(_) => &[],
+        }
+        .iter()
+        .enumerate()
+    }
+
+    /// Deserialize the positional argument at `index` into a user-defined
+    /// type. Fails with [ProtocolError::MissingArg] if there's no such
+    /// argument (including for Named params), or
+    /// [ProtocolError::InvalidArgType] if it doesn't fit `T`.
+    #[cfg(feature = "serde1")]
+    pub fn arg_as<T: serde::de::DeserializeOwned>(&self, index: usize) -> Result<T, ProtocolError> {
+        self.get(index)
+            .ok_or(ProtocolError::MissingArg(index))?
+            .deserialized()
+            .map_err(|_| ProtocolError::InvalidArgType(index))
+    }
+
+    /// Get a named argument by name. Always None for Array params.
+    ///
+    /// If the name appears more than once, this returns the first one.
+    pub fn get_named(&self, key: &str) -> Option<&Value> {
+        match self {
+            Params::Array(_) => None,
+            Params::Named(v) => v.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+        }
+    }
+
+    /// Turn Array params into Named params, naming each argument after the
+    /// matching entry in `keys`. Fails with [ProtocolError::InvalidParamType]
+    /// unless there's exactly one key per argument. Named params are returned
+    /// as they are.
+    pub fn into_named(self, keys: &[&str]) -> Result<Params, ProtocolError> {
+        match self {
+            Params::Array(v) if v.len() == keys.len() => Ok(Params::Named(
+                keys.iter().map(|k| k.to_string()).zip(v).collect(),
+            )),
+            Params::Array(_) => Err(ProtocolError::InvalidParamType),
+            named => Ok(named),
+        }
+    }
 
-// FUTURE: it'd be great if we had a v1 protocol that used CBOR tags to
-// identify the message parts rather than string identifiers.
-// Unfortunately, serde really has a hard time with non-string tags for enums,
-// so we'll probably have to handle the message framing ourselves...
+    /// Turn Named params into Array params, with the arguments in the order
+    /// given by `keys`. Fails with [ProtocolError::InvalidParamType] unless
+    /// each key names exactly one argument, and each argument is named by
+    /// exactly one key. Array params are returned as they are.
+    pub fn into_positional(self, keys: &[&str]) -> Result<Params, ProtocolError> {
+        let mut named = match self {
+            Params::Named(v) if v.len() == keys.len() => v,
+            Params::Named(_) => return Err(ProtocolError::InvalidParamType),
+            array => return Ok(array),
+        };
+        let mut args = Vec::with_capacity(keys.len());
+        for key in keys {
+            match named.iter().position(|(k, _)| k == key) {
+                Some(i) => args.push(named.swap_remove(i).1),
+                None => return Err(ProtocolError::InvalidParamType),
+            }
+        }
+        Ok(Params::Array(args))
+    }
 
-// ----- Value ----------------------------------------------------------------
+    /// Deserialize the Params into a user-defined type.
+    ///
+    /// Array params deserialize like a tuple (or tuple struct), and Named
+    /// params deserialize like a struct with matching field names. If the
+    /// Params don't fit `T` - wrong types, missing fields, etc. - this
+    /// returns [ProtocolError::InvalidParamType].
+    #[cfg(feature = "serde1")]
+    pub fn into_typed<T: serde::de::DeserializeOwned>(self) -> Result<T, ProtocolError> {
+        Value::from(self)
+            .deserialized()
+            .map_err(|_| ProtocolError::InvalidParamType)
+    }
+}
 
-// Our basic dynamic type - an arbitrary CBOR value.
-pub use ciborium::value::Value;
+/// Empty Array params.
+impl Default for Params {
+    fn default() -> Self {
+        Params::Array(Vec::new())
+    }
+}
 
-// ----- Message Types --------------------------------------------------------
+/// Iterate over the arguments as `(name, value)` pairs.
+///
+/// Positional arguments have no name, so Array params yield `(None, value)`.
+impl IntoIterator for Params {
+    type Item = (Option<String>, Value);
+    type IntoIter = ParamsIntoIter;
+    fn into_iter(self) -> Self::IntoIter {
+        match self {
+            Params::Array(v) => ParamsIntoIter::Array(v.into_iter()),
+            Params::Named(v) => ParamsIntoIter::Named(v.into_iter()),
+        }
+    }
+}
 
-/// A Request consists of the MethodID (a string or integer), the Params to
-/// pass to that method, and an optional RequestID.
+/// The iterator returned by [Params::into_iter].
+#[derive(Debug)]
+pub enum ParamsIntoIter {
+    Array(alloc::vec::IntoIter<Value>),
+    Named(alloc::vec::IntoIter<(String, Value)>),
+}
+
+impl Iterator for ParamsIntoIter {
+    type Item = (Option<String>, Value);
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            ParamsIntoIter::Array(i) => i.next().map(|v| (None, v)),
+            ParamsIntoIter::Named(i) => i.next().map(|(k, v)| (Some(k), v)),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            ParamsIntoIter::Array(i) => i.size_hint(),
+            ParamsIntoIter::Named(i) => i.size_hint(),
+        }
+    }
+}
+
+// ----- Builders -------------------------------------------------------------
+
+/// A RequestBuilder constructs a [Request] one argument at a time.
+///
+/// Positional arguments are added with [arg](RequestBuilder::arg) and named
+/// arguments with [param](RequestBuilder::param). A Request can't have both,
+/// so [build](RequestBuilder::build) fails if they've been mixed.
 #[derive(Debug, Clone, PartialEq)]
-#[cfg_attr(feature = "serde1", derive(Serialize, Deserialize))]
-pub struct Request {
+pub struct RequestBuilder {
     method: MethodID,
-    params: Option<Params>,
+    args: Vec<Value>,
+    named: Vec<(String, Value)>,
     req_id: Option<RequestID>,
 }
 
-/// A Response message has two variants: Ok and Err.
-/// An Ok response contains an application-defined CBOR Value, and an Err
-/// contains an [ErrorValue] describing the error that occurred.
-/// Both must include the RequestID that was in the Request.
-#[derive(Debug, Clone, PartialEq)]
-#[cfg_attr(feature = "serde1", derive(Serialize, Deserialize))]
-pub struct Response {
-    result: Result<Value, ErrorValue>,
-    req_id: RequestID,
-}
+impl RequestBuilder {
+    pub fn new(method: impl Into<MethodID>) -> Self {
+        Self {
+            method: method.into(),
+            args: Vec::new(),
+            named: Vec::new(),
+            req_id: None,
+        }
+    }
 
-// ----- Data Structures ------------------------------------------------------
+    /// Add a positional argument.
+    pub fn arg(mut self, value: impl Into<Value>) -> Self {
+        self.args.push(value.into());
+        self
+    }
 
-/// Methods can be referred to by name (String) or a numeric ID/index.
-#[derive(Debug, Clone, PartialEq, Hash)]
-#[cfg_attr(feature = "serde1", derive(Serialize, Deserialize))]
-#[cfg_attr(feature = "serde1", serde(untagged))]
-pub enum MethodID {
-    String(String),
-    Number(u64),
-}
+    /// Add a named argument.
+    pub fn param(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.named.push((name.into(), value.into()));
+        self
+    }
 
-/// A RequestID is a value that is used to identify a request so that it can
-/// be matched up with its corresponding Response.
-#[derive(Debug, Clone, PartialEq, Hash)]
-#[cfg_attr(feature = "serde1", derive(Serialize, Deserialize))]
-#[cfg_attr(feature = "serde1", serde(untagged))]
-pub enum RequestID {
-    Number(u64),
-    String(String),
-    Binary(Vec<u8>),
+    /// Set the RequestID. Requests without an id get no Response.
+    pub fn id(mut self, req_id: impl Into<RequestID>) -> Self {
+        self.req_id = Some(req_id.into());
+        self
+    }
+
+    /// Build the Request, or fail with [ProtocolError::MixedParams] if both
+    /// positional and named arguments were supplied.
+    pub fn build(self) -> Result<Request, ProtocolError> {
+        let params = match (self.args.is_empty(), self.named.is_empty()) {
+            (true, true) => None,
+            (false, true) => Some(Params::Array(self.args)),
+            (true, false) => Some(Params::Named(self.named)),
+            (false, false) => return Err(ProtocolError::MixedParams),
+        };
+        Ok(Request {
+            method: self.method,
+            params,
+            req_id: self.req_id,
+            deadline: None,
+        })
+    }
 }
 
-/// A `Params` item holds the arguments to be passed to a remote method.
-/// They can be sent in one of two forms:
+/// A ResponseBuilder answers the Request with a given [RequestID], finishing
+/// with either [ok](ResponseBuilder::ok) or [err](ResponseBuilder::err).
 ///
-///   Array: a simple argv-style list (`Vec<Value>`)
-///   Object: key-value pairs (`Vec<(String, Value)>`)
+/// `err` gives an [ErrorResponseBuilder], which can attach
+/// [data](ErrorResponseBuilder::data) before it builds the Response. It has
+/// no `ok` of its own, so a Response can't be both:
+/// ```compile_fail
+/// use ciborium_rpc::proto::ResponseBuilder;
 ///
-/// Note that (unlike, say, Python functions) a method's arguments must either
-/// be all key-value pairs or all simple values. You could have a method take
-/// an Array where each Value is (Option<String>, Value) if you wanted to mix
-/// keyval and non-keyval arguments, but... that's none of my business.
+/// let resp = ResponseBuilder::new(1u8).err(-1, "no").ok("yes");
+/// ```
 #[derive(Debug, Clone, PartialEq)]
-#[cfg_attr(feature = "serde1", derive(Serialize, Deserialize))]
-#[cfg_attr(feature = "serde1", serde(untagged))]
-pub enum Params {
-    Array(Vec<Value>),
-    Named(Vec<(String, Value)>),
+pub struct ResponseBuilder {
+    req_id: RequestID,
 }
 
-/// An ErrorValue is returned by the server when a Request does not complete
-/// successfully.
-#[derive(Debug, Clone, PartialEq)]
-#[cfg_attr(feature = "serde1", derive(Serialize, Deserialize))]
-pub struct ErrorValue {
-    code: i64,
-    message: String,
-    #[cfg_attr(feature = "serde1", serde(skip_serializing_if = "Option::is_none"))]
-    data: Option<Value>,
-}
+impl ResponseBuilder {
+    pub fn new(req_id: impl Into<RequestID>) -> Self {
+        Self {
+            req_id: req_id.into(),
+        }
+    }
 
-// ----- Useful methods for the above items -----------------------------------
+    /// Build a successful Response.
+    pub fn ok(self, value: impl Into<Value>) -> Response {
+        Response::ok(value, self.req_id)
+    }
 
-macro_rules! impl_getters {
-    ($(
-        $type:ty { $($field:ident: $fieldtype:ty),+ $(,)? }
-    ),+ $(,)?) => {
-        $(
-        impl $type {
-            $(
-            pub fn $field(&self) -> &$fieldtype {
-                &self.$field
-            }
-            )+
+    /// Start building an error Response.
+    pub fn err(self, code: i64, message: impl Into<String>) -> ErrorResponseBuilder {
+        ErrorResponseBuilder {
+            error: ErrorValue::new(code, message),
+            req_id: self.req_id,
         }
-        )+
-    };
+    }
 }
 
-impl_getters! {
-    ErrorValue { code: i64, message:String, data:Option<Value> },
-    Request { method: MethodID, params:Option<Params>, req_id:Option<RequestID> },
-    Response { result: Result<Value,ErrorValue>, req_id:RequestID }
+/// The error half of a [ResponseBuilder].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorResponseBuilder {
+    error: ErrorValue,
+    req_id: RequestID,
 }
 
-impl Params {
-    pub fn is_empty(&self) -> bool {
-        match self {
-            Params::Array(v) => v.is_empty(),
-            Params::Named(v) => v.is_empty(),
-        }
+impl ErrorResponseBuilder {
+    /// Attach extra data to the error, replacing any that's already there.
+    pub fn data(mut self, data: impl Into<Value>) -> Self {
+        self.error = self.error.with_data(data);
+        self
     }
 
-    /// Convert into Option<Params>, turning an empty set of Params into None.
-    pub fn into_option(self) -> Option<Self> {
-        if self.is_empty() {
-            None
-        } else {
-            Some(self)
-        }
+    pub fn build(self) -> Response {
+        Response::err(self.error, self.req_id)
     }
 }
 
 // ----- Value conversion impls for Params, RequestID, MethodID, etc ----------
 
-use crate::error::ProtocolError;
-
 fn to_keyval(pair: (Value, Value)) -> Result<(String, Value), ProtocolError> {
     match pair {
         (Value::Text(s), v) => Ok((s, v)),
@@ -169,6 +1586,10 @@ impl TryFrom<Value> for RequestID {
             },
             Value::Text(s) => Ok(s.into()),
             Value::Bytes(b) => Ok(b.into()),
+            Value::Tag(BIGPOS, v) => match *v {
+                Value::Bytes(b) => Ok(Self::from_bignum(&b)),
+                _ => Err(Self::Error::InvalidRequestID),
+            },
             _ => Err(Self::Error::InvalidRequestID),
         }
     }
@@ -183,11 +1604,40 @@ impl TryFrom<Value> for MethodID {
                 Err(_) => Err(Self::Error::InvalidMethodID),
             },
             Value::Text(s) => Ok(s.into()),
+            Value::Bytes(b) => Ok(b.into()),
             _ => Err(Self::Error::InvalidMethodID),
         }
     }
 }
 
+impl TryFrom<Value> for ErrorValue {
+    type Error = ProtocolError;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let (mut code, mut message, mut data) = (None, None, None);
+        let map = match value {
+            Value::Map(m) => m,
+            _ => return Err(Self::Error::InvalidMessage),
+        };
+        for (k, v) in map {
+            match (k.as_text(), v) {
+                (Some("code"), Value::Integer(i)) => code = i64::try_from(i).ok(),
+                (Some("message"), Value::Text(s)) => message = Some(s),
+                (Some("data"), v) => data = Some(v),
+                (Some("code" | "message"), _) => return Err(Self::Error::InvalidMessage),
+                _ => {}
+            }
+        }
+        match (code, message) {
+            (Some(code), Some(message)) => Ok(ErrorValue {
+                code,
+                message,
+                data,
+            }),
+            _ => Err(Self::Error::InvalidMessage),
+        }
+    }
+}
+
 impl From<Params> for Value {
     fn from(params: Params) -> Self {
         match params {
@@ -200,10 +1650,24 @@ impl From<Params> for Value {
 impl From<RequestID> for Value {
     fn from(r: RequestID) -> Self {
         match r {
-            RequestID::Binary(b) => Value::Bytes(b.into()),
+            RequestID::Binary(b) => Value::Bytes(b),
             RequestID::Number(i) => Value::Integer(i.into()),
-            RequestID::String(s) => Value::Text(s.into()),
+            RequestID::BigNum(b) => Value::Tag(BIGPOS, Box::new(Value::Bytes(b.0))),
+            RequestID::String(s) => Value::Text(s),
+        }
+    }
+}
+
+impl From<ErrorValue> for Value {
+    fn from(e: ErrorValue) -> Self {
+        let mut map = vec![
+            ("code".into(), e.code.into()),
+            ("message".into(), e.message.into()),
+        ];
+        if let Some(data) = e.data {
+            map.push(("data".into(), data));
         }
+        Value::Map(map)
     }
 }
 
@@ -211,7 +1675,8 @@ impl From<MethodID> for Value {
     fn from(m: MethodID) -> Self {
         match m {
             MethodID::Number(i) => Value::Integer(i.into()),
-            MethodID::String(s) => Value::Text(s.into()),
+            MethodID::String(s) => Value::Text(s),
+            MethodID::Binary(b) => Value::Bytes(b),
         }
     }
 }
@@ -244,6 +1709,8 @@ implfrom! {
     String => MethodID::String,
     &str => MethodID::String,
 
+    Vec<u8> => MethodID::Binary,
+
     u64 => RequestID::Number,
     u32 => RequestID::Number,
     u16 => RequestID::Number,
@@ -254,3 +1721,617 @@ implfrom! {
 
     Vec<u8> => RequestID::Binary,
 }
+
+impl From<BTreeMap<String, Value>> for Params {
+    fn from(map: BTreeMap<String, Value>) -> Self {
+        Params::Named(map.into_iter().collect())
+    }
+}
+
+/// HashMap iteration order is arbitrary, so the names are sorted to make the
+/// encoding deterministic.
+#[cfg(feature = "std")]
+impl From<HashMap<String, Value>> for Params {
+    fn from(map: HashMap<String, Value>) -> Self {
+        let mut named: Vec<_> = map.into_iter().collect();
+        named.sort_by(|a, b| a.0.cmp(&b.0));
+        Params::Named(named)
+    }
+}
+
+// Integer types that don't always fit in a u64; negative or oversized values
+// are rejected rather than wrapped.
+macro_rules! impltryfrom {
+    ($($fromtype:ty => $enum:ident::$variant:ident | $err:ident),+ $(,)?) => {
+        $(
+            impl TryFrom<$fromtype> for $enum {
+                type Error = ProtocolError;
+                #[inline]
+                fn try_from(value: $fromtype) -> Result<Self, Self::Error> {
+                    match u64::try_from(value) {
+                        Ok(u) => Ok(Self::$variant(u)),
+                        Err(_) => Err(ProtocolError::$err),
+                    }
+                }
+            }
+        )+
+    };
+}
+
+impltryfrom! {
+    i64 => MethodID::Number | InvalidMethodID,
+    i32 => MethodID::Number | InvalidMethodID,
+    i16 => MethodID::Number | InvalidMethodID,
+    i8 => MethodID::Number | InvalidMethodID,
+    usize => MethodID::Number | InvalidMethodID,
+    u128 => MethodID::Number | InvalidMethodID,
+
+    i64 => RequestID::Number | InvalidRequestID,
+    i32 => RequestID::Number | InvalidRequestID,
+    i16 => RequestID::Number | InvalidRequestID,
+    i8 => RequestID::Number | InvalidRequestID,
+    usize => RequestID::Number | InvalidRequestID,
+}
+
+impl From<u128> for RequestID {
+    fn from(value: u128) -> Self {
+        Self::from_bignum(&value.to_be_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_errors() {
+        let e = ErrorValue::method_not_found(&"frob".into());
+        assert_eq!(e.code(), &METHOD_NOT_FOUND);
+        assert_eq!(e.message(), "method not found: frob");
+        let e = ErrorValue::method_not_found(&7u8.into());
+        assert_eq!(e.message(), "method not found: #7");
+        let e = ErrorValue::method_not_found(&vec![0xca, 0xfe].into());
+        assert_eq!(e.message(), "method not found: 0xcafe");
+        assert_eq!(e.data(), &None);
+
+        let e = ErrorValue::invalid_params("expected 2 args").with_data(3);
+        assert_eq!(e.code(), &INVALID_PARAMS);
+        assert_eq!(e.message(), "expected 2 args");
+        assert_eq!(e.data(), &Some(Value::from(3)));
+
+        assert_eq!(ErrorValue::parse_error("").code(), &PARSE_ERROR);
+        assert_eq!(ErrorValue::invalid_request("").code(), &INVALID_REQUEST);
+        assert_eq!(ErrorValue::internal("").code(), &INTERNAL_ERROR);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn error_display() {
+        let e = ErrorValue::new(418, "I'm a teapot");
+        assert_eq!(e.to_string(), "error 418: I'm a teapot");
+        let e = e.with_data("short and stout");
+        assert_eq!(e.to_string(), "error 418: I'm a teapot (with data)");
+        let boxed: Box<dyn std::error::Error> = Box::new(e);
+        assert_eq!(boxed.to_string(), "error 418: I'm a teapot (with data)");
+    }
+
+    #[test]
+    fn error_value_conversion() {
+        let e = ErrorValue::new(418, "I'm a teapot").with_data("short and stout");
+        let v = Value::from(e.clone());
+        assert_eq!(
+            v,
+            Value::Map(vec![
+                ("code".into(), 418.into()),
+                ("message".into(), "I'm a teapot".into()),
+                ("data".into(), "short and stout".into()),
+            ])
+        );
+        assert_eq!(ErrorValue::try_from(v).unwrap(), e);
+
+        let v = Value::Map(vec![("message".into(), "no code".into())]);
+        assert!(matches!(
+            ErrorValue::try_from(v),
+            Err(ProtocolError::InvalidMessage)
+        ));
+        let v = Value::Map(vec![
+            ("code".into(), "418".into()),
+            ("message".into(), "text code".into()),
+        ]);
+        assert!(ErrorValue::try_from(v).is_err());
+    }
+
+    #[test]
+    fn error_chain() {
+        let root = ErrorValue::new(-5, "disk full").with_data("/var");
+        let err = ErrorValue::internal("write failed")
+            .chain(ErrorValue::new(SERVER_ERROR, "save failed").chain(root.clone()));
+        let resp = Response::err(err.clone(), 1u8.into());
+        let resp = v0::decode_response(&v0::encode_response(&resp)).unwrap();
+        assert_eq!(resp.result(), &Err(err.clone()));
+
+        let chain: Vec<_> = core::iter::successors(Some(err), ErrorValue::cause)
+            .map(|e| e.code)
+            .collect();
+        assert_eq!(chain, vec![INTERNAL_ERROR, SERVER_ERROR, -5]);
+        assert_eq!(root.cause(), None);
+    }
+
+    #[cfg(all(feature = "serde1", feature = "std"))]
+    #[test]
+    fn content_hash() {
+        let req = RequestBuilder::new("put")
+            .param("key", "k")
+            .param(
+                "opts",
+                Value::Map(vec![("a".into(), 1.into()), ("b".into(), 2.into())]),
+            )
+            .id(1u8)
+            .build()
+            .unwrap();
+        let retry = RequestBuilder::new("put")
+            .param(
+                "opts",
+                Value::Map(vec![("b".into(), 2.into()), ("a".into(), 1.into())]),
+            )
+            .param("key", "k")
+            .id(2u8)
+            .build()
+            .unwrap()
+            .with_deadline(1);
+        assert_eq!(req.content_hash(), retry.content_hash());
+
+        let other = RequestBuilder::new("put")
+            .param("key", "j")
+            .build()
+            .unwrap();
+        assert_ne!(req.content_hash(), other.content_hash());
+        let other = RequestBuilder::new("get")
+            .param("key", "k")
+            .build()
+            .unwrap();
+        assert_ne!(
+            other.content_hash(),
+            RequestBuilder::new("put")
+                .param("key", "k")
+                .build()
+                .unwrap()
+                .content_hash()
+        );
+        assert_eq!(
+            Request::new("ping", Some(Params::default()), None).content_hash(),
+            Request::new("ping", None, Some(3u8.into())).content_hash()
+        );
+    }
+
+    #[test]
+    fn request_limits() {
+        let limits = RequestLimits {
+            max_method_len: 8,
+            max_params: 2,
+        };
+        let ok = RequestBuilder::new("add").arg(1).arg(2).build().unwrap();
+        assert!(ok.validate(&limits).is_ok());
+        assert!(Request::new(u64::MAX, None, None).validate(&limits).is_ok());
+
+        let long = RequestBuilder::new("a_long_method").build().unwrap();
+        assert!(matches!(
+            long.validate(&limits),
+            Err(ProtocolError::MethodTooLong)
+        ));
+        let long = Request::new(vec![0u8; 9], None, None);
+        assert!(matches!(
+            long.validate(&limits),
+            Err(ProtocolError::MethodTooLong)
+        ));
+
+        let many = RequestBuilder::new("add")
+            .arg(1)
+            .arg(2)
+            .arg(3)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            many.validate(&limits),
+            Err(ProtocolError::TooManyParams)
+        ));
+        assert!(many.validate(&RequestLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn params_push_insert() {
+        let mut p = Params::default();
+        assert_eq!(p, Params::Array(vec![]));
+        p.push(1).unwrap();
+        p.push("two").unwrap();
+        assert_eq!(p, Params::Array(vec![1.into(), "two".into()]));
+        assert!(matches!(p.insert("x", 3), Err(ProtocolError::MixedParams)));
+        assert_eq!(p.len(), 2);
+
+        let mut p = Params::default();
+        p.insert("x", 1).unwrap();
+        p.insert("y", 2).unwrap();
+        assert_eq!(p.get_named("y"), Some(&Value::from(2)));
+        assert!(matches!(p.push(3), Err(ProtocolError::MixedParams)));
+        assert_eq!(
+            p,
+            Params::Named(vec![("x".into(), 1.into()), ("y".into(), 2.into())])
+        );
+
+        let mut p = Params::Named(vec![]);
+        p.push(1).unwrap();
+        assert_eq!(p, Params::Array(vec![1.into()]));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn into_error_value() {
+        #[derive(Debug)]
+        enum AppError {
+            Locked,
+            Io(std::io::Error),
+        }
+        impl IntoErrorValue for AppError {
+            fn into_error_value(self) -> ErrorValue {
+                match self {
+                    AppError::Locked => ErrorValue::new(423, "locked"),
+                    AppError::Io(e) => ErrorValue::from_error(&e),
+                }
+            }
+        }
+        fn handler(locked: bool) -> Result<Value, AppError> {
+            match locked {
+                true => Err(AppError::Locked),
+                false => Err(AppError::Io(std::io::ErrorKind::NotFound.into())),
+            }
+        }
+
+        let e = handler(true).map_err(IntoErrorValue::into_error_value);
+        assert_eq!(e, Err(ErrorValue::new(423, "locked")));
+        let e = handler(false).unwrap_err().into_error_value();
+        assert_eq!(e.code(), &SERVER_ERROR);
+        assert_eq!(e.message(), "entity not found");
+
+        let e = ErrorValue::internal("oops");
+        assert_eq!(e.clone().into_error_value(), e);
+        let e = ProtocolError::MixedParams.into_error_value();
+        assert_eq!(e.code(), &INVALID_PARAMS);
+    }
+
+    #[test]
+    fn params_named_positional() {
+        let array = Params::Array(vec![1.into(), "two".into()]);
+        let named = Params::Named(vec![("b".into(), "two".into()), ("a".into(), 1.into())]);
+
+        let p = array.clone().into_named(&["a", "b"]).unwrap();
+        assert_eq!(
+            p,
+            Params::Named(vec![("a".into(), 1.into()), ("b".into(), "two".into())])
+        );
+        assert_eq!(named.clone().into_positional(&["a", "b"]).unwrap(), array);
+        assert_eq!(p.into_positional(&["a", "b"]).unwrap(), array);
+
+        // Already in the right form
+        assert_eq!(array.clone().into_positional(&[]).unwrap(), array);
+        assert_eq!(named.clone().into_named(&[]).unwrap(), named);
+
+        for keys in [&["a"][..], &["a", "b", "c"]] {
+            assert!(matches!(
+                array.clone().into_named(keys),
+                Err(ProtocolError::InvalidParamType)
+            ));
+            assert!(matches!(
+                named.clone().into_positional(keys),
+                Err(ProtocolError::InvalidParamType)
+            ));
+        }
+        // Right number of keys, wrong names
+        assert!(named.clone().into_positional(&["a", "c"]).is_err());
+        assert!(named.into_positional(&["a", "a"]).is_err());
+    }
+
+    #[test]
+    fn response_map_value() {
+        let double = |v: Value| Value::from(value::as_i64(&v).unwrap() * 2);
+
+        let ok = Response::ok(21, 1u8.into());
+        assert!(ok.is_ok() && !ok.is_err());
+        let ok = ok.map_value(double);
+        assert_eq!(ok, Response::ok(42, 1u8.into()));
+
+        let partial = Response::partial(1.into(), 2u8.into()).map_value(double);
+        assert!(partial.is_partial());
+        assert_eq!(partial.result(), &Ok(Value::from(2)));
+
+        let err = Response::err(ErrorValue::internal("no"), 3u8.into());
+        assert!(err.is_err() && !err.is_ok());
+        assert_eq!(err.clone().map_value(double), err);
+    }
+
+    #[test]
+    fn signed_ids() {
+        assert_eq!(MethodID::try_from(5i32).unwrap(), MethodID::Number(5));
+        assert_eq!(RequestID::try_from(7usize).unwrap(), RequestID::Number(7));
+        assert!(matches!(
+            MethodID::try_from(-1i64),
+            Err(ProtocolError::InvalidMethodID)
+        ));
+        assert!(matches!(
+            RequestID::try_from(-3i8),
+            Err(ProtocolError::InvalidRequestID)
+        ));
+        assert!(
+            matches!(RequestID::from(u128::MAX), RequestID::BigNum(b) if b.as_bytes() == [0xff; 16])
+        );
+    }
+
+    #[test]
+    fn bignum_ids() {
+        let over = u128::from(u64::MAX) + 1;
+        let id = RequestID::from(over);
+        match &id {
+            RequestID::BigNum(b) => assert_eq!(b.as_bytes(), [1, 0, 0, 0, 0, 0, 0, 0, 0]),
+            id => panic!("expected a BigNum, got {:?}", id),
+        }
+        assert_eq!(RequestID::try_from(Value::from(id.clone())).unwrap(), id);
+        // Leading zeros don't count, so small values are still Numbers
+        assert_eq!(
+            RequestID::from_bignum(&[0, 0, 1, 2]),
+            RequestID::Number(258)
+        );
+        assert_eq!(RequestID::from(5u128), RequestID::Number(5));
+        assert_eq!(RequestID::from_bignum(&[]), RequestID::Number(0));
+
+        assert!(RequestID::from(u64::MAX) < id);
+        assert!(id < RequestID::from(over + 1));
+        // A longer BigNum is bigger, whatever its first byte
+        assert!(RequestID::from(u128::MAX) < RequestID::from_bignum(&[1; 17]));
+        assert!(RequestID::from_bignum(&[0xff; 20]) < RequestID::from(vec![]));
+
+        assert_eq!(id.to_string(), over.to_string());
+        assert_eq!(
+            RequestID::from(u128::MAX).to_string(),
+            u128::MAX.to_string()
+        );
+        assert_eq!(RequestID::from(7u8).to_string(), "7");
+        assert_eq!(RequestID::from(vec![0xab, 1]).to_string(), "0xab01");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn id_ordering() {
+        use std::collections::{BTreeMap, HashSet};
+        let mut map = BTreeMap::new();
+        map.insert(MethodID::from(vec![1u8]), "bin");
+        map.insert(MethodID::from(2u8), "two");
+        map.insert(MethodID::from("b"), "b");
+        map.insert(MethodID::from(1u8), "one");
+        map.insert(MethodID::from("a"), "a");
+        assert_eq!(map[&MethodID::from("b")], "b");
+        assert_eq!(map[&MethodID::from(1u8)], "one");
+        assert_eq!(map.get(&MethodID::from("c")), None);
+        let order: Vec<_> = map.values().copied().collect();
+        assert_eq!(order, ["a", "b", "one", "two", "bin"]);
+
+        assert!(RequestID::from("z") < RequestID::from(0u8));
+        assert!(RequestID::from(u64::MAX) < RequestID::from(vec![]));
+        let ids: HashSet<_> = [1u8, 2, 1].iter().map(|&i| RequestID::from(i)).collect();
+        assert_eq!(ids.len(), 2);
+    }
+
+    #[test]
+    fn response_builder() {
+        assert_eq!(
+            ResponseBuilder::new(1u8).ok("yes"),
+            Response::ok("yes", 1u8.into())
+        );
+        let resp = ResponseBuilder::new("a")
+            .err(INVALID_PARAMS, "bad")
+            .data(1)
+            .data(2)
+            .build();
+        let error = ErrorValue::new(INVALID_PARAMS, "bad").with_data(2);
+        assert_eq!(resp, Response::err(error, "a".into()));
+        let bare = ResponseBuilder::new(2u8).err(-1, "no").build();
+        assert_eq!(bare.result().as_ref().unwrap_err().data(), &None);
+    }
+
+    #[test]
+    fn params_kind() {
+        let array = Params::Array(vec![1.into()]);
+        let named = Params::Named(vec![]);
+        assert_eq!(array.kind(), ParamsKind::Array);
+        assert!(array.is_array() && !array.is_named());
+        // Empty Params still have a kind
+        assert_eq!(named.kind(), ParamsKind::Named);
+        assert!(named.is_named() && !named.is_array());
+    }
+
+    #[test]
+    fn params_lookup() {
+        let p = Params::Array(vec!["x".into(), 2.into()]);
+        assert_eq!(p.len(), 2);
+        assert_eq!(p.get(1), Some(&Value::from(2)));
+        assert_eq!(p.get(2), None);
+        assert_eq!(p.get_named("x"), None);
+
+        let p = Params::Named(vec![("x".into(), 1.into()), ("y".into(), 2.into())]);
+        assert_eq!(p.len(), 2);
+        assert_eq!(p.get_named("y"), Some(&Value::from(2)));
+        assert_eq!(p.get_named("z"), None);
+        assert_eq!(p.get(0), None);
+        assert_eq!(p.iter_positional().count(), 0);
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn params_arg_as() {
+        let p = Params::Array(vec!["x".into(), 2.into()]);
+        let args: Vec<_> = p.iter_positional().collect();
+        assert_eq!(args, vec![(0, &Value::from("x")), (1, &Value::from(2))]);
+        assert_eq!(p.arg_as::<String>(0).unwrap(), "x");
+        assert_eq!(p.arg_as::<u8>(1).unwrap(), 2);
+        assert!(matches!(
+            p.arg_as::<u8>(0),
+            Err(ProtocolError::InvalidArgType(0))
+        ));
+        let err = p.arg_as::<u8>(2).unwrap_err();
+        assert!(matches!(err, ProtocolError::MissingArg(2)));
+        assert_eq!(err.to_string(), "missing arg 2");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn params_from_map() {
+        let mut map = HashMap::new();
+        map.insert("b".to_string(), Value::from(2));
+        map.insert("a".to_string(), Value::from(1));
+        map.insert("c".to_string(), Value::from(3));
+        let p = Params::from(map.clone());
+        let expected = Params::Named(vec![
+            ("a".into(), 1.into()),
+            ("b".into(), 2.into()),
+            ("c".into(), 3.into()),
+        ]);
+        assert_eq!(p, expected);
+        let tree: BTreeMap<_, _> = map.into_iter().collect();
+        assert_eq!(Params::from(tree), expected);
+
+        let pairs: Vec<_> = expected.into_iter().collect();
+        assert_eq!(pairs[0], (Some("a".into()), 1.into()));
+        let pairs: Vec<_> = Params::Array(vec![true.into()]).into_iter().collect();
+        assert_eq!(pairs, vec![(None, true.into())]);
+    }
+
+    #[test]
+    fn matches_response() {
+        let req = RequestBuilder::new("f").id(1u8).build().unwrap();
+        assert!(req.matches_response(&Response::new(Ok(Value::Null), 1u8.into())));
+        assert!(!req.matches_response(&Response::new(Ok(Value::Null), 2u8.into())));
+        assert!(!req.matches_response(&Response::new(Ok(Value::Null), "1".into())));
+        let note = Request::from(Notification::new("f", None));
+        assert!(!note.matches_response(&Response::new(Ok(Value::Null), 1u8.into())));
+    }
+
+    #[test]
+    fn response_for_request() {
+        let req = Request::new("add", None, Some(4u8.into()));
+        let resp = Response::for_request(&req, Ok(3.into())).unwrap();
+        assert_eq!(resp, Response::new(Ok(3.into()), 4u8.into()));
+        assert!(req.matches_response(&resp));
+
+        let notification = Request::new("add", None, None);
+        assert!(matches!(
+            Response::for_request(&notification, Ok(3.into())),
+            Err(ProtocolError::InvalidRequestID)
+        ));
+    }
+
+    #[test]
+    fn method_not_found_response() {
+        let req = Request::new("frob", None, Some(1u8.into()));
+        let resp = req.method_not_found_response().unwrap();
+        assert!(req.matches_response(&resp));
+        let err = resp.result().as_ref().unwrap_err();
+        assert_eq!(err.code(), &METHOD_NOT_FOUND);
+        assert_eq!(err.message(), "method not found: frob");
+
+        let req = Request::new(42u8, None, Some("x".into()));
+        let resp = req.method_not_found_response().unwrap();
+        assert_eq!(resp.req_id(), &"x".into());
+        assert_eq!(
+            resp.result().as_ref().unwrap_err().message(),
+            "method not found: #42"
+        );
+        assert_eq!(
+            Request::new(42u8, None, None).method_not_found_response(),
+            None
+        );
+    }
+
+    #[test]
+    fn into_parts() {
+        let mut req = RequestBuilder::new("f").arg(1).id(2u8).build().unwrap();
+        let params = Params::Array(vec![1.into()]);
+        assert_eq!(
+            req.clone().into_parts(),
+            ("f".into(), Some(params.clone()), Some(2u8.into()))
+        );
+        assert_eq!(req.take_params(), Some(params));
+        assert_eq!(req.into_parts(), ("f".into(), None, Some(2u8.into())));
+
+        let resp = Response::new(Ok(3.into()), 2u8.into());
+        assert_eq!(resp.into_result_value(), Ok(3.into()));
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn typed_params() {
+        let p = Params::Array(vec!["x".into(), 2.into(), true.into()]);
+        let t: (String, u8, bool) = p.clone().into_typed().unwrap();
+        assert_eq!(t, ("x".into(), 2, true));
+        assert!(matches!(
+            p.into_typed::<(u8, u8, bool)>(),
+            Err(ProtocolError::InvalidParamType)
+        ));
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+        let p = Params::Named(vec![("y".into(), 2.into()), ("x".into(), (-1).into())]);
+        assert_eq!(p.into_typed::<Point>().unwrap(), Point { x: -1, y: 2 });
+        let p = Params::Named(vec![("x".into(), 1.into())]);
+        assert!(matches!(
+            p.into_typed::<Point>(),
+            Err(ProtocolError::InvalidParamType)
+        ));
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn typed_result() {
+        let resp = Response::new(Ok(Value::from(-7)), 1u8.into());
+        let n: i32 = resp.clone().into_result().unwrap().unwrap();
+        assert_eq!(n, -7);
+        assert!(matches!(
+            resp.into_result::<String>(),
+            Err(ProtocolError::InvalidResultType)
+        ));
+
+        let err = ErrorValue::internal("oh no");
+        let resp = Response::new(Err(err.clone()), 1u8.into());
+        assert_eq!(resp.into_result::<i32>().unwrap(), Err(err));
+    }
+
+    #[test]
+    fn bytes_and_text_results() {
+        let bytes = Response::ok(vec![0u8; 4096], 1u8.into());
+        let ptr = match bytes.result() {
+            Ok(Value::Bytes(b)) => b.as_ptr(),
+            _ => unreachable!(),
+        };
+        let taken = bytes.clone().into_bytes().unwrap().unwrap();
+        assert_eq!(taken, vec![0u8; 4096]);
+        // Moved out, not copied
+        let moved = bytes.into_bytes().unwrap().unwrap();
+        assert_eq!(moved.as_ptr(), ptr);
+
+        let text = Response::ok("hi", 1u8.into());
+        assert_eq!(text.clone().into_text().unwrap().unwrap(), "hi");
+        assert!(matches!(
+            text.into_bytes(),
+            Err(ProtocolError::InvalidResultType)
+        ));
+        assert!(matches!(
+            Response::ok(1, 1u8.into()).into_text(),
+            Err(ProtocolError::InvalidResultType)
+        ));
+
+        let err = ErrorValue::internal("oh no");
+        let resp = Response::err(err.clone(), 1u8.into());
+        assert_eq!(resp.clone().into_bytes().unwrap(), Err(err.clone()));
+        assert_eq!(resp.into_text().unwrap(), Err(err));
+    }
+}
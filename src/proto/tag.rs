@@ -0,0 +1,73 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A required CBOR tag that reports a missing or wrong tag as
+//! [ProtocolError::InvalidMessage](crate::error::ProtocolError::InvalidMessage).
+//!
+//! This works like [ciborium::tag::Required], except that it checks the tag
+//! before decoding the contents. A missing tag means "this isn't an RPC
+//! message at all", which callers want to tell apart from a corrupt one.
+
+use ciborium::tag::Required;
+use core::fmt;
+use core::marker::PhantomData;
+use serde::de::{self, EnumAccess, SeqAccess, VariantAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::InvalidMessageSignal;
+
+/// A data item that must be tagged with `TAG`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RPCTag<V, const TAG: u64>(pub V);
+
+impl<V: Serialize, const TAG: u64> Serialize for RPCTag<V, TAG> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Required::<&V, TAG>(&self.0).serialize(serializer)
+    }
+}
+
+// ciborium hands tags to serde as an enum with these magic names.
+#[derive(Deserialize)]
+#[serde(variant_identifier)]
+enum Variant {
+    #[serde(rename = "@@UNTAGGED@@")]
+    Untagged,
+    #[serde(rename = "@@TAGGED@@")]
+    Tagged,
+}
+
+impl<'de, V: Deserialize<'de>, const TAG: u64> Deserialize<'de> for RPCTag<V, TAG> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_enum(
+            "@@TAG@@",
+            &["@@UNTAGGED@@", "@@TAGGED@@"],
+            TagVisitor(PhantomData),
+        )
+    }
+}
+
+struct TagVisitor<V, const TAG: u64>(PhantomData<V>);
+
+impl<'de, V: Deserialize<'de>, const TAG: u64> Visitor<'de> for TagVisitor<V, TAG> {
+    type Value = RPCTag<V, TAG>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a data item tagged {}", TAG)
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        match data.variant()? {
+            (Variant::Untagged, _) => Err(de::Error::custom(InvalidMessageSignal)),
+            (Variant::Tagged, access) => access.tuple_variant(2, self),
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        if seq.next_element::<u64>()? != Some(TAG) {
+            return Err(de::Error::custom(InvalidMessageSignal));
+        }
+        match seq.next_element()? {
+            Some(v) => Ok(RPCTag(v)),
+            None => Err(de::Error::invalid_length(1, &self)),
+        }
+    }
+}
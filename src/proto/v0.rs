@@ -7,40 +7,76 @@
 //! 1. Every RPC message is tagged with a magic number ([TAG_ID_RPCV0]) that
 //!    identifies it as a ciborium-rpc message.
 //!
-//! 2. Each message is either a Request or a Response. Both are represented as
-//!    CBOR Maps with Text keys.
+//! 2. Each message is a Request, a Response, or a Control message. All of
+//!    them are represented as CBOR Maps with Text keys.
 //!
 //! 3. A Request has the following keys and values:
-//!     ```json
-//!     {"fn": MethodID, "args": Params, "id": RequestID}
-//!     ```
-//!     The `args` and `id` items may be omitted.
+//!    ```json
+//!    {"fn": MethodID, "args": Params, "id": RequestID, "deadline": u64}
+//!    ```
+//!    The `args`, `id`, and `deadline` items may be omitted. `deadline` is in
+//!    milliseconds since the Unix epoch; decoders ignore keys they don't
+//!    recognize, so older peers just don't see it.
 //!
 //! 4. A Response is a Map with one of two forms:
-//!     ```json
-//!     {"ok": Value, "id": RequestID}
-//!     ```
-//!     ```json
-//!     {"err": ErrorValue, "id": RequestID}`
-//!     ```
-//!     The `id` item MUST be present, and MUST contain the same value as the
-//!     `id` of the corresponding Request.
+//!    ```json
+//!    {"ok": Value, "id": RequestID}
+//!    ```
+//!    ```json
+//!    {"err": ErrorValue, "id": RequestID}`
+//!    ```
+//!    The `id` item MUST be present, and MUST contain the same value as the
+//!    `id` of the corresponding Request.
+//!
+//!    A long-running call may send partial results before its final Response.
+//!    These have the same form, but with `more` in place of `ok`:
+//!    ```json
+//!    {"more": Value, "id": RequestID}
+//!    ```
+//!    A decoder that predates partial results will fail to decode these, but
+//!    since each one is a complete CBOR item, it can skip over them and carry
+//!    on reading the final Response.
 //!
 //! 5. An ErrorValue is a Map with the form:
-//!     ```json
-//!     {"code": i32, "message": String, "data": Value}
-//!     ```
-//!     The `data` item is optional and may be omitted.
+//!    ```json
+//!    {"code": i32, "message": String, "data": Value}
+//!    ```
+//!    The `data` item is optional and may be omitted.
+//!
+//! 6. A Batch is a CBOR Array of one or more tagged Requests (or Responses).
+//!    The Response batch only contains items for Requests that had an `id`,
+//!    so a batch made entirely of notifications gets no reply at all.
+//!
+//! 7. A Control message is a Map with one item. Currently the only one is
+//!    `cancel`, which asks the server to stop working on a Request:
+//!    ```json
+//!    {"cancel": RequestID}
+//!    ```
 //!
 
-use ciborium::tag::Required;
-use std::convert::{TryFrom, TryInto};
+use core::convert::TryFrom;
+#[cfg(feature = "serde1")]
+use core::convert::TryInto;
 
-use super::{ErrorValue, MethodID, Params, Request, RequestID, Response, Value};
-use crate::error::{ProtocolError, TransportError};
-use crate::transport::simple::{ClientTransport, ServerTransport};
-use crate::transport::{Buf, BufMut, Read, Write};
-use crate::transport::{BufTransport, Transport};
+#[cfg(feature = "serde1")]
+use super::Batch;
+use super::{
+    Control, ErrorValue, MethodID, Params, Request, RequestID, Response, ResponseKind, Value,
+};
+use crate::alloc_prelude::*;
+use crate::error::ProtocolError;
+#[cfg(all(feature = "serde1", feature = "std"))]
+use crate::error::TransportError;
+#[cfg(all(feature = "serde1", feature = "std"))]
+use crate::proto::codec::{CborCodec, Ciborium};
+#[cfg(all(feature = "serde1", feature = "std"))]
+use crate::proto::{ReadCbor, WriteCbor};
+#[cfg(all(feature = "serde1", feature = "std"))]
+use crate::trace;
+#[cfg(all(feature = "serde1", feature = "std"))]
+use crate::transport::simple::{ClientTransport, Incoming, ServerTransport};
+#[cfg(all(feature = "serde1", feature = "std"))]
+use crate::transport::{Buf, BufMut, BufTransport, Read, SplitTransport, Transport, Write};
 
 /// Magic number / tag ID to identify RPC V0 requests
 pub const TAG_ID_RPCV0: u64 = 4036988077;
@@ -53,7 +89,13 @@ pub const TAG_ID_RPCV0: u64 = 4036988077;
 #[cfg(feature = "serde1")]
 mod serde_v0 {
     use super::*;
-    use serde::{Deserialize, Serialize};
+    use crate::error::InvalidMessageSignal;
+    use crate::proto::tag::RPCTag;
+    use crate::proto::Notification;
+    use core::fmt;
+    use serde::de::{self, IgnoredAny, MapAccess, Visitor};
+    use serde::ser::SerializeMap;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
     // ----- RPC format / framing -------------------------------------------------
 
     /// RPCMsg is the toplevel type for this version of the protocol.
@@ -61,61 +103,285 @@ mod serde_v0 {
     /// Every RPC message is tagged with CBOR tag [TAG_ID_RPCV0] so we can identify
     /// it as an RPC message. It then contains either a Request or a Response.
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-    pub struct RPCMsg(Required<Msg, TAG_ID_RPCV0>);
+    pub struct RPCMsg(RPCTag<Msg, TAG_ID_RPCV0>);
 
-    /// The Msg enum encapsulates all well-formatted RPC message contents.
+    /// A borrowed RPCMsg, so Requests and Responses can be sent without giving
+    /// them up. Only the transports use these.
+    #[cfg(feature = "std")]
+    #[derive(Debug, Clone, Serialize)]
+    pub(crate) struct RPCMsgRef<'a>(RPCTag<MsgRef<'a>, TAG_ID_RPCV0>);
+
+    /// RPCBatch is a CBOR Array of RPCMsg items, each with its own tag.
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct RPCBatch(Vec<RPCMsg>);
+
+    /// The Msg enum encapsulates all well-formatted RPC message contents.
+    #[derive(Debug, Clone, PartialEq, Serialize)]
     #[serde(untagged)]
     enum Msg {
-        Request(#[serde(with = "RequestMsg")] crate::proto::Request),
-        Response(#[serde(with = "ResponseMsg")] crate::proto::Response),
+        Request(#[serde(serialize_with = "request_ref")] crate::proto::Request),
+        Response(#[serde(serialize_with = "response_msg::serialize")] crate::proto::Response),
+        Control(#[serde(serialize_with = "control_ref")] crate::proto::Control),
     }
 
-    /// This defines how we serialize/deserialize the Request struct.
-    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-    #[serde(remote = "crate::proto::Request")]
-    struct RequestMsg {
-        #[serde(rename = "fn")]
-        method: MethodID,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        #[serde(rename = "args")]
-        params: Option<Params>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        #[serde(rename = "id")]
-        req_id: Option<RequestID>,
-    }
-
-    /// This defines how we serialize/deserialize the Result inside a Response.
-    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-    #[serde(remote = "core::result::Result")]
-    enum ResultMsg<T, E> {
-        #[serde(rename = "ok")]
-        Ok(T),
-        #[serde(rename = "err")]
-        Err(E),
+    /// MsgRef serializes exactly like Msg, but borrows its contents.
+    #[cfg(feature = "std")]
+    #[derive(Debug, Clone, Copy, Serialize)]
+    #[serde(untagged)]
+    enum MsgRef<'a> {
+        Request(#[serde(serialize_with = "request_ref")] &'a crate::proto::Request),
+        Response(#[serde(serialize_with = "response_msg::serialize")] &'a crate::proto::Response),
+        Control(#[serde(serialize_with = "control_ref")] &'a crate::proto::Control),
     }
 
-    /// This is how we serialize/deserialize the Response struct.
-    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-    #[serde(remote = "crate::proto::Response")]
-    struct ResponseMsg {
-        #[serde(flatten, with = "ResultMsg")]
-        result: Result<Value, ErrorValue>,
-        #[serde(rename = "id")]
-        req_id: RequestID,
+    fn request_ref<S: serde::Serializer>(r: &Request, serializer: S) -> Result<S::Ok, S::Error> {
+        RequestMsg::serialize(r, serializer)
+    }
+
+    fn control_ref<S: serde::Serializer>(c: &Control, serializer: S) -> Result<S::Ok, S::Error> {
+        ControlMsg::serialize(c, serializer)
+    }
+
+    // The messages are written out by hand, rather than derived, so that
+    // their keys come from Keys::V0.
+
+    /// This defines how we serialize the Request struct.
+    struct RequestMsg;
+
+    impl RequestMsg {
+        fn serialize<S: Serializer>(r: &Request, serializer: S) -> Result<S::Ok, S::Error> {
+            let keys = Keys::V0;
+            let len = 1
+                + r.params.is_some() as usize
+                + r.req_id.is_some() as usize
+                + r.deadline.is_some() as usize;
+            let mut map = serializer.serialize_map(Some(len))?;
+            map.serialize_entry(keys.method, &r.method)?;
+            if let Some(params) = &r.params {
+                map.serialize_entry(keys.params, params)?;
+            }
+            if let Some(req_id) = &r.req_id {
+                map.serialize_entry(keys.id, req_id)?;
+            }
+            if let Some(deadline) = &r.deadline {
+                map.serialize_entry(keys.deadline, deadline)?;
+            }
+            map.end()
+        }
+    }
+
+    /// This is how we serialize the Response struct: the result is an "ok",
+    /// "err", or "more" item, followed by the "id".
+    mod response_msg {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(r: &Response, serializer: S) -> Result<S::Ok, S::Error> {
+            let keys = Keys::V0;
+            // No length, just like the flattened struct this used to be
+            let mut map = serializer.serialize_map(None)?;
+            match &r.result {
+                Ok(value) if r.is_partial() => map.serialize_entry(keys.more, value)?,
+                Ok(value) => map.serialize_entry(keys.ok, value)?,
+                Err(error) => map.serialize_entry(keys.err, error)?,
+            }
+            map.serialize_entry(keys.id, &r.req_id)?;
+            map.end()
+        }
+    }
+
+    /// A Control message is a Map with a single item, named for the control
+    /// and holding its argument: `{"cancel": RequestID}`.
+    struct ControlMsg;
+
+    impl ControlMsg {
+        fn serialize<S: Serializer>(c: &Control, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut map = serializer.serialize_map(Some(1))?;
+            match c {
+                Control::Cancel(req_id) => map.serialize_entry(Keys::V0.cancel, req_id)?,
+            }
+            map.end()
+        }
+    }
+
+    // Decoding goes through the map by hand, like v1, rather than through an
+    // untagged enum: serde buffers untagged and flattened contents in a
+    // format that can't hold CBOR tags, so tagged params and results would
+    // fail to decode.
+    fn from_value<T, E>(value: Value) -> Result<T, E>
+    where
+        T: TryFrom<Value, Error = ProtocolError>,
+        E: de::Error,
+    {
+        T::try_from(value).map_err(E::custom)
+    }
+
+    impl<'de> Deserialize<'de> for Msg {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_map(MsgVisitor { strict: false })
+        }
+    }
+
+    /// A Msg that's decoded strictly: any key that doesn't belong in the kind
+    /// of message it turns out to be - including a repeated one - makes it
+    /// [ProtocolError::InvalidMessage], rather than being ignored.
+    #[derive(Debug)]
+    struct StrictMsg(Msg);
+
+    impl<'de> Deserialize<'de> for StrictMsg {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer
+                .deserialize_map(MsgVisitor { strict: true })
+                .map(StrictMsg)
+        }
+    }
+
+    /// RPCMsg, decoded strictly.
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct StrictRPCMsg(RPCTag<StrictMsg, TAG_ID_RPCV0>);
+
+    /// RPCBatch, decoded strictly.
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct StrictRPCBatch(Vec<StrictRPCMsg>);
+
+    impl From<StrictRPCMsg> for RPCMsg {
+        fn from(msg: StrictRPCMsg) -> Self {
+            RPCMsg(RPCTag((msg.0).0 .0))
+        }
+    }
+
+    impl From<StrictRPCBatch> for RPCBatch {
+        fn from(batch: StrictRPCBatch) -> Self {
+            RPCBatch(batch.0.into_iter().map(Into::into).collect())
+        }
+    }
+
+    // The keys each kind of message may have.
+    fn allowed_keys(msg: &Msg) -> &'static [&'static str] {
+        const K: Keys = Keys::V0;
+        match msg {
+            Msg::Request(_) => &[K.method, K.params, K.id, K.deadline],
+            Msg::Response(r) if r.result.is_err() => &[K.err, K.id],
+            Msg::Response(r) if r.is_partial() => &[K.more, K.id],
+            Msg::Response(_) => &[K.ok, K.id],
+            Msg::Control(_) => &[K.cancel],
+        }
+    }
+
+    struct MsgVisitor {
+        strict: bool,
+    }
+
+    impl<'de> Visitor<'de> for MsgVisitor {
+        type Value = Msg;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a v0 Request, Response, or Control map")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Msg, A::Error> {
+            let mut method = None;
+            let mut params = None;
+            let mut req_id = None;
+            let mut deadline = None;
+            let mut result = None;
+            let mut cancel = None;
+            let mut keys = Vec::new();
+            let k0 = Keys::V0;
+            while let Some(key) = map.next_key::<String>()? {
+                match key.as_str() {
+                    k if k == k0.method => method = Some(from_value(map.next_value()?)?),
+                    k if k == k0.params => params = Some(from_value(map.next_value()?)?),
+                    k if k == k0.id => req_id = Some(from_value(map.next_value()?)?),
+                    k if k == k0.deadline => deadline = Some(map.next_value()?),
+                    k if k == k0.ok => result = Some((Ok(map.next_value()?), ResponseKind::Final)),
+                    k if k == k0.more => {
+                        result = Some((Ok(map.next_value()?), ResponseKind::Partial))
+                    }
+                    k if k == k0.err => {
+                        result = Some((Err(map.next_value()?), ResponseKind::Final))
+                    }
+                    k if k == k0.cancel => cancel = Some(from_value(map.next_value()?)?),
+                    _ => {
+                        map.next_value::<IgnoredAny>()?;
+                    }
+                }
+                if self.strict {
+                    keys.push(key);
+                }
+            }
+            let msg = match (method, result, req_id, cancel) {
+                (Some(method), _, req_id, _) => Ok(Msg::Request(Request {
+                    method,
+                    params,
+                    req_id,
+                    deadline,
+                })),
+                (None, Some((result, kind)), Some(req_id), _) => Ok(Msg::Response(Response {
+                    result,
+                    req_id,
+                    kind,
+                })),
+                (None, None, None, Some(req_id)) => Ok(Msg::Control(Control::Cancel(req_id))),
+                (None, Some(_), None, _) => Err(de::Error::missing_field(k0.id)),
+                _ => Err(de::Error::custom("not a v0 Request, Response, or Control")),
+            }?;
+            let allowed = allowed_keys(&msg);
+            let unexpected = keys
+                .iter()
+                .enumerate()
+                .any(|(i, k)| !allowed.contains(&k.as_str()) || keys[..i].contains(k));
+            if unexpected {
+                return Err(de::Error::custom(InvalidMessageSignal));
+            }
+            Ok(msg)
+        }
     }
 
     // ----- Conversions to/from RPCMsg -------------------------------------------
 
     impl From<Request> for RPCMsg {
         fn from(r: Request) -> Self {
-            RPCMsg(Required(Msg::Request(r)))
+            RPCMsg(RPCTag(Msg::Request(r)))
         }
     }
 
     impl From<Response> for RPCMsg {
         fn from(r: Response) -> Self {
-            RPCMsg(Required(Msg::Response(r)))
+            RPCMsg(RPCTag(Msg::Response(r)))
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<'a> From<&'a Request> for RPCMsgRef<'a> {
+        fn from(r: &'a Request) -> Self {
+            RPCMsgRef(RPCTag(MsgRef::Request(r)))
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<'a> From<&'a Response> for RPCMsgRef<'a> {
+        fn from(r: &'a Response) -> Self {
+            RPCMsgRef(RPCTag(MsgRef::Response(r)))
+        }
+    }
+
+    impl From<Control> for RPCMsg {
+        fn from(c: Control) -> Self {
+            RPCMsg(RPCTag(Msg::Control(c)))
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<'a> From<&'a Control> for RPCMsgRef<'a> {
+        fn from(c: &'a Control) -> Self {
+            RPCMsgRef(RPCTag(MsgRef::Control(c)))
+        }
+    }
+
+    // A Notification is just a Request without an "id" item.
+    impl From<Notification> for RPCMsg {
+        fn from(n: Notification) -> Self {
+            Request::from(n).into()
         }
     }
 
@@ -124,7 +390,7 @@ mod serde_v0 {
         fn try_from(msg: RPCMsg) -> Result<Self, Self::Error> {
             match msg.0 .0 {
                 Msg::Request(r) => Ok(r),
-                Msg::Response(_) => Err(ProtocolError::UnexpectedMessage),
+                Msg::Response(_) | Msg::Control(_) => Err(ProtocolError::UnexpectedMessage),
             }
         }
     }
@@ -133,145 +399,2166 @@ mod serde_v0 {
         type Error = ProtocolError;
         fn try_from(msg: RPCMsg) -> Result<Self, Self::Error> {
             match msg.0 .0 {
-                Msg::Request(_) => Err(ProtocolError::UnexpectedMessage),
+                Msg::Request(_) | Msg::Control(_) => Err(ProtocolError::UnexpectedMessage),
                 Msg::Response(r) => Ok(r),
             }
         }
     }
+
+    #[cfg(feature = "std")]
+    impl TryFrom<RPCMsg> for Incoming {
+        type Error = ProtocolError;
+        fn try_from(msg: RPCMsg) -> Result<Self, Self::Error> {
+            match msg.0 .0 {
+                Msg::Request(r) => Ok(r.into()),
+                Msg::Control(c) => Ok(Incoming::Control(c)),
+                Msg::Response(_) => Err(ProtocolError::UnexpectedMessage),
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl From<RPCMsg> for crate::peer::PeerMessage {
+        fn from(msg: RPCMsg) -> Self {
+            use crate::peer::PeerMessage;
+            match msg.0 .0 {
+                Msg::Request(r) => PeerMessage::Request(r),
+                Msg::Response(r) => PeerMessage::Response(r),
+                Msg::Control(c) => PeerMessage::Control(c),
+            }
+        }
+    }
+
+    impl From<Decoded> for RPCMsg {
+        fn from(d: Decoded) -> Self {
+            match d {
+                Decoded::Request(r) => r.into(),
+                Decoded::Response(r) => r.into(),
+                Decoded::Control(c) => c.into(),
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl RPCBatch {
+        pub(crate) fn from_msgs(msgs: Vec<RPCMsg>) -> Self {
+            RPCBatch(msgs)
+        }
+    }
+
+    impl<T: Into<RPCMsg>> From<Batch<T>> for RPCBatch {
+        fn from(b: Batch<T>) -> Self {
+            RPCBatch(b.into_iter().map(Into::into).collect())
+        }
+    }
+
+    impl<T> TryFrom<RPCBatch> for Batch<T>
+    where
+        T: TryFrom<RPCMsg, Error = ProtocolError>,
+    {
+        type Error = ProtocolError;
+        fn try_from(batch: RPCBatch) -> Result<Self, Self::Error> {
+            Batch::new(
+                batch
+                    .0
+                    .into_iter()
+                    .map(T::try_from)
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+    }
+
+    impl TryFrom<RPCMsg> for Notification {
+        type Error = ProtocolError;
+        fn try_from(msg: RPCMsg) -> Result<Self, Self::Error> {
+            Request::try_from(msg)?.try_into()
+        }
+    }
 }
 
 #[cfg(feature = "serde1")]
-use serde_v0::RPCMsg;
+pub use serde_v0::{RPCBatch, RPCMsg};
+#[cfg(all(feature = "serde1", feature = "std"))]
+pub(crate) use serde_v0::{RPCMsgRef, StrictRPCBatch, StrictRPCMsg};
+
+// For encode errors: the part of a message that each key holds.
+#[cfg(all(feature = "serde1", feature = "std"))]
+fn field_name(key: &Value) -> Option<&'static str> {
+    let keys = Keys::V0;
+    match key.as_text()? {
+        k if k == keys.method => Some("method"),
+        k if k == keys.params => Some("params"),
+        k if k == keys.id => Some("id"),
+        k if k == keys.deadline => Some("deadline"),
+        k if k == keys.ok || k == keys.more => Some("result"),
+        k if k == keys.err => Some("error"),
+        k if k == keys.cancel => Some("cancel"),
+        _ => None,
+    }
+}
 
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl_cbor_io!(RPCMsg, RPCBatch);
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl_cbor_io!(@write RPCMsgRef<'_>);
+
+#[cfg(all(feature = "serde1", feature = "std"))]
 impl RPCMsg {
-    fn from_reader(reader: &mut impl Read) -> Result<Self, TransportError> {
-        Ok(ciborium::de::from_reader(reader)?)
+    /// Try to decode an RPCMsg from the start of `bytes`.
+    ///
+    /// Returns `Ok(None)` if `bytes` doesn't (yet) hold a complete message.
+    /// Otherwise, returns the message and the number of bytes it used.
+    pub(crate) fn from_slice_partial(
+        bytes: &[u8],
+    ) -> Result<Option<(Self, usize)>, TransportError> {
+        Self::from_slice_partial_with(bytes, false)
+    }
+
+    /// [from_slice_partial](Self::from_slice_partial), decoding strictly if
+    /// `strict` is set.
+    pub(crate) fn from_slice_partial_with(
+        bytes: &[u8],
+        strict: bool,
+    ) -> Result<Option<(Self, usize)>, TransportError> {
+        let mut reader = bytes;
+        match Self::from_reader_with(&mut reader, strict) {
+            Ok(msg) => Ok(Some((msg, bytes.len() - reader.len()))),
+            Err(TransportError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // The transports read through this, so their `strict` option works the
+    // same everywhere.
+    pub(crate) fn from_reader_with(
+        reader: &mut impl Read,
+        strict: bool,
+    ) -> Result<Self, TransportError> {
+        if strict {
+            Ok(Ciborium.from_reader::<StrictRPCMsg>(reader)?.into())
+        } else {
+            Self::from_reader(reader)
+        }
+    }
+
+    /// Decode exactly one RPCMsg from `bytes`.
+    fn from_slice(bytes: &[u8]) -> Result<Self, TransportError> {
+        match Self::from_slice_partial(bytes).map_err(|e| e.with_raw(bytes))? {
+            Some((msg, len)) if len == bytes.len() => Ok(msg),
+            Some((_, len)) => Err(TransportError::Decode {
+                msg: "trailing data after message".into(),
+                pos: Some(len),
+                raw: Some(bytes.to_vec()),
+            }),
+            None => Err(TransportError::Decode {
+                msg: "truncated message".into(),
+                pos: Some(bytes.len()),
+                raw: Some(bytes.to_vec()),
+            }),
+        }
+    }
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl RPCBatch {
+    pub(crate) fn from_reader_with(
+        reader: &mut impl Read,
+        strict: bool,
+    ) -> Result<Self, TransportError> {
+        if strict {
+            Ok(Ciborium.from_reader::<StrictRPCBatch>(reader)?.into())
+        } else {
+            Self::from_reader(reader)
+        }
+    }
+}
+
+// Standalone encoding, for when there's no transport handy.
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl Request {
+    /// Encode this Request as a v0 RPC message.
+    pub fn to_cbor_vec(&self) -> Result<Vec<u8>, TransportError> {
+        let mut buf = Vec::new();
+        RPCMsgRef::from(self).to_writer(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// The length of [to_cbor_vec](Request::to_cbor_vec)'s output, without
+    /// allocating it.
+    pub fn encoded_len(&self) -> Result<usize, TransportError> {
+        RPCMsgRef::from(self).encoded_len()
+    }
+
+    /// Decode a Request from a v0 RPC message. `bytes` must hold exactly one
+    /// message.
+    pub fn from_cbor_slice(bytes: &[u8]) -> Result<Self, TransportError> {
+        Ok(RPCMsg::from_slice(bytes)?.try_into()?)
+    }
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl Response {
+    /// Encode this Response as a v0 RPC message.
+    pub fn to_cbor_vec(&self) -> Result<Vec<u8>, TransportError> {
+        let mut buf = Vec::new();
+        RPCMsgRef::from(self).to_writer(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// The length of [to_cbor_vec](Response::to_cbor_vec)'s output, without
+    /// allocating it.
+    pub fn encoded_len(&self) -> Result<usize, TransportError> {
+        RPCMsgRef::from(self).encoded_len()
+    }
+
+    /// Decode a Response from a v0 RPC message. `bytes` must hold exactly one
+    /// message.
+    pub fn from_cbor_slice(bytes: &[u8]) -> Result<Self, TransportError> {
+        Ok(RPCMsg::from_slice(bytes)?.try_into()?)
     }
-    fn into_writer(&self, writer: &mut impl Write) -> Result<(), TransportError> {
-        Ok(ciborium::ser::into_writer(self, writer)?)
+}
+
+// A framing layer that hands over whole messages can decode them directly.
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl TryFrom<&[u8]> for Request {
+    type Error = TransportError;
+    /// Same as [Request::from_cbor_slice].
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Request::from_cbor_slice(bytes)
+    }
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl TryFrom<&[u8]> for Response {
+    type Error = TransportError;
+    /// Same as [Response::from_cbor_slice].
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Response::from_cbor_slice(bytes)
     }
-    fn from_buf(buf: &mut impl Buf) -> Result<Self, TransportError> {
-        Self::from_reader(&mut buf.reader())
+}
+
+// Now we implement ClientTransport/ServerTransport so Transport<C>,
+// BufTransport<B>, and SplitTransport<R, W> can transport RPCMsg items.
+// They all read through `read_msg` and `read_batch`, which honor `strict`.
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl<C: Read + Write> Transport<C> {
+    pub(crate) fn read_msg(&mut self) -> Result<RPCMsg, TransportError> {
+        let strict = self.strict;
+        self.read_limited(|r| RPCMsg::from_reader_with(r, strict))
+    }
+    fn read_batch(&mut self) -> Result<RPCBatch, TransportError> {
+        let strict = self.strict;
+        self.read_limited(|r| RPCBatch::from_reader_with(r, strict))
     }
-    fn into_buf_mut(&self, buf_mut: &mut impl BufMut) -> Result<(), TransportError> {
-        self.into_writer(&mut buf_mut.writer())
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl<B: Buf + BufMut> BufTransport<B> {
+    fn read_msg(&mut self) -> Result<RPCMsg, TransportError> {
+        let strict = self.strict;
+        self.read_limited(|r| RPCMsg::from_reader_with(r, strict))
+    }
+    fn read_batch(&mut self) -> Result<RPCBatch, TransportError> {
+        let strict = self.strict;
+        self.read_limited(|r| RPCBatch::from_reader_with(r, strict))
     }
 }
 
-// Now we implement ClientTransport/ServerTransport so Transport<C> and
-// BufTransport<B> can transport RPCMsg items.
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl<R: Read, W: Write> SplitTransport<R, W> {
+    fn read_msg(&mut self) -> Result<RPCMsg, TransportError> {
+        let strict = self.strict;
+        self.read_limited(|r| RPCMsg::from_reader_with(r, strict))
+    }
+    fn read_batch(&mut self) -> Result<RPCBatch, TransportError> {
+        let strict = self.strict;
+        self.read_limited(|r| RPCBatch::from_reader_with(r, strict))
+    }
+}
 
+#[cfg(all(feature = "serde1", feature = "std"))]
 impl<C: Read + Write> ClientTransport for Transport<C> {
     type Error = TransportError;
     type SendResult = ();
     fn read_response(&mut self) -> Result<Response, Self::Error> {
-        Ok(RPCMsg::from_reader(&mut self.channel)?.try_into()?)
+        trace::read_response(|| Ok(self.read_msg()?.try_into()?))
+    }
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        trace::send_request(request, || {
+            RPCMsgRef::from(request).to_writer_with(&mut self.channel, self.canonical)
+        })
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        RPCMsgRef::from(control).to_writer_with(&mut self.channel, self.canonical)
     }
-    fn send_request(&mut self, request: Request) -> Result<Self::SendResult, Self::Error> {
-        Ok(RPCMsg::from(request).into_writer(&mut self.channel)?)
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        Ok(self.read_batch()?.try_into()?)
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        RPCBatch::from(batch).to_writer_with(&mut self.channel, self.canonical)
     }
 }
 
+#[cfg(all(feature = "serde1", feature = "std"))]
 impl<C: Read + Write> ServerTransport for Transport<C> {
     type Error = TransportError;
     type SendResult = ();
     fn read_request(&mut self) -> Result<Request, Self::Error> {
-        Ok(RPCMsg::from_reader(&mut self.channel)?.try_into()?)
+        trace::read_request(|| Ok(self.read_msg()?.try_into()?))
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        trace::read_incoming(|| Ok(self.read_msg()?.try_into()?))
+    }
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        trace::send_response(response, || {
+            RPCMsgRef::from(response).to_writer_with(&mut self.channel, self.canonical)
+        })
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error> {
+        Ok(self.read_batch()?.try_into()?)
     }
-    fn send_response(&mut self, response: Response) -> Result<Self::SendResult, Self::Error> {
-        Ok(RPCMsg::from(response).into_writer(&mut self.channel)?)
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        RPCBatch::from(batch).to_writer_with(&mut self.channel, self.canonical)
     }
 }
 
+#[cfg(all(feature = "serde1", feature = "std"))]
 impl<B: Buf + BufMut> ClientTransport for BufTransport<B> {
     type Error = TransportError;
     type SendResult = ();
     fn read_response(&mut self) -> Result<Response, Self::Error> {
-        Ok(RPCMsg::from_buf(&mut self.buffer)?.try_into()?)
+        trace::read_response(|| Ok(self.read_msg()?.try_into()?))
     }
-    fn send_request(&mut self, request: Request) -> Result<Self::SendResult, Self::Error> {
-        Ok(RPCMsg::from(request).into_buf_mut(&mut self.buffer)?)
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        trace::send_request(request, || {
+            RPCMsgRef::from(request).to_buf_with(&mut self.buffer, self.canonical)
+        })
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        RPCMsgRef::from(control).to_buf_with(&mut self.buffer, self.canonical)
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        Ok(self.read_batch()?.try_into()?)
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        RPCBatch::from(batch).to_buf_with(&mut self.buffer, self.canonical)
     }
 }
 
+#[cfg(all(feature = "serde1", feature = "std"))]
 impl<B: Buf + BufMut> ServerTransport for BufTransport<B> {
     type Error = TransportError;
     type SendResult = ();
     fn read_request(&mut self) -> Result<Request, Self::Error> {
-        Ok(RPCMsg::from_buf(&mut self.buffer)?.try_into()?)
+        trace::read_request(|| Ok(self.read_msg()?.try_into()?))
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        trace::read_incoming(|| Ok(self.read_msg()?.try_into()?))
     }
-    fn send_response(&mut self, response: Response) -> Result<Self::SendResult, Self::Error> {
-        Ok(RPCMsg::from(response).into_buf_mut(&mut self.buffer)?)
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        trace::send_response(response, || {
+            RPCMsgRef::from(response).to_buf_with(&mut self.buffer, self.canonical)
+        })
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error> {
+        Ok(self.read_batch()?.try_into()?)
+    }
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        RPCBatch::from(batch).to_buf_with(&mut self.buffer, self.canonical)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{Request, Response};
-    use crate::proto::{ErrorValue, Params, Value};
-    use crate::transport::cbor::CBORTransport;
-    use crate::transport::simple::{ClientTransport, ServerTransport};
-    use crate::transport::BufTransport;
-    use bytes::BytesMut;
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl<R: Read, W: Write> ClientTransport for SplitTransport<R, W> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        trace::read_response(|| Ok(self.read_msg()?.try_into()?))
+    }
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        trace::send_request(request, || {
+            RPCMsgRef::from(request).to_writer_with(&mut self.writer, self.canonical)
+        })
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        RPCMsgRef::from(control).to_writer_with(&mut self.writer, self.canonical)
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        Ok(self.read_batch()?.try_into()?)
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        RPCBatch::from(batch).to_writer_with(&mut self.writer, self.canonical)
+    }
+}
 
-    macro_rules! params {
-        ($($v:expr),+ $(,)?) => {
-            Params::Array(vec![$(
-                Value::from($v),
-            )+
-            ])
-        };
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl<R: Read, W: Write> ServerTransport for SplitTransport<R, W> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_request(&mut self) -> Result<Request, Self::Error> {
+        trace::read_request(|| Ok(self.read_msg()?.try_into()?))
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        trace::read_incoming(|| Ok(self.read_msg()?.try_into()?))
     }
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        trace::send_response(response, || {
+            RPCMsgRef::from(response).to_writer_with(&mut self.writer, self.canonical)
+        })
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error> {
+        Ok(self.read_batch()?.try_into()?)
+    }
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        RPCBatch::from(batch).to_writer_with(&mut self.writer, self.canonical)
+    }
+}
 
-    #[test]
-    fn encode_request() {
-        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
-        let mut req = Request {
-            method: "hello".into(),
-            params: Some(params!["one", 2, "three"]),
-            req_id: Some(42u32.into()),
-        };
-        tr.send_request(req.clone()).unwrap();
-        assert!(tr.buffer.len() <= 38);
-        let req2: Request = tr.read_request().unwrap();
-        println!("req: {:?}", req2);
-        assert_eq!(req, req2);
-        req.params = None;
-        tr.send_request(req.clone()).unwrap();
-        let req2: Request = tr.read_request().unwrap();
-        println!("req: {:?}", req2);
-        assert_eq!(req, req2);
-        req.req_id = None;
-        tr.send_request(req.clone()).unwrap();
-        let req2: Request = tr.read_request().unwrap();
-        println!("req: {:?}", req2);
-        assert_eq!(req, req2);
+// Reads with more control over buffering, for busy servers or a BufTransport
+// that's being filled as data arrives.
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl<B: Buf + BufMut> BufTransport<B> {
+    /// Read a Request, using `scratch` as the decoder's scratch space instead
+    /// of a fresh buffer. Text and byte strings that fit in `scratch` are
+    /// decoded through it; longer ones are still decoded, just less
+    /// efficiently.
+    ///
+    /// As with [read_request](ServerTransport::read_request), the bytes of
+    /// the message are consumed from the buffer.
+    pub fn read_request_into(&mut self, scratch: &mut [u8]) -> Result<Request, TransportError> {
+        Ok(self
+            .read_limited(|r| RPCMsg::from_reader_with_buffer(r, scratch))?
+            .try_into()?)
     }
 
-    #[test]
-    fn encode_response() {
-        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
-        let mut resp = Response {
-            result: Ok("yay".into()),
-            req_id: 42u32.into(),
-        };
-        tr.send_response(resp.clone()).unwrap();
-        let resp2: Response = tr.read_response().unwrap();
-        println!("resp: {:?}", resp2);
-        assert_eq!(resp, resp2);
-        resp.result = Err(ErrorValue {
-            code: 418,
-            message: "I'm a teapot".into(),
-            data: None,
-        });
-        tr.send_response(resp.clone()).unwrap();
-        println!("len: {:?}", tr.buffer.len());
-        println!("msg: {:?}", tr.buffer);
-        let val = tr.read_cbor().unwrap();
-        println!("val: {:?}", val);
-        tr.send_response(resp.clone()).unwrap();
-        let resp2: Response = tr.read_response().unwrap();
-        println!("resp: {:?}", resp2);
-        assert_eq!(resp, resp2);
+    /// Read a Response using `scratch`; see
+    /// [read_request_into](Self::read_request_into).
+    pub fn read_response_into(&mut self, scratch: &mut [u8]) -> Result<Response, TransportError> {
+        Ok(self
+            .read_limited(|r| RPCMsg::from_reader_with_buffer(r, scratch))?
+            .try_into()?)
+    }
+
+    /// Read a Request if the buffer holds a complete one. If it doesn't, this
+    /// returns `Ok(None)` and leaves the buffer untouched, so it's safe to
+    /// add more data and try again.
+    ///
+    /// This only looks at the first contiguous chunk of the buffer; for a
+    /// `BytesMut`, that's the whole thing.
+    pub fn try_read_request(&mut self) -> Result<Option<Request>, TransportError> {
+        match self.try_read_msg()? {
+            Some(msg) => Ok(Some(msg.try_into()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read a Response if the buffer holds a complete one. See
+    /// [try_read_request](Self::try_read_request).
+    pub fn try_read_response(&mut self) -> Result<Option<Response>, TransportError> {
+        match self.try_read_msg()? {
+            Some(msg) => Ok(Some(msg.try_into()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn try_read_msg(&mut self) -> Result<Option<RPCMsg>, TransportError> {
+        let bytes = self.buffer.chunk();
+        let limit = self.max_message_size();
+        match (RPCMsg::from_slice_partial_with(bytes, self.strict)?, limit) {
+            (Some((_, len)), Some(limit)) if len > limit => {
+                self.buffer.advance(len);
+                Err(TransportError::MessageTooLarge { limit })
+            }
+            (Some((msg, len)), _) => {
+                self.buffer.advance(len);
+                if self.reject_trailing() && self.buffer.has_remaining() {
+                    return Err(TransportError::Decode {
+                        msg: "trailing data after message".into(),
+                        pos: Some(len),
+                        raw: None,
+                    });
+                }
+                Ok(Some(msg))
+            }
+            // It's incomplete, but it's already too big
+            (None, Some(limit)) if bytes.len() > limit => {
+                Err(TransportError::MessageTooLarge { limit })
+            }
+            (None, _) => Ok(None),
+        }
+    }
+}
+
+// Indefinite-length encoding doesn't change what's on the wire as far as a
+// decoder is concerned, so there's only a send side. The Request is written
+// item by item, like a streamed Response, so nothing gets copied first.
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+fn write_indefinite(writer: &mut impl Write, request: &Request) -> Result<(), TransportError> {
+    use super::indefinite::{encode, SEGMENT};
+    use ciborium_ll::{Encoder, Header};
+
+    let keys = Keys::V0;
+    let mut enc = Encoder::from(writer);
+    enc.push(Header::Tag(TAG_ID_RPCV0))?;
+    enc.push(Header::Map(None))?;
+    enc.text(keys.method, None)?;
+    match &request.method {
+        MethodID::String(s) => enc.text(s, SEGMENT)?,
+        MethodID::Number(n) => enc.push(Header::Positive(*n))?,
+        MethodID::Binary(b) => enc.bytes(b, SEGMENT)?,
+    }
+    match &request.params {
+        Some(Params::Array(items)) => {
+            enc.text(keys.params, None)?;
+            enc.push(Header::Array(None))?;
+            for item in items {
+                encode(item, &mut enc)?;
+            }
+            enc.push(Header::Break)?;
+        }
+        Some(Params::Named(items)) => {
+            enc.text(keys.params, None)?;
+            enc.push(Header::Map(None))?;
+            for (name, value) in items {
+                enc.text(name, SEGMENT)?;
+                encode(value, &mut enc)?;
+            }
+            enc.push(Header::Break)?;
+        }
+        None => {}
+    }
+    if let Some(req_id) = &request.req_id {
+        enc.text(keys.id, None)?;
+        encode(&Value::from(req_id.clone()), &mut enc)?;
+    }
+    if let Some(deadline) = request.deadline {
+        enc.text(keys.deadline, None)?;
+        enc.push(Header::Positive(deadline))?;
+    }
+    enc.push(Header::Break)?;
+    Ok(())
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl<C: Read + Write> Transport<C> {
+    /// Send a Request with indefinite-length Arrays and Maps, and with long
+    /// Bytes and Text split into segments.
+    pub fn send_request_indefinite(&mut self, request: &Request) -> Result<(), TransportError> {
+        write_indefinite(&mut self.channel, request)
+    }
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl<B: Buf + BufMut> BufTransport<B> {
+    /// Send a Request with indefinite-length Arrays and Maps, and with long
+    /// Bytes and Text split into segments.
+    pub fn send_request_indefinite(&mut self, request: &Request) -> Result<(), TransportError> {
+        write_indefinite(&mut (&mut self.buffer).writer(), request)
+    }
+}
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+impl<R: Read, W: Write> SplitTransport<R, W> {
+    /// Send a Request with indefinite-length Arrays and Maps, and with long
+    /// Bytes and Text split into segments.
+    pub fn send_request_indefinite(&mut self, request: &Request) -> Result<(), TransportError> {
+        write_indefinite(&mut self.writer, request)
+    }
+}
+
+// ----- Streaming Bytes results ----------------------------------------------
+
+#[cfg(all(feature = "serde1", feature = "std"))]
+pub use streaming::{BytesReader, StreamingResponse};
+
+// A Bytes result can go out as an indefinite-length byte string, one chunk
+// at a time, so a big one - a file, say - never has to be in memory all at
+// once. Any decoder reads it back as a single Bytes item, but the reading
+// side can stream it too.
+#[cfg(all(feature = "serde1", feature = "std"))]
+mod streaming {
+    use super::{decode_items, Decoded, Keys, RequestID, Response, Value, TAG_ID_RPCV0};
+    use crate::error::{ProtocolError, TransportError};
+    use crate::proto::codec::{CborCodec, Ciborium};
+    use crate::transport::versioned::read_header;
+    use crate::transport::{read_limited, Read, SplitTransport, Transport, Write};
+    use ciborium_ll::{Encoder, Header};
+    use std::convert::TryFrom;
+    use std::io;
+
+    const BYTES: u8 = 2;
+    const MAP: u8 = 5;
+    const TAG: u8 = 6;
+    // A break has major type 7 and no argument.
+    const BREAK: (u8, Option<u64>) = (7, None);
+
+    /// The most we read from the source of a streamed result at a time.
+    const CHUNK: usize = 64 * 1024;
+
+    fn write_streaming(
+        writer: &mut impl Write,
+        req_id: &RequestID,
+        mut data: impl Read,
+    ) -> Result<(), TransportError> {
+        let mut enc = Encoder::from(&mut *writer);
+        enc.push(Header::Tag(TAG_ID_RPCV0))?;
+        enc.push(Header::Map(Some(2)))?;
+        enc.text(Keys::V0.ok, None)?;
+        enc.push(Header::Bytes(None))?;
+        let mut chunk = vec![0u8; CHUNK];
+        loop {
+            match data.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => enc.bytes(&chunk[..n], None)?,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        enc.push(Header::Break)?;
+        enc.text(Keys::V0.id, None)?;
+        Ciborium.to_writer(&Value::from(req_id.clone()), writer)?;
+        Ok(())
+    }
+
+    /// A Response read by `read_response_streaming`.
+    pub enum StreamingResponse<'a, R> {
+        /// An `ok` result holding Bytes, which can be read as they arrive.
+        Bytes(BytesReader<'a, R>),
+        /// Any other Response, read in full.
+        Response(Response),
+    }
+
+    /// Reads the contents of a Bytes result straight off the channel.
+    ///
+    /// The RequestID comes after the result, so once the Bytes have been
+    /// read, call [finish](Self::finish) to read the rest of the message and
+    /// find out which call it was for. Dropping the reader before then leaves
+    /// the channel in the middle of a message.
+    pub struct BytesReader<'a, R> {
+        reader: &'a mut R,
+        // Bytes left in the current chunk
+        left: u64,
+        // Whether more chunks may follow, up to a break
+        chunked: bool,
+        // Items left in the message after the result; None if it's an
+        // indefinite-length map
+        items_left: Option<u64>,
+        // The max message size, for the rest of the message
+        limit: Option<usize>,
+    }
+
+    fn invalid(e: TransportError) -> io::Error {
+        match e {
+            TransportError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+
+    impl<R: Read> Read for BytesReader<'_, R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.left == 0 {
+                if !self.chunked {
+                    return Ok(0);
+                }
+                match read_header(self.reader, &mut Vec::new()).map_err(invalid)? {
+                    (BYTES, Some(len)) => self.left = len,
+                    BREAK => self.chunked = false,
+                    _ => return Err(invalid(ProtocolError::InvalidMessage.into())),
+                }
+            }
+            let max = usize::try_from(self.left).map_or(buf.len(), |left| left.min(buf.len()));
+            let n = self.reader.read(&mut buf[..max])?;
+            if n == 0 && max > 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.left -= n as u64;
+            Ok(n)
+        }
+    }
+
+    impl<R: Read> BytesReader<'_, R> {
+        /// Skip whatever's left of the Bytes, read the rest of the message,
+        /// and return its RequestID.
+        pub fn finish(mut self) -> Result<RequestID, TransportError> {
+            io::copy(&mut self, &mut io::sink())?;
+            let items_left = self.items_left;
+            let (_, req_id) =
+                read_limited(&mut *self.reader, self.limit, |r| read_items(r, items_left))?
+                    .into_iter()
+                    .find(|(key, _)| key.as_text() == Some(Keys::V0.id))
+                    .ok_or(ProtocolError::InvalidMessage)?;
+            Ok(RequestID::try_from(req_id)?)
+        }
+    }
+
+    // Read one Value, or None if there's a break instead and `may_break`.
+    fn read_value(
+        reader: &mut impl Read,
+        may_break: bool,
+    ) -> Result<Option<Value>, TransportError> {
+        let mut head = Vec::new();
+        match read_header(reader, &mut head)? {
+            BREAK if may_break => Ok(None),
+            _ => Ok(Some(
+                Ciborium.from_reader(&mut Read::chain(&head[..], reader))?,
+            )),
+        }
+    }
+
+    // Read `count` more map items, or up to a break if `count` is None.
+    fn read_items(
+        reader: &mut impl Read,
+        count: Option<u64>,
+    ) -> Result<Vec<(Value, Value)>, TransportError> {
+        let mut items = Vec::new();
+        for _ in 0..count.unwrap_or(u64::MAX) {
+            let key = match read_value(reader, count.is_none())? {
+                Some(key) => key,
+                None => break,
+            };
+            let value = read_value(reader, false)?.ok_or(ProtocolError::InvalidMessage)?;
+            items.push((key, value));
+        }
+        Ok(items)
+    }
+
+    // How a message starts: with a Bytes result that can be streamed, or as
+    // some other Response, which has been read in full.
+    enum Start {
+        Bytes {
+            len: Option<u64>,
+            items_left: Option<u64>,
+        },
+        Response(Response),
+    }
+
+    // The max message size applies to everything but the Bytes: the start of
+    // the message, and then the rest once the Bytes are done.
+    fn read_streaming<R: Read>(
+        reader: &mut R,
+        limit: Option<usize>,
+    ) -> Result<StreamingResponse<'_, R>, TransportError> {
+        match read_limited(&mut *reader, limit, read_start)? {
+            Start::Bytes { len, items_left } => Ok(StreamingResponse::Bytes(BytesReader {
+                reader,
+                left: len.unwrap_or(0),
+                chunked: len.is_none(),
+                items_left,
+                limit,
+            })),
+            Start::Response(response) => Ok(StreamingResponse::Response(response)),
+        }
+    }
+
+    fn read_start(reader: &mut impl Read) -> Result<Start, TransportError> {
+        let mut head = Vec::new();
+        if read_header(reader, &mut head)? != (TAG, Some(TAG_ID_RPCV0)) {
+            return Err(ProtocolError::InvalidMessage.into());
+        }
+        let count = match read_header(reader, &mut head)? {
+            (MAP, Some(0)) => return Err(ProtocolError::InvalidMessage.into()),
+            (MAP, count) => count,
+            _ => return Err(ProtocolError::InvalidMessage.into()),
+        };
+        let items_left = count.map(|n| n - 1);
+        let key = read_value(reader, count.is_none())?.ok_or(ProtocolError::InvalidMessage)?;
+        head.clear();
+        let header = read_header(reader, &mut head)?;
+        if let (true, (BYTES, len)) = (key.as_text() == Some(Keys::V0.ok), header) {
+            return Ok(Start::Bytes { len, items_left });
+        }
+        // Not something we can stream, so read the whole thing
+        let value = Ciborium.from_reader(&mut Read::chain(&head[..], &mut *reader))?;
+        let mut items = vec![(key, value)];
+        items.extend(read_items(reader, items_left)?);
+        match decode_items(items, &Keys::V0)? {
+            Decoded::Response(response) => Ok(Start::Response(response)),
+            _ => Err(ProtocolError::UnexpectedMessage.into()),
+        }
+    }
+
+    // BufTransport already has the whole message in memory, so there's
+    // nothing to gain there.
+
+    impl<C: Read + Write> Transport<C> {
+        /// Send an `ok` Response whose result is Bytes read from `data`, a
+        /// chunk at a time, instead of reading it all into memory first.
+        ///
+        /// If reading `data` fails partway, the message is left unfinished,
+        /// and the connection is no good afterward. The Bytes go out with an
+        /// indefinite length, so this ignores [canonical](Self::canonical).
+        pub fn send_response_streaming(
+            &mut self,
+            req_id: &RequestID,
+            data: impl Read,
+        ) -> Result<(), TransportError> {
+            write_streaming(&mut self.channel, req_id, data)
+        }
+
+        /// Read a Response, streaming its result if it's Bytes; see
+        /// [BytesReader]. The max message size applies to all of the message
+        /// except the streamed Bytes.
+        pub fn read_response_streaming(
+            &mut self,
+        ) -> Result<StreamingResponse<'_, C>, TransportError> {
+            let limit = self.max_message_size();
+            read_streaming(&mut self.channel, limit)
+        }
+    }
+
+    impl<R: Read, W: Write> SplitTransport<R, W> {
+        /// See [Transport::send_response_streaming].
+        pub fn send_response_streaming(
+            &mut self,
+            req_id: &RequestID,
+            data: impl Read,
+        ) -> Result<(), TransportError> {
+            write_streaming(&mut self.writer, req_id, data)
+        }
+
+        /// See [Transport::read_response_streaming].
+        pub fn read_response_streaming(
+            &mut self,
+        ) -> Result<StreamingResponse<'_, R>, TransportError> {
+            let limit = self.max_message_size();
+            read_streaming(&mut self.reader, limit)
+        }
+    }
+}
+
+// ----- Hand-written codec ---------------------------------------------------
+
+/// The names of the items in a message's map.
+///
+/// [V0](Keys::V0) is the protocol's own set, and the only one the serde-based
+/// encoding uses. [JSON_RPC](Keys::JSON_RPC) uses the names from JSON-RPC 2.0
+/// instead, for a peer - like a JSON-RPC gateway - that expects those; the
+/// `_with` functions below and [KeyedFraming](crate::transport::framing::KeyedFraming)
+/// take either. The items of an ErrorValue are `code`, `message`, and `data`
+/// in both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keys {
+    pub method: &'static str,
+    pub params: &'static str,
+    pub id: &'static str,
+    pub deadline: &'static str,
+    pub ok: &'static str,
+    pub more: &'static str,
+    pub err: &'static str,
+    pub cancel: &'static str,
+}
+
+impl Keys {
+    pub const V0: Keys = Keys {
+        method: "fn",
+        params: "args",
+        id: "id",
+        deadline: "deadline",
+        ok: "ok",
+        more: "more",
+        err: "err",
+        cancel: "cancel",
+    };
+
+    /// JSON-RPC has no partial results, deadlines, or cancellation, so those
+    /// keep their v0 names.
+    pub const JSON_RPC: Keys = Keys {
+        method: "method",
+        params: "params",
+        id: "id",
+        deadline: "deadline",
+        ok: "result",
+        more: "more",
+        err: "error",
+        cancel: "cancel",
+    };
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Keys::V0
+    }
+}
+
+// This encodes and decodes messages by way of a plain Value, using the usual
+// conversions for each part, rather than through serde's derives. That means
+// it works without serde1 (or std), and tags in the params or result come
+// through like any other part of a Value. Requests come out byte-for-byte
+// the same as the serde-based encoding; Responses use a definite-length map
+// where serde writes an indefinite one, but each can read the other's.
+
+fn tagged_map(items: Vec<(&str, Value)>) -> Value {
+    let map = items
+        .into_iter()
+        .map(|(key, value)| (Value::Text(key.into()), value))
+        .collect();
+    Value::Tag(TAG_ID_RPCV0, Box::new(Value::Map(map)))
+}
+
+fn to_vec(msg: &Value) -> Vec<u8> {
+    let mut buf = Vec::new();
+    // Writing a Value into a Vec can't fail
+    ciborium::ser::into_writer(msg, &mut buf).unwrap();
+    buf
+}
+
+#[cfg(test)]
+fn encode_map(items: Vec<(&str, Value)>) -> Vec<u8> {
+    to_vec(&tagged_map(items))
+}
+
+pub(crate) fn request_value(request: &Request, keys: &Keys) -> Value {
+    let mut items = vec![(keys.method, request.method.clone().into())];
+    if let Some(params) = &request.params {
+        items.push((keys.params, params.clone().into()));
+    }
+    if let Some(req_id) = &request.req_id {
+        items.push((keys.id, req_id.clone().into()));
+    }
+    if let Some(deadline) = request.deadline {
+        items.push((keys.deadline, deadline.into()));
+    }
+    tagged_map(items)
+}
+
+pub(crate) fn response_value(response: &Response, keys: &Keys) -> Value {
+    let result = match &response.result {
+        Ok(value) if response.is_partial() => (keys.more, value.clone()),
+        Ok(value) => (keys.ok, value.clone()),
+        Err(error) => (keys.err, error.clone().into()),
+    };
+    tagged_map(vec![result, (keys.id, response.req_id.clone().into())])
+}
+
+pub(crate) fn control_value(control: &Control, keys: &Keys) -> Value {
+    match control {
+        Control::Cancel(req_id) => tagged_map(vec![(keys.cancel, req_id.clone().into())]),
+    }
+}
+
+/// Encode a Request as a v0 RPC message, without needing serde1.
+pub fn encode_request(request: &Request) -> Vec<u8> {
+    encode_request_with(request, &Keys::V0)
+}
+
+/// Encode a Response as a v0 RPC message, without needing serde1.
+pub fn encode_response(response: &Response) -> Vec<u8> {
+    encode_response_with(response, &Keys::V0)
+}
+
+/// Encode a Control message as a v0 RPC message, without needing serde1.
+pub fn encode_control(control: &Control) -> Vec<u8> {
+    encode_control_with(control, &Keys::V0)
+}
+
+/// [encode_request], using `keys` for the names of the items.
+pub fn encode_request_with(request: &Request, keys: &Keys) -> Vec<u8> {
+    to_vec(&request_value(request, keys))
+}
+
+/// [encode_response], using `keys` for the names of the items.
+pub fn encode_response_with(response: &Response, keys: &Keys) -> Vec<u8> {
+    to_vec(&response_value(response, keys))
+}
+
+/// [encode_control], using `keys` for the names of the items.
+pub fn encode_control_with(control: &Control, keys: &Keys) -> Vec<u8> {
+    to_vec(&control_value(control, keys))
+}
+
+pub(crate) enum Decoded {
+    Request(Request),
+    Response(Response),
+    Control(Control),
+}
+
+// Decode exactly one message from `bytes`. Like the serde-based decoder,
+// this skips over keys it doesn't recognize.
+fn decode(bytes: &[u8], keys: &Keys) -> Result<Decoded, ProtocolError> {
+    let mut reader = bytes;
+    let msg: Value =
+        ciborium::de::from_reader(&mut reader).map_err(|_| ProtocolError::InvalidMessage)?;
+    if !reader.is_empty() {
+        return Err(ProtocolError::InvalidMessage);
+    }
+    decode_value(msg, keys)
+}
+
+// Decode a message that's already been read as a Value.
+pub(crate) fn decode_value(msg: Value, keys: &Keys) -> Result<Decoded, ProtocolError> {
+    match msg {
+        Value::Tag(TAG_ID_RPCV0, map) => match *map {
+            Value::Map(items) => decode_items(items, keys),
+            _ => Err(ProtocolError::InvalidMessage),
+        },
+        _ => Err(ProtocolError::InvalidMessage),
+    }
+}
+
+// Make a message out of the items of its map.
+fn decode_items(items: Vec<(Value, Value)>, keys: &Keys) -> Result<Decoded, ProtocolError> {
+    let mut method = None;
+    let mut params = None;
+    let mut req_id = None;
+    let mut deadline = None;
+    let mut result = None;
+    let mut cancel = None;
+    for (key, value) in items {
+        let key = match key {
+            Value::Text(key) => key,
+            _ => return Err(ProtocolError::InvalidMessage),
+        };
+        match key.as_str() {
+            k if k == keys.method => method = Some(MethodID::try_from(value)?),
+            k if k == keys.params => params = Some(Params::try_from(value)?),
+            k if k == keys.id => req_id = Some(RequestID::try_from(value)?),
+            k if k == keys.deadline => match value {
+                Value::Integer(i) => {
+                    deadline = Some(u64::try_from(i).map_err(|_| ProtocolError::InvalidMessage)?)
+                }
+                _ => return Err(ProtocolError::InvalidMessage),
+            },
+            k if k == keys.ok => result = Some((Ok(value), ResponseKind::Final)),
+            k if k == keys.more => result = Some((Ok(value), ResponseKind::Partial)),
+            k if k == keys.err => {
+                result = Some((Err(ErrorValue::try_from(value)?), ResponseKind::Final))
+            }
+            k if k == keys.cancel => cancel = Some(RequestID::try_from(value)?),
+            _ => {}
+        }
+    }
+    match (method, result, req_id, cancel) {
+        (Some(method), _, req_id, _) => Ok(Decoded::Request(Request {
+            method,
+            params,
+            req_id,
+            deadline,
+        })),
+        (None, Some((result, kind)), Some(req_id), _) => Ok(Decoded::Response(Response {
+            result,
+            req_id,
+            kind,
+        })),
+        (None, None, None, Some(req_id)) => Ok(Decoded::Control(Control::Cancel(req_id))),
+        _ => Err(ProtocolError::InvalidMessage),
+    }
+}
+
+/// Decode a Request from a v0 RPC message, without needing serde1. `bytes`
+/// must hold exactly one message; if it's some other kind of message, this
+/// fails with [ProtocolError::UnexpectedMessage].
+pub fn decode_request(bytes: &[u8]) -> Result<Request, ProtocolError> {
+    decode_request_with(bytes, &Keys::V0)
+}
+
+/// Decode a Response from a v0 RPC message; see [decode_request].
+pub fn decode_response(bytes: &[u8]) -> Result<Response, ProtocolError> {
+    decode_response_with(bytes, &Keys::V0)
+}
+
+/// Decode a Control message from a v0 RPC message; see [decode_request].
+pub fn decode_control(bytes: &[u8]) -> Result<Control, ProtocolError> {
+    decode_control_with(bytes, &Keys::V0)
+}
+
+/// [decode_request], for a message that uses `keys` for the names of its
+/// items.
+pub fn decode_request_with(bytes: &[u8], keys: &Keys) -> Result<Request, ProtocolError> {
+    match decode(bytes, keys)? {
+        Decoded::Request(request) => Ok(request),
+        _ => Err(ProtocolError::UnexpectedMessage),
+    }
+}
+
+/// [decode_response], for a message that uses `keys`.
+pub fn decode_response_with(bytes: &[u8], keys: &Keys) -> Result<Response, ProtocolError> {
+    match decode(bytes, keys)? {
+        Decoded::Response(response) => Ok(response),
+        _ => Err(ProtocolError::UnexpectedMessage),
+    }
+}
+
+/// [decode_control], for a message that uses `keys`.
+pub fn decode_control_with(bytes: &[u8], keys: &Keys) -> Result<Control, ProtocolError> {
+    match decode(bytes, keys)? {
+        Decoded::Control(control) => Ok(control),
+        _ => Err(ProtocolError::UnexpectedMessage),
+    }
+}
+
+// ----- Peeking ---------------------------------------------------------------
+
+/// The kinds of v0 message; see [peek_kind].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Request,
+    Response,
+    Control,
+}
+
+// Take one CBOR item header off the front of `bytes`. Returns the major type
+// and the argument, which is None for indefinite-length items.
+fn take_header(bytes: &mut &[u8]) -> Result<(u8, Option<u64>), ProtocolError> {
+    let (&first, rest) = bytes.split_first().ok_or(ProtocolError::InvalidMessage)?;
+    let (major, info) = (first >> 5, first & 0x1f);
+    let len = match info {
+        0..=23 => {
+            *bytes = rest;
+            return Ok((major, Some(info.into())));
+        }
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        31 => {
+            *bytes = rest;
+            return Ok((major, None));
+        }
+        _ => return Err(ProtocolError::InvalidMessage),
+    };
+    if rest.len() < len {
+        return Err(ProtocolError::InvalidMessage);
+    }
+    let mut arg = [0u8; 8];
+    arg[8 - len..].copy_from_slice(&rest[..len]);
+    *bytes = &rest[len..];
+    Ok((major, Some(u64::from_be_bytes(arg))))
+}
+
+/// Find out what kind of message `bytes` holds, without decoding the params
+/// or result. This only looks at the tag and the first key of the message,
+/// so `bytes` doesn't have to hold the whole thing - except in the unusual
+/// case that the first key doesn't settle it (e.g. `id`), where the whole
+/// message is decoded to find out.
+///
+/// A batch isn't a single message, so it's a [ProtocolError::InvalidMessage].
+pub fn peek_kind(bytes: &[u8]) -> Result<MessageKind, ProtocolError> {
+    const MAP: u8 = 5;
+    const TAG: u8 = 6;
+    const TEXT: u8 = 3;
+    let mut rest = bytes;
+    if take_header(&mut rest)? != (TAG, Some(TAG_ID_RPCV0)) {
+        return Err(ProtocolError::InvalidMessage);
+    }
+    match take_header(&mut rest)? {
+        (MAP, Some(0)) => return Err(ProtocolError::InvalidMessage),
+        (MAP, _) => {}
+        _ => return Err(ProtocolError::InvalidMessage),
+    }
+    let key = match take_header(&mut rest)? {
+        (TEXT, Some(len)) => rest.get(..len as usize),
+        (TEXT, None) => None,
+        _ => return Err(ProtocolError::InvalidMessage),
+    };
+    let keys = Keys::V0;
+    match key.map(|k| core::str::from_utf8(k).unwrap_or("")) {
+        Some(k) if [keys.method, keys.params, keys.deadline].contains(&k) => {
+            Ok(MessageKind::Request)
+        }
+        Some(k) if [keys.ok, keys.err, keys.more].contains(&k) => Ok(MessageKind::Response),
+        Some(k) if k == keys.cancel => Ok(MessageKind::Control),
+        _ => Ok(match decode(bytes, &keys)? {
+            Decoded::Request(_) => MessageKind::Request,
+            Decoded::Response(_) => MessageKind::Response,
+            Decoded::Control(_) => MessageKind::Control,
+        }),
+    }
+}
+
+#[cfg(all(test, feature = "serde1", feature = "std"))]
+mod tests {
+    use super::{Request, Response};
+    use crate::error::ProtocolError;
+    use crate::error::TransportError;
+    use crate::proto::{
+        value, Batch, Control, ErrorValue, Notification, Params, RequestBuilder, Value, WriteCbor,
+    };
+    use crate::transport::cbor::CBORTransport;
+    use crate::transport::simple::{ClientTransport, Incoming, ServerTransport};
+    use crate::transport::BufTransport;
+    use bytes::BytesMut;
+    use core::convert::TryFrom;
+
+    macro_rules! params {
+        ($($v:expr),+ $(,)?) => {
+            Params::Array(vec![$(
+                Value::from($v),
+            )+
+            ])
+        };
+    }
+
+    #[test]
+    fn encode_request() {
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let mut req = Request {
+            method: "hello".into(),
+            params: Some(params!["one", 2, "three"]),
+            req_id: Some(42u32.into()),
+            deadline: None,
+        };
+        tr.send_request(req.clone()).unwrap();
+        assert!(tr.buffer.len() <= 38);
+        assert_eq!(req.encoded_len().unwrap(), tr.buffer.len());
+        let req2: Request = tr.read_request().unwrap();
+        println!("req: {:?}", req2);
+        assert_eq!(req, req2);
+        req.params = None;
+        tr.send_request(req.clone()).unwrap();
+        let req2: Request = tr.read_request().unwrap();
+        println!("req: {:?}", req2);
+        assert_eq!(req, req2);
+        req.req_id = None;
+        tr.send_request(req.clone()).unwrap();
+        let req2: Request = tr.read_request().unwrap();
+        println!("req: {:?}", req2);
+        assert_eq!(req, req2);
+    }
+
+    #[test]
+    fn encode_response() {
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let mut resp = Response::new(Ok("yay".into()), 42u32.into());
+        tr.send_response(resp.clone()).unwrap();
+        let resp2: Response = tr.read_response().unwrap();
+        println!("resp: {:?}", resp2);
+        assert_eq!(resp, resp2);
+        resp.result = Err(ErrorValue {
+            code: 418,
+            message: "I'm a teapot".into(),
+            data: None,
+        });
+        tr.send_response(resp.clone()).unwrap();
+        println!("len: {:?}", tr.buffer.len());
+        println!("msg: {:?}", tr.buffer);
+        let val = tr.read_cbor().unwrap();
+        println!("val: {:?}", val);
+        tr.send_response(resp.clone()).unwrap();
+        let resp2: Response = tr.read_response().unwrap();
+        println!("resp: {:?}", resp2);
+        assert_eq!(resp, resp2);
+    }
+
+    #[test]
+    fn tagged_params() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        let when = value::tagged(1, 1_700_000_000);
+        let nested = Value::Map(vec![("when".into(), when.clone())]);
+        let req = RequestBuilder::new("schedule")
+            .arg(when.clone())
+            .arg(nested)
+            .id(1u8)
+            .build()
+            .unwrap();
+        tr.send_request(req.clone()).unwrap();
+        let req2 = tr.read_request().unwrap();
+        assert_eq!(req2, req);
+        let param = req2.params().as_ref().unwrap().get(0).unwrap();
+        assert_eq!(
+            value::as_tagged(param),
+            Some((1, &Value::from(1_700_000_000)))
+        );
+
+        let resp = Response::ok(when, 1u8.into());
+        tr.send_response(resp.clone()).unwrap();
+        assert_eq!(tr.read_response().unwrap(), resp);
+    }
+
+    #[test]
+    fn error_value() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        let plain = ErrorValue::new(-1, "nope");
+        let detailed = ErrorValue::new(-2, String::from("nope")).with_data(vec![1, 2, 3]);
+        assert_eq!(plain.data(), &None);
+        assert_eq!(detailed.data(), &Some(Value::from(vec![1, 2, 3])));
+        for error in [plain, detailed] {
+            let resp = Response::new(Err(error), 1u8.into());
+            tr.send_response(resp.clone()).unwrap();
+            assert_eq!(tr.read_response().unwrap(), resp);
+        }
+    }
+
+    #[test]
+    fn build_request() {
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let req = RequestBuilder::new("add")
+            .arg(1)
+            .arg(2)
+            .arg(3)
+            .id(7u8)
+            .build()
+            .unwrap();
+        assert_eq!(req.params(), &Some(params![1, 2, 3]));
+        tr.send_request(req.clone()).unwrap();
+        assert_eq!(req, tr.read_request().unwrap());
+
+        let req = RequestBuilder::new("greet")
+            .param("name", "world")
+            .param("loud", true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            req.params(),
+            &Some(Params::Named(vec![
+                ("name".into(), "world".into()),
+                ("loud".into(), true.into()),
+            ]))
+        );
+        assert_eq!(req.req_id(), &None);
+        tr.send_request(req.clone()).unwrap();
+        assert_eq!(req, tr.read_request().unwrap());
+
+        let mixed = RequestBuilder::new("oops").arg(1).param("two", 2).build();
+        assert!(matches!(mixed, Err(ProtocolError::MixedParams)));
+    }
+
+    #[test]
+    fn send_by_ref() {
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let req = RequestBuilder::new("again").arg(1).id(5u8).build().unwrap();
+        tr.send_request_ref(&req).unwrap();
+        tr.send_request_ref(&req).unwrap();
+        assert_eq!(tr.read_request().unwrap(), req);
+        assert_eq!(tr.read_request().unwrap(), req);
+
+        let resp = Response::partial(2.into(), 5u8.into());
+        tr.send_response_ref(&resp).unwrap();
+        assert_eq!(tr.read_response().unwrap(), resp);
+    }
+
+    #[test]
+    fn empty_params() {
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        for params in [Params::Array(vec![]), Params::Named(vec![])] {
+            let req = Request::new("f", Some(params), Some(1u8.into()));
+            tr.send_request(req.clone()).unwrap();
+            assert_eq!(tr.read_request().unwrap(), req);
+        }
+    }
+
+    #[test]
+    fn binary_method() {
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let req = RequestBuilder::new(vec![0xde, 0xad, 0xbe, 0xef])
+            .arg(1)
+            .id(1u8)
+            .build()
+            .unwrap();
+        tr.send_request(req.clone()).unwrap();
+        match tr.read_cbor().unwrap() {
+            Value::Tag(_, map) => {
+                let method = &map.as_map().unwrap()[0].1;
+                assert_eq!(method, &Value::Bytes(vec![0xde, 0xad, 0xbe, 0xef]));
+            }
+            v => panic!("expected tagged message, got {:?}", v),
+        }
+        tr.send_request(req.clone()).unwrap();
+        assert_eq!(tr.read_request().unwrap(), req);
+
+        // Bytes that look like UTF-8 are still Binary
+        let req = RequestBuilder::new(b"abc".to_vec()).build().unwrap();
+        tr.send_request(req.clone()).unwrap();
+        assert_eq!(tr.read_request().unwrap(), req);
+    }
+
+    #[test]
+    fn request_ids() {
+        use crate::proto::RequestID;
+        let mut tr = BufTransport::new(BytesMut::new());
+        for (id, wire) in [
+            (RequestID::from("abc"), Value::Text("abc".into())),
+            (RequestID::from(7u8), Value::Integer(7.into())),
+            // Bytes that look like UTF-8 are still Binary
+            (
+                RequestID::from(b"abc".to_vec()),
+                Value::Bytes(b"abc".to_vec()),
+            ),
+            // Bignums, both small enough for a u128 and not
+            (
+                RequestID::from(u128::MAX),
+                Value::Tag(2, Box::new(Value::Bytes(vec![0xff; 16]))),
+            ),
+            (
+                RequestID::from_bignum(&[0x12; 20]),
+                Value::Tag(2, Box::new(Value::Bytes(vec![0x12; 20]))),
+            ),
+        ] {
+            let resp = Response::ok(1, id.clone());
+            tr.send_response_ref(&resp).unwrap();
+            match tr.read_cbor().unwrap() {
+                Value::Tag(_, map) => assert_eq!(map.as_map().unwrap()[1].1, wire),
+                v => panic!("expected tagged message, got {:?}", v),
+            }
+            tr.send_response_ref(&resp).unwrap();
+            assert_eq!(tr.read_response().unwrap().req_id(), &id);
+        }
+
+        // Negative ids aren't allowed
+        let resp = Value::Map(vec![("ok".into(), 1.into()), ("id".into(), (-1).into())]);
+        tr.send_cbor(Value::Tag(super::TAG_ID_RPCV0, Box::new(resp)))
+            .unwrap();
+        assert!(tr.read_response().is_err());
+    }
+
+    #[test]
+    fn encode_notification() {
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let note = Notification::new("ping", Some(params![1]));
+        tr.send_notification(note.clone()).unwrap();
+        match tr.read_cbor().unwrap() {
+            Value::Tag(_, map) => {
+                let keys: Vec<_> = map
+                    .as_map()
+                    .unwrap()
+                    .iter()
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                assert_eq!(keys, vec![Value::from("fn"), Value::from("args")]);
+            }
+            v => panic!("expected tagged message, got {:?}", v),
+        }
+        tr.send_notification(note.clone()).unwrap();
+        assert_eq!(tr.read_incoming().unwrap(), Incoming::Notification(note));
+
+        let req = RequestBuilder::new("ping").id(1u8).build().unwrap();
+        assert!(!req.is_notification());
+        tr.send_request(req.clone()).unwrap();
+        assert_eq!(tr.read_incoming().unwrap(), Incoming::Request(req));
+    }
+
+    #[test]
+    fn encode_batch() {
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let batch = Batch::new(vec![
+            RequestBuilder::new("add")
+                .arg(1)
+                .arg(2)
+                .id(1u8)
+                .build()
+                .unwrap(),
+            Notification::new("log", Some(params!["hi"])).into(),
+            RequestBuilder::new("add")
+                .arg(3)
+                .arg(4)
+                .id(2u8)
+                .build()
+                .unwrap(),
+        ])
+        .unwrap();
+        tr.send_request_batch(batch.clone()).unwrap();
+        let batch2 = tr.read_request_batch().unwrap();
+        assert_eq!(batch, batch2);
+
+        let mut handled = 0;
+        let responses = batch2
+            .respond(|req| {
+                handled += 1;
+                match req.params() {
+                    Some(Params::Array(a)) if req.method() == &"add".into() => {
+                        let sum: i128 = a.iter().map(|v| i128::from(v.as_integer().unwrap())).sum();
+                        Ok(Value::Integer((sum as i64).into()))
+                    }
+                    _ => Ok(Value::Null),
+                }
+            })
+            .unwrap();
+        assert_eq!(handled, 3);
+        tr.send_response_batch(responses).unwrap();
+        let responses = tr.read_response_batch().unwrap();
+        assert_eq!(responses.len(), 2);
+        let results: Vec<_> = responses
+            .iter()
+            .map(|r| (r.req_id().clone(), r.result().clone()))
+            .collect();
+        assert_eq!(
+            results,
+            vec![(1u8.into(), Ok(3.into())), (2u8.into(), Ok(7.into()))]
+        );
+    }
+
+    #[test]
+    fn empty_batch() {
+        assert!(matches!(
+            Batch::<Request>::new(vec![]),
+            Err(ProtocolError::EmptyBatch)
+        ));
+        let only_notes = Batch::new(vec![Request::from(Notification::new("log", None))]).unwrap();
+        assert!(only_notes.respond(|_| Ok(Value::Null)).is_none());
+
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        tr.send_cbor(Value::Array(vec![])).unwrap();
+        assert!(matches!(
+            tr.read_request_batch(),
+            Err(TransportError::Proto(ProtocolError::EmptyBatch))
+        ));
+    }
+
+    #[test]
+    fn request_iter() {
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let reqs = vec![
+            RequestBuilder::new("one").id(1u8).build().unwrap(),
+            RequestBuilder::new("two").arg(2).build().unwrap(),
+        ];
+        for req in &reqs {
+            tr.send_request(req.clone()).unwrap();
+        }
+        let got: Vec<_> = tr.requests().map(Result::unwrap).collect();
+        assert_eq!(got, reqs);
+
+        // A truncated message is an error, not the end of the stream
+        tr.send_request(reqs[0].clone()).unwrap();
+        tr.send_request(reqs[1].clone()).unwrap();
+        let len = tr.buffer.len();
+        tr.buffer.truncate(len - 1);
+        let mut iter = tr.requests();
+        assert_eq!(iter.next().unwrap().unwrap(), reqs[0]);
+        assert!(matches!(
+            iter.next(),
+            Some(Err(TransportError::Decode { .. }))
+        ));
+        assert!(iter.next().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn eof() {
+        use crate::transport::Transport;
+        use std::os::unix::net::UnixStream;
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut c_tr = Transport::new(s1);
+        let mut s_tr = Transport::new(s2);
+        let req = RequestBuilder::new("bye").id(9u8).build().unwrap();
+        c_tr.send_request(req.clone()).unwrap();
+        drop(c_tr);
+        assert_eq!(s_tr.read_request().unwrap(), req);
+        assert!(matches!(s_tr.read_request(), Err(TransportError::Eof)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_timeout() {
+        use crate::transport::Transport;
+        use std::os::unix::net::UnixStream;
+        use std::time::{Duration, Instant};
+        let (s1, _s2) = UnixStream::pair().unwrap();
+        let mut c_tr = Transport::new(s1);
+        c_tr.set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        c_tr.send_request(RequestBuilder::new("hello?").id(1u8).build().unwrap())
+            .unwrap();
+        let start = Instant::now();
+        assert!(matches!(c_tr.read_response(), Err(TransportError::Timeout)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn split_transport() {
+        use crate::transport::SplitTransport;
+        use std::io::Cursor;
+        let req = RequestBuilder::new("split")
+            .arg("up")
+            .id(3u8)
+            .build()
+            .unwrap();
+        let mut input = BufTransport::new(BytesMut::new());
+        input.send_request(req.clone()).unwrap();
+        let mut tr = SplitTransport::new(Cursor::new(input.buffer.to_vec()), Vec::new());
+        assert_eq!(tr.read_request().unwrap(), req);
+        assert!(matches!(tr.read_request(), Err(TransportError::Eof)));
+
+        let resp = Response::new(Ok("down".into()), 3u8.into());
+        tr.send_response(resp.clone()).unwrap();
+        let (_, output) = tr.into_inner();
+        let mut output = BufTransport::new(BytesMut::from(&output[..]));
+        assert_eq!(output.read_response().unwrap(), resp);
+    }
+
+    #[test]
+    fn indefinite_length() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        let blob = vec![0xa5u8; 10_000];
+        let req = RequestBuilder::new("upload")
+            .arg(blob.clone())
+            .arg(-1i64 << 40)
+            .id(1u8)
+            .build()
+            .unwrap();
+        tr.send_request_indefinite(&req).unwrap();
+        // 0x9f starts an indefinite-length Array, 0x5f indefinite-length Bytes
+        assert!(tr.buffer.windows(2).any(|w| w == [0x9f, 0x5f]));
+        assert_eq!(tr.read_request().unwrap(), req);
+        assert!(tr.buffer.is_empty());
+
+        // Named params go out as an indefinite-length Map
+        let req = RequestBuilder::new("put")
+            .param("data", blob)
+            .id("x")
+            .build()
+            .unwrap()
+            .with_deadline(1);
+        tr.send_request_indefinite(&req).unwrap();
+        assert!(tr.buffer.windows(2).any(|w| w == [0xbf, 0x64]));
+        assert_eq!(tr.read_request().unwrap(), req);
+        assert!(tr.buffer.is_empty());
+    }
+
+    // A megabyte of bytes that's never in memory all at once.
+    struct Pattern(usize);
+
+    const PATTERN_LEN: usize = 1 << 20;
+
+    fn pattern_byte(pos: usize) -> u8 {
+        (pos % 251) as u8
+    }
+
+    impl std::io::Read for Pattern {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(PATTERN_LEN - self.0);
+            for (i, b) in buf[..n].iter_mut().enumerate() {
+                *b = pattern_byte(self.0 + i);
+            }
+            self.0 += n;
+            Ok(n)
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streaming_bytes() {
+        use super::StreamingResponse;
+        use crate::transport::Transport;
+        use std::io::Read;
+        use std::os::unix::net::UnixStream;
+
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut tr = Transport::new(s2);
+            tr.send_response_streaming(&1u8.into(), Pattern(0)).unwrap();
+            tr.send_response_streaming(&2u8.into(), &b"small"[..])
+                .unwrap();
+            let error = ErrorValue::new(-1, "no file");
+            tr.send_response(Response::err(error, 3u8.into())).unwrap();
+            tr.send_response(Response::ok("x".repeat(2000), 4u8.into()))
+                .unwrap();
+        });
+        // The streamed Bytes don't count toward the limit, but a Response
+        // that's read in full does
+        let mut client = Transport::new(s1).with_max_message_size(1000);
+        let mut reader = match client.read_response_streaming().unwrap() {
+            StreamingResponse::Bytes(reader) => reader,
+            StreamingResponse::Response(r) => panic!("expected Bytes, got {:?}", r),
+        };
+        let mut buf = [0u8; 10_000];
+        let mut pos = 0;
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            assert!(buf[..n]
+                .iter()
+                .enumerate()
+                .all(|(i, &b)| b == pattern_byte(pos + i)));
+            pos += n;
+        }
+        assert_eq!(pos, PATTERN_LEN);
+        assert_eq!(reader.finish().unwrap(), 1u8.into());
+
+        // It's an ordinary Response to anyone else
+        let resp = client.read_response().unwrap();
+        assert_eq!(resp, Response::ok(b"small".to_vec(), 2u8.into()));
+        match client.read_response_streaming().unwrap() {
+            StreamingResponse::Response(resp) => assert!(resp.is_err()),
+            StreamingResponse::Bytes(_) => panic!("expected an error"),
+        }
+        assert!(matches!(
+            client.read_response_streaming(),
+            Err(TransportError::MessageTooLarge { limit: 1000 })
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn streaming_ignores_canonical() {
+        use crate::transport::Transport;
+        use std::io::Cursor;
+
+        let mut tr = Transport::new(Cursor::new(Vec::new())).with_canonical(true);
+        tr.send_response_streaming(&1u8.into(), &b"abc"[..])
+            .unwrap();
+        let bytes = tr.channel.into_inner();
+        // "ok", then an indefinite-length Bytes holding one chunk
+        let ok = [0x62, b'o', b'k', 0x5f, 0x43, b'a', b'b', b'c', 0xff];
+        assert!(bytes.windows(ok.len()).any(|w| w == ok));
+        let mut tr = Transport::new(Cursor::new(bytes));
+        assert_eq!(
+            tr.read_response().unwrap(),
+            Response::ok(b"abc".to_vec(), 1u8.into())
+        );
+    }
+
+    #[test]
+    fn reuse_buffers() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        let mut scratch = vec![0u8; 256];
+        let req = RequestBuilder::new("again")
+            .arg("x".repeat(100))
+            .arg(vec![1u8; 300])
+            .id(1u8)
+            .build()
+            .unwrap();
+        let len = req.to_cbor_vec().unwrap().len();
+        for _ in 0..1000 {
+            tr.send_request_ref(&req).unwrap();
+            assert_eq!(tr.read_request_into(&mut scratch).unwrap(), req);
+        }
+        // Read bytes are dropped from the buffer, so it doesn't keep growing
+        assert!(tr.buffer.is_empty());
+        assert!(tr.buffer.capacity() < 2 * len);
+    }
+
+    #[test]
+    fn try_read() {
+        let mut full = BufTransport::new(BytesMut::new());
+        let req = RequestBuilder::new("slow")
+            .arg("x")
+            .id(1u8)
+            .build()
+            .unwrap();
+        full.send_request(req.clone()).unwrap();
+        let bytes = full.buffer.to_vec();
+        let (head, tail) = bytes.split_at(bytes.len() / 2);
+
+        let mut tr = BufTransport::new(BytesMut::new());
+        assert_eq!(tr.try_read_request().unwrap(), None);
+        tr.buffer.extend_from_slice(head);
+        assert_eq!(tr.try_read_request().unwrap(), None);
+        assert_eq!(&tr.buffer[..], head);
+        tr.buffer.extend_from_slice(tail);
+        assert_eq!(tr.try_read_request().unwrap(), Some(req));
+        assert!(tr.buffer.is_empty());
+
+        let mut tr = BufTransport::new(BytesMut::from(head)).with_max_message_size(2);
+        assert!(matches!(
+            tr.try_read_request(),
+            Err(TransportError::MessageTooLarge { limit: 2 })
+        ));
+
+        let mut tr = BufTransport::new(BytesMut::from(&bytes[..])).with_reject_trailing(true);
+        tr.buffer.extend_from_slice(b"junk");
+        assert!(matches!(
+            tr.try_read_request(),
+            Err(TransportError::Decode { pos: Some(p), .. }) if p == bytes.len()
+        ));
+        assert_eq!(&tr.buffer[..], b"junk");
+    }
+
+    #[test]
+    fn fill_from_chunks() {
+        // Hands out one chunk per read
+        struct Chunks(Vec<Vec<u8>>);
+        impl std::io::Read for Chunks {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0.is_empty() {
+                    return Ok(0);
+                }
+                let chunk = self.0.remove(0);
+                buf[..chunk.len()].copy_from_slice(&chunk);
+                Ok(chunk.len())
+            }
+        }
+
+        let req = RequestBuilder::new("slow")
+            .arg("a few bytes")
+            .id(1u8)
+            .build()
+            .unwrap();
+        let bytes = req.to_cbor_vec().unwrap();
+        let third = bytes.len() / 3;
+        let mut reader = Chunks(vec![
+            bytes[..third].to_vec(),
+            bytes[third..2 * third].to_vec(),
+            bytes[2 * third..].to_vec(),
+        ]);
+
+        let mut tr = BufTransport::new(BytesMut::new());
+        let mut reads = 0;
+        let got = loop {
+            if let Some(got) = tr.try_read_request().unwrap() {
+                break got;
+            }
+            assert!(tr.fill_from(&mut reader).unwrap() > 0);
+            reads += 1;
+        };
+        assert_eq!(got, req);
+        assert_eq!(reads, 3);
+        assert_eq!(tr.fill_from(&mut reader).unwrap(), 0);
+    }
+
+    #[test]
+    fn untagged_message() {
+        let mut untagged = Vec::new();
+        let map = Value::Map(vec![
+            ("method".into(), "hi".into()),
+            ("id".into(), 1.into()),
+        ]);
+        ciborium::ser::into_writer(&map, &mut untagged).unwrap();
+        let mut tr = BufTransport::new(BytesMut::from(&untagged[..]));
+        assert!(matches!(
+            tr.read_request(),
+            Err(TransportError::Proto(ProtocolError::InvalidMessage))
+        ));
+
+        // A v1 message has the wrong tag
+        let mut v1 = BufTransport::new(BytesMut::new());
+        crate::proto::v1::RPCMsg::from(RequestBuilder::new("hi").build().unwrap())
+            .to_buf_with(&mut v1.buffer, false)
+            .unwrap();
+        assert!(matches!(
+            BufTransport::new(v1.buffer).read_request(),
+            Err(TransportError::Proto(ProtocolError::InvalidMessage))
+        ));
+    }
+
+    #[test]
+    fn strict_keys() {
+        let tagged = |items: Vec<(&str, Value)>| {
+            let map = Value::Map(items.into_iter().map(|(k, v)| (k.into(), v)).collect());
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(&value::tagged(super::TAG_ID_RPCV0, map), &mut bytes)
+                .unwrap();
+            BytesMut::from(&bytes[..])
+        };
+        let typo = tagged(vec![
+            ("fn", "add".into()),
+            ("fnn", "add".into()),
+            ("id", 1.into()),
+        ]);
+        let req = BufTransport::new(typo.clone()).read_request().unwrap();
+        assert_eq!(req, RequestBuilder::new("add").id(1u8).build().unwrap());
+        let mut strict = BufTransport::new(typo).with_strict(true);
+        assert!(matches!(
+            strict.read_request(),
+            Err(TransportError::Proto(ProtocolError::InvalidMessage))
+        ));
+
+        // A key from another kind of message doesn't belong either
+        let mixed = tagged(vec![
+            ("ok", 1.into()),
+            ("id", 1.into()),
+            ("deadline", 5.into()),
+        ]);
+        assert!(BufTransport::new(mixed.clone()).read_response().is_ok());
+        let mut strict = BufTransport::new(mixed).with_strict(true);
+        assert!(strict.read_response().is_err());
+
+        // Well-formed messages are fine
+        let mut strict = BufTransport::new(BytesMut::new()).with_strict(true);
+        let req = RequestBuilder::new("add").arg(1).id(2u8).build().unwrap();
+        strict.send_request(req.clone()).unwrap();
+        assert_eq!(strict.read_request().unwrap(), req);
+        let batch = Batch::new(vec![Response::ok(1, 2u8.into())]).unwrap();
+        strict.send_response_batch(batch.clone()).unwrap();
+        assert_eq!(strict.read_response_batch().unwrap(), batch);
+    }
+
+    #[test]
+    fn encoded_len() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        let req = RequestBuilder::new("count")
+            .param("blob", vec![0u8; 1000])
+            .id("me")
+            .build()
+            .unwrap();
+        tr.send_request_ref(&req).unwrap();
+        assert_eq!(req.encoded_len().unwrap(), tr.buffer.len());
+        assert_eq!(req.encoded_len().unwrap(), req.to_cbor_vec().unwrap().len());
+        assert_eq!(
+            super::RPCMsg::from(req).encoded_len().unwrap(),
+            tr.buffer.len()
+        );
+
+        tr.buffer.clear();
+        let resp = Response::err(ErrorValue::internal("no"), "me".into());
+        tr.send_response_ref(&resp).unwrap();
+        assert_eq!(resp.encoded_len().unwrap(), tr.buffer.len());
+    }
+
+    #[test]
+    fn cbor_vec() {
+        let req = RequestBuilder::new("vec").arg(1).id(2u8).build().unwrap();
+        let bytes = req.to_cbor_vec().unwrap();
+        // A 4-byte tag header, then the tag itself
+        assert_eq!(bytes[0], 0xda);
+        assert_eq!(&bytes[1..5], &(super::TAG_ID_RPCV0 as u32).to_be_bytes());
+        assert_eq!(Request::from_cbor_slice(&bytes).unwrap(), req);
+
+        let resp = Response::new(Ok("ok".into()), 2u8.into());
+        let bytes = resp.to_cbor_vec().unwrap();
+        assert_eq!(Response::from_cbor_slice(&bytes).unwrap(), resp);
+
+        // Exactly one message, please
+        assert!(Response::from_cbor_slice(&bytes[..bytes.len() - 1]).is_err());
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(matches!(
+            Response::from_cbor_slice(&extra),
+            Err(TransportError::Decode { pos: Some(p), .. }) if p == bytes.len()
+        ));
+    }
+
+    #[test]
+    fn try_from_slice() {
+        let req = RequestBuilder::new("slice").arg(1).id(2u8).build().unwrap();
+        let resp = Response::ok("ok", 2u8.into());
+        let req_bytes = req.to_cbor_vec().unwrap();
+        let resp_bytes = resp.to_cbor_vec().unwrap();
+        assert_eq!(Request::try_from(&req_bytes[..]).unwrap(), req);
+        assert_eq!(Response::try_from(&resp_bytes[..]).unwrap(), resp);
+        assert!(matches!(
+            Request::try_from(&resp_bytes[..]),
+            Err(TransportError::Proto(ProtocolError::UnexpectedMessage))
+        ));
+        assert!(matches!(
+            Response::try_from(&req_bytes[..]),
+            Err(TransportError::Proto(ProtocolError::UnexpectedMessage))
+        ));
+    }
+
+    #[test]
+    fn canonical() {
+        let opts = |a, b| Value::Map(vec![("zz".into(), a), ("a".into(), b)]);
+        let one = RequestBuilder::new("sign")
+            .param("opts", opts(1.into(), 2.into()))
+            .param("data", vec![1u8, 2])
+            .id(1u8)
+            .build()
+            .unwrap();
+        let two = RequestBuilder::new("sign")
+            .param("data", vec![1u8, 2])
+            .param("opts", opts(1.into(), 2.into()))
+            .id(1u8)
+            .build()
+            .unwrap();
+        let encode = |req: &Request, canonical| {
+            let mut tr = BufTransport::new(BytesMut::new()).with_canonical(canonical);
+            tr.send_request_ref(req).unwrap();
+            tr.buffer
+        };
+        assert_ne!(encode(&one, false), encode(&two, false));
+        let bytes = encode(&one, true);
+        assert_eq!(bytes, encode(&two, true));
+
+        // Integers are as short as they can be, and the envelope's keys are
+        // sorted too: "fn" and "id" encode shorter than "args".
+        let value: Value = ciborium::de::from_reader(&bytes[..]).unwrap();
+        let keys: Vec<_> = match &value {
+            Value::Tag(_, map) => map
+                .as_map()
+                .unwrap()
+                .iter()
+                .map(|(k, _)| k.clone())
+                .collect(),
+            v => panic!("untagged message: {:?}", v),
+        };
+        assert_eq!(keys, vec!["fn".into(), "id".into(), "args".into()]);
+        assert_eq!(
+            encode(&Request::new("x", None, Some(23u8.into())), true).len(),
+            encode(&Request::new("x", None, Some(23u64.into())), true).len()
+        );
+
+        // It's still an ordinary message, with the params in sorted order
+        let mut tr = BufTransport::new(bytes);
+        let read = tr.read_request().unwrap();
+        assert_eq!(
+            read.params().as_ref().unwrap().get(0),
+            one.params().as_ref().unwrap().get(1)
+        );
+
+        // A map with the same key twice goes out with both items
+        let twice = Value::Map(vec![("k".into(), 1.into()), ("k".into(), 2.into())]);
+        let req = RequestBuilder::new("f").arg(twice).id(1u8).build().unwrap();
+        let mut tr = BufTransport::new(encode(&req, true));
+        assert_eq!(tr.read_request().unwrap(), req);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        tr.send_cancel(3u8.into()).unwrap();
+        let mut expected = Vec::new();
+        let cancel = Value::Map(vec![("cancel".into(), 3.into())]);
+        ciborium::ser::into_writer(
+            &Value::Tag(super::TAG_ID_RPCV0, Box::new(cancel)),
+            &mut expected,
+        )
+        .unwrap();
+        assert_eq!(&tr.buffer[..], &expected[..]);
+
+        tr.send_cancel(4u8.into()).unwrap();
+        assert_eq!(
+            tr.read_incoming().unwrap(),
+            Incoming::Control(Control::Cancel(3u8.into()))
+        );
+        assert!(matches!(
+            tr.read_request(),
+            Err(TransportError::Proto(ProtocolError::UnexpectedMessage))
+        ));
+    }
+
+    #[test]
+    fn deadline() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        let req = RequestBuilder::new("slow").id(1u8).build().unwrap();
+        assert_eq!(req.deadline(), None);
+        let late = req.clone().with_deadline(1_700_000_000_000);
+        assert_eq!(late.deadline(), Some(1_700_000_000_000));
+        for r in [&req, &late] {
+            tr.send_request_ref(r).unwrap();
+            assert_eq!(&tr.read_request().unwrap(), r);
+        }
+
+        // It's just another item in the map, which decoders that don't know
+        // about deadlines skip over.
+        let bytes = late.to_cbor_vec().unwrap();
+        let value: Value = ciborium::de::from_reader(&bytes[..]).unwrap();
+        let map = match value {
+            Value::Tag(_, map) => map.into_map().unwrap(),
+            v => panic!("untagged message: {:?}", v),
+        };
+        assert_eq!(map.len(), 3);
+        assert!(map.contains(&("deadline".into(), 1_700_000_000_000u64.into())));
+    }
+}
+
+// These run without serde1 or std, so they can't use the transports.
+#[cfg(test)]
+mod codec_tests {
+    use super::{
+        decode_control, decode_request, decode_response, encode_control, encode_map, peek_kind,
+        MessageKind,
+    };
+    use crate::alloc_prelude::*;
+    use crate::error::ProtocolError;
+    use crate::proto::{Control, ErrorValue, Params, Request, RequestBuilder, Response, Value};
+
+    #[test]
+    fn encode_request() {
+        let mut req = Request {
+            method: "hello".into(),
+            params: Some(Params::Array(vec!["one".into(), 2.into(), "three".into()])),
+            req_id: Some(42u32.into()),
+            deadline: Some(1_700_000_000_000),
+        };
+        let bytes = super::encode_request(&req);
+        #[cfg(all(feature = "serde1", feature = "std"))]
+        assert_eq!(bytes, req.to_cbor_vec().unwrap());
+        assert_eq!(decode_request(&bytes).unwrap(), req);
+        req.params = None;
+        req.deadline = None;
+        assert_eq!(decode_request(&super::encode_request(&req)).unwrap(), req);
+        req.req_id = None;
+        assert_eq!(decode_request(&super::encode_request(&req)).unwrap(), req);
+    }
+
+    #[test]
+    fn encode_response() {
+        let when = Value::Tag(1, Box::new(1_700_000_000.into()));
+        let teapot = ErrorValue::new(418, "I'm a teapot").with_data(vec![1, 2, 3]);
+        for resp in [
+            Response::ok("yay", 42u32.into()),
+            Response::partial(when, 42u32.into()),
+            Response::err(teapot, "x".into()),
+        ] {
+            let bytes = super::encode_response(&resp);
+            assert_eq!(decode_response(&bytes).unwrap(), resp);
+            // The serde encoding uses an indefinite-length map, but each side
+            // can read the other's.
+            #[cfg(all(feature = "serde1", feature = "std"))]
+            {
+                assert_eq!(Response::from_cbor_slice(&bytes).unwrap(), resp);
+                let serde_bytes = resp.to_cbor_vec().unwrap();
+                assert_eq!(decode_response(&serde_bytes).unwrap(), resp);
+            }
+        }
+    }
+
+    #[test]
+    fn peek() {
+        let req = RequestBuilder::new("big")
+            .arg(vec![0u8; 1000])
+            .id(1u8)
+            .build()
+            .unwrap();
+        let bytes = super::encode_request(&req);
+        // The start of the message is enough
+        assert_eq!(peek_kind(&bytes[..12]).unwrap(), MessageKind::Request);
+        let bytes = super::encode_response(&Response::ok(vec![0u8; 1000], 1u8.into()));
+        assert_eq!(peek_kind(&bytes).unwrap(), MessageKind::Response);
+        let bytes = encode_control(&Control::Cancel(1u8.into()));
+        assert_eq!(peek_kind(&bytes).unwrap(), MessageKind::Control);
+        #[cfg(all(feature = "serde1", feature = "std"))]
+        {
+            // The serde encoding starts a Response with an indefinite map
+            let bytes = Response::ok(1, 1u8.into()).to_cbor_vec().unwrap();
+            assert_eq!(peek_kind(&bytes).unwrap(), MessageKind::Response);
+            let bytes = req.to_cbor_vec().unwrap();
+            assert_eq!(peek_kind(&bytes).unwrap(), MessageKind::Request);
+        }
+
+        // A Response with its "id" first has to be decoded to tell
+        let error = ErrorValue::internal("x").into();
+        let bytes = encode_map(vec![("id", 1.into()), ("err", error)]);
+        assert_eq!(peek_kind(&bytes).unwrap(), MessageKind::Response);
+        // Not an RPC message at all
+        assert!(matches!(
+            peek_kind(&bytes[5..]),
+            Err(ProtocolError::InvalidMessage)
+        ));
+        assert!(matches!(peek_kind(&[]), Err(ProtocolError::InvalidMessage)));
+    }
+
+    #[test]
+    fn wrong_message() {
+        let cancel = Control::Cancel(7u8.into());
+        let bytes = encode_control(&cancel);
+        assert_eq!(decode_control(&bytes).unwrap(), cancel);
+        assert!(matches!(
+            decode_request(&bytes),
+            Err(ProtocolError::UnexpectedMessage)
+        ));
+        let bytes = super::encode_response(&Response::ok(Value::Null, 7u8.into()));
+        assert!(matches!(
+            decode_request(&bytes),
+            Err(ProtocolError::UnexpectedMessage)
+        ));
+        // Trailing bytes, or no tag at all
+        let mut long = bytes.clone();
+        long.push(0);
+        assert!(decode_response(&long).is_err());
+        assert!(decode_response(&bytes[2..]).is_err());
     }
 }
@@ -0,0 +1,664 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Experimental/draft version 1 of the ciborium-rpc protocol.
+//!
+//! v1 carries the same information as [v0](super::v0), but it identifies the
+//! parts of each message with small integer keys rather than Text keys, which
+//! makes every message a few bytes smaller. The v1 message format works like
+//! this:
+//!
+//! 1. Every RPC message is tagged with a magic number ([TAG_ID_RPCV1]) that
+//!    identifies it as a ciborium-rpc v1 message.
+//!
+//! 2. A Request is a Map with the following keys and values:
+//!    ```text
+//!    {0: MethodID, 1: Params, 2: RequestID, 7: u64}
+//!    ```
+//!    The `1` (params), `2` (id), and `7` (deadline) items may be omitted.
+//!    The deadline is in milliseconds since the Unix epoch.
+//!
+//! 3. A Response is a Map with one of three forms:
+//!    ```text
+//!    {3: Value, 2: RequestID}
+//!    ```
+//!    ```text
+//!    {4: ErrorValue, 2: RequestID}
+//!    ```
+//!    ```text
+//!    {5: Value, 2: RequestID}
+//!    ```
+//!    The `2` (id) item MUST be present. The last form is a partial result,
+//!    which may be followed by more partial results before the final one.
+//!
+//! 4. An ErrorValue is a Map with the form:
+//!    ```text
+//!    {0: i64, 1: String, 2: Value}
+//!    ```
+//!    which hold the `code`, `message`, and `data`. `data` may be omitted.
+//!
+//! 5. A Batch is a CBOR Array of one or more tagged Requests (or Responses).
+//!
+//! 6. A Control message is a Map with one item. A cancel looks like:
+//!    ```text
+//!    {6: RequestID}
+//!    ```
+//!
+//! Decoders ignore any keys they don't recognize. There's no strict mode
+//! like v0's, so [V1] ignores a wrapped transport's `strict` setting.
+
+use core::convert::{TryFrom, TryInto};
+use core::fmt;
+use serde::de::{self, IgnoredAny, MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::tag::RPCTag;
+use super::{Batch, Control, ErrorValue, Notification, Request, Response, ResponseKind, Value};
+use crate::alloc_prelude::*;
+use crate::error::ProtocolError;
+#[cfg(feature = "std")]
+use crate::error::TransportError;
+#[cfg(feature = "std")]
+use crate::proto::{ReadCbor, WriteCbor};
+#[cfg(feature = "std")]
+use crate::trace;
+#[cfg(feature = "std")]
+use crate::transport::simple::{ClientTransport, Incoming, ServerTransport};
+#[cfg(feature = "std")]
+use crate::transport::{Buf, BufMut, BufTransport, Read, Transport, Write};
+
+/// Magic number / tag ID to identify RPC V1 requests
+pub const TAG_ID_RPCV1: u64 = 4036988078;
+
+// Keys for Request/Response maps
+const KEY_METHOD: u64 = 0;
+const KEY_PARAMS: u64 = 1;
+const KEY_ID: u64 = 2;
+const KEY_OK: u64 = 3;
+const KEY_ERR: u64 = 4;
+const KEY_MORE: u64 = 5;
+const KEY_CANCEL: u64 = 6;
+const KEY_DEADLINE: u64 = 7;
+
+// Keys for ErrorValue maps
+const KEY_CODE: u64 = 0;
+const KEY_MESSAGE: u64 = 1;
+const KEY_DATA: u64 = 2;
+
+// ----- RPC format / framing -------------------------------------------------
+
+/// RPCMsg is the toplevel type for this version of the protocol.
+///
+/// Every RPC message is tagged with CBOR tag [TAG_ID_RPCV1] so we can identify
+/// it as an RPC message. It then contains either a Request or a Response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RPCMsg(RPCTag<Msg, TAG_ID_RPCV1>);
+
+/// RPCBatch is a CBOR Array of RPCMsg items, each with its own tag.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RPCBatch(Vec<RPCMsg>);
+
+/// The Msg enum encapsulates all well-formatted RPC message contents.
+#[derive(Debug, Clone, PartialEq)]
+enum Msg {
+    Request(Request),
+    Response(Response),
+    Control(Control),
+}
+
+/// A borrowed RPCMsg, so Requests and Responses can be sent without giving
+/// them up. Only the transports use these.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct RPCMsgRef<'a>(RPCTag<MsgRef<'a>, TAG_ID_RPCV1>);
+
+#[derive(Debug, Clone, Copy)]
+enum MsgRef<'a> {
+    Request(&'a Request),
+    Response(&'a Response),
+    Control(&'a Control),
+}
+
+impl Serialize for Msg {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Msg::Request(r) => MsgRef::Request(r).serialize(serializer),
+            Msg::Response(r) => MsgRef::Response(r).serialize(serializer),
+            Msg::Control(c) => MsgRef::Control(c).serialize(serializer),
+        }
+    }
+}
+
+impl Serialize for MsgRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            MsgRef::Request(r) => {
+                let len = 1
+                    + r.params.is_some() as usize
+                    + r.req_id.is_some() as usize
+                    + r.deadline.is_some() as usize;
+                let mut map = serializer.serialize_map(Some(len))?;
+                map.serialize_entry(&KEY_METHOD, &r.method)?;
+                if let Some(params) = &r.params {
+                    map.serialize_entry(&KEY_PARAMS, params)?;
+                }
+                if let Some(req_id) = &r.req_id {
+                    map.serialize_entry(&KEY_ID, req_id)?;
+                }
+                if let Some(deadline) = &r.deadline {
+                    map.serialize_entry(&KEY_DEADLINE, deadline)?;
+                }
+                map.end()
+            }
+            MsgRef::Response(r) => {
+                let mut map = serializer.serialize_map(Some(2))?;
+                match &r.result {
+                    Ok(value) if r.is_partial() => map.serialize_entry(&KEY_MORE, value)?,
+                    Ok(value) => map.serialize_entry(&KEY_OK, value)?,
+                    Err(error) => map.serialize_entry(&KEY_ERR, &ErrorRef(error))?,
+                }
+                map.serialize_entry(&KEY_ID, &r.req_id)?;
+                map.end()
+            }
+            MsgRef::Control(Control::Cancel(req_id)) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(&KEY_CANCEL, req_id)?;
+                map.end()
+            }
+        }
+    }
+}
+
+// We decode the parts of the message as plain Values and then use the usual
+// TryFrom<Value> conversions, rather than relying on the untagged enums.
+fn from_value<T, E>(value: Value) -> Result<T, E>
+where
+    T: TryFrom<Value, Error = ProtocolError>,
+    E: de::Error,
+{
+    T::try_from(value).map_err(E::custom)
+}
+
+impl<'de> Deserialize<'de> for Msg {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MsgVisitor;
+
+        impl<'de> Visitor<'de> for MsgVisitor {
+            type Value = Msg;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a v1 Request, Response, or Control map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Msg, A::Error> {
+                let mut method = None;
+                let mut params = None;
+                let mut req_id = None;
+                let mut deadline = None;
+                let mut result = None;
+                let mut cancel = None;
+                while let Some(key) = map.next_key::<u64>()? {
+                    match key {
+                        KEY_METHOD => method = Some(from_value(map.next_value()?)?),
+                        KEY_PARAMS => params = Some(from_value(map.next_value()?)?),
+                        KEY_ID => req_id = Some(from_value(map.next_value()?)?),
+                        KEY_DEADLINE => deadline = Some(map.next_value()?),
+                        KEY_OK => result = Some((Ok(map.next_value()?), ResponseKind::Final)),
+                        KEY_MORE => result = Some((Ok(map.next_value()?), ResponseKind::Partial)),
+                        KEY_ERR => {
+                            let error = map.next_value::<ErrorMsg>()?.0;
+                            result = Some((Err(error), ResponseKind::Final))
+                        }
+                        KEY_CANCEL => cancel = Some(from_value(map.next_value()?)?),
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                match (method, result, req_id, cancel) {
+                    (Some(method), None, req_id, None) => Ok(Msg::Request(Request {
+                        method,
+                        params,
+                        req_id,
+                        deadline,
+                    })),
+                    (None, Some((result, kind)), Some(req_id), None) => {
+                        Ok(Msg::Response(Response {
+                            result,
+                            req_id,
+                            kind,
+                        }))
+                    }
+                    (None, None, None, Some(req_id)) => Ok(Msg::Control(Control::Cancel(req_id))),
+                    (None, Some(_), None, None) => Err(de::Error::missing_field("id")),
+                    _ => Err(de::Error::custom("not a v1 Request, Response, or Control")),
+                }
+            }
+        }
+
+        deserializer.deserialize_map(MsgVisitor)
+    }
+}
+
+/// Serializes an ErrorValue as a v1 map.
+struct ErrorRef<'a>(&'a ErrorValue);
+
+impl Serialize for ErrorRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let e = self.0;
+        let mut map = serializer.serialize_map(Some(2 + e.data.is_some() as usize))?;
+        map.serialize_entry(&KEY_CODE, &e.code)?;
+        map.serialize_entry(&KEY_MESSAGE, &e.message)?;
+        if let Some(data) = &e.data {
+            map.serialize_entry(&KEY_DATA, data)?;
+        }
+        map.end()
+    }
+}
+
+/// Deserializes an ErrorValue from a v1 map.
+struct ErrorMsg(ErrorValue);
+
+impl<'de> Deserialize<'de> for ErrorMsg {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ErrorVisitor;
+
+        impl<'de> Visitor<'de> for ErrorVisitor {
+            type Value = ErrorMsg;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a v1 ErrorValue map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<ErrorMsg, A::Error> {
+                let mut code = None;
+                let mut message = None;
+                let mut data = None;
+                while let Some(key) = map.next_key::<u64>()? {
+                    match key {
+                        KEY_CODE => code = Some(map.next_value()?),
+                        KEY_MESSAGE => message = Some(map.next_value()?),
+                        KEY_DATA => data = Some(map.next_value()?),
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(ErrorMsg(ErrorValue {
+                    code: code.ok_or_else(|| de::Error::missing_field("code"))?,
+                    message: message.ok_or_else(|| de::Error::missing_field("message"))?,
+                    data,
+                }))
+            }
+        }
+
+        deserializer.deserialize_map(ErrorVisitor)
+    }
+}
+
+// For encode errors: the part of a message that each key holds.
+#[cfg(feature = "std")]
+fn field_name(key: &Value) -> Option<&'static str> {
+    match key.as_integer().and_then(|i| u64::try_from(i).ok())? {
+        KEY_METHOD => Some("method"),
+        KEY_PARAMS => Some("params"),
+        KEY_ID => Some("id"),
+        KEY_DEADLINE => Some("deadline"),
+        KEY_OK | KEY_MORE => Some("result"),
+        KEY_ERR => Some("error"),
+        KEY_CANCEL => Some("cancel"),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "std")]
+impl_cbor_io!(RPCMsg, RPCBatch);
+#[cfg(feature = "std")]
+impl_cbor_io!(@write RPCMsgRef<'_>);
+
+// ----- Conversions to/from RPCMsg -------------------------------------------
+
+impl From<Request> for RPCMsg {
+    fn from(r: Request) -> Self {
+        RPCMsg(RPCTag(Msg::Request(r)))
+    }
+}
+
+impl From<Response> for RPCMsg {
+    fn from(r: Response) -> Self {
+        RPCMsg(RPCTag(Msg::Response(r)))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> From<&'a Request> for RPCMsgRef<'a> {
+    fn from(r: &'a Request) -> Self {
+        RPCMsgRef(RPCTag(MsgRef::Request(r)))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> From<&'a Response> for RPCMsgRef<'a> {
+    fn from(r: &'a Response) -> Self {
+        RPCMsgRef(RPCTag(MsgRef::Response(r)))
+    }
+}
+
+impl From<Control> for RPCMsg {
+    fn from(c: Control) -> Self {
+        RPCMsg(RPCTag(Msg::Control(c)))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> From<&'a Control> for RPCMsgRef<'a> {
+    fn from(c: &'a Control) -> Self {
+        RPCMsgRef(RPCTag(MsgRef::Control(c)))
+    }
+}
+
+impl From<Notification> for RPCMsg {
+    fn from(n: Notification) -> Self {
+        Request::from(n).into()
+    }
+}
+
+impl TryFrom<RPCMsg> for Request {
+    type Error = ProtocolError;
+    fn try_from(msg: RPCMsg) -> Result<Self, Self::Error> {
+        match msg.0 .0 {
+            Msg::Request(r) => Ok(r),
+            Msg::Response(_) | Msg::Control(_) => Err(ProtocolError::UnexpectedMessage),
+        }
+    }
+}
+
+impl TryFrom<RPCMsg> for Response {
+    type Error = ProtocolError;
+    fn try_from(msg: RPCMsg) -> Result<Self, Self::Error> {
+        match msg.0 .0 {
+            Msg::Request(_) | Msg::Control(_) => Err(ProtocolError::UnexpectedMessage),
+            Msg::Response(r) => Ok(r),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl TryFrom<RPCMsg> for Incoming {
+    type Error = ProtocolError;
+    fn try_from(msg: RPCMsg) -> Result<Self, Self::Error> {
+        match msg.0 .0 {
+            Msg::Request(r) => Ok(r.into()),
+            Msg::Control(c) => Ok(Incoming::Control(c)),
+            Msg::Response(_) => Err(ProtocolError::UnexpectedMessage),
+        }
+    }
+}
+
+impl TryFrom<RPCMsg> for Notification {
+    type Error = ProtocolError;
+    fn try_from(msg: RPCMsg) -> Result<Self, Self::Error> {
+        Request::try_from(msg)?.try_into()
+    }
+}
+
+impl<T: Into<RPCMsg>> From<Batch<T>> for RPCBatch {
+    fn from(b: Batch<T>) -> Self {
+        RPCBatch(b.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T> TryFrom<RPCBatch> for Batch<T>
+where
+    T: TryFrom<RPCMsg, Error = ProtocolError>,
+{
+    type Error = ProtocolError;
+    fn try_from(batch: RPCBatch) -> Result<Self, Self::Error> {
+        Batch::new(
+            batch
+                .0
+                .into_iter()
+                .map(T::try_from)
+                .collect::<Result<_, _>>()?,
+        )
+    }
+}
+
+// ----- Transports -----------------------------------------------------------
+
+/// Transport and BufTransport speak v0 by default; wrap one in V1 to make it
+/// send and receive v1 messages instead.
+pub struct V1<T>(pub T);
+
+impl<T> V1<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C: Read + Write> ClientTransport for V1<Transport<C>> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        trace::read_response(|| Ok(self.0.read_limited(RPCMsg::from_reader)?.try_into()?))
+    }
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        trace::send_request(request, || {
+            RPCMsgRef::from(request).to_writer_with(&mut self.0.channel, self.0.canonical)
+        })
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        RPCMsgRef::from(control).to_writer_with(&mut self.0.channel, self.0.canonical)
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        Ok(self.0.read_limited(RPCBatch::from_reader)?.try_into()?)
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        RPCBatch::from(batch).to_writer_with(&mut self.0.channel, self.0.canonical)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C: Read + Write> ServerTransport for V1<Transport<C>> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_request(&mut self) -> Result<Request, Self::Error> {
+        trace::read_request(|| Ok(self.0.read_limited(RPCMsg::from_reader)?.try_into()?))
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        trace::read_incoming(|| Ok(self.0.read_limited(RPCMsg::from_reader)?.try_into()?))
+    }
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        trace::send_response(response, || {
+            RPCMsgRef::from(response).to_writer_with(&mut self.0.channel, self.0.canonical)
+        })
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error> {
+        Ok(self.0.read_limited(RPCBatch::from_reader)?.try_into()?)
+    }
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        RPCBatch::from(batch).to_writer_with(&mut self.0.channel, self.0.canonical)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<B: Buf + BufMut> ClientTransport for V1<BufTransport<B>> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        trace::read_response(|| {
+            Ok(self
+                .0
+                .read_limited(|r| RPCMsg::from_reader(r))?
+                .try_into()?)
+        })
+    }
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        trace::send_request(request, || {
+            RPCMsgRef::from(request).to_buf_with(&mut self.0.buffer, self.0.canonical)
+        })
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        RPCMsgRef::from(control).to_buf_with(&mut self.0.buffer, self.0.canonical)
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        Ok(self
+            .0
+            .read_limited(|r| RPCBatch::from_reader(r))?
+            .try_into()?)
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        RPCBatch::from(batch).to_buf_with(&mut self.0.buffer, self.0.canonical)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<B: Buf + BufMut> ServerTransport for V1<BufTransport<B>> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_request(&mut self) -> Result<Request, Self::Error> {
+        trace::read_request(|| {
+            Ok(self
+                .0
+                .read_limited(|r| RPCMsg::from_reader(r))?
+                .try_into()?)
+        })
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        trace::read_incoming(|| {
+            Ok(self
+                .0
+                .read_limited(|r| RPCMsg::from_reader(r))?
+                .try_into()?)
+        })
+    }
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        trace::send_response(response, || {
+            RPCMsgRef::from(response).to_buf_with(&mut self.0.buffer, self.0.canonical)
+        })
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error> {
+        Ok(self
+            .0
+            .read_limited(|r| RPCBatch::from_reader(r))?
+            .try_into()?)
+    }
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        RPCBatch::from(batch).to_buf_with(&mut self.0.buffer, self.0.canonical)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::{TAG_ID_RPCV1, V1};
+    use crate::proto::{
+        value, Batch, Control, ErrorValue, Notification, Params, RequestBuilder, Response, Value,
+    };
+    use crate::transport::simple::{ClientTransport, Incoming, ServerTransport};
+    use crate::transport::BufTransport;
+    use bytes::BytesMut;
+    use core::convert::TryFrom;
+
+    #[test]
+    fn tagged_params() {
+        let mut tr = V1(BufTransport::new(BytesMut::new()));
+        let when = value::tagged(1, 1_700_000_000);
+        let req = RequestBuilder::new(1u8)
+            .param("when", when.clone())
+            .id(1u8)
+            .build()
+            .unwrap();
+        tr.send_request(req.clone()).unwrap();
+        assert_eq!(tr.read_request().unwrap(), req);
+        let resp = Response::ok(when, 1u8.into());
+        tr.send_response(resp.clone()).unwrap();
+        assert_eq!(tr.read_response().unwrap(), resp);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut tr = V1(BufTransport::new(BytesMut::with_capacity(4096)));
+        let req = RequestBuilder::new("hello")
+            .param("one", 1)
+            .param("two", "2")
+            .id(42u8)
+            .build()
+            .unwrap();
+        tr.send_request(req.clone()).unwrap();
+        assert_eq!(req, tr.read_request().unwrap());
+        let req = req.with_deadline(1_700_000_000_000);
+        tr.send_request(req.clone()).unwrap();
+        assert_eq!(req, tr.read_request().unwrap());
+
+        let note = Notification::new(3u8, None);
+        tr.send_notification(note.clone()).unwrap();
+        assert_eq!(
+            note,
+            Notification::try_from(tr.read_request().unwrap()).unwrap()
+        );
+
+        let ok = Response::new(Ok("yay".into()), 42u8.into());
+        let err = Response::new(
+            Err(ErrorValue::new(418, "I'm a teapot").with_data(vec![1, 2])),
+            "x".into(),
+        );
+        let partial = Response::partial(7.into(), 42u8.into());
+        let batch = Batch::new(vec![partial, ok, err]).unwrap();
+        tr.send_response_batch(batch.clone()).unwrap();
+        assert_eq!(batch, tr.read_response_batch().unwrap());
+
+        tr.send_cancel(42u8.into()).unwrap();
+        assert_eq!(
+            tr.read_incoming().unwrap(),
+            Incoming::Control(Control::Cancel(42u8.into()))
+        );
+        assert!(tr.0.buffer.is_empty());
+    }
+
+    #[test]
+    fn smaller_than_v0() {
+        let req = RequestBuilder::new("hello")
+            .arg("one")
+            .arg(2)
+            .arg("three")
+            .id(42u32)
+            .build()
+            .unwrap();
+        let resp = Response::new(Ok(Params::Array(vec![Value::Null]).into()), 42u32.into());
+
+        let mut v0 = BufTransport::new(BytesMut::with_capacity(4096));
+        let mut v1 = V1(BufTransport::new(BytesMut::with_capacity(4096)));
+        v0.send_request(req.clone()).unwrap();
+        v1.send_request(req).unwrap();
+        // The tag, then {0: "hello", 1: ["one", 2, "three"], 2: 42}
+        let mut expected = vec![0xda];
+        expected.extend_from_slice(&(TAG_ID_RPCV1 as u32).to_be_bytes());
+        expected.extend_from_slice(b"\xa3\x00\x65hello\x01\x83\x63one\x02\x65three\x02\x18\x2a");
+        assert_eq!(&v1.0.buffer[..], &expected[..]);
+        assert!(v1.0.buffer.len() < v0.buffer.len());
+
+        v0.buffer.clear();
+        v1.0.buffer.clear();
+        v0.send_response(resp.clone()).unwrap();
+        v1.send_response(resp).unwrap();
+        // The tag, then {3: [null], 2: 42}
+        let mut expected = vec![0xda];
+        expected.extend_from_slice(&(TAG_ID_RPCV1 as u32).to_be_bytes());
+        expected.extend_from_slice(b"\xa2\x03\x81\xf6\x02\x18\x2a");
+        assert_eq!(&v1.0.buffer[..], &expected[..]);
+        assert!(v1.0.buffer.len() < v0.buffer.len());
+    }
+}
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Shortcuts for putting together and picking apart a [Value].
+//!
+//! Each `as_` function returns None if the Value isn't the expected type (or,
+//! for [as_i64], if the integer doesn't fit).
+
+use super::Value;
+use crate::alloc_prelude::*;
+use core::convert::TryFrom;
+#[cfg(feature = "std")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The CBOR tag for a timestamp, as seconds since the Unix epoch (RFC 8949,
+/// section 3.4.2).
+pub const TAG_EPOCH_TIME: u64 = 1;
+
+pub fn as_i64(value: &Value) -> Option<i64> {
+    match value {
+        Value::Integer(i) => i64::try_from(*i).ok(),
+        _ => None,
+    }
+}
+
+pub fn as_bool(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(b) => Some(*b),
+        _ => None,
+    }
+}
+
+pub fn as_str(value: &Value) -> Option<&str> {
+    match value {
+        Value::Text(s) => Some(s),
+        _ => None,
+    }
+}
+
+pub fn as_bytes(value: &Value) -> Option<&[u8]> {
+    match value {
+        Value::Bytes(b) => Some(b),
+        _ => None,
+    }
+}
+
+pub fn as_array(value: &Value) -> Option<&[Value]> {
+    match value {
+        Value::Array(a) => Some(a),
+        _ => None,
+    }
+}
+
+pub fn as_map(value: &Value) -> Option<&[(Value, Value)]> {
+    match value {
+        Value::Map(m) => Some(m),
+        _ => None,
+    }
+}
+
+/// Wrap `value` in a CBOR tag, like tag 1 for an epoch timestamp. Tags are
+/// kept as-is when the Value is sent and read back.
+pub fn tagged(tag: u64, value: impl Into<Value>) -> Value {
+    Value::Tag(tag, Box::new(value.into()))
+}
+
+/// The tag and the tagged Value.
+pub fn as_tagged(value: &Value) -> Option<(u64, &Value)> {
+    match value {
+        Value::Tag(tag, v) => Some((*tag, v)),
+        _ => None,
+    }
+}
+
+/// A tag 1 timestamp for `t`: whole seconds are written as an integer, and
+/// anything finer as a float.
+#[cfg(feature = "std")]
+pub fn timestamp(t: SystemTime) -> Value {
+    let (d, sign) = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d, 1i32),
+        Err(e) => (e.duration(), -1),
+    };
+    match i64::try_from(d.as_secs()) {
+        Ok(secs) if d.subsec_nanos() == 0 => tagged(TAG_EPOCH_TIME, i64::from(sign) * secs),
+        _ => tagged(TAG_EPOCH_TIME, f64::from(sign) * d.as_secs_f64()),
+    }
+}
+
+/// The time held by a tag 1 timestamp, from either an integer or a float.
+#[cfg(feature = "std")]
+pub fn as_timestamp(value: &Value) -> Option<SystemTime> {
+    match as_tagged(value)? {
+        (TAG_EPOCH_TIME, Value::Integer(i)) => {
+            let secs = i128::from(*i);
+            let d = Duration::from_secs(u64::try_from(secs.unsigned_abs()).ok()?);
+            from_epoch(secs < 0, d)
+        }
+        (TAG_EPOCH_TIME, Value::Float(f)) => {
+            let d = Duration::try_from_secs_f64(f.abs()).ok()?;
+            from_epoch(*f < 0.0, d)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(feature = "std")]
+fn from_epoch(before: bool, d: Duration) -> Option<SystemTime> {
+    if before {
+        UNIX_EPOCH.checked_sub(d)
+    } else {
+        UNIX_EPOCH.checked_add(d)
+    }
+}
+
+/// Put every map in `value`, however deeply nested, into the order used by
+/// CBOR's deterministic encoding (RFC 8949, section 4.2.1): sorted by the
+/// bytes of each encoded key. Two Values that differ only in the order of
+/// their map items come out the same.
+#[cfg(all(feature = "serde1", feature = "std"))]
+pub fn canonical(value: Value) -> Value {
+    match value {
+        Value::Array(a) => Value::Array(a.into_iter().map(canonical).collect()),
+        Value::Tag(tag, v) => Value::Tag(tag, Box::new(canonical(*v))),
+        Value::Map(m) => {
+            let mut items: Vec<(Vec<u8>, (Value, Value))> = m
+                .into_iter()
+                .map(|(k, v)| {
+                    let k = canonical(k);
+                    let mut key_bytes = Vec::new();
+                    // Encoding a Value into a Vec can't fail
+                    ciborium::ser::into_writer(&k, &mut key_bytes).unwrap();
+                    (key_bytes, (k, canonical(v)))
+                })
+                .collect();
+            items.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Map(items.into_iter().map(|(_, item)| item).collect())
+        }
+        v => v,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extractors() {
+        let int = Value::from(-5);
+        let text = Value::from("hi");
+        let bytes = Value::from(vec![1u8, 2]);
+        let array = Value::Array(vec![int.clone()]);
+        let map = Value::Map(vec![(text.clone(), int.clone())]);
+
+        assert_eq!(as_i64(&int), Some(-5));
+        assert_eq!(as_i64(&Value::from(u64::MAX)), None);
+        assert_eq!(as_i64(&text), None);
+
+        assert_eq!(as_bool(&Value::Bool(true)), Some(true));
+        assert_eq!(as_bool(&Value::Null), None);
+
+        assert_eq!(as_str(&text), Some("hi"));
+        assert_eq!(as_str(&bytes), None);
+
+        assert_eq!(as_bytes(&bytes), Some(&[1u8, 2][..]));
+        assert_eq!(as_bytes(&text), None);
+
+        assert_eq!(as_array(&array), Some(&[int.clone()][..]));
+        assert_eq!(as_array(&map), None);
+
+        assert_eq!(as_map(&map), Some(&[(text, int)][..]));
+        assert_eq!(as_map(&array), None);
+
+        let tag = tagged(1, 1_700_000_000);
+        assert_eq!(as_tagged(&tag), Some((1, &Value::from(1_700_000_000))));
+        assert_eq!(as_tagged(&array), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn timestamps() {
+        use crate::proto::{v0, RequestBuilder};
+
+        let times = [
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            UNIX_EPOCH + Duration::from_millis(1_700_000_000_500),
+            UNIX_EPOCH - Duration::from_secs(86400),
+        ];
+        assert_eq!(timestamp(times[0]), tagged(1, 1_700_000_000));
+        assert_eq!(timestamp(times[1]), tagged(1, 1_700_000_000.5));
+        assert_eq!(timestamp(times[2]), tagged(1, -86400));
+        for t in times {
+            let req = RequestBuilder::new("at").arg(timestamp(t)).build().unwrap();
+            let req = v0::decode_request(&v0::encode_request(&req)).unwrap();
+            let arg = req.params().as_ref().unwrap().get(0).unwrap();
+            assert_eq!(as_timestamp(arg), Some(t));
+        }
+        assert_eq!(as_timestamp(&Value::from(1_700_000_000)), None);
+        assert_eq!(as_timestamp(&tagged(1, "yesterday")), None);
+        assert_eq!(as_timestamp(&tagged(1, f64::NAN)), None);
+    }
+
+    #[cfg(all(feature = "serde1", feature = "std"))]
+    #[test]
+    fn canonical_order() {
+        let inner = |a, b| Value::Map(vec![("b".into(), a), ("a".into(), b)]);
+        let v = Value::Map(vec![
+            ("aa".into(), 1.into()),
+            (10.into(), inner(1.into(), 2.into())),
+            ("b".into(), Value::Array(vec![inner(3.into(), 4.into())])),
+        ]);
+        let sorted = |a, b| Value::Map(vec![("a".into(), b), ("b".into(), a)]);
+        // Integers encode before text, and shorter text before longer
+        assert_eq!(
+            canonical(v),
+            Value::Map(vec![
+                (10.into(), sorted(1.into(), 2.into())),
+                ("b".into(), Value::Array(vec![sorted(3.into(), 4.into())])),
+                ("aa".into(), 1.into()),
+            ])
+        );
+    }
+}
@@ -0,0 +1,619 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A simple RPC server that dispatches Requests to registered handlers.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::proto::{ErrorValue, MethodID, Params, Response, Value};
+use crate::transport::simple::{Incoming, ServerTransport};
+
+/// A Handler takes the Server's context and the Params from a Request, and
+/// returns its result.
+pub type Handler<Ctx = ()> = Box<dyn Fn(&Ctx, Option<Params>) -> Result<Value, ErrorValue>>;
+
+/// The shape of the Params a method expects, so the [Server] can turn away
+/// calls that don't fit before they reach the handler.
+///
+/// A call must have between `min_args` and `max_args` arguments, positional
+/// or named; no Params at all counts as none. If `named_keys` is set, named
+/// arguments must each use one of those names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodSignature {
+    pub min_args: usize,
+    pub max_args: usize,
+    pub named_keys: Option<Vec<String>>,
+}
+
+impl MethodSignature {
+    pub fn new(min_args: usize, max_args: usize) -> Self {
+        Self {
+            min_args,
+            max_args,
+            named_keys: None,
+        }
+    }
+
+    /// Only accept named arguments with these names.
+    pub fn with_named_keys<S: Into<String>>(mut self, keys: impl IntoIterator<Item = S>) -> Self {
+        self.named_keys = Some(keys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Check `params` against the signature, giving an
+    /// [ErrorValue::invalid_params] that says what's wrong if they don't fit.
+    pub fn check(&self, params: Option<&Params>) -> Result<(), ErrorValue> {
+        let count = params.map_or(0, Params::len);
+        if count < self.min_args {
+            return Err(ErrorValue::invalid_params(format!(
+                "expected at least {} args, got {}",
+                self.min_args, count
+            )));
+        }
+        if count > self.max_args {
+            return Err(ErrorValue::invalid_params(format!(
+                "expected at most {} args, got {}",
+                self.max_args, count
+            )));
+        }
+        if let (Some(Params::Named(named)), Some(keys)) = (params, &self.named_keys) {
+            if let Some((key, _)) = named.iter().find(|(k, _)| !keys.contains(k)) {
+                return Err(ErrorValue::invalid_params(format!(
+                    "unexpected param: {}",
+                    key
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A MethodRegistry gives each method a small integer index, so that the
+/// tables behind it can be plain Vecs.
+///
+/// Matching an incoming MethodID - usually a string - costs one hash lookup
+/// in [index](Self::index); after that, everything about the method can be
+/// found by indexing, rather than by hashing and comparing the name again.
+/// Indexes count up from 0 in the order methods are registered.
+#[derive(Debug, Clone, Default)]
+pub struct MethodRegistry {
+    indexes: HashMap<MethodID, u32>,
+    methods: Vec<MethodID>,
+}
+
+impl MethodRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The index for `method`, giving it the next one if it's new.
+    pub fn register(&mut self, method: impl Into<MethodID>) -> u32 {
+        let method = method.into();
+        if let Some(&index) = self.indexes.get(&method) {
+            return index;
+        }
+        let index = self.methods.len() as u32;
+        self.indexes.insert(method.clone(), index);
+        self.methods.push(method);
+        index
+    }
+
+    /// The index for `method`, or None if it was never registered.
+    pub fn index(&self, method: &MethodID) -> Option<u32> {
+        self.indexes.get(method).copied()
+    }
+
+    /// The method with the given index.
+    pub fn method(&self, index: u32) -> Option<&MethodID> {
+        self.methods.get(index as usize)
+    }
+
+    pub fn len(&self) -> usize {
+        self.methods.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty()
+    }
+}
+
+/// A token bucket: it holds up to `rate` tokens, refills at `rate` tokens per
+/// second, and each call takes one.
+struct RateLimit {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    fn new(max_per_sec: u32) -> Self {
+        Self {
+            rate: max_per_sec.into(),
+            tokens: max_per_sec.into(),
+            last: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A Server holds a table of [Handler]s, keyed by [MethodID].
+///
+/// `Ctx` is whatever per-connection state the handlers need - the peer's
+/// address, say, or who they've authenticated as. The serve loop passes one
+/// to [serve_one_with](Self::serve_one_with) for each Request, and handlers
+/// registered with [register_with_context](Self::register_with_context) get
+/// a reference to it. Servers that don't need any just use `()`.
+///
+/// Methods are kept in a [MethodRegistry], so dispatching a call looks up its
+/// MethodID once and finds the handler, signature, and rate limit by index.
+pub struct Server<Ctx = ()> {
+    registry: MethodRegistry,
+    methods: Vec<Option<Handler<Ctx>>>,
+    limits: Vec<Option<RateLimit>>,
+    signatures: Vec<Option<MethodSignature>>,
+}
+
+impl<Ctx> Default for Server<Ctx> {
+    fn default() -> Self {
+        Self {
+            registry: MethodRegistry::new(),
+            methods: Vec::new(),
+            limits: Vec::new(),
+            signatures: Vec::new(),
+        }
+    }
+}
+
+impl<Ctx> Server<Ctx> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The index for `method`, with a slot in each table.
+    fn slot(&mut self, method: impl Into<MethodID>) -> usize {
+        let index = self.registry.register(method) as usize;
+        if index == self.methods.len() {
+            self.methods.push(None);
+            self.limits.push(None);
+            self.signatures.push(None);
+        }
+        index
+    }
+
+    /// Register `handler` as the implementation of `method`, replacing any
+    /// previously registered handler (and its signature, if it had one).
+    pub fn register<F>(&mut self, method: impl Into<MethodID>, handler: F)
+    where
+        F: Fn(Option<Params>) -> Result<Value, ErrorValue> + 'static,
+    {
+        self.register_with_context(method, move |_: &Ctx, params| handler(params));
+    }
+
+    /// Like [register](Self::register), for a handler whose errors are some
+    /// other [std::error::Error] - an [std::io::Error], say. They go back to
+    /// the client as [ErrorValue::from_error], with the generic server error
+    /// code, so a handler that picks its own codes should use `register`.
+    pub fn register_fallible<F, E>(&mut self, method: impl Into<MethodID>, handler: F)
+    where
+        F: Fn(Option<Params>) -> Result<Value, E> + 'static,
+        E: std::error::Error,
+    {
+        self.register(method, move |params| {
+            handler(params).map_err(|e| ErrorValue::from_error(&e))
+        });
+    }
+
+    /// Like [register](Self::register), for a handler that also wants the
+    /// context the Request was served with.
+    pub fn register_with_context<F>(&mut self, method: impl Into<MethodID>, handler: F)
+    where
+        F: Fn(&Ctx, Option<Params>) -> Result<Value, ErrorValue> + 'static,
+    {
+        let index = self.slot(method);
+        self.signatures[index] = None;
+        self.methods[index] = Some(Box::new(handler));
+    }
+
+    /// Like [register](Self::register), but calls whose Params don't match
+    /// `signature` get an [ErrorValue::invalid_params] instead of reaching
+    /// `handler`.
+    pub fn register_with_signature<F>(
+        &mut self,
+        method: impl Into<MethodID>,
+        signature: MethodSignature,
+        handler: F,
+    ) where
+        F: Fn(Option<Params>) -> Result<Value, ErrorValue> + 'static,
+    {
+        let method = method.into();
+        self.register(method.clone(), handler);
+        let index = self.slot(method);
+        self.signatures[index] = Some(signature);
+    }
+
+    /// Allow at most `max_per_sec` calls to `method` per second, with bursts
+    /// of up to that many at once. Calls over the limit - notifications
+    /// included - aren't dispatched, and get an [ErrorValue::rate_limited]
+    /// instead. This replaces any previous limit on `method`.
+    pub fn set_rate_limit(&mut self, method: impl Into<MethodID>, max_per_sec: u32) {
+        let index = self.slot(method);
+        self.limits[index] = Some(RateLimit::new(max_per_sec));
+    }
+
+    /// Remove the rate limit on `method`, if it had one.
+    pub fn clear_rate_limit(&mut self, method: &MethodID) {
+        if let Some(index) = self.registry.index(method) {
+            self.limits[index as usize] = None;
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        ctx: &Ctx,
+        method: &MethodID,
+        params: Option<Params>,
+    ) -> Result<Value, ErrorValue> {
+        let index = match self.registry.index(method) {
+            Some(index) => index as usize,
+            None => return Err(ErrorValue::method_not_found(method)),
+        };
+        if let Some(limit) = &mut self.limits[index] {
+            if !limit.take() {
+                return Err(ErrorValue::rate_limited(method));
+            }
+        }
+        if let Some(signature) = &self.signatures[index] {
+            signature.check(params.as_ref())?;
+        }
+        match &self.methods[index] {
+            Some(handler) => handler(ctx, params),
+            None => Err(ErrorValue::method_not_found(method)),
+        }
+    }
+
+    /// Read one Request from `transport`, dispatch it with `ctx`, and send
+    /// the Response.
+    ///
+    /// Notifications are dispatched like any other Request, but no Response
+    /// is sent - not even if the method wasn't found.
+    ///
+    /// Each Request runs to completion before the next one is read, so there's
+    /// never anything in flight to cancel; a [Control](crate::proto::Control)
+    /// message is read and ignored.
+    pub fn serve_one_with<T: ServerTransport>(
+        &mut self,
+        transport: &mut T,
+        ctx: &Ctx,
+    ) -> Result<(), T::Error> {
+        let request = match transport.read_incoming()? {
+            Incoming::Request(request) => request,
+            Incoming::Notification(notification) => notification.into(),
+            Incoming::Control(_) => return Ok(()),
+        };
+        let (method, params, req_id) = request.into_parts();
+        let result = self.dispatch(ctx, &method, params);
+        if let Some(req_id) = req_id {
+            transport.send_response(Response::new(result, req_id))?;
+        }
+        Ok(())
+    }
+}
+
+impl Server {
+    /// Read one Request from `transport`, dispatch it, and send the Response.
+    /// See [serve_one_with](Self::serve_one_with).
+    pub fn serve_one<T: ServerTransport>(&mut self, transport: &mut T) -> Result<(), T::Error> {
+        self.serve_one_with(transport, &())
+    }
+}
+
+#[cfg(all(test, feature = "serde1"))]
+mod tests {
+    use super::{MethodRegistry, MethodSignature, Server};
+    use crate::error::ProtocolError;
+    use crate::proto::{
+        ErrorValue, MethodID, Notification, Params, Request, RequestBuilder, Response, Value,
+        INVALID_PARAMS, METHOD_NOT_FOUND, RATE_LIMITED, SERVER_ERROR,
+    };
+    use crate::transport::simple::ClientTransport;
+    use crate::transport::BufTransport;
+    use bytes::BytesMut;
+    use serde::{Deserialize, Serialize};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    fn test_server() -> Server {
+        let mut server = Server::new();
+        server.register("len", |params: Option<Params>| {
+            Ok(Value::from(
+                params
+                    .map(|p| match p {
+                        Params::Array(a) => a.len(),
+                        Params::Named(n) => n.len(),
+                    })
+                    .unwrap_or(0) as u64,
+            ))
+        });
+        server
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut server = test_server();
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let req = RequestBuilder::new("len")
+            .arg(1)
+            .arg(2)
+            .id(5u8)
+            .build()
+            .unwrap();
+        tr.send_request(req).unwrap();
+        server.serve_one(&mut tr).unwrap();
+        let resp = tr.read_response().unwrap();
+        assert_eq!(resp.req_id(), &5u8.into());
+        assert_eq!(resp.result(), &Ok(Value::from(2)));
+    }
+
+    #[test]
+    fn method_not_found() {
+        let mut server = test_server();
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let req = RequestBuilder::new("nope").id(6u8).build().unwrap();
+        tr.send_request(req).unwrap();
+        server.serve_one(&mut tr).unwrap();
+        let resp = tr.read_response().unwrap();
+        assert_eq!(resp.req_id(), &6u8.into());
+        assert_eq!(
+            resp.result().as_ref().unwrap_err().code(),
+            &METHOD_NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn error_data() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Quota {
+            used: u64,
+            limit: u64,
+        }
+
+        let mut server = Server::new();
+        server.register("upload", |_| {
+            let quota = Quota {
+                used: 120,
+                limit: 100,
+            };
+            let data =
+                Value::serialized(&quota).map_err(|e| ErrorValue::internal(e.to_string()))?;
+            Err(ErrorValue::new(1, "over quota").with_data(data))
+        });
+        let mut tr = BufTransport::new(BytesMut::new());
+        let req = RequestBuilder::new("upload").id(1u8).build().unwrap();
+        tr.send_request(req).unwrap();
+        server.serve_one(&mut tr).unwrap();
+
+        let resp = tr.read_response().unwrap();
+        let error = resp.result().as_ref().unwrap_err();
+        let quota: Quota = error.data_as().unwrap().unwrap();
+        assert_eq!(
+            quota,
+            Quota {
+                used: 120,
+                limit: 100
+            }
+        );
+        assert!(matches!(
+            error.data_as::<String>(),
+            Some(Err(ProtocolError::InvalidDataType))
+        ));
+        assert!(ErrorValue::new(1, "plain").data_as::<Quota>().is_none());
+    }
+
+    #[test]
+    fn fallible() {
+        let mut server = Server::new();
+        server.register_fallible("read", |_| {
+            std::fs::read("/nonexistent/ciborium-rpc").map(Value::from)
+        });
+        let mut tr = BufTransport::new(BytesMut::new());
+        tr.send_request(RequestBuilder::new("read").id(1u8).build().unwrap())
+            .unwrap();
+        server.serve_one(&mut tr).unwrap();
+        let resp = tr.read_response().unwrap();
+        let error = resp.result().as_ref().unwrap_err();
+        assert_eq!(error.code(), &SERVER_ERROR);
+    }
+
+    #[test]
+    fn notification() {
+        let mut server = test_server();
+        let called = Rc::new(Cell::new(false));
+        let flag = called.clone();
+        server.register("ping", move |_| {
+            flag.set(true);
+            Ok(Value::Null)
+        });
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        tr.send_notification(Notification::new("ping", None))
+            .unwrap();
+        tr.send_notification(Notification::new("nope", None))
+            .unwrap();
+        server.serve_one(&mut tr).unwrap();
+        server.serve_one(&mut tr).unwrap();
+        assert!(called.get());
+        assert!(tr.buffer.is_empty());
+    }
+
+    #[test]
+    fn cancel_is_ignored() {
+        let mut server = test_server();
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        tr.send_cancel(7u8.into()).unwrap();
+        server.serve_one(&mut tr).unwrap();
+        assert!(tr.buffer.is_empty());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut server = test_server();
+        server.set_rate_limit("len", 3);
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        // A notification uses up one call, too
+        tr.send_notification(Notification::new("len", None))
+            .unwrap();
+        server.serve_one(&mut tr).unwrap();
+        let mut call = |id: u8| {
+            let req = RequestBuilder::new("len").id(id).build().unwrap();
+            tr.send_request(req).unwrap();
+            server.serve_one(&mut tr).unwrap();
+            tr.read_response().unwrap()
+        };
+        assert!(call(1).result().is_ok());
+        assert!(call(2).result().is_ok());
+        let resp = call(3);
+        assert_eq!(resp.req_id(), &3u8.into());
+        assert_eq!(resp.result().as_ref().unwrap_err().code(), &RATE_LIMITED);
+
+        // Other methods aren't affected
+        let req = RequestBuilder::new("nope").id(4u8).build().unwrap();
+        tr.send_request(req).unwrap();
+        server.serve_one(&mut tr).unwrap();
+        let resp = tr.read_response().unwrap();
+        assert_eq!(
+            resp.result().as_ref().unwrap_err().code(),
+            &METHOD_NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn signature() {
+        let mut server = test_server();
+        let sig = MethodSignature::new(1, 2).with_named_keys(["a", "b"]);
+        server.register_with_signature("pair", sig, |_| Ok(Value::Null));
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let mut call = |req: Request| -> Response {
+            tr.send_request(req).unwrap();
+            server.serve_one(&mut tr).unwrap();
+            tr.read_response().unwrap()
+        };
+        let message = |resp: Response| {
+            let err = resp.result().as_ref().unwrap_err();
+            assert_eq!(err.code(), &INVALID_PARAMS);
+            err.message().clone()
+        };
+
+        let ok = RequestBuilder::new("pair").arg(1).id(1u8).build().unwrap();
+        assert!(call(ok).result().is_ok());
+        let named = RequestBuilder::new("pair")
+            .param("b", 2)
+            .id(2u8)
+            .build()
+            .unwrap();
+        assert!(call(named).result().is_ok());
+
+        let too_few = RequestBuilder::new("pair").id(3u8).build().unwrap();
+        assert_eq!(message(call(too_few)), "expected at least 1 args, got 0");
+        let too_many = RequestBuilder::new("pair")
+            .arg(1)
+            .arg(2)
+            .arg(3)
+            .id(4u8)
+            .build()
+            .unwrap();
+        assert_eq!(message(call(too_many)), "expected at most 2 args, got 3");
+        let unexpected = RequestBuilder::new("pair")
+            .param("a", 1)
+            .param("c", 3)
+            .id(5u8)
+            .build()
+            .unwrap();
+        assert_eq!(message(call(unexpected)), "unexpected param: c");
+    }
+
+    #[test]
+    fn context() {
+        struct Session {
+            user: String,
+        }
+        let mut server = Server::new();
+        server.register_with_context("whoami", |session: &Session, _| {
+            Ok(session.user.as_str().into())
+        });
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        for user in ["alice", "bob"] {
+            let session = Session { user: user.into() };
+            let req = RequestBuilder::new("whoami").id(1u8).build().unwrap();
+            tr.send_request(req).unwrap();
+            server.serve_one_with(&mut tr, &session).unwrap();
+            let resp = tr.read_response().unwrap();
+            assert_eq!(resp.result(), &Ok(Value::from(user)));
+        }
+    }
+
+    #[test]
+    fn many_methods() {
+        let mut server = Server::new();
+        // A limit can come before the handler, and doesn't make it callable
+        server.set_rate_limit("add.0", 100);
+        let mut tr = BufTransport::new(BytesMut::new());
+        tr.send_request(RequestBuilder::new("add.0").id(0u8).build().unwrap())
+            .unwrap();
+        server.serve_one(&mut tr).unwrap();
+        let resp = tr.read_response().unwrap();
+        assert_eq!(
+            resp.result().as_ref().unwrap_err().code(),
+            &METHOD_NOT_FOUND
+        );
+
+        for n in 0..100u64 {
+            server.register(format!("add.{}", n), move |_| Ok(Value::from(n)));
+        }
+        for n in [0u8, 42, 99] {
+            let method = format!("add.{}", n);
+            tr.send_request(RequestBuilder::new(method).id(n).build().unwrap())
+                .unwrap();
+            server.serve_one(&mut tr).unwrap();
+            assert_eq!(tr.read_response().unwrap().result(), &Ok(Value::from(n)));
+        }
+    }
+
+    #[test]
+    fn method_registry() {
+        let names: Vec<MethodID> = (0..100).map(|i| format!("method.{}", i).into()).collect();
+        let mut registry = MethodRegistry::new();
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(registry.register(name.clone()), i as u32);
+        }
+        assert_eq!(registry.register("method.7"), 7);
+        assert_eq!(registry.len(), 100);
+        assert_eq!(registry.method(7), Some(&names[7]));
+        assert_eq!(registry.index(&"nope".into()), None);
+
+        // Count 10k calls by index, and again in a map keyed by name; this
+        // checks the two agree, not which is quicker
+        let mut by_index = vec![0u32; registry.len()];
+        let mut by_name: HashMap<MethodID, u32> = HashMap::new();
+        for call in 0..10_000 {
+            let method = &names[call * 37 % names.len()];
+            by_index[registry.index(method).unwrap() as usize] += 1;
+            *by_name.entry(method.clone()).or_default() += 1;
+        }
+        for (index, count) in by_index.into_iter().enumerate() {
+            let method = registry.method(index as u32).unwrap();
+            assert_eq!(by_name[method], count);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for testing code that makes RPC calls.
+//!
+//! [MockServer] stands in for a server with a script of canned Responses. It's
+//! a [ClientTransport], so a [Client](crate::client::Client) can use it in
+//! place of a real connection. Every message still goes through the v0
+//! encoding on the way in and out (over a [BufTransport]), so the calls are
+//! checked about as thoroughly as they would be on the wire.
+//!
+//! This module is built for the crate's own tests, and for other crates when
+//! they turn on the `testing` feature.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use bytes::BytesMut;
+
+use crate::error::TransportError;
+use crate::proto::{Batch, Control, Request, Response};
+use crate::transport::simple::{ClientTransport, ServerTransport};
+use crate::transport::BufTransport;
+
+type Responder = Box<dyn FnMut(&Request) -> Response>;
+
+/// A fake server that answers Requests from a script.
+///
+/// Each Request that expects a Response gets the next one queued with
+/// [reply](Self::reply). Once those run out, Requests go to the
+/// [respond_with](Self::respond_with) closure, if there is one; a Request
+/// with nothing left to answer it is a test failure, so it panics.
+///
+/// Every Request is kept, notifications included, so the test can check them
+/// afterward with [received](Self::received) or
+/// [assert_received](Self::assert_received). Control messages are dropped.
+pub struct MockServer {
+    wire: BufTransport<BytesMut>,
+    script: VecDeque<Response>,
+    responder: Option<Responder>,
+    received: Vec<Request>,
+}
+
+impl MockServer {
+    pub fn new() -> Self {
+        Self {
+            wire: BufTransport::new(BytesMut::new()),
+            script: VecDeque::new(),
+            responder: None,
+            received: Vec::new(),
+        }
+    }
+
+    /// Queue up `response` as the answer to the next Request. It's sent
+    /// exactly as given, so its RequestID should be the one the Request will
+    /// have.
+    pub fn reply(mut self, response: Response) -> Self {
+        self.script.push_back(response);
+        self
+    }
+
+    /// Answer Requests with `f` once the queued replies have all been used.
+    pub fn respond_with(mut self, f: impl FnMut(&Request) -> Response + 'static) -> Self {
+        self.responder = Some(Box::new(f));
+        self
+    }
+
+    /// The Requests received so far, in the order they arrived.
+    pub fn received(&self) -> &[Request] {
+        &self.received
+    }
+
+    /// The queued replies that haven't been sent yet.
+    pub fn unused_replies(&self) -> usize {
+        self.script.len()
+    }
+
+    /// Panic unless the Requests received so far are exactly `expected`, and
+    /// every queued reply was sent.
+    pub fn assert_received(&self, expected: &[Request]) {
+        assert_eq!(self.received, expected, "MockServer got other requests");
+        assert_eq!(self.unused_replies(), 0, "MockServer has unused replies");
+    }
+
+    // Read back a Request we just encoded, and work out its Response.
+    fn answer(&mut self) -> Result<Option<Response>, TransportError> {
+        let request = self.wire.read_request()?;
+        let response = match request.req_id() {
+            None => None,
+            Some(_) => Some(match (self.script.pop_front(), &mut self.responder) {
+                (Some(response), _) => response,
+                (None, Some(f)) => f(&request),
+                (None, None) => panic!("MockServer has no reply for {:?}", request),
+            }),
+        };
+        self.received.push(request);
+        Ok(response)
+    }
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for MockServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockServer")
+            .field("script", &self.script)
+            .field("received", &self.received)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientTransport for MockServer {
+    type Error = TransportError;
+    type SendResult = ();
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        self.wire.send_request_ref(request)?;
+        if let Some(response) = self.answer()? {
+            self.wire.send_response(response)?;
+        }
+        Ok(())
+    }
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        ClientTransport::read_response(&mut self.wire)
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        let mut responses = Vec::new();
+        for request in batch.iter() {
+            self.wire.send_request_ref(request)?;
+            responses.extend(self.answer()?);
+        }
+        // An all-notification batch gets no Response batch at all
+        if let Ok(batch) = Batch::new(responses) {
+            self.wire.send_response_batch(batch)?;
+        }
+        Ok(())
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        ClientTransport::read_response_batch(&mut self.wire)
+    }
+    fn send_control(&mut self, _control: &Control) -> Result<Self::SendResult, Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockServer;
+    use crate::client::Client;
+    use crate::error::ClientError;
+    use crate::proto::{Batch, ErrorValue, Params, RequestBuilder, Response, Value};
+    use crate::transport::simple::ClientTransport;
+
+    #[test]
+    fn scripted_client() {
+        let mock = MockServer::new()
+            .reply(Response::ok(3, 0u8.into()))
+            .reply(Response::err(ErrorValue::internal("nope"), 1u8.into()))
+            .respond_with(|req| Response::ok("later", req.req_id().clone().unwrap()));
+        let mut client = Client::new(mock);
+        let args = Params::Array(vec![1.into(), 2.into()]);
+        assert_eq!(client.call("add", Some(args)).unwrap(), Value::from(3));
+        client.notify("log", None).unwrap();
+        assert!(matches!(
+            client.call("add", None),
+            Err(ClientError::Application(e)) if e.message() == "nope"
+        ));
+        assert_eq!(client.call("wait", None).unwrap(), Value::from("later"));
+
+        client.transport().assert_received(&[
+            RequestBuilder::new("add")
+                .arg(1)
+                .arg(2)
+                .id(0u8)
+                .build()
+                .unwrap(),
+            RequestBuilder::new("log").build().unwrap(),
+            RequestBuilder::new("add").id(1u8).build().unwrap(),
+            RequestBuilder::new("wait").id(2u8).build().unwrap(),
+        ]);
+    }
+
+    #[test]
+    fn batches() {
+        let mut mock = MockServer::new().respond_with(|req| {
+            Response::ok(req.method().to_string(), req.req_id().clone().unwrap())
+        });
+        let requests = vec![
+            RequestBuilder::new("a").id(1u8).build().unwrap(),
+            RequestBuilder::new("b").build().unwrap(),
+            RequestBuilder::new("c").id(2u8).build().unwrap(),
+        ];
+        mock.send_request_batch(Batch::new(requests.clone()).unwrap())
+            .unwrap();
+        let responses = mock.read_response_batch().unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses.iter().last().unwrap().result(), &Ok("c".into()));
+        mock.assert_received(&requests);
+    }
+
+    #[test]
+    #[should_panic(expected = "no reply")]
+    fn unscripted() {
+        let mut client = Client::new(MockServer::new());
+        let _ = client.call("anything", None);
+    }
+}
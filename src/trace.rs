@@ -0,0 +1,278 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Spans around the transports' sends and reads, for the `tracing` feature.
+//!
+//! Sending or reading a single Request or Response - not a batch or a
+//! Control message - happens inside a DEBUG span named `rpc.send_request`,
+//! `rpc.read_request`, `rpc.send_response`, or `rpc.read_response`. Request
+//! spans record the `method` and `id`, and Response spans record the `id`
+//! and, for an error, its `code`; reads fill these in once the message has
+//! arrived. Each span gets a TRACE event if the operation works, or a DEBUG
+//! event holding the `error` if it doesn't.
+//!
+//! Every transport with those methods is traced, AsyncTransport included;
+//! its spans cover the whole of each `.await`.
+//!
+//! Without the feature, these just run the operation.
+
+pub(crate) use imp::*;
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use crate::proto::{Request, Response};
+    use crate::transport::simple::Incoming;
+    use std::fmt::Display;
+    #[cfg(feature = "tokio")]
+    use std::future::Future;
+    use tracing::field::{self, Empty};
+    #[cfg(feature = "tokio")]
+    use tracing::Instrument;
+    use tracing::Span;
+
+    fn record_request(span: &Span, request: &Request) {
+        span.record("method", field::display(request.method()));
+        if let Some(id) = request.req_id() {
+            span.record("id", field::display(id));
+        }
+    }
+
+    fn record_response(span: &Span, response: &Response) {
+        span.record("id", field::display(response.req_id()));
+        if let Err(error) = response.result() {
+            span.record("code", error.code());
+        }
+    }
+
+    // Run `op` inside `span`, then let `record` fill in the span from what
+    // it returned.
+    fn traced<T, E: Display>(
+        span: Span,
+        op: impl FnOnce() -> Result<T, E>,
+        record: impl FnOnce(&Span, &T),
+    ) -> Result<T, E> {
+        let _entered = span.enter();
+        let result = op();
+        finish(&span, &result, record);
+        result
+    }
+
+    // The async version of traced: `op` is polled inside `span`.
+    #[cfg(feature = "tokio")]
+    async fn traced_async<T, E: Display>(
+        span: Span,
+        op: impl Future<Output = Result<T, E>>,
+        record: impl FnOnce(&Span, &T),
+    ) -> Result<T, E> {
+        let result = op.instrument(span.clone()).await;
+        let _entered = span.enter();
+        finish(&span, &result, record);
+        result
+    }
+
+    fn finish<T, E: Display>(span: &Span, result: &Result<T, E>, record: impl FnOnce(&Span, &T)) {
+        match result {
+            Ok(value) => {
+                record(span, value);
+                tracing::trace!("done");
+            }
+            Err(e) => tracing::debug!(error = %e, "failed"),
+        }
+    }
+
+    pub(crate) fn send_request<T, E: Display>(
+        request: &Request,
+        send: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let span = tracing::debug_span!("rpc.send_request", method = Empty, id = Empty);
+        record_request(&span, request);
+        traced(span, send, |_, _| {})
+    }
+
+    pub(crate) fn send_response<T, E: Display>(
+        response: &Response,
+        send: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let span = tracing::debug_span!("rpc.send_response", id = Empty, code = Empty);
+        record_response(&span, response);
+        traced(span, send, |_, _| {})
+    }
+
+    pub(crate) fn read_request<E: Display>(
+        read: impl FnOnce() -> Result<Request, E>,
+    ) -> Result<Request, E> {
+        let span = tracing::debug_span!("rpc.read_request", method = Empty, id = Empty);
+        traced(span, read, record_request)
+    }
+
+    /// Only Requests and notifications fill in the span.
+    pub(crate) fn read_incoming<E: Display>(
+        read: impl FnOnce() -> Result<Incoming, E>,
+    ) -> Result<Incoming, E> {
+        let span = tracing::debug_span!("rpc.read_request", method = Empty, id = Empty);
+        traced(span, read, |span, incoming| match incoming {
+            Incoming::Request(request) => record_request(span, request),
+            Incoming::Notification(note) => {
+                span.record("method", field::display(note.method()));
+            }
+            Incoming::Control(_) => {}
+        })
+    }
+
+    pub(crate) fn read_response<E: Display>(
+        read: impl FnOnce() -> Result<Response, E>,
+    ) -> Result<Response, E> {
+        let span = tracing::debug_span!("rpc.read_response", id = Empty, code = Empty);
+        traced(span, read, record_response)
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn send_request_async<T, E: Display>(
+        request: &Request,
+        send: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let span = tracing::debug_span!("rpc.send_request", method = Empty, id = Empty);
+        record_request(&span, request);
+        traced_async(span, send, |_, _| {}).await
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn send_response_async<T, E: Display>(
+        response: &Response,
+        send: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let span = tracing::debug_span!("rpc.send_response", id = Empty, code = Empty);
+        record_response(&span, response);
+        traced_async(span, send, |_, _| {}).await
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn read_request_async<E: Display>(
+        read: impl Future<Output = Result<Request, E>>,
+    ) -> Result<Request, E> {
+        let span = tracing::debug_span!("rpc.read_request", method = Empty, id = Empty);
+        traced_async(span, read, record_request).await
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn read_response_async<E: Display>(
+        read: impl Future<Output = Result<Response, E>>,
+    ) -> Result<Response, E> {
+        let span = tracing::debug_span!("rpc.read_response", id = Empty, code = Empty);
+        traced_async(span, read, record_response).await
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use crate::proto::{Request, Response};
+    use crate::transport::simple::Incoming;
+    #[cfg(feature = "tokio")]
+    use std::future::Future;
+
+    #[inline]
+    pub(crate) fn send_request<T>(_: &Request, send: impl FnOnce() -> T) -> T {
+        send()
+    }
+
+    #[inline]
+    pub(crate) fn send_response<T>(_: &Response, send: impl FnOnce() -> T) -> T {
+        send()
+    }
+
+    #[inline]
+    pub(crate) fn read_request<E>(read: impl FnOnce() -> Result<Request, E>) -> Result<Request, E> {
+        read()
+    }
+
+    #[inline]
+    pub(crate) fn read_incoming<E>(
+        read: impl FnOnce() -> Result<Incoming, E>,
+    ) -> Result<Incoming, E> {
+        read()
+    }
+
+    #[inline]
+    pub(crate) fn read_response<E>(
+        read: impl FnOnce() -> Result<Response, E>,
+    ) -> Result<Response, E> {
+        read()
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn send_request_async<T>(_: &Request, send: impl Future<Output = T>) -> T {
+        send.await
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn send_response_async<T>(_: &Response, send: impl Future<Output = T>) -> T {
+        send.await
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn read_request_async<T>(read: impl Future<Output = T>) -> T {
+        read.await
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn read_response_async<T>(read: impl Future<Output = T>) -> T {
+        read.await
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::proto::v1::V1;
+    use crate::proto::{ErrorValue, RequestBuilder, Response};
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+    #[cfg(feature = "tokio")]
+    use crate::transport::AsyncTransport;
+    use crate::transport::BufTransport;
+    use bytes::BytesMut;
+    use tracing_test::traced_test;
+
+    #[test]
+    #[traced_test]
+    fn spans() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        let req = RequestBuilder::new("add").arg(1).id(7u8).build().unwrap();
+        tr.send_request(req).unwrap();
+        assert!(logs_contain("rpc.send_request{method=add id=7}"));
+        tr.read_request().unwrap();
+        assert!(logs_contain("rpc.read_request{method=add id=7}"));
+
+        let resp = Response::err(ErrorValue::internal("no"), 7u8.into());
+        tr.send_response(resp).unwrap();
+        assert!(logs_contain("rpc.send_response{id=7 code=-32603}"));
+        // Reading a Request where a Response should be fails, inside the span
+        tr.send_request(RequestBuilder::new("x").build().unwrap())
+            .unwrap();
+        tr.read_response().unwrap();
+        assert!(tr.read_response().is_err());
+        assert!(logs_contain("rpc.read_response{id=7 code=-32603}"));
+        assert!(logs_contain("failed error="));
+    }
+
+    #[test]
+    #[traced_test]
+    fn v1_spans() {
+        let mut tr = V1(BufTransport::new(BytesMut::new()));
+        let req = RequestBuilder::new("sub").id("x").build().unwrap();
+        tr.send_request(req).unwrap();
+        assert!(logs_contain("rpc.send_request{method=sub id=x}"));
+        tr.read_request().unwrap();
+        assert!(logs_contain("rpc.read_request{method=sub id=x}"));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    #[traced_test]
+    async fn async_spans() {
+        let (c, s) = tokio::io::duplex(64);
+        let (mut client, mut server) = (AsyncTransport::new(c), AsyncTransport::new(s));
+        let req = RequestBuilder::new("add").id(7u8).build().unwrap();
+        client.send_request(req).await.unwrap();
+        assert!(logs_contain("rpc.send_request{method=add id=7}"));
+        server.read_request().await.unwrap();
+        assert!(logs_contain("rpc.read_request{method=add id=7}"));
+    }
+}
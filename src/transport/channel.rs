@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! An in-process transport, for testing.
+//!
+//! [ChannelTransport] passes Requests and Responses over a pair of
+//! [std::sync::mpsc] channels as they are, without encoding them. That makes
+//! it handy for testing application code against the transport traits
+//! without setting up sockets or buffers.
+
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use super::simple::{ClientTransport, Incoming, ServerTransport};
+use crate::error::{ProtocolError, TransportError};
+use crate::proto::{Batch, Control, Request, Response};
+
+// Everything one end can send to the other.
+#[derive(Debug)]
+enum Packet {
+    Request(Request),
+    Response(Response),
+    Control(Control),
+    RequestBatch(Batch<Request>),
+    ResponseBatch(Batch<Response>),
+}
+
+/// One end of an in-process connection; see [ChannelTransport::pair].
+///
+/// Reading fails with [TransportError::Eof] once the other end has been
+/// dropped, and with [ProtocolError::UnexpectedMessage] if the other end sent
+/// something other than what was asked for.
+pub struct ChannelTransport {
+    tx: Sender<Packet>,
+    rx: Receiver<Packet>,
+}
+
+impl ChannelTransport {
+    /// Create a connected pair of transports. Either one can be the client,
+    /// and the other the server.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        (Self { tx: a_tx, rx: a_rx }, Self { tx: b_tx, rx: b_rx })
+    }
+
+    fn send(&mut self, packet: Packet) -> Result<(), TransportError> {
+        self.tx
+            .send(packet)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into())
+    }
+
+    fn recv(&mut self) -> Result<Packet, TransportError> {
+        self.rx.recv().map_err(|_| TransportError::Eof)
+    }
+}
+
+impl ClientTransport for ChannelTransport {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        match self.recv()? {
+            Packet::Response(response) => Ok(response),
+            _ => Err(ProtocolError::UnexpectedMessage.into()),
+        }
+    }
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        self.send(Packet::Request(request.clone()))
+    }
+    fn send_request(&mut self, request: Request) -> Result<Self::SendResult, Self::Error> {
+        self.send(Packet::Request(request))
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        self.send(Packet::Control(control.clone()))
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        match self.recv()? {
+            Packet::ResponseBatch(batch) => Ok(batch),
+            _ => Err(ProtocolError::UnexpectedMessage.into()),
+        }
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        self.send(Packet::RequestBatch(batch))
+    }
+}
+
+impl ServerTransport for ChannelTransport {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_request(&mut self) -> Result<Request, Self::Error> {
+        match self.recv()? {
+            Packet::Request(request) => Ok(request),
+            _ => Err(ProtocolError::UnexpectedMessage.into()),
+        }
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        match self.recv()? {
+            Packet::Request(request) => Ok(request.into()),
+            Packet::Control(control) => Ok(Incoming::Control(control)),
+            _ => Err(ProtocolError::UnexpectedMessage.into()),
+        }
+    }
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        self.send(Packet::Response(response.clone()))
+    }
+    fn send_response(&mut self, response: Response) -> Result<Self::SendResult, Self::Error> {
+        self.send(Packet::Response(response))
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error> {
+        match self.recv()? {
+            Packet::RequestBatch(batch) => Ok(batch),
+            _ => Err(ProtocolError::UnexpectedMessage.into()),
+        }
+    }
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        self.send(Packet::ResponseBatch(batch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChannelTransport;
+    use crate::error::{ProtocolError, TransportError};
+    use crate::proto::{RequestBuilder, Response, Value};
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+    use std::thread;
+
+    #[test]
+    fn client_and_server() {
+        let (mut client, mut server) = ChannelTransport::pair();
+        let server = thread::spawn(move || loop {
+            let request = match server.read_request() {
+                Ok(request) => request,
+                Err(TransportError::Eof) => break,
+                Err(e) => panic!("{}", e),
+            };
+            let req_id = request.req_id().clone().unwrap();
+            let reply = Response::ok(request.method().to_string(), req_id);
+            server.send_response(reply).unwrap();
+        });
+        let req = RequestBuilder::new("echo").id(1u8).build().unwrap();
+        client.send_request(req).unwrap();
+        let resp = client.read_response().unwrap();
+        assert_eq!(resp.result(), &Ok(Value::from("echo")));
+
+        // The server hasn't sent a batch
+        client
+            .send_request(RequestBuilder::new("x").id(2u8).build().unwrap())
+            .unwrap();
+        assert!(matches!(
+            client.read_response_batch(),
+            Err(TransportError::Proto(_))
+        ));
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn request() {
+        let (mut client, mut server) = ChannelTransport::pair();
+        let server = thread::spawn(move || loop {
+            let request = match server.read_request() {
+                Ok(request) => request,
+                Err(TransportError::Eof) => break,
+                Err(e) => panic!("{}", e),
+            };
+            let req_id = match request.method().to_string().as_str() {
+                "wrong_id" => 99u8.into(),
+                _ => request.req_id().clone().unwrap(),
+            };
+            server.send_response(Response::ok(1, req_id)).unwrap();
+        });
+        let req = RequestBuilder::new("ok").id(1u8).build().unwrap();
+        let resp = client.request(req).unwrap();
+        assert_eq!(resp.req_id(), &1u8.into());
+        assert_eq!(resp.result(), &Ok(Value::from(1)));
+
+        let req = RequestBuilder::new("wrong_id").id(2u8).build().unwrap();
+        assert!(matches!(
+            client.request(req),
+            Err(TransportError::Proto(ProtocolError::UnexpectedMessage))
+        ));
+
+        drop(client);
+        server.join().unwrap();
+    }
+}
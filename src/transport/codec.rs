@@ -0,0 +1,78 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A tokio_util codec for v0 messages, for use with `Framed` streams.
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::error::TransportError;
+use crate::proto::v0::RPCMsg;
+use crate::proto::WriteCbor;
+
+/// RpcCodec finds the boundaries between [RPCMsg] items in a byte stream.
+///
+/// CBOR items are self-delimiting, so there's no extra framing: the decoder
+/// just waits until the buffer holds a complete message.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RpcCodec;
+
+impl Encoder<RPCMsg> for RpcCodec {
+    type Error = TransportError;
+    fn encode(&mut self, item: RPCMsg, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.to_buf(dst)
+    }
+}
+
+impl Decoder for RpcCodec {
+    type Item = RPCMsg;
+    type Error = TransportError;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match RPCMsg::from_slice_partial(src)? {
+            Some((msg, len)) => {
+                src.advance(len);
+                Ok(Some(msg))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RpcCodec;
+    use crate::proto::v0::RPCMsg;
+    use crate::proto::{Request, RequestBuilder, Response};
+    use bytes::BytesMut;
+    use std::convert::TryFrom;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    #[test]
+    fn one_byte_at_a_time() {
+        let req = RequestBuilder::new("hello").arg(1).id(1u8).build().unwrap();
+        let resp = Response::new(Ok("world".into()), 1u8.into());
+        let mut codec = RpcCodec;
+        let mut encoded = BytesMut::new();
+        codec.encode(req.clone().into(), &mut encoded).unwrap();
+        let req_len = encoded.len();
+        codec.encode(resp.clone().into(), &mut encoded).unwrap();
+
+        let mut src = BytesMut::new();
+        let mut decoded: Vec<RPCMsg> = Vec::new();
+        for (i, b) in encoded.iter().enumerate() {
+            src.extend_from_slice(&[*b]);
+            let before = src.len();
+            match codec.decode(&mut src).unwrap() {
+                Some(msg) => {
+                    assert!(i + 1 == req_len || i + 1 == encoded.len());
+                    assert!(src.is_empty());
+                    decoded.push(msg);
+                }
+                // Nothing gets consumed until there's a whole message
+                None => assert_eq!(src.len(), before),
+            }
+        }
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(Request::try_from(decoded[0].clone()).unwrap(), req);
+        assert_eq!(Response::try_from(decoded[1].clone()).unwrap(), resp);
+    }
+}
@@ -0,0 +1,250 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! zstd compression for messages, for links where bandwidth is scarce.
+//!
+//! [Compressed] wraps another [Framing]: each message (or batch) is encoded
+//! as usual, compressed, and written as a CBOR byte string - which carries
+//! its own length - tagged with [TAG_ID_ZSTD]. The inner encoding keeps its
+//! own tag, so once it's decompressed the message is exactly what the inner
+//! Framing would have sent. Use it with a
+//! [FramedTransport](super::framing::FramedTransport):
+//!
+//! ```
+//! use ciborium_rpc::transport::compress::Compressed;
+//! use ciborium_rpc::transport::framing::{FramedTransport, V0Framing};
+//! # let channel = std::io::Cursor::new(Vec::new());
+//! let transport = FramedTransport::new(channel, Compressed::new(V0Framing));
+//! ```
+//!
+//! Both ends have to agree to use compression; a reader that's expecting
+//! compressed messages rejects uncompressed ones as
+//! [InvalidMessage](crate::error::ProtocolError::InvalidMessage), and vice
+//! versa.
+//!
+//! A small compressed message can decompress to something enormous, so
+//! reads stop at [DEFAULT_MAX_MESSAGE_SIZE] bytes of decompressed data unless
+//! told otherwise, failing with
+//! [MessageTooLarge](TransportError::MessageTooLarge).
+
+use super::framing::{Framing, Outgoing, OutgoingBatch};
+use super::{read_limited, Read, Write};
+use crate::error::{ProtocolError, TransportError};
+use crate::proto::codec::{CborCodec, Ciborium};
+use crate::proto::Value;
+
+/// Tag ID marking a zstd-compressed message
+pub const TAG_ID_ZSTD: u64 = 4036988333;
+
+/// The default limit on the size of a message once it's decompressed.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 << 20;
+
+// Room for the tag and byte string header around the compressed data
+const FRAME_OVERHEAD: usize = 16;
+
+/// A [Framing] that compresses whatever `inner` encodes.
+#[derive(Debug, Clone, Copy)]
+pub struct Compressed<F: Framing> {
+    pub inner: F,
+    level: i32,
+    max_message_size: Option<usize>,
+}
+
+impl<F: Framing> Compressed<F> {
+    /// Compress with zstd's default level, and read messages of up to
+    /// [DEFAULT_MAX_MESSAGE_SIZE] bytes.
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+            level: 0,
+            max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+        }
+    }
+
+    /// Set the zstd compression level; 0 means zstd's default, and higher
+    /// levels trade speed for size.
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn set_level(&mut self, level: i32) {
+        self.level = level;
+    }
+
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    /// Reject incoming messages that decompress to more than `limit` bytes.
+    /// The compressed data can't be much bigger than that, either.
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    /// Set the limit on decompressed messages; `None` removes it, which is
+    /// only safe if the peer is trusted.
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.max_message_size = limit;
+    }
+
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    fn write_compressed(&self, cbor: &[u8], writer: &mut impl Write) -> Result<(), TransportError> {
+        let compressed = zstd::bulk::compress(cbor, self.level)?;
+        let value = Value::Tag(TAG_ID_ZSTD, Box::new(Value::Bytes(compressed)));
+        Ciborium.to_writer(&value, writer)
+    }
+
+    fn read_compressed(&self, reader: &mut impl Read) -> Result<Vec<u8>, TransportError> {
+        let limit = self.max_message_size;
+        let frame_limit = limit.map(|l| zstd::zstd_safe::compress_bound(l) + FRAME_OVERHEAD);
+        // The compressed frame can't be much bigger than the message, so it
+        // gets a limit too; going over either one counts as going over ours.
+        let frame =
+            read_limited(reader, frame_limit, |r| Ciborium.from_reader(r)).map_err(|e| {
+                match (e, limit) {
+                    (TransportError::MessageTooLarge { .. }, Some(limit)) => {
+                        TransportError::MessageTooLarge { limit }
+                    }
+                    (e, _) => e,
+                }
+            })?;
+        let compressed = match frame {
+            Value::Tag(TAG_ID_ZSTD, value) => match *value {
+                Value::Bytes(compressed) => compressed,
+                _ => return Err(ProtocolError::InvalidMessage.into()),
+            },
+            _ => return Err(ProtocolError::InvalidMessage.into()),
+        };
+        // As in read_limited: let the decoder go one byte over, so we can
+        // tell when it had more to give.
+        let decoder = zstd::stream::read::Decoder::new(compressed.as_slice())?;
+        let mut cbor = Vec::new();
+        let max = limit.map_or(u64::MAX, |l| l as u64 + 1);
+        decoder.take(max).read_to_end(&mut cbor)?;
+        match limit {
+            Some(limit) if cbor.len() > limit => Err(TransportError::MessageTooLarge { limit }),
+            _ => Ok(cbor),
+        }
+    }
+}
+
+impl<F: Framing + Default> Default for Compressed<F> {
+    fn default() -> Self {
+        Self::new(F::default())
+    }
+}
+
+impl<F: Framing> Framing for Compressed<F> {
+    type Msg = F::Msg;
+    type Batch = F::Batch;
+    fn encode(&mut self, msg: Outgoing<'_>, writer: &mut impl Write) -> Result<(), TransportError> {
+        let mut cbor = Vec::new();
+        self.inner.encode(msg, &mut cbor)?;
+        self.write_compressed(&cbor, writer)
+    }
+    fn decode(&mut self, reader: &mut impl Read) -> Result<Self::Msg, TransportError> {
+        let cbor = self.read_compressed(reader)?;
+        self.inner.decode(&mut cbor.as_slice())
+    }
+    fn encode_batch(
+        &mut self,
+        batch: OutgoingBatch,
+        writer: &mut impl Write,
+    ) -> Result<(), TransportError> {
+        let mut cbor = Vec::new();
+        self.inner.encode_batch(batch, &mut cbor)?;
+        self.write_compressed(&cbor, writer)
+    }
+    fn decode_batch(&mut self, reader: &mut impl Read) -> Result<Self::Batch, TransportError> {
+        let cbor = self.read_compressed(reader)?;
+        self.inner.decode_batch(&mut cbor.as_slice())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{Compressed, TAG_ID_ZSTD};
+    use crate::error::TransportError;
+    use crate::proto::{Request, RequestBuilder, Response, Value};
+    use crate::transport::framing::{FramedTransport, Framing, Outgoing, V0Framing};
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+    use crate::transport::Transport;
+    use std::convert::TryInto;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn shrinks() {
+        let req = RequestBuilder::new("store")
+            .arg("spam ".repeat(1000))
+            .id(1u8)
+            .build()
+            .unwrap();
+        let (mut plain, mut small) = (Vec::new(), Vec::new());
+        V0Framing
+            .encode(Outgoing::Request(&req), &mut plain)
+            .unwrap();
+        let mut framing = Compressed::new(V0Framing);
+        framing.encode(Outgoing::Request(&req), &mut small).unwrap();
+        assert!(small.len() * 10 < plain.len());
+        let decoded: Request = framing
+            .decode(&mut small.as_slice())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(decoded, req);
+
+        // Over a real channel, too
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut client = FramedTransport::new(s1, framing);
+        let mut server = FramedTransport::new(s2, Compressed::new(V0Framing).with_level(19));
+        client.send_request(req.clone()).unwrap();
+        assert_eq!(server.read_request().unwrap(), req);
+        server.send_response(Response::ok(1, 1u8.into())).unwrap();
+        assert_eq!(client.read_response().unwrap(), Response::ok(1, 1u8.into()));
+
+        // An uncompressed message isn't accepted
+        let mut plain_server = Transport::new(server.into_inner().0);
+        plain_server
+            .send_response(Response::ok(1, 1u8.into()))
+            .unwrap();
+        assert!(client.read_response().is_err());
+    }
+
+    #[test]
+    fn decompression_limit() {
+        // Compresses to a few hundred bytes
+        let req = RequestBuilder::new("bomb")
+            .arg(vec![0u8; 1 << 20])
+            .id(1u8)
+            .build()
+            .unwrap();
+        let mut bomb = Vec::new();
+        let mut framing = Compressed::new(V0Framing);
+        framing.encode(Outgoing::Request(&req), &mut bomb).unwrap();
+        assert!(bomb.len() < 1000);
+
+        let mut small = Compressed::new(V0Framing).with_max_message_size(4096);
+        assert!(matches!(
+            small.decode(&mut bomb.as_slice()),
+            Err(TransportError::MessageTooLarge { limit: 4096 })
+        ));
+        // An oversized frame is rejected before it's decompressed at all
+        let big = Value::Tag(TAG_ID_ZSTD, Box::new(Value::Bytes(vec![0; 8192])));
+        let mut frame = Vec::new();
+        ciborium::ser::into_writer(&big, &mut frame).unwrap();
+        assert!(matches!(
+            small.decode(&mut frame.as_slice()),
+            Err(TransportError::MessageTooLarge { limit: 4096 })
+        ));
+        // The default limit leaves room for it
+        assert!(framing.decode(&mut bomb.as_slice()).is_ok());
+    }
+}
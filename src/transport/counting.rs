@@ -0,0 +1,281 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Message and byte counters for a transport, for observability.
+//!
+//! [CountingTransport] wraps a client-side transport and tallies the
+//! messages that go through it in a shared [TransportStats]. The counters
+//! are atomics, so a monitoring thread can keep a handle from
+//! [stats](CountingTransport::stats) and read them while the transport is in
+//! use elsewhere.
+//!
+//! Bytes are counted where they really cross: wrap the transport's channel
+//! in a [CountingChannel] that shares the same stats, and it adds up every
+//! byte read from or written to the channel, framing and all.
+//!
+//! ```no_run
+//! # use ciborium_rpc::transport::counting::{CountingChannel, CountingTransport};
+//! # use ciborium_rpc::transport::Transport;
+//! # let stream = std::net::TcpStream::connect("localhost:9000").unwrap();
+//! let channel = CountingChannel::new(stream);
+//! let stats = channel.stats();
+//! let client = CountingTransport::with_stats(Transport::new(channel), stats);
+//! ```
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::cbor::CBORTransport;
+use super::simple::ClientTransport;
+use super::{Read, ReadTimeout, Write};
+use crate::error::TransportError;
+use crate::proto::{Batch, Control, Request, Response, Value};
+
+/// The counters kept by a [CountingTransport] and a [CountingChannel].
+#[derive(Debug, Default)]
+pub struct TransportStats {
+    requests_sent: AtomicU64,
+    responses_read: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_read: AtomicU64,
+    decode_errors: AtomicU64,
+}
+
+impl TransportStats {
+    /// Requests sent, including notifications and each item of a batch.
+    pub fn requests_sent(&self) -> u64 {
+        self.requests_sent.load(Ordering::Relaxed)
+    }
+
+    /// Responses read, including each item of a batch.
+    pub fn responses_read(&self) -> u64 {
+        self.responses_read.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written to a [CountingChannel].
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read from a [CountingChannel].
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Reads that failed because the message was malformed or wasn't what
+    /// was asked for.
+    pub fn decode_errors(&self) -> u64 {
+        self.decode_errors.load(Ordering::Relaxed)
+    }
+
+    fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    // Count a failed read if it failed because of what was read.
+    fn tally_read<R>(&self, result: Result<R, TransportError>) -> Result<R, TransportError> {
+        if let Err(TransportError::Decode { .. }) | Err(TransportError::Proto(_)) = &result {
+            Self::add(&self.decode_errors, 1)
+        }
+        result
+    }
+}
+
+/// A channel that counts the bytes read from and written to another one.
+/// See the [module docs](self) for how to use it with a [CountingTransport].
+pub struct CountingChannel<C> {
+    pub inner: C,
+    stats: Arc<TransportStats>,
+}
+
+impl<C> CountingChannel<C> {
+    pub fn new(inner: C) -> Self {
+        Self::with_stats(inner, Arc::default())
+    }
+
+    /// Count into `stats`, which might belong to a [CountingTransport].
+    pub fn with_stats(inner: C, stats: Arc<TransportStats>) -> Self {
+        Self { inner, stats }
+    }
+
+    pub fn stats(&self) -> Arc<TransportStats> {
+        self.stats.clone()
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Read> Read for CountingChannel<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        TransportStats::add(&self.stats.bytes_read, n);
+        Ok(n)
+    }
+}
+
+impl<C: Write> Write for CountingChannel<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        TransportStats::add(&self.stats.bytes_sent, n);
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<C: ReadTimeout> ReadTimeout for CountingChannel<C> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+/// A transport that counts the messages going through another one. See the
+/// [module docs](self) for counting bytes too.
+pub struct CountingTransport<T> {
+    pub inner: T,
+    stats: Arc<TransportStats>,
+}
+
+impl<T> CountingTransport<T> {
+    pub fn new(inner: T) -> Self {
+        Self::with_stats(inner, Arc::default())
+    }
+
+    /// Count into `stats`, which might belong to a [CountingChannel].
+    pub fn with_stats(inner: T, stats: Arc<TransportStats>) -> Self {
+        Self { inner, stats }
+    }
+
+    /// A handle to the counters, which stays valid (and keeps counting) for
+    /// as long as the transport is around.
+    pub fn stats(&self) -> Arc<TransportStats> {
+        self.stats.clone()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> ClientTransport for CountingTransport<T>
+where
+    T: ClientTransport<Error = TransportError>,
+{
+    type Error = TransportError;
+    type SendResult = T::SendResult;
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        let sent = self.inner.send_request_ref(request)?;
+        TransportStats::add(&self.stats.requests_sent, 1);
+        Ok(sent)
+    }
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        let response = self.stats.tally_read(self.inner.read_response())?;
+        TransportStats::add(&self.stats.responses_read, 1);
+        Ok(response)
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        let count = batch.len();
+        let sent = self.inner.send_request_batch(batch)?;
+        TransportStats::add(&self.stats.requests_sent, count);
+        Ok(sent)
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        let batch = self.stats.tally_read(self.inner.read_response_batch())?;
+        TransportStats::add(&self.stats.responses_read, batch.len());
+        Ok(batch)
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        self.inner.send_control(control)
+    }
+}
+
+impl<T> CBORTransport for CountingTransport<T>
+where
+    T: CBORTransport<Error = TransportError>,
+{
+    type Error = TransportError;
+    type SendResult = T::SendResult;
+    fn send_cbor(&mut self, value: Value) -> Result<Self::SendResult, Self::Error> {
+        self.inner.send_cbor(value)
+    }
+    fn read_cbor(&mut self) -> Result<Value, Self::Error> {
+        self.stats.tally_read(self.inner.read_cbor())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{CountingChannel, CountingTransport};
+    use crate::proto::{Batch, Request, RequestBuilder, Response};
+    use crate::transport::cbor::CBORTransport;
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+    use crate::transport::Transport;
+    use std::os::unix::net::UnixStream;
+
+    fn request(id: u8) -> Request {
+        RequestBuilder::new("add")
+            .arg(id)
+            .arg(1)
+            .id(id)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn counts() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let channel = CountingChannel::new(s1);
+        let stats = channel.stats();
+        let mut client = CountingTransport::with_stats(Transport::new(channel), stats.clone());
+        // The server just counts bytes, to check the client's counts against
+        let mut server = Transport::new(CountingChannel::new(s2));
+        let server_stats = server.channel.stats();
+
+        for id in 0..3 {
+            client.send_request(request(id)).unwrap();
+        }
+        let batch = Batch::new(vec![request(3), request(4)]).unwrap();
+        client.send_request_batch(batch).unwrap();
+        assert_eq!(stats.requests_sent(), 5);
+
+        // Answer, ending with something that isn't a Response
+        for _ in 0..3 {
+            let req = server.read_request().unwrap();
+            let resp = Response::ok(0, req.req_id().clone().unwrap());
+            server.send_response(resp).unwrap();
+        }
+        server.read_request_batch().unwrap();
+        assert_eq!(server_stats.bytes_read(), stats.bytes_sent());
+        let replies = vec![Response::ok(0, 3u8.into()), Response::ok(0, 4u8.into())];
+        server
+            .send_response_batch(Batch::new(replies).unwrap())
+            .unwrap();
+        server.send_request(request(5)).unwrap();
+
+        for _ in 0..3 {
+            client.read_response().unwrap();
+        }
+        assert_eq!(client.read_response_batch().unwrap().len(), 2);
+        assert!(client.read_response().is_err());
+        assert_eq!(stats.responses_read(), 5);
+        assert_eq!(stats.decode_errors(), 1);
+        assert_eq!(stats.bytes_read(), server_stats.bytes_sent());
+
+        // And at the CBOR level
+        let (sent, read) = (stats.bytes_sent(), stats.bytes_read());
+        client.send_cbor("hi".into()).unwrap();
+        assert_eq!(server.read_cbor().unwrap(), "hi".into());
+        server.send_cbor("hi".into()).unwrap();
+        assert_eq!(client.read_cbor().unwrap(), "hi".into());
+        assert_eq!(stats.bytes_sent(), sent + 3);
+        assert_eq!(stats.bytes_read(), read + 3);
+        assert_eq!(stats.decode_errors(), 1);
+    }
+}
@@ -0,0 +1,144 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Length-delimited framing for v0 messages.
+//!
+//! Each frame is a 4-byte big-endian length, followed by exactly that many
+//! bytes of CBOR holding a single [RPCMsg]. This lets a reader pull a whole
+//! message off a stream before it starts decoding it.
+
+use std::convert::TryFrom;
+
+use super::{Read, Write};
+use crate::error::TransportError;
+use crate::proto::v0::RPCMsg;
+use crate::proto::{ReadCbor, WriteCbor};
+
+/// Write `msg` to `writer` as a single length-prefixed frame.
+pub fn write_framed<W: Write>(writer: &mut W, msg: &RPCMsg) -> Result<(), TransportError> {
+    let mut payload = Vec::new();
+    msg.to_writer(&mut payload)?;
+    let len = u32::try_from(payload.len())
+        .map_err(|_| TransportError::Encode("message too large for frame".into()))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Read a single length-prefixed frame from `reader` and decode its contents.
+pub fn read_framed<R: Read>(reader: &mut R) -> Result<RPCMsg, TransportError> {
+    read_frame(reader, None)
+}
+
+/// Like [read_framed], but reject frames longer than `limit` bytes, without
+/// reading their contents.
+pub fn read_framed_limited<R: Read>(
+    reader: &mut R,
+    limit: usize,
+) -> Result<RPCMsg, TransportError> {
+    read_frame(reader, Some(limit))
+}
+
+fn read_frame<R: Read>(reader: &mut R, limit: Option<usize>) -> Result<RPCMsg, TransportError> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if let Some(limit) = limit.filter(|&limit| len > limit) {
+        return Err(TransportError::MessageTooLarge { limit });
+    }
+    // The length hasn't been checked against anything yet, so the payload
+    // only grows as its bytes arrive.
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(TransportError::Decode {
+            msg: "truncated frame".into(),
+            pos: Some(payload.len()),
+            raw: Some(payload),
+        });
+    }
+    let mut bytes = payload.as_slice();
+    let msg = RPCMsg::from_reader(&mut bytes).map_err(|e| e.with_raw(&payload))?;
+    if !bytes.is_empty() {
+        return Err(TransportError::Decode {
+            msg: "trailing data in frame".into(),
+            pos: Some(payload.len() - bytes.len()),
+            raw: Some(payload),
+        });
+    }
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_framed, read_framed_limited, write_framed};
+    use crate::error::TransportError;
+    use crate::proto::v0::RPCMsg;
+    use crate::proto::{Notification, Request, RequestBuilder, Response, WriteCbor};
+
+    #[test]
+    fn three_messages() {
+        let msgs: Vec<RPCMsg> = vec![
+            RequestBuilder::new("one").id(1u8).build().unwrap().into(),
+            Response::new(Ok(2.into()), 1u8.into()).into(),
+            RequestBuilder::new("three").arg(3).build().unwrap().into(),
+        ];
+        let mut buf = Vec::new();
+        for msg in &msgs {
+            write_framed(&mut buf, msg).unwrap();
+        }
+        let mut reader = buf.as_slice();
+        for msg in msgs {
+            assert_eq!(read_framed(&mut reader).unwrap(), msg);
+        }
+        assert!(reader.is_empty());
+        assert!(matches!(
+            read_framed(&mut reader),
+            Err(TransportError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn trailing_data() {
+        let mut payload = Vec::new();
+        RPCMsg::from(Request::from(Notification::new("hi", None)))
+            .to_writer(&mut payload)
+            .unwrap();
+        payload.push(0);
+        let mut buf = (payload.len() as u32).to_be_bytes().to_vec();
+        buf.extend(payload);
+        let err = read_framed(&mut buf.as_slice()).unwrap_err();
+        assert!(matches!(err, TransportError::Decode { .. }));
+    }
+
+    #[test]
+    fn truncated() {
+        // A huge length with nothing after it is just a truncated frame
+        let buf = u32::MAX.to_be_bytes();
+        assert!(matches!(
+            read_framed(&mut &buf[..]),
+            Err(TransportError::Decode { pos: Some(0), .. })
+        ));
+
+        let msg = RPCMsg::from(Request::from(Notification::new("hi", None)));
+        let mut buf = Vec::new();
+        write_framed(&mut buf, &msg).unwrap();
+        buf.pop();
+        assert!(matches!(
+            read_framed(&mut buf.as_slice()),
+            Err(TransportError::Decode { pos: Some(p), .. }) if p == buf.len() - 4
+        ));
+    }
+
+    #[test]
+    fn limited() {
+        let msg = RPCMsg::from(Request::from(Notification::new("hi", None)));
+        let mut buf = Vec::new();
+        write_framed(&mut buf, &msg).unwrap();
+        let len = buf.len() - 4;
+        assert_eq!(read_framed_limited(&mut buf.as_slice(), len).unwrap(), msg);
+        assert!(matches!(
+            read_framed_limited(&mut buf.as_slice(), len - 1),
+            Err(TransportError::MessageTooLarge { .. })
+        ));
+    }
+}
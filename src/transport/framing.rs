@@ -0,0 +1,761 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A transport that's generic over its wire format.
+//!
+//! [Transport](super::Transport) and friends always speak v0. A
+//! [FramedTransport] instead hands each message to a [Framing], which decides
+//! how it's written to (and read from) the channel. [V0Framing] and
+//! [V1Framing] are the two protocol versions, [KeyedFraming] is v0 with
+//! other names for its keys, and [JsonRpcCompat] makes v0 look like
+//! JSON-RPC 2.0; implement Framing to plug in something else, like a length
+//! prefix or a different encoding entirely.
+
+use std::convert::TryInto;
+
+use super::simple::{ClientTransport, Incoming, ServerTransport};
+use super::{Read, Write};
+use crate::error::{ProtocolError, TransportError};
+use crate::proto::codec::{CborCodec, Ciborium};
+use crate::proto::v0::Keys;
+use crate::proto::{v0, v1, Batch, Control, Request, Response, Value};
+use crate::proto::{ReadCbor, WriteCbor};
+use crate::trace;
+
+/// A single outgoing message, for [Framing::encode].
+#[derive(Debug, Clone, Copy)]
+pub enum Outgoing<'a> {
+    Request(&'a Request),
+    Response(&'a Response),
+    Control(&'a Control),
+}
+
+/// An outgoing batch, for [Framing::encode_batch].
+#[derive(Debug, Clone)]
+pub enum OutgoingBatch {
+    Requests(Batch<Request>),
+    Responses(Batch<Response>),
+}
+
+/// A wire format for RPC messages.
+///
+/// Decoding gives back the format's own message type, which the transport
+/// then converts to whatever it was expecting to read; a message of the
+/// wrong kind should fail that conversion with
+/// [ProtocolError::UnexpectedMessage].
+pub trait Framing {
+    type Msg: TryInto<Request, Error = ProtocolError>
+        + TryInto<Response, Error = ProtocolError>
+        + TryInto<Incoming, Error = ProtocolError>;
+    type Batch: TryInto<Batch<Request>, Error = ProtocolError>
+        + TryInto<Batch<Response>, Error = ProtocolError>;
+
+    fn encode(&mut self, msg: Outgoing<'_>, writer: &mut impl Write) -> Result<(), TransportError>;
+    fn decode(&mut self, reader: &mut impl Read) -> Result<Self::Msg, TransportError>;
+    fn encode_batch(
+        &mut self,
+        batch: OutgoingBatch,
+        writer: &mut impl Write,
+    ) -> Result<(), TransportError>;
+    fn decode_batch(&mut self, reader: &mut impl Read) -> Result<Self::Batch, TransportError>;
+}
+
+/// The v0 protocol, as used by [Transport](super::Transport).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct V0Framing;
+
+impl Framing for V0Framing {
+    type Msg = v0::RPCMsg;
+    type Batch = v0::RPCBatch;
+    fn encode(&mut self, msg: Outgoing<'_>, writer: &mut impl Write) -> Result<(), TransportError> {
+        match msg {
+            Outgoing::Request(request) => v0::RPCMsgRef::from(request).to_writer(writer),
+            Outgoing::Response(response) => v0::RPCMsgRef::from(response).to_writer(writer),
+            Outgoing::Control(control) => v0::RPCMsgRef::from(control).to_writer(writer),
+        }
+    }
+    fn decode(&mut self, reader: &mut impl Read) -> Result<Self::Msg, TransportError> {
+        v0::RPCMsg::from_reader(reader)
+    }
+    fn encode_batch(
+        &mut self,
+        batch: OutgoingBatch,
+        writer: &mut impl Write,
+    ) -> Result<(), TransportError> {
+        match batch {
+            OutgoingBatch::Requests(batch) => v0::RPCBatch::from(batch).to_writer(writer),
+            OutgoingBatch::Responses(batch) => v0::RPCBatch::from(batch).to_writer(writer),
+        }
+    }
+    fn decode_batch(&mut self, reader: &mut impl Read) -> Result<Self::Batch, TransportError> {
+        v0::RPCBatch::from_reader(reader)
+    }
+}
+
+/// The v0 protocol, encoded and decoded by a [CborCodec] other than the
+/// default. The codec has to pass tags through serde the way ciborium does;
+/// see [proto::codec](crate::proto::codec).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CodecFraming<K: CborCodec>(pub K);
+
+impl<K: CborCodec> Framing for CodecFraming<K> {
+    type Msg = v0::RPCMsg;
+    type Batch = v0::RPCBatch;
+    fn encode(&mut self, msg: Outgoing<'_>, writer: &mut impl Write) -> Result<(), TransportError> {
+        let codec = &mut self.0;
+        match msg {
+            Outgoing::Request(request) => {
+                v0::RPCMsgRef::from(request).to_writer_using(codec, writer)
+            }
+            Outgoing::Response(response) => {
+                v0::RPCMsgRef::from(response).to_writer_using(codec, writer)
+            }
+            Outgoing::Control(control) => {
+                v0::RPCMsgRef::from(control).to_writer_using(codec, writer)
+            }
+        }
+    }
+    fn decode(&mut self, reader: &mut impl Read) -> Result<Self::Msg, TransportError> {
+        v0::RPCMsg::from_reader_using(&mut self.0, reader)
+    }
+    fn encode_batch(
+        &mut self,
+        batch: OutgoingBatch,
+        writer: &mut impl Write,
+    ) -> Result<(), TransportError> {
+        let codec = &mut self.0;
+        match batch {
+            OutgoingBatch::Requests(batch) => {
+                v0::RPCBatch::from(batch).to_writer_using(codec, writer)
+            }
+            OutgoingBatch::Responses(batch) => {
+                v0::RPCBatch::from(batch).to_writer_using(codec, writer)
+            }
+        }
+    }
+    fn decode_batch(&mut self, reader: &mut impl Read) -> Result<Self::Batch, TransportError> {
+        v0::RPCBatch::from_reader_using(&mut self.0, reader)
+    }
+}
+
+/// The v0 protocol, with the names of the items in each message's map taken
+/// from `keys`. `KeyedFraming::new(Keys::JSON_RPC)` talks to a peer that
+/// wants JSON-RPC's names, like `method` and `result`; see [Keys].
+///
+/// This goes through the hand-written codec in [v0] rather than serde, so
+/// it can't have a [CborCodec] of its own.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyedFraming {
+    pub keys: Keys,
+}
+
+impl KeyedFraming {
+    pub fn new(keys: Keys) -> Self {
+        Self { keys }
+    }
+
+    fn message_value(&self, msg: Outgoing<'_>) -> Value {
+        match msg {
+            Outgoing::Request(request) => v0::request_value(request, &self.keys),
+            Outgoing::Response(response) => v0::response_value(response, &self.keys),
+            Outgoing::Control(control) => v0::control_value(control, &self.keys),
+        }
+    }
+
+    fn batch_values(&self, batch: OutgoingBatch) -> Vec<Value> {
+        let keys = &self.keys;
+        match batch {
+            OutgoingBatch::Requests(batch) => batch
+                .iter()
+                .map(|request| v0::request_value(request, keys))
+                .collect(),
+            OutgoingBatch::Responses(batch) => batch
+                .iter()
+                .map(|response| v0::response_value(response, keys))
+                .collect(),
+        }
+    }
+
+    fn decode_value(&self, value: Value) -> Result<v0::RPCMsg, TransportError> {
+        Ok(v0::decode_value(value, &self.keys)?.into())
+    }
+
+    fn decode_batch_values(&self, items: Vec<Value>) -> Result<v0::RPCBatch, TransportError> {
+        Ok(v0::RPCBatch::from_msgs(
+            items
+                .into_iter()
+                .map(|item| self.decode_value(item))
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+fn write_value(value: &Value, writer: &mut impl Write) -> Result<(), TransportError> {
+    Ciborium.to_writer(value, writer)
+}
+
+fn read_value(reader: &mut impl Read) -> Result<Value, TransportError> {
+    Ciborium.from_reader(reader)
+}
+
+fn read_array(reader: &mut impl Read) -> Result<Vec<Value>, TransportError> {
+    match read_value(reader)? {
+        Value::Array(items) => Ok(items),
+        _ => Err(ProtocolError::InvalidMessage.into()),
+    }
+}
+
+impl Framing for KeyedFraming {
+    type Msg = v0::RPCMsg;
+    type Batch = v0::RPCBatch;
+    fn encode(&mut self, msg: Outgoing<'_>, writer: &mut impl Write) -> Result<(), TransportError> {
+        write_value(&self.message_value(msg), writer)
+    }
+    fn decode(&mut self, reader: &mut impl Read) -> Result<Self::Msg, TransportError> {
+        self.decode_value(read_value(reader)?)
+    }
+    fn encode_batch(
+        &mut self,
+        batch: OutgoingBatch,
+        writer: &mut impl Write,
+    ) -> Result<(), TransportError> {
+        write_value(&Value::Array(self.batch_values(batch)), writer)
+    }
+    fn decode_batch(&mut self, reader: &mut impl Read) -> Result<Self::Batch, TransportError> {
+        self.decode_batch_values(read_array(reader)?)
+    }
+}
+
+const JSONRPC_KEY: &str = "jsonrpc";
+const JSONRPC_VERSION: &str = "2.0";
+
+/// Messages shaped like JSON-RPC 2.0's, for bridging to JSON-RPC peers: the
+/// [JSON_RPC](Keys::JSON_RPC) key names, and a `"jsonrpc": "2.0"` item at the
+/// start of every message.
+///
+/// A message with some other version is rejected with
+/// [ProtocolError::InvalidMessage]. So is one with no version at all, if
+/// [strict](Self::strict) is set; otherwise that's accepted, since it's
+/// still a valid v0 message.
+///
+/// Only the keys change, so the messages are still tagged CBOR; the `json`
+/// feature has conversions to actual JSON.
+#[derive(Debug, Clone, Copy)]
+pub struct JsonRpcCompat {
+    inner: KeyedFraming,
+    strict: bool,
+}
+
+impl JsonRpcCompat {
+    pub fn new() -> Self {
+        Self {
+            inner: KeyedFraming::new(Keys::JSON_RPC),
+            strict: false,
+        }
+    }
+
+    /// Require the version item on everything that's read.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.set_strict(strict);
+        self
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    fn add_version(msg: Value) -> Value {
+        match msg {
+            Value::Tag(tag, map) => match *map {
+                Value::Map(mut items) => {
+                    items.insert(0, (JSONRPC_KEY.into(), JSONRPC_VERSION.into()));
+                    Value::Tag(tag, Box::new(Value::Map(items)))
+                }
+                map => Value::Tag(tag, Box::new(map)),
+            },
+            msg => msg,
+        }
+    }
+
+    fn check_version(&self, msg: Value) -> Result<Value, TransportError> {
+        let version = match &msg {
+            Value::Tag(_, map) => map
+                .as_map()
+                .and_then(|items| {
+                    items
+                        .iter()
+                        .find(|(key, _)| key.as_text() == Some(JSONRPC_KEY))
+                })
+                .map(|(_, version)| version.as_text()),
+            _ => None,
+        };
+        match version {
+            Some(Some(JSONRPC_VERSION)) => Ok(msg),
+            None if !self.strict => Ok(msg),
+            _ => Err(ProtocolError::InvalidMessage.into()),
+        }
+    }
+}
+
+impl Default for JsonRpcCompat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framing for JsonRpcCompat {
+    type Msg = v0::RPCMsg;
+    type Batch = v0::RPCBatch;
+    fn encode(&mut self, msg: Outgoing<'_>, writer: &mut impl Write) -> Result<(), TransportError> {
+        write_value(&Self::add_version(self.inner.message_value(msg)), writer)
+    }
+    fn decode(&mut self, reader: &mut impl Read) -> Result<Self::Msg, TransportError> {
+        let msg = self.check_version(read_value(reader)?)?;
+        self.inner.decode_value(msg)
+    }
+    fn encode_batch(
+        &mut self,
+        batch: OutgoingBatch,
+        writer: &mut impl Write,
+    ) -> Result<(), TransportError> {
+        let items = self.inner.batch_values(batch);
+        let items = items.into_iter().map(Self::add_version).collect();
+        write_value(&Value::Array(items), writer)
+    }
+    fn decode_batch(&mut self, reader: &mut impl Read) -> Result<Self::Batch, TransportError> {
+        let items = read_array(reader)?
+            .into_iter()
+            .map(|item| self.check_version(item))
+            .collect::<Result<_, _>>()?;
+        self.inner.decode_batch_values(items)
+    }
+}
+
+/// The v1 protocol; see [v1].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct V1Framing;
+
+impl Framing for V1Framing {
+    type Msg = v1::RPCMsg;
+    type Batch = v1::RPCBatch;
+    fn encode(&mut self, msg: Outgoing<'_>, writer: &mut impl Write) -> Result<(), TransportError> {
+        match msg {
+            Outgoing::Request(request) => v1::RPCMsgRef::from(request).to_writer(writer),
+            Outgoing::Response(response) => v1::RPCMsgRef::from(response).to_writer(writer),
+            Outgoing::Control(control) => v1::RPCMsgRef::from(control).to_writer(writer),
+        }
+    }
+    fn decode(&mut self, reader: &mut impl Read) -> Result<Self::Msg, TransportError> {
+        v1::RPCMsg::from_reader(reader)
+    }
+    fn encode_batch(
+        &mut self,
+        batch: OutgoingBatch,
+        writer: &mut impl Write,
+    ) -> Result<(), TransportError> {
+        match batch {
+            OutgoingBatch::Requests(batch) => v1::RPCBatch::from(batch).to_writer(writer),
+            OutgoingBatch::Responses(batch) => v1::RPCBatch::from(batch).to_writer(writer),
+        }
+    }
+    fn decode_batch(&mut self, reader: &mut impl Read) -> Result<Self::Batch, TransportError> {
+        v1::RPCBatch::from_reader(reader)
+    }
+}
+
+/// A transport that sends and reads messages over `channel` using `framing`.
+pub struct FramedTransport<C: Read + Write, F: Framing> {
+    pub channel: C,
+    pub framing: F,
+    max_message_size: Option<usize>,
+}
+
+impl<C: Read + Write, F: Framing> FramedTransport<C, F> {
+    pub fn new(channel: C, framing: F) -> Self {
+        Self {
+            channel,
+            framing,
+            max_message_size: None,
+        }
+    }
+
+    pub fn into_inner(self) -> (C, F) {
+        (self.channel, self.framing)
+    }
+
+    /// Reject incoming messages bigger than `limit` bytes.
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.max_message_size = limit;
+    }
+
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    fn read_msg(&mut self) -> Result<F::Msg, TransportError> {
+        let framing = &mut self.framing;
+        super::read_limited(&mut self.channel, self.max_message_size, |r| {
+            framing.decode(r)
+        })
+    }
+
+    fn read_batch(&mut self) -> Result<F::Batch, TransportError> {
+        let framing = &mut self.framing;
+        super::read_limited(&mut self.channel, self.max_message_size, |r| {
+            framing.decode_batch(r)
+        })
+    }
+}
+
+impl<C: Read + Write, F: Framing> ClientTransport for FramedTransport<C, F> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        trace::send_request(request, || {
+            self.framing
+                .encode(Outgoing::Request(request), &mut self.channel)
+        })
+    }
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        trace::read_response(|| Ok(self.read_msg()?.try_into()?))
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        self.framing
+            .encode_batch(OutgoingBatch::Requests(batch), &mut self.channel)
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        Ok(self.read_batch()?.try_into()?)
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        self.framing
+            .encode(Outgoing::Control(control), &mut self.channel)
+    }
+}
+
+impl<C: Read + Write, F: Framing> ServerTransport for FramedTransport<C, F> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        trace::send_response(response, || {
+            self.framing
+                .encode(Outgoing::Response(response), &mut self.channel)
+        })
+    }
+    fn read_request(&mut self) -> Result<Request, Self::Error> {
+        trace::read_request(|| Ok(self.read_msg()?.try_into()?))
+    }
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        self.framing
+            .encode_batch(OutgoingBatch::Responses(batch), &mut self.channel)
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error> {
+        Ok(self.read_batch()?.try_into()?)
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        trace::read_incoming(|| Ok(self.read_msg()?.try_into()?))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{
+        CodecFraming, FramedTransport, Framing, JsonRpcCompat, KeyedFraming, Outgoing, V0Framing,
+        V1Framing,
+    };
+    use crate::error::{ProtocolError, TransportError};
+    use crate::proto::codec::{CborCodec, Ciborium};
+    use crate::proto::v0::{self, Keys};
+    use crate::proto::{Batch, ErrorValue, RequestBuilder, Response, Value};
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+    use crate::transport::Transport;
+    use crate::transport::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn v0_roundtrip() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut client = FramedTransport::new(s1, V0Framing);
+        // The other end is a plain v0 Transport, which should agree
+        let mut server = Transport::new(s2);
+        let req = RequestBuilder::new("add")
+            .arg(1)
+            .arg(2)
+            .id(1u8)
+            .build()
+            .unwrap();
+        client.send_request(req.clone()).unwrap();
+        assert_eq!(server.read_request().unwrap(), req);
+        let resp = Response::ok(3, 1u8.into());
+        server.send_response(resp.clone()).unwrap();
+        assert_eq!(client.read_response().unwrap(), resp);
+
+        let batch = Batch::new(vec![req.clone(), req]).unwrap();
+        client.send_request_batch(batch.clone()).unwrap();
+        assert_eq!(server.read_request_batch().unwrap(), batch);
+        // Asking for the wrong kind of message is an error
+        server.send_response(Response::ok(3, 1u8.into())).unwrap();
+        assert!(client.read_request().is_err());
+    }
+
+    #[test]
+    fn v1_roundtrip() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut client = FramedTransport::new(s1, V1Framing);
+        let mut server = FramedTransport::new(s2, V1Framing);
+        let req = RequestBuilder::new("add")
+            .arg(1)
+            .arg(2)
+            .id(1u8)
+            .build()
+            .unwrap();
+        client.send_request(req.clone()).unwrap();
+        assert_eq!(server.read_request().unwrap(), req);
+        let batch = Batch::new(vec![Response::ok(3, 1u8.into())]).unwrap();
+        server.send_response_batch(batch.clone()).unwrap();
+        assert_eq!(client.read_response_batch().unwrap(), batch);
+    }
+
+    #[test]
+    fn max_message_size() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut client = FramedTransport::new(s1, V0Framing);
+        let mut server = FramedTransport::new(s2, V0Framing).with_max_message_size(64);
+        assert_eq!(server.max_message_size(), Some(64));
+        let small = RequestBuilder::new("f").arg(1).id(1u8).build().unwrap();
+        client.send_request(small.clone()).unwrap();
+        assert_eq!(server.read_request().unwrap(), small);
+        let big = RequestBuilder::new("f")
+            .arg(vec![0u8; 100])
+            .id(2u8)
+            .build()
+            .unwrap();
+        client.send_request(big.clone()).unwrap();
+        assert!(matches!(
+            server.read_request(),
+            Err(TransportError::MessageTooLarge { limit: 64 })
+        ));
+
+        // The rest of the big Request is still waiting, so start over
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut client = FramedTransport::new(s1, V0Framing);
+        let mut server = FramedTransport::new(s2, V0Framing);
+        server.set_max_message_size(Some(64));
+        client
+            .send_request_batch(Batch::new(vec![big]).unwrap())
+            .unwrap();
+        assert!(matches!(
+            server.read_request_batch(),
+            Err(TransportError::MessageTooLarge { limit: 64 })
+        ));
+    }
+
+    // Counts what goes through it, and leaves the real work to ciborium
+    #[derive(Default)]
+    struct Recording {
+        encoded: usize,
+        decoded: usize,
+    }
+
+    impl CborCodec for Recording {
+        fn to_writer<T: serde::Serialize + ?Sized>(
+            &mut self,
+            value: &T,
+            writer: &mut impl Write,
+        ) -> Result<(), TransportError> {
+            self.encoded += 1;
+            Ciborium.to_writer(value, writer)
+        }
+        fn from_reader<T: serde::de::DeserializeOwned>(
+            &mut self,
+            reader: &mut impl Read,
+        ) -> Result<T, TransportError> {
+            self.decoded += 1;
+            Ciborium.from_reader(reader)
+        }
+    }
+
+    #[test]
+    fn custom_codec() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut client = FramedTransport::new(s1, CodecFraming(Recording::default()));
+        let mut server = Transport::new(s2);
+        let req = RequestBuilder::new("add").arg(1).id(1u8).build().unwrap();
+        client.send_request(req.clone()).unwrap();
+        client.send_cancel(1u8.into()).unwrap();
+        assert_eq!(server.read_request().unwrap(), req);
+        server.read_incoming().unwrap();
+        let batch = Batch::new(vec![Response::ok(1, 1u8.into())]).unwrap();
+        server.send_response_batch(batch.clone()).unwrap();
+        assert_eq!(client.read_response_batch().unwrap(), batch);
+        assert_eq!(client.framing.0.encoded, 2);
+        assert_eq!(client.framing.0.decoded, 1);
+    }
+
+    // ciborium, except that it won't encode floats
+    struct NoFloats;
+
+    fn has_float(value: &Value) -> bool {
+        match value {
+            Value::Float(_) => true,
+            Value::Array(items) => items.iter().any(has_float),
+            Value::Map(items) => items.iter().any(|(k, v)| has_float(k) || has_float(v)),
+            Value::Tag(_, v) => has_float(v),
+            _ => false,
+        }
+    }
+
+    impl CborCodec for NoFloats {
+        fn to_writer<T: serde::Serialize + ?Sized>(
+            &mut self,
+            value: &T,
+            writer: &mut impl Write,
+        ) -> Result<(), TransportError> {
+            match Value::serialized(value) {
+                Ok(v) if has_float(&v) => Err(TransportError::Encode("no floats".into())),
+                _ => Ciborium.to_writer(value, writer),
+            }
+        }
+        fn from_reader<T: serde::de::DeserializeOwned>(
+            &mut self,
+            reader: &mut impl Read,
+        ) -> Result<T, TransportError> {
+            Ciborium.from_reader(reader)
+        }
+    }
+
+    #[test]
+    fn encode_error_context() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut tr = FramedTransport::new(s1, CodecFraming(NoFloats));
+        let encode_error = |e| match e {
+            TransportError::Encode(msg) => msg,
+            e => panic!("expected an encode error, got {:?}", e),
+        };
+
+        let req = RequestBuilder::new("f").arg(1.5).id(1u8).build().unwrap();
+        let msg = encode_error(tr.send_request(req).unwrap_err());
+        assert_eq!(msg, "while encoding params: no floats");
+        let msg = encode_error(tr.send_response(Response::ok(1.5, 1u8.into())).unwrap_err());
+        assert_eq!(msg, "while encoding result: no floats");
+        let batch = Batch::new(vec![
+            Response::ok(1, 1u8.into()),
+            Response::err(ErrorValue::new(1, "x").with_data(1.5), 2u8.into()),
+        ])
+        .unwrap();
+        let msg = encode_error(tr.send_response_batch(batch).unwrap_err());
+        assert_eq!(msg, "while encoding error of batch item 1: no floats");
+        // Nothing was sent
+        drop(tr);
+        let mut rest = Vec::new();
+        let mut s2 = s2;
+        s2.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn json_rpc_keys() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut client = FramedTransport::new(s1, KeyedFraming::new(Keys::JSON_RPC));
+        let mut server = FramedTransport::new(s2, KeyedFraming::new(Keys::JSON_RPC));
+        let req = RequestBuilder::new("add")
+            .arg(1)
+            .arg(2)
+            .id(1u8)
+            .build()
+            .unwrap();
+        client.send_request(req.clone()).unwrap();
+        assert_eq!(server.read_request().unwrap(), req);
+        let batch = Batch::new(vec![
+            Response::ok(3, 1u8.into()),
+            Response::err(ErrorValue::internal("no"), 2u8.into()),
+        ])
+        .unwrap();
+        server.send_response_batch(batch.clone()).unwrap();
+        assert_eq!(client.read_response_batch().unwrap(), batch);
+
+        // The keys on the wire are JSON-RPC's
+        let mut buf = Vec::new();
+        KeyedFraming::new(Keys::JSON_RPC)
+            .encode(Outgoing::Request(&req), &mut buf)
+            .unwrap();
+        let keys: Vec<_> = match ciborium::de::from_reader(&buf[..]).unwrap() {
+            Value::Tag(_, map) => map.into_map().unwrap().into_iter().map(|(k, _)| k),
+            v => panic!("expected tagged message, got {:?}", v),
+        }
+        .collect();
+        let expected: Vec<Value> = vec!["method".into(), "params".into(), "id".into()];
+        assert_eq!(keys, expected);
+        assert_eq!(buf, v0::encode_request_with(&req, &Keys::JSON_RPC));
+        assert_eq!(v0::decode_request_with(&buf, &Keys::JSON_RPC).unwrap(), req);
+        // A v0 peer doesn't know what it is
+        assert!(v0::decode_request(&buf).is_err());
+        let resp = v0::encode_response_with(&Response::ok(3, 1u8.into()), &Keys::JSON_RPC);
+        assert_eq!(
+            v0::decode_response_with(&resp, &Keys::JSON_RPC).unwrap(),
+            Response::ok(3, 1u8.into())
+        );
+    }
+
+    #[test]
+    fn json_rpc_version() {
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut client = FramedTransport::new(s1, JsonRpcCompat::new());
+        let mut server = FramedTransport::new(s2, JsonRpcCompat::new().with_strict(true));
+        let req = RequestBuilder::new("add").arg(1).id(1u8).build().unwrap();
+        client.send_request(req.clone()).unwrap();
+        assert_eq!(server.read_request().unwrap(), req);
+        let batch = Batch::new(vec![Response::ok(1, 1u8.into())]).unwrap();
+        server.send_response_batch(batch.clone()).unwrap();
+        assert_eq!(client.read_response_batch().unwrap(), batch);
+
+        let mut buf = Vec::new();
+        let mut framing = JsonRpcCompat::new();
+        framing.encode(Outgoing::Request(&req), &mut buf).unwrap();
+        match ciborium::de::from_reader(&buf[..]).unwrap() {
+            Value::Tag(_, map) => assert_eq!(
+                map.as_map().unwrap()[0],
+                (Value::from("jsonrpc"), Value::from("2.0"))
+            ),
+            v => panic!("expected tagged message, got {:?}", v),
+        }
+
+        // No version is fine unless we're strict, but the wrong one never is
+        let plain = v0::encode_request_with(&req, &Keys::JSON_RPC);
+        assert_eq!(framing.decode(&mut &plain[..]).unwrap(), req.clone().into());
+        framing.set_strict(true);
+        assert!(matches!(
+            framing.decode(&mut &plain[..]),
+            Err(TransportError::Proto(ProtocolError::InvalidMessage))
+        ));
+        let mut msg: Value = ciborium::de::from_reader(&buf[..]).unwrap();
+        if let Value::Tag(_, map) = &mut msg {
+            for (key, value) in map.as_map_mut().unwrap() {
+                if key.as_text() == Some("jsonrpc") {
+                    *value = Value::from("1.0");
+                }
+            }
+        }
+        let mut wrong = Vec::new();
+        ciborium::ser::into_writer(&msg, &mut wrong).unwrap();
+        assert_ne!(wrong, buf);
+        framing.set_strict(false);
+        assert!(matches!(
+            framing.decode(&mut &wrong[..]),
+            Err(TransportError::Proto(ProtocolError::InvalidMessage))
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hooks that run around a [ServerTransport], for cross-cutting behavior
+//! like logging, auth, or metrics.
+//!
+//! [Intercepted] wraps a transport and a [RequestInterceptor]. Each incoming
+//! Request is shown to the interceptor before the server sees it, and each
+//! outgoing Response is shown to it before it's sent. If the interceptor
+//! rejects a Request, the error goes straight back to the client and the
+//! server never sees the Request at all.
+
+use super::simple::{Incoming, ServerTransport};
+use crate::error::ProtocolError;
+use crate::proto::{Batch, ErrorValue, Request, Response};
+
+pub trait RequestInterceptor {
+    /// Look at an incoming Request. Returning an error rejects it: the error
+    /// is sent back as its Response (unless it's a notification, which is
+    /// dropped) and the server moves on to the next Request.
+    fn before(&mut self, req: &Request) -> Result<(), ErrorValue>;
+
+    /// Look at a Response just before it's sent. This includes partial
+    /// results and the errors for Requests rejected by
+    /// [before](Self::before).
+    fn after(&mut self, resp: &Response);
+}
+
+/// A [ServerTransport] that runs a [RequestInterceptor] around another one.
+///
+/// Control messages aren't Requests, so they pass through untouched.
+pub struct Intercepted<T, I> {
+    pub inner: T,
+    pub interceptor: I,
+    // Errors for the Requests rejected from the last batch, which go out
+    // with the server's Response batch if there's going to be one.
+    rejected: Vec<Response>,
+}
+
+impl<T, I> Intercepted<T, I> {
+    pub fn new(inner: T, interceptor: I) -> Self {
+        Self {
+            inner,
+            interceptor,
+            rejected: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> (T, I) {
+        (self.inner, self.interceptor)
+    }
+}
+
+impl<T: ServerTransport, I: RequestInterceptor> Intercepted<T, I> {
+    /// Run `before` on `request`, and answer it if it's rejected. Returns the
+    /// Request if the server should see it.
+    fn check(&mut self, request: Request) -> Result<Option<Request>, T::Error> {
+        match self.interceptor.before(&request) {
+            Ok(()) => Ok(Some(request)),
+            Err(error) => {
+                if let Some(req_id) = request.req_id().clone() {
+                    self.send_response(Response::err(error, req_id))?;
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<T: ServerTransport, I: RequestInterceptor> ServerTransport for Intercepted<T, I> {
+    type Error = T::Error;
+    type SendResult = T::SendResult;
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        self.interceptor.after(response);
+        self.inner.send_response_ref(response)
+    }
+    fn read_request(&mut self) -> Result<Request, Self::Error> {
+        loop {
+            let request = self.inner.read_request()?;
+            if let Some(request) = self.check(request)? {
+                return Ok(request);
+            }
+        }
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        loop {
+            let request = match self.inner.read_incoming()? {
+                Incoming::Request(request) => request,
+                Incoming::Notification(notification) => notification.into(),
+                control => return Ok(control),
+            };
+            if let Some(request) = self.check(request)? {
+                return Ok(request.into());
+            }
+        }
+    }
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error>
+    where
+        Self::Error: From<ProtocolError>,
+    {
+        let mut responses = batch.into_inner();
+        responses.append(&mut self.rejected);
+        for response in &responses {
+            self.interceptor.after(response);
+        }
+        // Not empty, since we started with a Batch
+        self.inner
+            .send_response_batch(Batch::new(responses).unwrap())
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error>
+    where
+        Self::Error: From<ProtocolError>,
+    {
+        // Errors left over from the last batch mean the server never sent
+        // its Responses; they're still owed to the client.
+        self.send_rejected()?;
+        loop {
+            let mut accepted = Vec::new();
+            for request in self.inner.read_request_batch()? {
+                match self.interceptor.before(&request) {
+                    Ok(()) => accepted.push(request),
+                    Err(error) => {
+                        if let Some(req_id) = request.req_id().clone() {
+                            self.rejected.push(Response::err(error, req_id));
+                        }
+                    }
+                }
+            }
+            // If the server won't be sending a Response batch - because
+            // everything left is a notification, or nothing is left at all -
+            // the errors have to go out on their own.
+            if accepted.iter().all(Request::is_notification) {
+                self.send_rejected()?;
+            }
+            if let Ok(batch) = Batch::new(accepted) {
+                return Ok(batch);
+            }
+        }
+    }
+}
+
+impl<T: ServerTransport, I: RequestInterceptor> Intercepted<T, I>
+where
+    T::Error: From<ProtocolError>,
+{
+    /// Send the errors for rejected Requests as a batch of their own, if
+    /// there are any.
+    fn send_rejected(&mut self) -> Result<(), T::Error> {
+        let rejected = std::mem::take(&mut self.rejected);
+        for response in &rejected {
+            self.interceptor.after(response);
+        }
+        if let Ok(batch) = Batch::new(rejected) {
+            self.inner.send_response_batch(batch)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Intercepted, RequestInterceptor};
+    use crate::proto::{Batch, ErrorValue, Notification, Request, RequestBuilder, Response, Value};
+    use crate::transport::channel::ChannelTransport;
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+
+    #[derive(Default)]
+    struct NoSecrets {
+        sent: Vec<Response>,
+    }
+
+    impl RequestInterceptor for NoSecrets {
+        fn before(&mut self, req: &Request) -> Result<(), ErrorValue> {
+            match req.method().to_string().as_str() {
+                "secret" => Err(ErrorValue::new(403, "forbidden")),
+                _ => Ok(()),
+            }
+        }
+        fn after(&mut self, resp: &Response) {
+            self.sent.push(resp.clone());
+        }
+    }
+
+    fn request(method: &str, id: u8) -> Request {
+        RequestBuilder::new(method).id(id).build().unwrap()
+    }
+
+    #[test]
+    fn reject() {
+        let (mut client, server) = ChannelTransport::pair();
+        let mut server = Intercepted::new(server, NoSecrets::default());
+        client.send_request(request("secret", 1)).unwrap();
+        client
+            .send_notification(Notification::new("secret", None))
+            .unwrap();
+        client.send_request(request("public", 2)).unwrap();
+
+        // The server only sees the allowed Request...
+        let req = server.read_request().unwrap();
+        assert_eq!(req, request("public", 2));
+        server
+            .send_response(Response::ok("ok", 2u8.into()))
+            .unwrap();
+
+        // ...and the client gets an error for the rejected one.
+        let denied = Response::err(ErrorValue::new(403, "forbidden"), 1u8.into());
+        assert_eq!(client.read_response().unwrap(), denied);
+        let ok = Response::ok("ok", 2u8.into());
+        assert_eq!(client.read_response().unwrap(), ok);
+        assert_eq!(server.interceptor.sent, vec![denied, ok]);
+    }
+
+    #[test]
+    fn reject_in_batch() {
+        let (mut client, server) = ChannelTransport::pair();
+        let mut server = Intercepted::new(server, NoSecrets::default());
+        let batch = Batch::new(vec![request("secret", 1), request("public", 2)]).unwrap();
+        client.send_request_batch(batch).unwrap();
+        let only_secrets = Batch::new(vec![request("secret", 3)]).unwrap();
+        client.send_request_batch(only_secrets).unwrap();
+        client
+            .send_request_batch(Batch::new(vec![request("public", 4)]).unwrap())
+            .unwrap();
+
+        let batch = server.read_request_batch().unwrap();
+        assert_eq!(batch.into_inner(), vec![request("public", 2)]);
+        let reply = Batch::new(vec![Response::ok(Value::Null, 2u8.into())]).unwrap();
+        server.send_response_batch(reply).unwrap();
+        let ids: Vec<_> = client
+            .read_response_batch()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.req_id().clone())
+            .collect();
+        assert_eq!(ids, vec![2u8.into(), 1u8.into()]);
+
+        // A batch with nothing left is answered without the server
+        let batch = server.read_request_batch().unwrap();
+        assert_eq!(batch.into_inner(), vec![request("public", 4)]);
+        let denied = client.read_response_batch().unwrap().into_inner();
+        assert_eq!(denied[0].req_id(), &3u8.into());
+        assert!(denied[0].is_err());
+    }
+
+    #[test]
+    fn reject_before_notifications() {
+        let (mut client, server) = ChannelTransport::pair();
+        let mut server = Intercepted::new(server, NoSecrets::default());
+        let note = Request::from(Notification::new("public", None));
+        let batch = Batch::new(vec![request("secret", 1), note.clone()]).unwrap();
+        client.send_request_batch(batch).unwrap();
+
+        // The server has nothing to answer, so the error can't wait for it
+        let batch = server.read_request_batch().unwrap();
+        assert_eq!(batch.into_inner(), vec![note]);
+        let denied = client.read_response_batch().unwrap().into_inner();
+        assert_eq!(denied.len(), 1);
+        assert_eq!(denied[0].req_id(), &1u8.into());
+        assert_eq!(server.interceptor.sent, denied);
+    }
+}
@@ -0,0 +1,951 @@
+// SPDX-License-Identifier: Apache-2.0
+
+pub use bytes::{Buf, BufMut};
+pub use std::io::{Read, Write};
+
+pub mod channel;
+#[cfg(all(feature = "tokio", feature = "serde1"))]
+pub mod codec;
+#[cfg(feature = "zstd")]
+pub mod compress;
+#[cfg(feature = "serde1")]
+pub mod counting;
+#[cfg(feature = "serde1")]
+pub mod framed;
+#[cfg(feature = "serde1")]
+pub mod framing;
+pub mod intercept;
+pub mod pipeline;
+#[cfg(all(feature = "tokio", feature = "serde1"))]
+pub mod tokio_io;
+#[cfg(all(feature = "tokio", feature = "serde1"))]
+pub use tokio_io::AsyncTransport;
+#[cfg(feature = "serde1")]
+pub mod versioned;
+#[cfg(feature = "websocket")]
+pub mod ws;
+
+use crate::error::TransportError;
+use std::io;
+use std::time::Duration;
+
+/// Run `decode` on `reader`, failing with [TransportError::MessageTooLarge]
+/// if it tries to read more than `limit` bytes.
+pub(crate) fn read_limited<R, T, F>(
+    reader: R,
+    limit: Option<usize>,
+    decode: F,
+) -> Result<T, TransportError>
+where
+    R: Read,
+    F: FnOnce(&mut io::Take<R>) -> Result<T, TransportError>,
+{
+    // Allow one extra byte; if the decoder gets that far, the message is
+    // bigger than the limit, however the decode itself turned out.
+    let max = limit.map_or(u64::MAX, |l| l as u64 + 1);
+    let mut reader = reader.take(max);
+    let result = decode(&mut reader);
+    let used = (max - reader.limit()) as usize;
+    match (limit, result) {
+        (Some(limit), _) if reader.limit() == 0 => Err(TransportError::MessageTooLarge { limit }),
+        // A non-blocking or timed-out read just gives up, wherever it was.
+        (_, Err(TransportError::Io(e)))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Err(TransportError::Timeout)
+        }
+        // Running out before the first byte is just the end of the stream;
+        // running out partway through a message is a decode error.
+        (_, Err(TransportError::Io(e))) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            if used == 0 {
+                Err(TransportError::Eof)
+            } else {
+                Err(TransportError::Decode {
+                    msg: "truncated message".into(),
+                    pos: Some(used),
+                    raw: None,
+                })
+            }
+        }
+        (_, result) => result,
+    }
+}
+
+pub struct Transport<C: Read + Write> {
+    pub channel: C,
+    max_message_size: Option<usize>,
+    pub(crate) canonical: bool,
+    pub(crate) strict: bool,
+}
+
+impl<C> Transport<C>
+where
+    C: Read + Write,
+{
+    pub fn new(channel: C) -> Self {
+        Self {
+            channel,
+            max_message_size: None,
+            canonical: false,
+            strict: false,
+        }
+    }
+
+    /// Reject incoming messages bigger than `limit` bytes.
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.max_message_size = limit;
+    }
+
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    /// Send every message in CBOR's deterministic encoding (RFC 8949,
+    /// section 4.2.1), with the items of every map sorted - including named
+    /// params - so equal messages always encode to the same bytes. That's
+    /// what signing or byte-for-byte comparison needs; it costs an extra
+    /// copy of each message, so it's off by default.
+    ///
+    /// This is the same for [BufTransport] and [SplitTransport], and a
+    /// [VersionedTransport](versioned::VersionedTransport) wrapping a
+    /// Transport or BufTransport sends canonically too.
+    /// `send_request_indefinite` and `send_response_streaming` ignore it,
+    /// since indefinite lengths are never canonical. The other transports have no such option and always
+    /// use the default encoding.
+    pub fn with_canonical(mut self, canonical: bool) -> Self {
+        self.canonical = canonical;
+        self
+    }
+
+    pub fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    pub fn canonical(&self) -> bool {
+        self.canonical
+    }
+
+    /// Decode incoming messages strictly: a key that doesn't belong in the
+    /// kind of message it's in - a typo like `"fnn"`, say, or an `"ok"` in a
+    /// Request - is [ProtocolError::InvalidMessage](crate::error::ProtocolError::InvalidMessage)
+    /// instead of being ignored. That's for conformance testing, and for
+    /// catching a peer's bugs early; it's off by default, so that peers can
+    /// add keys of their own.
+    ///
+    /// This is the same for [BufTransport] and [SplitTransport], and a
+    /// [VersionedTransport](versioned::VersionedTransport) wrapping a
+    /// Transport or BufTransport reads its v0 messages strictly too. v1 has
+    /// no strict mode, so [V1](crate::proto::v1::V1) ignores it, as does a
+    /// VersionedTransport reading a v1 message.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    pub(crate) fn read_limited<'a, T, F>(&'a mut self, decode: F) -> Result<T, TransportError>
+    where
+        F: FnOnce(&mut io::Take<&'a mut C>) -> Result<T, TransportError>,
+    {
+        read_limited(&mut self.channel, self.max_message_size, decode)
+    }
+}
+
+/// A channel that can stop waiting for a read after a while, like a socket.
+pub trait ReadTimeout {
+    /// See [TcpStream::set_read_timeout](std::net::TcpStream::set_read_timeout).
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for std::net::TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::net::TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl ReadTimeout for std::os::unix::net::UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl<C> Transport<C>
+where
+    C: Read + Write + ReadTimeout,
+{
+    /// Give up on reads that wait longer than `timeout`, so a peer that never
+    /// replies can't block us forever. Reads that time out fail with
+    /// [TransportError::Timeout]; `None` means wait forever.
+    ///
+    /// A read can time out partway through a message, in which case the rest
+    /// of it is still waiting in the channel.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), TransportError> {
+        Ok(self.channel.set_read_timeout(timeout)?)
+    }
+}
+
+/// A BufTransport writes messages to the end of a buffer and reads them from
+/// the front.
+///
+/// Each read consumes exactly the bytes of the message it decoded, leaving
+/// anything after it in place. A `BytesMut` reuses the consumed space for
+/// later writes, so a long-lived BufTransport doesn't keep growing. For a
+/// buffer that should hold a single message, use
+/// [with_reject_trailing](Self::with_reject_trailing) to make anything after
+/// it an error.
+pub struct BufTransport<B: Buf + BufMut> {
+    pub buffer: B,
+    max_message_size: Option<usize>,
+    reject_trailing: bool,
+    pub(crate) canonical: bool,
+    pub(crate) strict: bool,
+}
+
+impl<B> BufTransport<B>
+where
+    B: Buf + BufMut,
+{
+    pub fn new(buffer: B) -> Self {
+        Self {
+            buffer,
+            max_message_size: None,
+            reject_trailing: false,
+            canonical: false,
+            strict: false,
+        }
+    }
+
+    /// Fail any read that leaves bytes in the buffer after the message, for
+    /// a buffer that should hold exactly one - like the contents of a file.
+    /// The message is still consumed, and the extra bytes are left in place.
+    /// That goes for the `try_read_*` methods too.
+    ///
+    /// This doesn't make sense for a buffer that's being used as a stream,
+    /// where the next message may already be waiting; that's the default.
+    pub fn with_reject_trailing(mut self, reject: bool) -> Self {
+        self.reject_trailing = reject;
+        self
+    }
+
+    /// See [with_reject_trailing](Self::with_reject_trailing).
+    pub fn set_reject_trailing(&mut self, reject: bool) {
+        self.reject_trailing = reject;
+    }
+
+    pub fn reject_trailing(&self) -> bool {
+        self.reject_trailing
+    }
+
+    /// Reject incoming messages bigger than `limit` bytes.
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.max_message_size = limit;
+    }
+
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    /// Send every message in CBOR's deterministic encoding; see
+    /// [Transport::with_canonical].
+    pub fn with_canonical(mut self, canonical: bool) -> Self {
+        self.canonical = canonical;
+        self
+    }
+
+    pub fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    pub fn canonical(&self) -> bool {
+        self.canonical
+    }
+
+    /// Decode incoming messages strictly; see [Transport::with_strict].
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Do a single read from `reader` and append whatever it gave to the
+    /// buffer, returning how many bytes that was; 0 means `reader` is at
+    /// EOF. Together with
+    /// [try_read_request](Self::try_read_request), this lets a server loop
+    /// pull messages off a socket as the bytes come in.
+    pub fn fill_from(&mut self, reader: &mut impl Read) -> io::Result<usize> {
+        let mut chunk = [0u8; 8192];
+        let n = reader.read(&mut chunk)?;
+        self.buffer.put_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    // If the buffer is contiguous we decode straight from its bytes, so a
+    // Decode error can carry a copy of the bytes that failed. Otherwise we
+    // read through it like a stream, and there's no copy. Either way, the
+    // buffer is advanced past exactly the bytes the decoder used.
+    pub(crate) fn read_limited<T, F>(&mut self, decode: F) -> Result<T, TransportError>
+    where
+        F: FnOnce(&mut io::Take<&mut dyn Read>) -> Result<T, TransportError>,
+    {
+        let limit = self.max_message_size;
+        let before = self.buffer.remaining();
+        let result = if self.buffer.chunk().len() < before {
+            let mut reader = (&mut self.buffer).reader();
+            read_limited(&mut reader as &mut dyn Read, limit, decode)
+        } else {
+            let bytes = self.buffer.chunk();
+            let mut unread = bytes;
+            let result = read_limited(&mut unread as &mut dyn Read, limit, decode);
+            let used = bytes.len() - unread.len();
+            let result = result.map_err(|e| e.with_raw(&bytes[..used]));
+            self.buffer.advance(used);
+            result
+        };
+        match result {
+            Ok(_) if self.reject_trailing && self.buffer.has_remaining() => {
+                Err(TransportError::Decode {
+                    msg: "trailing data after message".into(),
+                    pos: Some(before - self.buffer.remaining()),
+                    raw: None,
+                })
+            }
+            result => result,
+        }
+    }
+}
+
+/// A SplitTransport reads from one channel and writes to another - like the
+/// two halves of a split socket, or a BufReader and a BufWriter.
+pub struct SplitTransport<R: Read, W: Write> {
+    pub reader: R,
+    pub writer: W,
+    max_message_size: Option<usize>,
+    pub(crate) canonical: bool,
+    pub(crate) strict: bool,
+}
+
+impl<R, W> SplitTransport<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader,
+            writer,
+            max_message_size: None,
+            canonical: false,
+            strict: false,
+        }
+    }
+
+    /// Reject incoming messages bigger than `limit` bytes.
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.max_message_size = limit;
+    }
+
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    /// Send every message in CBOR's deterministic encoding; see
+    /// [Transport::with_canonical].
+    pub fn with_canonical(mut self, canonical: bool) -> Self {
+        self.canonical = canonical;
+        self
+    }
+
+    pub fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    pub fn canonical(&self) -> bool {
+        self.canonical
+    }
+
+    /// Decode incoming messages strictly; see [Transport::with_strict].
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+
+    pub(crate) fn read_limited<'a, T, F>(&'a mut self, decode: F) -> Result<T, TransportError>
+    where
+        F: FnOnce(&mut io::Take<&'a mut R>) -> Result<T, TransportError>,
+    {
+        read_limited(&mut self.reader, self.max_message_size, decode)
+    }
+}
+
+pub mod cbor {
+    use super::{Buf, BufMut, BufTransport, Read, SplitTransport, Transport, Write};
+    use crate::error::TransportError;
+    use crate::proto::Value;
+    use std::error::Error;
+
+    pub trait CBORTransport {
+        type Error: Error;
+        type SendResult;
+        fn send_cbor(&mut self, value: Value) -> Result<Self::SendResult, Self::Error>;
+        fn read_cbor(&mut self) -> Result<Value, Self::Error>;
+
+        /// Iterate over the Values in the channel, reading until EOF - for
+        /// example, to go through a capture of concatenated CBOR items.
+        fn read_cbor_iter(&mut self) -> Values<'_, Self>
+        where
+            Self: Sized,
+        {
+            Values {
+                transport: self,
+                done: false,
+            }
+        }
+    }
+
+    /// An iterator over the Values read from a [CBORTransport].
+    ///
+    /// The iterator ends when the channel reaches EOF between Values. If a
+    /// read fails for any other reason - including EOF in the middle of a
+    /// Value - the error is returned and then the iterator ends.
+    pub struct Values<'a, T> {
+        transport: &'a mut T,
+        done: bool,
+    }
+
+    impl<T> Iterator for Values<'_, T>
+    where
+        T: CBORTransport<Error = TransportError>,
+    {
+        type Item = Result<Value, TransportError>;
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.done {
+                return None;
+            }
+            match self.transport.read_cbor() {
+                Ok(value) => Some(Ok(value)),
+                Err(TransportError::Eof) => {
+                    self.done = true;
+                    None
+                }
+                Err(e) => {
+                    self.done = true;
+                    Some(Err(e))
+                }
+            }
+        }
+    }
+
+    impl<C: Read + Write> CBORTransport for Transport<C> {
+        type Error = TransportError;
+        type SendResult = ();
+        fn send_cbor(&mut self, value: Value) -> Result<Self::SendResult, Self::Error> {
+            Ok(ciborium::ser::into_writer(&value, &mut self.channel)?)
+        }
+        fn read_cbor(&mut self) -> Result<Value, Self::Error> {
+            self.read_limited(|r| Ok(ciborium::de::from_reader(r)?))
+        }
+    }
+    impl<B: Buf + BufMut> CBORTransport for BufTransport<B> {
+        type Error = TransportError;
+        type SendResult = ();
+        fn send_cbor(&mut self, value: Value) -> Result<Self::SendResult, Self::Error> {
+            Ok(ciborium::ser::into_writer(
+                &value,
+                (&mut self.buffer).writer(),
+            )?)
+        }
+        fn read_cbor(&mut self) -> Result<Value, Self::Error> {
+            self.read_limited(|r| Ok(ciborium::de::from_reader(r)?))
+        }
+    }
+    impl<R: Read, W: Write> CBORTransport for SplitTransport<R, W> {
+        type Error = TransportError;
+        type SendResult = ();
+        fn send_cbor(&mut self, value: Value) -> Result<Self::SendResult, Self::Error> {
+            Ok(ciborium::ser::into_writer(&value, &mut self.writer)?)
+        }
+        fn read_cbor(&mut self) -> Result<Value, Self::Error> {
+            self.read_limited(|r| Ok(ciborium::de::from_reader(r)?))
+        }
+    }
+}
+
+pub mod simple {
+    use crate::error::{ProtocolError, TransportError};
+    use crate::proto::{Batch, Control, Notification, Request, RequestID, Response, Value};
+    use std::convert::TryFrom;
+    use std::error::Error;
+
+    /// An incoming message for a server: a Request, classified by whether
+    /// the sender expects a Response, or a [Control] message.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Incoming {
+        Request(Request),
+        Notification(Notification),
+        Control(Control),
+    }
+
+    impl From<Request> for Incoming {
+        fn from(request: Request) -> Self {
+            if request.is_notification() {
+                // is_notification() means this can't fail
+                Incoming::Notification(Notification::try_from(request).unwrap())
+            } else {
+                Incoming::Request(request)
+            }
+        }
+    }
+
+    pub trait ClientTransport {
+        type Error: Error;
+        type SendResult;
+        /// Send a Request without giving it up, so it can be kept (or sent
+        /// again) afterward. [send_request](Self::send_request) calls this.
+        fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error>;
+        fn read_response(&mut self) -> Result<Response, Self::Error>;
+
+        /// Send several Requests as one batch. Transports that can't send
+        /// batches fail with [ProtocolError::Unsupported].
+        fn send_request_batch(
+            &mut self,
+            batch: Batch<Request>,
+        ) -> Result<Self::SendResult, Self::Error>
+        where
+            Self::Error: From<ProtocolError>,
+        {
+            let _ = batch;
+            Err(ProtocolError::Unsupported.into())
+        }
+
+        /// Read the Responses to a batch. Transports that can't read batches
+        /// fail with [ProtocolError::Unsupported].
+        fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error>
+        where
+            Self::Error: From<ProtocolError>,
+        {
+            Err(ProtocolError::Unsupported.into())
+        }
+
+        /// Send a [Control] message. Transports that can't send them fail
+        /// with [ProtocolError::Unsupported].
+        fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error>
+        where
+            Self::Error: From<ProtocolError>,
+        {
+            let _ = control;
+            Err(ProtocolError::Unsupported.into())
+        }
+
+        fn send_request(&mut self, request: Request) -> Result<Self::SendResult, Self::Error> {
+            self.send_request_ref(&request)
+        }
+
+        /// Send a Notification. There's no Response to read afterward.
+        fn send_notification(
+            &mut self,
+            notification: Notification,
+        ) -> Result<Self::SendResult, Self::Error> {
+            self.send_request(notification.into())
+        }
+
+        /// Ask the server to cancel the Request with id `req_id`. See
+        /// [Control] for how the server handles this.
+        fn send_cancel(&mut self, req_id: RequestID) -> Result<Self::SendResult, Self::Error>
+        where
+            Self::Error: From<ProtocolError>,
+        {
+            self.send_control(&Control::Cancel(req_id))
+        }
+
+        /// Send `request` and read the Response to it. If the Response has
+        /// a different RequestID, this fails with
+        /// [ProtocolError::UnexpectedMessage]. Notifications get no Response,
+        /// so they fail with [ProtocolError::InvalidRequestID] without being
+        /// sent.
+        fn request(&mut self, request: Request) -> Result<Response, Self::Error>
+        where
+            Self::Error: From<ProtocolError>,
+        {
+            let req_id = match request.req_id() {
+                Some(id) => id.clone(),
+                None => return Err(ProtocolError::InvalidRequestID.into()),
+            };
+            self.send_request(request)?;
+            let response = self.read_response()?;
+            if &req_id != response.req_id() {
+                return Err(ProtocolError::UnexpectedMessage.into());
+            }
+            Ok(response)
+        }
+    }
+
+    pub trait ServerTransport {
+        type Error: Error;
+        type SendResult;
+        /// Send a Response without giving it up.
+        /// [send_response](Self::send_response) calls this.
+        fn send_response_ref(
+            &mut self,
+            response: &Response,
+        ) -> Result<Self::SendResult, Self::Error>;
+        fn read_request(&mut self) -> Result<Request, Self::Error>;
+
+        /// Send the Responses to a batch. Transports that can't send batches
+        /// fail with [ProtocolError::Unsupported].
+        fn send_response_batch(
+            &mut self,
+            batch: Batch<Response>,
+        ) -> Result<Self::SendResult, Self::Error>
+        where
+            Self::Error: From<ProtocolError>,
+        {
+            let _ = batch;
+            Err(ProtocolError::Unsupported.into())
+        }
+
+        /// Read a batch of Requests. Transports that can't read batches fail
+        /// with [ProtocolError::Unsupported].
+        fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error>
+        where
+            Self::Error: From<ProtocolError>,
+        {
+            Err(ProtocolError::Unsupported.into())
+        }
+
+        /// Read the next message and classify it, so the server knows
+        /// whether it needs to send a Response - or whether it's a Control
+        /// message instead. [read_request](Self::read_request) treats a
+        /// Control message as an error.
+        ///
+        /// By default this just classifies [read_request](Self::read_request),
+        /// for transports that don't carry Control messages.
+        fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+            self.read_request().map(Into::into)
+        }
+
+        fn send_response(&mut self, response: Response) -> Result<Self::SendResult, Self::Error> {
+            self.send_response_ref(&response)
+        }
+
+        /// Send a partial result for the Request with id `req_id`. The call
+        /// still needs a final Response afterward.
+        fn send_partial(
+            &mut self,
+            req_id: RequestID,
+            value: Value,
+        ) -> Result<Self::SendResult, Self::Error> {
+            self.send_response(Response::partial(value, req_id))
+        }
+
+        /// Iterate over incoming Requests until the peer closes the channel.
+        fn requests(&mut self) -> Requests<'_, Self>
+        where
+            Self: Sized,
+        {
+            Requests {
+                transport: self,
+                done: false,
+            }
+        }
+    }
+
+    /// An iterator over the Requests read from a [ServerTransport].
+    ///
+    /// The iterator ends when the channel reaches EOF between messages. If a
+    /// read fails for any other reason - including EOF in the middle of a
+    /// message - the error is returned and then the iterator ends.
+    pub struct Requests<'a, T> {
+        transport: &'a mut T,
+        done: bool,
+    }
+
+    impl<T> Iterator for Requests<'_, T>
+    where
+        T: ServerTransport<Error = TransportError>,
+    {
+        type Item = Result<Request, TransportError>;
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.done {
+                return None;
+            }
+            match self.transport.read_request() {
+                Ok(request) => Some(Ok(request)),
+                Err(TransportError::Eof) => {
+                    self.done = true;
+                    None
+                }
+                Err(e) => {
+                    self.done = true;
+                    Some(Err(e))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cbor::CBORTransport;
+    use super::simple::{ClientTransport, Incoming, ServerTransport};
+    use super::{BufTransport, Transport};
+    use crate::error::{ProtocolError, TransportError};
+    use crate::proto::{Batch, Request, RequestBuilder, Response, Value};
+    use bytes::{BufMut, BytesMut};
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_transport() {
+        use std::os::unix::net::UnixStream;
+        let (s1, s2) = UnixStream::pair().unwrap();
+        let mut c_tr = Transport::new(s1);
+        let mut s_tr = Transport::new(s2);
+        let v = Value::from(vec![1, 2, 5]);
+        c_tr.send_cbor(v.clone()).unwrap();
+        assert_eq!(s_tr.read_cbor().unwrap(), v);
+    }
+
+    #[test]
+    fn buf_transport() {
+        let mut tr = BufTransport::new(BytesMut::with_capacity(4096));
+        let str_vec = ["one", "two", "three"];
+        let v = Value::Array(str_vec.iter().map(|s| Value::from(s.to_string())).collect());
+        tr.send_cbor(v.clone()).unwrap();
+        assert_eq!(
+            tr.buffer.len(),
+            str_vec.iter().map(|s| s.len() + 1).sum::<usize>() + 1
+        );
+        assert_eq!(tr.read_cbor().unwrap(), v);
+    }
+
+    #[test]
+    fn read_cbor_iter() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        let values = vec![Value::from(1), Value::from("two"), Value::from(vec![3])];
+        for v in &values {
+            tr.send_cbor(v.clone()).unwrap();
+        }
+        let read: Vec<Value> = tr.read_cbor_iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(read, values);
+        assert!(tr.buffer.is_empty());
+
+        // EOF partway through a Value is an error, not the end.
+        tr.send_cbor(Value::from("four")).unwrap();
+        tr.send_cbor(Value::from("five")).unwrap();
+        tr.buffer.truncate(tr.buffer.len() - 1);
+        let mut iter = tr.read_cbor_iter();
+        assert_eq!(iter.next().unwrap().unwrap(), Value::from("four"));
+        assert!(matches!(
+            iter.next(),
+            Some(Err(TransportError::Decode { .. }))
+        ));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn reject_trailing() {
+        let mut bytes = BytesMut::new();
+        ciborium::ser::into_writer(&Value::from("msg"), (&mut bytes).writer()).unwrap();
+        let len = bytes.len();
+        bytes.extend_from_slice(b"junk");
+
+        // By default the junk is just the start of the next message
+        let mut tr = BufTransport::new(bytes.clone());
+        assert_eq!(tr.read_cbor().unwrap(), Value::from("msg"));
+        assert_eq!(&tr.buffer[..], b"junk");
+
+        let mut tr = BufTransport::new(bytes).with_reject_trailing(true);
+        assert!(matches!(
+            tr.read_cbor(),
+            Err(TransportError::Decode { pos: Some(p), .. }) if p == len
+        ));
+        assert_eq!(&tr.buffer[..], b"junk");
+
+        tr.buffer.clear();
+        tr.send_cbor(Value::from("just one")).unwrap();
+        assert_eq!(tr.read_cbor().unwrap(), Value::from("just one"));
+    }
+
+    #[test]
+    fn pipelined() {
+        let mut tr = BufTransport::new(BytesMut::new());
+        let first = Value::from("first");
+        let second = Value::from(vec![2, 2]);
+        tr.send_cbor(first.clone()).unwrap();
+        let first_len = tr.buffer.len();
+        tr.send_cbor(second.clone()).unwrap();
+        // The start of a third message
+        tr.buffer.extend_from_slice(&[0x83, 0x01]);
+        let total = tr.buffer.len();
+
+        assert_eq!(tr.read_cbor().unwrap(), first);
+        assert_eq!(tr.buffer.len(), total - first_len);
+        assert_eq!(tr.read_cbor().unwrap(), second);
+        assert_eq!(&tr.buffer[..], &[0x83, 0x01]);
+
+        tr.buffer.extend_from_slice(&[0x02, 0x03]);
+        let third = Value::Array(vec![1.into(), 2.into(), 3.into()]);
+        assert_eq!(tr.read_cbor().unwrap(), third);
+        assert!(tr.buffer.is_empty());
+    }
+
+    #[test]
+    fn max_message_size() {
+        const MB: usize = 1024 * 1024;
+        let big = Value::Bytes(vec![0u8; 10 * MB]);
+        let mut tr = BufTransport::new(BytesMut::new()).with_max_message_size(MB);
+        tr.send_cbor(big.clone()).unwrap();
+        assert!(matches!(
+            tr.read_cbor(),
+            Err(TransportError::MessageTooLarge { limit: MB })
+        ));
+
+        let mut tr = BufTransport::new(BytesMut::new()).with_max_message_size(20 * MB);
+        tr.send_cbor(big.clone()).unwrap();
+        assert_eq!(tr.read_cbor().unwrap(), big);
+
+        // The limit is inclusive
+        let small = Value::from("hello");
+        let mut tr = BufTransport::new(BytesMut::new());
+        tr.send_cbor(small.clone()).unwrap();
+        tr.set_max_message_size(Some(tr.buffer.len()));
+        assert_eq!(tr.read_cbor().unwrap(), small);
+    }
+
+    #[test]
+    fn recursion_limit() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0x81; 1000]);
+        buf.extend_from_slice(&[0x00]);
+        let mut tr = BufTransport::new(buf);
+        assert!(matches!(
+            tr.read_cbor(),
+            Err(TransportError::RecursionLimitExceeded)
+        ));
+    }
+
+    #[test]
+    fn decode_error_raw_bytes() {
+        let mut buf = BytesMut::new();
+        // An array of two items, where the second one is malformed
+        buf.extend_from_slice(&[0x82, 0x01, 0x1c]);
+        let mut tr = BufTransport::new(buf);
+        match tr.read_cbor() {
+            Err(TransportError::Decode { raw, .. }) => {
+                assert_eq!(raw.as_deref(), Some(&[0x82, 0x01, 0x1c][..]))
+            }
+            r => panic!("expected a decode error, got {:?}", r),
+        }
+    }
+
+    // A transport with only the methods every implementation has to write.
+    struct OneAtATime(Vec<Request>);
+
+    impl ClientTransport for OneAtATime {
+        type Error = TransportError;
+        type SendResult = ();
+        fn send_request_ref(&mut self, request: &Request) -> Result<(), TransportError> {
+            self.0.push(request.clone());
+            Ok(())
+        }
+        fn read_response(&mut self) -> Result<Response, TransportError> {
+            Err(TransportError::Eof)
+        }
+    }
+
+    impl ServerTransport for OneAtATime {
+        type Error = TransportError;
+        type SendResult = ();
+        fn send_response_ref(&mut self, _response: &Response) -> Result<(), TransportError> {
+            Ok(())
+        }
+        fn read_request(&mut self) -> Result<Request, TransportError> {
+            self.0.pop().ok_or(TransportError::Eof)
+        }
+    }
+
+    #[test]
+    fn default_batches() {
+        let mut tr = OneAtATime(Vec::new());
+        let batch = Batch::new(vec![RequestBuilder::new("a").build().unwrap()]).unwrap();
+        assert!(matches!(
+            tr.send_request_batch(batch),
+            Err(TransportError::Proto(ProtocolError::Unsupported))
+        ));
+        assert!(matches!(
+            tr.read_response_batch(),
+            Err(TransportError::Proto(ProtocolError::Unsupported))
+        ));
+        assert!(tr.0.is_empty());
+    }
+
+    #[test]
+    fn default_control() {
+        let mut tr = OneAtATime(Vec::new());
+        assert!(matches!(
+            tr.send_cancel(1u8.into()),
+            Err(TransportError::Proto(ProtocolError::Unsupported))
+        ));
+        let request = RequestBuilder::new("a").id(1u8).build().unwrap();
+        tr.send_request(request.clone()).unwrap();
+        tr.send_request(RequestBuilder::new("b").build().unwrap())
+            .unwrap();
+        assert!(matches!(tr.read_incoming(), Ok(Incoming::Notification(_))));
+        assert_eq!(tr.read_incoming().unwrap(), Incoming::Request(request));
+        tr.send_response_ref(&Response::ok(1, 1u8.into())).unwrap();
+    }
+
+    #[test]
+    fn request_notification() {
+        // There'd be no Response to wait for, so it never goes out
+        let mut tr = OneAtATime(Vec::new());
+        assert!(matches!(
+            tr.request(RequestBuilder::new("a").build().unwrap()),
+            Err(TransportError::Proto(ProtocolError::InvalidRequestID))
+        ));
+        assert!(tr.0.is_empty());
+    }
+}
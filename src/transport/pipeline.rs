@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Matching up Responses that arrive out of order, without an async runtime.
+//!
+//! A client that pipelines its calls - sending several Requests before
+//! reading any Responses - can't count on the server answering them in
+//! order. [Pipelined] wraps its transport, and
+//! [read_response_for](Pipelined::read_response_for) reads until the
+//! Response to one particular Request turns up, keeping any others it reads
+//! along the way until they're asked for. It only keeps so many, though: a
+//! peer that never answers, or answers Requests nobody sent, would otherwise
+//! fill up memory with them.
+
+use std::collections::VecDeque;
+
+use super::simple::ClientTransport;
+use crate::error::TransportError;
+use crate::proto::{Batch, Control, Request, RequestID, Response};
+
+/// The default limit on the number of Responses a [Pipelined] holds.
+pub const DEFAULT_MAX_STASHED: usize = 1024;
+
+/// A [ClientTransport] that holds on to Responses nobody has asked for yet.
+///
+/// Every read checks the held Responses first; plain
+/// [read_response](ClientTransport::read_response) hands them back in the
+/// order they arrived before it reads anything new.
+pub struct Pipelined<T> {
+    pub inner: T,
+    stash: VecDeque<Response>,
+    max_stashed: usize,
+}
+
+impl<T> Pipelined<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            stash: VecDeque::new(),
+            max_stashed: DEFAULT_MAX_STASHED,
+        }
+    }
+
+    /// Hold at most `limit` Responses at once.
+    pub fn with_max_stashed(mut self, limit: usize) -> Self {
+        self.max_stashed = limit;
+        self
+    }
+
+    pub fn set_max_stashed(&mut self, limit: usize) {
+        self.max_stashed = limit;
+    }
+
+    pub fn max_stashed(&self) -> usize {
+        self.max_stashed
+    }
+
+    /// The Responses that have been read but not returned yet, oldest first.
+    pub fn stashed(&self) -> impl Iterator<Item = &Response> {
+        self.stash.iter()
+    }
+
+    /// Take the oldest held Response for `req_id`, if there is one.
+    fn take(&mut self, req_id: &RequestID) -> Option<Response> {
+        let index = self.stash.iter().position(|r| r.req_id() == req_id)?;
+        self.stash.remove(index)
+    }
+
+    /// Give back the inner transport. Any held Responses are dropped.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> Pipelined<T>
+where
+    T: ClientTransport<Error = TransportError>,
+{
+    /// Read until a Response for `req_id` arrives, and return it. Responses
+    /// for other Requests are held for later reads.
+    ///
+    /// A call with partial results gets a Response for each of them, so this
+    /// returns them one at a time, in order, before the final one.
+    ///
+    /// If there's no room to hold another Response, this fails with
+    /// [TransportError::TooManyStashed] rather than reading it. Nothing is
+    /// lost; [read_response](ClientTransport::read_response) can still
+    /// collect the ones being held.
+    pub fn read_response_for(&mut self, req_id: &RequestID) -> Result<Response, TransportError> {
+        if let Some(response) = self.take(req_id) {
+            return Ok(response);
+        }
+        loop {
+            if self.stash.len() >= self.max_stashed {
+                return Err(TransportError::TooManyStashed {
+                    limit: self.max_stashed,
+                });
+            }
+            let response = self.inner.read_response()?;
+            if response.req_id() == req_id {
+                return Ok(response);
+            }
+            self.stash.push_back(response);
+        }
+    }
+}
+
+impl<T> ClientTransport for Pipelined<T>
+where
+    T: ClientTransport<Error = TransportError>,
+{
+    type Error = TransportError;
+    type SendResult = T::SendResult;
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        self.inner.send_request_ref(request)
+    }
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        match self.stash.pop_front() {
+            Some(response) => Ok(response),
+            None => self.inner.read_response(),
+        }
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        self.inner.send_request_batch(batch)
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        self.inner.read_response_batch()
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        self.inner.send_control(control)
+    }
+}
+
+#[cfg(all(test, feature = "serde1"))]
+mod tests {
+    use super::Pipelined;
+    use crate::error::TransportError;
+    use crate::proto::{RequestBuilder, RequestID, Response};
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+    use crate::transport::BufTransport;
+    use bytes::BytesMut;
+
+    #[test]
+    fn out_of_order() {
+        let mut client = Pipelined::new(BufTransport::new(BytesMut::new()));
+        for id in 0..4u8 {
+            let req = RequestBuilder::new("echo").arg(id).id(id).build().unwrap();
+            client.send_request(req).unwrap();
+        }
+
+        // The server answers them backward
+        let mut server = BufTransport::new(client.inner.buffer.split());
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            ids.push(server.read_request().unwrap().req_id().clone().unwrap());
+        }
+        for id in ids.into_iter().rev() {
+            let resp = Response::ok(id.clone(), id);
+            server.send_response(resp).unwrap();
+        }
+        client.inner.buffer = server.buffer;
+
+        let id = |n: u8| RequestID::from(n);
+        assert_eq!(client.read_response_for(&id(1)).unwrap().req_id(), &id(1));
+        let held: Vec<_> = client.stashed().map(|r| r.req_id().clone()).collect();
+        assert_eq!(held, [id(3), id(2)]);
+        // Held Responses come back without touching the transport
+        assert_eq!(client.read_response_for(&id(3)).unwrap().req_id(), &id(3));
+        assert_eq!(client.read_response_for(&id(0)).unwrap().req_id(), &id(0));
+        assert_eq!(client.read_response().unwrap().req_id(), &id(2));
+        assert_eq!(client.stashed().count(), 0);
+        assert!(client.inner.buffer.is_empty());
+    }
+
+    #[test]
+    fn stash_limit() {
+        let mut client = Pipelined::new(BufTransport::new(BytesMut::new())).with_max_stashed(2);
+        let mut server = BufTransport::new(BytesMut::new());
+        for id in 1..=3u8 {
+            server.send_response(Response::ok(id, id.into())).unwrap();
+        }
+        client.inner.buffer = server.buffer;
+
+        assert!(matches!(
+            client.read_response_for(&RequestID::from(7u8)),
+            Err(TransportError::TooManyStashed { limit: 2 })
+        ));
+        assert_eq!(client.stashed().count(), 2);
+        // Making room lets it read the one that didn't fit
+        assert_eq!(
+            client.read_response().unwrap().req_id(),
+            &RequestID::from(1u8)
+        );
+        assert_eq!(
+            client
+                .read_response_for(&RequestID::from(3u8))
+                .unwrap()
+                .req_id(),
+            &RequestID::from(3u8)
+        );
+        assert_eq!(
+            client.read_response().unwrap().req_id(),
+            &RequestID::from(2u8)
+        );
+    }
+}
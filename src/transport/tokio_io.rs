@@ -0,0 +1,254 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! An async transport for tokio's AsyncRead/AsyncWrite channels.
+
+use bytes::{Buf, BytesMut};
+use std::convert::TryInto;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::TransportError;
+use crate::proto::v0::{RPCMsg, RPCMsgRef};
+use crate::proto::WriteCbor;
+use crate::proto::{Request, Response, Value};
+use crate::trace;
+
+/// AsyncTransport is the async equivalent of [Transport](super::Transport).
+///
+/// Outgoing messages are encoded into a buffer and then written in one go.
+/// The buffer is kept and reused, so sending doesn't allocate once it's big
+/// enough for the messages going through it. Incoming bytes are buffered
+/// until they hold a complete message, so it's fine for a message to arrive
+/// in several pieces.
+///
+/// There's no canonical option; messages always go out in the default
+/// encoding.
+pub struct AsyncTransport<C: AsyncRead + AsyncWrite + Unpin> {
+    pub channel: C,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+}
+
+impl<C> AsyncTransport<C>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(channel: C) -> Self {
+        Self {
+            channel,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    // The buffer is only ever emptied by writing it out. If a send gets cut
+    // off - an error, or the future being dropped - whatever's left of that
+    // message goes out ahead of the next one, rather than being thrown away.
+    async fn send_msg(&mut self, msg: RPCMsgRef<'_>) -> Result<(), TransportError> {
+        msg.to_buf(&mut self.write_buf)?;
+        self.channel.write_all_buf(&mut self.write_buf).await?;
+        Ok(self.channel.flush().await?)
+    }
+
+    // A message that fails to decode is dropped, so the next read starts
+    // after it. The decoder can give up before all of it has arrived, so we
+    // wait for the rest before skipping it. If it isn't even well-formed CBOR
+    // there's no telling where it ends, so everything buffered goes.
+    async fn read_msg(&mut self) -> Result<RPCMsg, TransportError> {
+        loop {
+            match RPCMsg::from_slice_partial(&self.read_buf) {
+                Ok(Some((msg, len))) => {
+                    self.read_buf.advance(len);
+                    return Ok(msg);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    let e = e.with_raw(&self.read_buf);
+                    self.skip_item().await;
+                    return Err(e);
+                }
+            }
+            if self.channel.read_buf(&mut self.read_buf).await? == 0 {
+                return Err(if self.read_buf.is_empty() {
+                    TransportError::Eof
+                } else {
+                    TransportError::Decode {
+                        msg: "truncated message".into(),
+                        pos: Some(self.read_buf.len()),
+                        raw: Some(self.read_buf.to_vec()),
+                    }
+                });
+            }
+        }
+    }
+
+    // Drop the CBOR item at the front of the buffer, reading until it's all
+    // there. If the channel fails or closes first, the buffer is cleared;
+    // the next read finds out why.
+    async fn skip_item(&mut self) {
+        loop {
+            let mut rest = &self.read_buf[..];
+            match ciborium::de::from_reader::<Value, _>(&mut rest) {
+                Ok(_) => {
+                    let len = self.read_buf.len() - rest.len();
+                    self.read_buf.advance(len);
+                    return;
+                }
+                Err(ciborium::de::Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    match self.channel.read_buf(&mut self.read_buf).await {
+                        Ok(n) if n > 0 => continue,
+                        _ => {}
+                    }
+                }
+                Err(_) => {}
+            }
+            self.read_buf.clear();
+            return;
+        }
+    }
+
+    pub async fn send_request(&mut self, request: Request) -> Result<(), TransportError> {
+        trace::send_request_async(&request, self.send_msg(RPCMsgRef::from(&request))).await
+    }
+
+    pub async fn read_response(&mut self) -> Result<Response, TransportError> {
+        trace::read_response_async(async { Ok(self.read_msg().await?.try_into()?) }).await
+    }
+
+    pub async fn send_response(&mut self, response: Response) -> Result<(), TransportError> {
+        trace::send_response_async(&response, self.send_msg(RPCMsgRef::from(&response))).await
+    }
+
+    pub async fn read_request(&mut self) -> Result<Request, TransportError> {
+        trace::read_request_async(async { Ok(self.read_msg().await?.try_into()?) }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncTransport;
+    use crate::error::TransportError;
+    use crate::proto::{Request, RequestBuilder, Response, Value};
+    use futures::FutureExt;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn duplex_roundtrip() {
+        let (c, s) = tokio::io::duplex(64);
+        let mut client = AsyncTransport::new(c);
+        let mut server = AsyncTransport::new(s);
+        let req = RequestBuilder::new("hello")
+            .arg("a fairly long argument, so the message takes a few reads")
+            .arg(vec![7u8; 100])
+            .id(1u8)
+            .build()
+            .unwrap();
+        let expected = req.clone();
+        let server = tokio::spawn(async move {
+            let req = server.read_request().await.unwrap();
+            assert_eq!(req, expected);
+            let resp = Response::new(Ok("yay".into()), req.req_id().clone().unwrap());
+            server.send_response(resp).await.unwrap();
+        });
+        client.send_request(req).await.unwrap();
+        let resp = client.read_response().await.unwrap();
+        assert_eq!(resp.result(), &Ok(Value::from("yay")));
+        server.await.unwrap();
+
+        // A closed channel is an error, not a hang
+        client.channel.shutdown().await.unwrap();
+        assert!(matches!(
+            client.read_response().await,
+            Err(TransportError::Eof)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reuse_write_buf() {
+        let (c, mut s) = tokio::io::duplex(4096);
+        let mut client = AsyncTransport::new(c);
+        let drain = tokio::spawn(async move {
+            tokio::io::copy(&mut s, &mut tokio::io::sink())
+                .await
+                .unwrap()
+        });
+        let req = RequestBuilder::new("ping").arg(1).id(1u8).build().unwrap();
+        let len = req.encoded_len().unwrap();
+
+        // Every send encodes into the same allocation, which never grows.
+        client.send_request(req.clone()).await.unwrap();
+        let (ptr, capacity) = (client.write_buf.as_ptr(), client.write_buf.capacity());
+        for _ in 1..10_000 {
+            client.send_request(req.clone()).await.unwrap();
+            assert!(client.write_buf.is_empty());
+            assert_eq!(client.write_buf.as_ptr(), ptr);
+            assert!(client.write_buf.capacity() <= capacity);
+        }
+        drop(client);
+        assert_eq!(drain.await.unwrap(), 10_000 * len as u64);
+    }
+
+    #[tokio::test]
+    async fn read_after_garbage() {
+        let (c, mut s) = tokio::io::duplex(4096);
+        let mut client = AsyncTransport::new(c);
+        let resp = Response::ok(1, 1u8.into());
+        let good = resp.to_cbor_vec().unwrap();
+
+        // Well-formed CBOR that isn't a message: just that item is skipped
+        s.write_all(&[0x01]).await.unwrap();
+        s.write_all(&good).await.unwrap();
+        assert!(client.read_response().await.is_err());
+        assert_eq!(client.read_response().await.unwrap(), resp);
+
+        // Not CBOR at all: what's buffered is dropped
+        s.write_all(&[0xff]).await.unwrap();
+        assert!(client.read_response().await.is_err());
+        assert!(client.read_buf.is_empty());
+        s.write_all(&good).await.unwrap();
+        assert_eq!(client.read_response().await.unwrap(), resp);
+    }
+
+    #[tokio::test]
+    async fn garbage_in_pieces() {
+        let (c, mut s) = tokio::io::duplex(4096);
+        let mut client = AsyncTransport::new(c);
+        let resp = Response::ok(1, 1u8.into());
+        let good = resp.to_cbor_vec().unwrap();
+        let mut bad = Vec::new();
+        let msg = Value::Tag(999, Box::new(Value::Map(vec![("ok".into(), 1.into())])));
+        ciborium::ser::into_writer(&msg, &mut bad).unwrap();
+
+        // The wrong tag fails the read as soon as it arrives, but the rest
+        // of the item is still on its way; it gets skipped too, once it's in
+        s.write_all(&bad[..3]).await.unwrap();
+        let (result, ()) = tokio::join!(client.read_response(), async {
+            tokio::task::yield_now().await;
+            s.write_all(&bad[3..]).await.unwrap();
+            s.write_all(&good).await.unwrap();
+        });
+        assert!(result.is_err());
+        assert_eq!(client.read_response().await.unwrap(), resp);
+    }
+
+    #[tokio::test]
+    async fn cut_off_send() {
+        let (c, s) = tokio::io::duplex(8);
+        let mut client = AsyncTransport::new(c);
+        let mut server = AsyncTransport::new(s);
+        let req = |id: u8| -> Request { RequestBuilder::new("hi").arg(id).id(id).build().unwrap() };
+
+        // The channel fills up partway through, and we give up on the send.
+        assert!(client.send_request(req(1)).now_or_never().is_none());
+        assert!(!client.write_buf.is_empty());
+
+        // The rest of it goes out before the next message.
+        let server = tokio::spawn(async move {
+            let first = server.read_request().await.unwrap();
+            let second = server.read_request().await.unwrap();
+            (first, second)
+        });
+        client.send_request(req(2)).await.unwrap();
+        assert_eq!(server.await.unwrap(), (req(1), req(2)));
+    }
+}
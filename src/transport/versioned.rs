@@ -0,0 +1,463 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A transport that reads both v0 and v1 messages.
+//!
+//! Every RPC message starts with a CBOR tag that says which version of the
+//! protocol it uses. [VersionedTransport] reads that tag first, then decodes
+//! the rest of the message with the matching codec. Outgoing messages all use
+//! the same, configured, [ProtoVersion].
+
+use std::convert::{TryFrom, TryInto};
+
+use super::simple::{ClientTransport, Incoming, ServerTransport};
+use super::{Buf, BufMut, BufTransport, Read, Transport, Write};
+use crate::error::{ProtocolError, TransportError};
+use crate::proto::{v0, v1, Batch, Control, Request, Response};
+use crate::proto::{ReadCbor, WriteCbor};
+use crate::trace;
+
+/// The versions of the ciborium-rpc protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ProtoVersion {
+    #[default]
+    V0,
+    V1,
+}
+
+impl ProtoVersion {
+    /// The CBOR tag that identifies messages of this version.
+    pub fn tag(self) -> u64 {
+        match self {
+            ProtoVersion::V0 => v0::TAG_ID_RPCV0,
+            ProtoVersion::V1 => v1::TAG_ID_RPCV1,
+        }
+    }
+
+    /// The version identified by `tag`, if there is one.
+    pub fn from_tag(tag: u64) -> Option<Self> {
+        match tag {
+            v0::TAG_ID_RPCV0 => Some(ProtoVersion::V0),
+            v1::TAG_ID_RPCV1 => Some(ProtoVersion::V1),
+            _ => None,
+        }
+    }
+}
+
+/// VersionedTransport wraps a Transport or BufTransport. It decodes each
+/// incoming message according to its tag, and sends messages as `version`.
+///
+/// A message without a known tag is a [ProtocolError::InvalidMessage]. The
+/// wrapped transport's `canonical` setting applies to every message it
+/// sends, but its `strict` setting only to the v0 messages it reads.
+pub struct VersionedTransport<T> {
+    pub inner: T,
+    pub version: ProtoVersion,
+}
+
+impl<T> VersionedTransport<T> {
+    pub fn new(inner: T, version: ProtoVersion) -> Self {
+        Self { inner, version }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+// ----- Decoding -------------------------------------------------------------
+
+// Read one CBOR data item header, appending its bytes to `head`. Returns the
+// major type and the argument, which is None for indefinite-length items.
+pub(crate) fn read_header(
+    reader: &mut impl Read,
+    head: &mut Vec<u8>,
+) -> Result<(u8, Option<u64>), TransportError> {
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    head.push(byte[0]);
+    let (major, info) = (byte[0] >> 5, byte[0] & 0x1f);
+    let len = match info {
+        0..=23 => return Ok((major, Some(info.into()))),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        31 => return Ok((major, None)),
+        _ => return Err(ProtocolError::InvalidMessage.into()),
+    };
+    let mut arg = [0u8; 8];
+    reader.read_exact(&mut arg[8 - len..])?;
+    head.extend_from_slice(&arg[8 - len..]);
+    Ok((major, Some(u64::from_be_bytes(arg))))
+}
+
+// Read up to (and including) the tag of the next message, or of the first
+// message in the next batch. Returns the version and the bytes that were read,
+// so the caller can put them back in front of the decoder.
+fn peek_version(
+    reader: &mut impl Read,
+    batch: bool,
+) -> Result<(ProtoVersion, Vec<u8>), TransportError> {
+    const ARRAY: u8 = 4;
+    const TAG: u8 = 6;
+    let mut head = Vec::new();
+    if batch {
+        match read_header(reader, &mut head)? {
+            (ARRAY, Some(0)) => return Err(ProtocolError::EmptyBatch.into()),
+            (ARRAY, _) => {}
+            _ => return Err(ProtocolError::InvalidMessage.into()),
+        }
+    }
+    match read_header(reader, &mut head)? {
+        (TAG, Some(tag)) => match ProtoVersion::from_tag(tag) {
+            Some(version) => Ok((version, head)),
+            None => Err(ProtocolError::InvalidMessage.into()),
+        },
+        _ => Err(ProtocolError::InvalidMessage.into()),
+    }
+}
+
+// `strict` is the wrapped transport's; v1 has no strict mode, so it only
+// applies to v0 messages.
+fn read_msg<T>(reader: &mut impl Read, strict: bool) -> Result<T, TransportError>
+where
+    T: TryFrom<v0::RPCMsg, Error = ProtocolError> + TryFrom<v1::RPCMsg, Error = ProtocolError>,
+{
+    let (version, head) = peek_version(reader, false)?;
+    let mut reader = Read::chain(&head[..], reader);
+    Ok(match version {
+        ProtoVersion::V0 => v0::RPCMsg::from_reader_with(&mut reader, strict)?.try_into()?,
+        ProtoVersion::V1 => v1::RPCMsg::from_reader(&mut reader)?.try_into()?,
+    })
+}
+
+// The first message in a batch picks the codec for all of them.
+fn read_batch<T>(reader: &mut impl Read, strict: bool) -> Result<Batch<T>, TransportError>
+where
+    T: TryFrom<v0::RPCMsg, Error = ProtocolError> + TryFrom<v1::RPCMsg, Error = ProtocolError>,
+{
+    let (version, head) = peek_version(reader, true)?;
+    let mut reader = Read::chain(&head[..], reader);
+    Ok(match version {
+        ProtoVersion::V0 => v0::RPCBatch::from_reader_with(&mut reader, strict)?.try_into()?,
+        ProtoVersion::V1 => v1::RPCBatch::from_reader(&mut reader)?.try_into()?,
+    })
+}
+
+// ----- Encoding -------------------------------------------------------------
+
+fn write_request(
+    version: ProtoVersion,
+    request: &Request,
+    writer: &mut impl Write,
+    canonical: bool,
+) -> Result<(), TransportError> {
+    match version {
+        ProtoVersion::V0 => v0::RPCMsgRef::from(request).to_writer_with(writer, canonical),
+        ProtoVersion::V1 => v1::RPCMsgRef::from(request).to_writer_with(writer, canonical),
+    }
+}
+
+fn write_response(
+    version: ProtoVersion,
+    response: &Response,
+    writer: &mut impl Write,
+    canonical: bool,
+) -> Result<(), TransportError> {
+    match version {
+        ProtoVersion::V0 => v0::RPCMsgRef::from(response).to_writer_with(writer, canonical),
+        ProtoVersion::V1 => v1::RPCMsgRef::from(response).to_writer_with(writer, canonical),
+    }
+}
+
+fn write_control(
+    version: ProtoVersion,
+    control: &Control,
+    writer: &mut impl Write,
+    canonical: bool,
+) -> Result<(), TransportError> {
+    match version {
+        ProtoVersion::V0 => v0::RPCMsgRef::from(control).to_writer_with(writer, canonical),
+        ProtoVersion::V1 => v1::RPCMsgRef::from(control).to_writer_with(writer, canonical),
+    }
+}
+
+fn write_batch<T>(
+    version: ProtoVersion,
+    batch: Batch<T>,
+    writer: &mut impl Write,
+    canonical: bool,
+) -> Result<(), TransportError>
+where
+    T: Into<v0::RPCMsg> + Into<v1::RPCMsg>,
+{
+    match version {
+        ProtoVersion::V0 => v0::RPCBatch::from(batch).to_writer_with(writer, canonical),
+        ProtoVersion::V1 => v1::RPCBatch::from(batch).to_writer_with(writer, canonical),
+    }
+}
+
+// ----- Transports -----------------------------------------------------------
+
+impl<C: Read + Write> ClientTransport for VersionedTransport<Transport<C>> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        let strict = self.inner.strict;
+        trace::read_response(|| self.inner.read_limited(|r| read_msg(r, strict)))
+    }
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        trace::send_request(request, || {
+            write_request(
+                self.version,
+                request,
+                &mut self.inner.channel,
+                self.inner.canonical,
+            )
+        })
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        write_control(
+            self.version,
+            control,
+            &mut self.inner.channel,
+            self.inner.canonical,
+        )
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        let strict = self.inner.strict;
+        self.inner.read_limited(|r| read_batch(r, strict))
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        write_batch(
+            self.version,
+            batch,
+            &mut self.inner.channel,
+            self.inner.canonical,
+        )
+    }
+}
+
+impl<C: Read + Write> ServerTransport for VersionedTransport<Transport<C>> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_request(&mut self) -> Result<Request, Self::Error> {
+        let strict = self.inner.strict;
+        trace::read_request(|| self.inner.read_limited(|r| read_msg(r, strict)))
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        let strict = self.inner.strict;
+        trace::read_incoming(|| self.inner.read_limited(|r| read_msg(r, strict)))
+    }
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        trace::send_response(response, || {
+            write_response(
+                self.version,
+                response,
+                &mut self.inner.channel,
+                self.inner.canonical,
+            )
+        })
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error> {
+        let strict = self.inner.strict;
+        self.inner.read_limited(|r| read_batch(r, strict))
+    }
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        write_batch(
+            self.version,
+            batch,
+            &mut self.inner.channel,
+            self.inner.canonical,
+        )
+    }
+}
+
+impl<B: Buf + BufMut> ClientTransport for VersionedTransport<BufTransport<B>> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        let strict = self.inner.strict;
+        trace::read_response(|| self.inner.read_limited(|r| read_msg(r, strict)))
+    }
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        trace::send_request(request, || {
+            write_request(
+                self.version,
+                request,
+                &mut (&mut self.inner.buffer).writer(),
+                self.inner.canonical,
+            )
+        })
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        write_control(
+            self.version,
+            control,
+            &mut (&mut self.inner.buffer).writer(),
+            self.inner.canonical,
+        )
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        let strict = self.inner.strict;
+        self.inner.read_limited(|r| read_batch(r, strict))
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        write_batch(
+            self.version,
+            batch,
+            &mut (&mut self.inner.buffer).writer(),
+            self.inner.canonical,
+        )
+    }
+}
+
+impl<B: Buf + BufMut> ServerTransport for VersionedTransport<BufTransport<B>> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_request(&mut self) -> Result<Request, Self::Error> {
+        let strict = self.inner.strict;
+        trace::read_request(|| self.inner.read_limited(|r| read_msg(r, strict)))
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        let strict = self.inner.strict;
+        trace::read_incoming(|| self.inner.read_limited(|r| read_msg(r, strict)))
+    }
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        trace::send_response(response, || {
+            write_response(
+                self.version,
+                response,
+                &mut (&mut self.inner.buffer).writer(),
+                self.inner.canonical,
+            )
+        })
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error> {
+        let strict = self.inner.strict;
+        self.inner.read_limited(|r| read_batch(r, strict))
+    }
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        write_batch(
+            self.version,
+            batch,
+            &mut (&mut self.inner.buffer).writer(),
+            self.inner.canonical,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProtoVersion, VersionedTransport};
+    use crate::error::{ProtocolError, TransportError};
+    use crate::proto::v0::TAG_ID_RPCV0;
+    use crate::proto::v1::V1;
+    use crate::proto::{Batch, RequestBuilder, Response, Value};
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+    use crate::transport::BufTransport;
+    use bytes::BytesMut;
+
+    #[test]
+    fn mixed_versions() {
+        let old = RequestBuilder::new("old").arg(0).id(0u8).build().unwrap();
+        let new = RequestBuilder::new("new").arg(1).id(1u8).build().unwrap();
+        let mut v0 = BufTransport::new(BytesMut::new());
+        v0.send_request_ref(&old).unwrap();
+        let mut v1 = V1(v0);
+        v1.send_request_ref(&new).unwrap();
+        v1.send_request_batch(Batch::new(vec![new.clone()]).unwrap())
+            .unwrap();
+
+        let mut tr = VersionedTransport::new(v1.into_inner(), ProtoVersion::V1);
+        assert_eq!(tr.read_request().unwrap(), old);
+        assert_eq!(tr.read_request().unwrap(), new);
+        assert_eq!(
+            tr.read_request_batch().unwrap().into_iter().next(),
+            Some(new)
+        );
+
+        // Replies go out as v1
+        let resp = Response::new(Ok("ok".into()), 1u8.into());
+        tr.send_response_ref(&resp).unwrap();
+        let mut v1 = V1(tr.into_inner());
+        assert_eq!(v1.read_response().unwrap(), resp);
+    }
+
+    #[test]
+    fn strict() {
+        let mut raw = Vec::new();
+        let msg = Value::Tag(
+            TAG_ID_RPCV0,
+            Box::new(Value::Map(vec![
+                ("fn".into(), "add".into()),
+                ("fnn".into(), "typo".into()),
+            ])),
+        );
+        ciborium::ser::into_writer(&msg, &mut raw).unwrap();
+        let mut batch = vec![0x81];
+        batch.extend_from_slice(&raw);
+
+        for strict in [false, true] {
+            let inner = BufTransport::new(BytesMut::from(&raw[..])).with_strict(strict);
+            let mut tr = VersionedTransport::new(inner, ProtoVersion::V0);
+            let result = tr.read_request();
+            assert_eq!(result.is_err(), strict, "{:?}", result);
+            tr.inner.buffer = BytesMut::from(&batch[..]);
+            assert_eq!(tr.read_request_batch().is_err(), strict);
+            if strict {
+                assert!(matches!(
+                    result,
+                    Err(TransportError::Proto(ProtocolError::InvalidMessage))
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn canonical() {
+        let req = RequestBuilder::new("sign")
+            .param("zz", 1)
+            .param("a", 2)
+            .id(1u8)
+            .build()
+            .unwrap();
+        for version in [ProtoVersion::V0, ProtoVersion::V1] {
+            let encode = |canonical| {
+                let inner = BufTransport::new(BytesMut::new()).with_canonical(canonical);
+                let mut tr = VersionedTransport::new(inner, version);
+                tr.send_request_ref(&req).unwrap();
+                tr.into_inner().buffer
+            };
+            // The sorted order puts "a" first
+            let (plain, sorted) = (encode(false), encode(true));
+            assert_ne!(plain, sorted);
+            let mut tr = VersionedTransport::new(BufTransport::new(sorted), version);
+            let first = tr.read_request().unwrap().params().clone().unwrap();
+            assert_eq!(first.into_iter().next(), Some((Some("a".into()), 2.into())));
+        }
+    }
+
+    #[test]
+    fn unknown_tag() {
+        let mut tr = VersionedTransport::new(BufTransport::new(BytesMut::new()), ProtoVersion::V0);
+        // A timestamp: tag 1, then the integer 0
+        tr.inner.buffer.extend_from_slice(&[0xc1, 0x00]);
+        assert!(matches!(
+            tr.read_request(),
+            Err(TransportError::Proto(ProtocolError::InvalidMessage))
+        ));
+    }
+}
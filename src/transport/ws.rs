@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A transport for WebSocket connections, using [tungstenite].
+//!
+//! Each v0 message (or batch) is sent as a single binary WebSocket message.
+//! WebSocket messages are already delimited, so there's no extra framing.
+
+use std::convert::TryInto;
+use tungstenite::{Message, WebSocket};
+
+use super::simple::{ClientTransport, Incoming, ServerTransport};
+use super::{Read, Write};
+use crate::error::{ProtocolError, TransportError};
+use crate::proto::v0::{RPCBatch, RPCMsg, RPCMsgRef};
+use crate::proto::{Batch, Control, Request, Response};
+use crate::proto::{ReadCbor, WriteCbor};
+use crate::trace;
+
+/// WsTransport sends and receives RPC messages over a [WebSocket].
+///
+/// Pings and pongs are handled by tungstenite and skipped over here. A Close
+/// message from the peer is reported as [TransportError::Eof], and a Text
+/// message is [ProtocolError::InvalidMessage]. There's no canonical option;
+/// messages always go out in the default encoding.
+pub struct WsTransport<S: Read + Write> {
+    pub socket: WebSocket<S>,
+}
+
+impl<S> WsTransport<S>
+where
+    S: Read + Write,
+{
+    pub fn new(socket: WebSocket<S>) -> Self {
+        Self { socket }
+    }
+
+    pub fn into_inner(self) -> WebSocket<S> {
+        self.socket
+    }
+
+    fn send_with<F>(&mut self, encode: F) -> Result<(), TransportError>
+    where
+        F: FnOnce(&mut Vec<u8>) -> Result<(), TransportError>,
+    {
+        let mut payload = Vec::new();
+        encode(&mut payload)?;
+        Ok(self.socket.send(Message::Binary(payload))?)
+    }
+
+    // Read the next binary message, and decode exactly one item from it.
+    fn read_with<T, F>(&mut self, decode: F) -> Result<T, TransportError>
+    where
+        F: FnOnce(&mut &[u8]) -> Result<T, TransportError>,
+    {
+        let payload = loop {
+            match self.socket.read()? {
+                Message::Binary(payload) => break payload,
+                Message::Text(_) => return Err(ProtocolError::InvalidMessage.into()),
+                Message::Close(_) => return Err(TransportError::Eof),
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            }
+        };
+        let mut bytes = &payload[..];
+        let item = decode(&mut bytes).map_err(|e| e.with_raw(&payload))?;
+        if !bytes.is_empty() {
+            return Err(TransportError::Decode {
+                msg: "trailing data in message".into(),
+                pos: Some(payload.len() - bytes.len()),
+                raw: Some(payload),
+            });
+        }
+        Ok(item)
+    }
+}
+
+impl<S: Read + Write> ClientTransport for WsTransport<S> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_response(&mut self) -> Result<Response, Self::Error> {
+        trace::read_response(|| Ok(self.read_with(|r| RPCMsg::from_reader(r))?.try_into()?))
+    }
+    fn send_request_ref(&mut self, request: &Request) -> Result<Self::SendResult, Self::Error> {
+        trace::send_request(request, || {
+            self.send_with(|buf| RPCMsgRef::from(request).to_writer(buf))
+        })
+    }
+    fn send_control(&mut self, control: &Control) -> Result<Self::SendResult, Self::Error> {
+        self.send_with(|buf| RPCMsgRef::from(control).to_writer(buf))
+    }
+    fn read_response_batch(&mut self) -> Result<Batch<Response>, Self::Error> {
+        Ok(self.read_with(|r| RPCBatch::from_reader(r))?.try_into()?)
+    }
+    fn send_request_batch(
+        &mut self,
+        batch: Batch<Request>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        self.send_with(|buf| RPCBatch::from(batch).to_writer(buf))
+    }
+}
+
+impl<S: Read + Write> ServerTransport for WsTransport<S> {
+    type Error = TransportError;
+    type SendResult = ();
+    fn read_request(&mut self) -> Result<Request, Self::Error> {
+        trace::read_request(|| Ok(self.read_with(|r| RPCMsg::from_reader(r))?.try_into()?))
+    }
+    fn read_incoming(&mut self) -> Result<Incoming, Self::Error> {
+        trace::read_incoming(|| Ok(self.read_with(|r| RPCMsg::from_reader(r))?.try_into()?))
+    }
+    fn send_response_ref(&mut self, response: &Response) -> Result<Self::SendResult, Self::Error> {
+        trace::send_response(response, || {
+            self.send_with(|buf| RPCMsgRef::from(response).to_writer(buf))
+        })
+    }
+    fn read_request_batch(&mut self) -> Result<Batch<Request>, Self::Error> {
+        Ok(self.read_with(|r| RPCBatch::from_reader(r))?.try_into()?)
+    }
+    fn send_response_batch(
+        &mut self,
+        batch: Batch<Response>,
+    ) -> Result<Self::SendResult, Self::Error> {
+        self.send_with(|buf| RPCBatch::from(batch).to_writer(buf))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::WsTransport;
+    use crate::error::TransportError;
+    use crate::proto::{RequestBuilder, Response};
+    use crate::transport::simple::{ClientTransport, ServerTransport};
+    use std::os::unix::net::UnixStream;
+    use tungstenite::protocol::Role;
+    use tungstenite::{Message, WebSocket};
+
+    #[test]
+    fn roundtrip() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut client = WsTransport::new(WebSocket::from_raw_socket(a, Role::Client, None));
+        let mut server = WsTransport::new(WebSocket::from_raw_socket(b, Role::Server, None));
+
+        let req = RequestBuilder::new("hello")
+            .arg("ws")
+            .id(1u8)
+            .build()
+            .unwrap();
+        client.send_request_ref(&req).unwrap();
+        client.socket.send(Message::Ping(vec![1])).unwrap();
+        assert_eq!(server.read_request().unwrap(), req);
+
+        let resp = Response::ok("hi", 1u8.into());
+        server.send_response_ref(&resp).unwrap();
+        assert_eq!(client.read_response().unwrap(), resp);
+
+        client.socket.send(Message::Text("hi".into())).unwrap();
+        assert!(matches!(
+            server.read_request(),
+            Err(TransportError::Proto(_))
+        ));
+
+        client.socket.close(None).unwrap();
+        assert!(matches!(server.read_request(), Err(TransportError::Eof)));
+    }
+}